@@ -0,0 +1,81 @@
+use std::io::{self, Write};
+
+use telemetry::{phase_name, Frame, Packet, StateFrame};
+
+const HEADER: &str = "seq,time_ms,phase,health,\
+acc_x_g,acc_y_g,acc_z_g,gyro_x_dps,gyro_y_dps,gyro_z_dps,\
+pressure_pa,temp_c,altitude_m,\
+mag_x_gauss,mag_y_gauss,mag_z_gauss,\
+latitude,longitude,gps_altitude_m,satellites";
+
+/// Writes decoded frames as CSV, one row per frame.
+///
+/// Readings the can didn't have are left empty.
+pub struct CsvWriter<W: Write> {
+    writer: W,
+}
+
+impl<W: Write> CsvWriter<W> {
+    /// Writes the header row.
+    pub fn new(mut writer: W) -> io::Result<Self> {
+        writeln!(writer, "{HEADER}")?;
+
+        Ok(Self { writer })
+    }
+
+    pub fn write_frame(&mut self, frame: &Frame) -> io::Result<()> {
+        match &frame.packet {
+            Packet::State(state) => self.write_state(frame, state),
+        }
+    }
+
+    fn write_state(&mut self, frame: &Frame, state: &StateFrame) -> io::Result<()> {
+        let w = &mut self.writer;
+
+        write!(
+            w,
+            "{},{},{},{:#06x}",
+            frame.seq,
+            frame.time_ms,
+            phase_name(state.phase),
+            state.health
+        )?;
+
+        write_fields(w, state.imu.map(|imu| imu.acc), 3)?;
+        write_fields(w, state.imu.map(|imu| imu.gyro), 3)?;
+        write_fields(
+            w,
+            state
+                .baro
+                .map(|baro| [baro.pressure, baro.temperature, baro.altitude]),
+            3,
+        )?;
+        write_fields(w, state.mag, 3)?;
+
+        match state.gps {
+            Some(gps) => write!(
+                w,
+                ",{:.7},{:.7},{:.2},{}",
+                gps.latitude, gps.longitude, gps.altitude, gps.satellites
+            )?,
+            None => write!(w, ",,,,")?,
+        }
+
+        writeln!(w)
+    }
+
+    pub fn flush(&mut self) -> io::Result<()> {
+        self.writer.flush()
+    }
+}
+
+fn write_fields<const N: usize>(
+    w: &mut impl Write,
+    values: Option<[f32; N]>,
+    count: usize,
+) -> io::Result<()> {
+    match values {
+        Some(values) => values.iter().try_for_each(|value| write!(w, ",{value}")),
+        None => (0..count).try_for_each(|_| write!(w, ",")),
+    }
+}
@@ -0,0 +1,323 @@
+//! Decodes the cansat's telemetry on the ground.
+//!
+//! Frames are read from the serial port of the LoRa receiver, or from a file
+//! recorded earlier, printed as they arrive and written to a CSV file. The
+//! sequence numbers of the frames are tracked, to report how many were lost.
+//!
+//! This runs on the host rather than the esp32, so has to be built for it:
+//!
+//! ```sh
+//! cargo +stable run -p ground-station --target x86_64-unknown-linux-gnu -- --file flight.bin
+//! ```
+
+mod csv;
+mod stats;
+
+use std::{
+    fs::File,
+    io::{self, BufWriter, ErrorKind, Read, Write},
+    path::PathBuf,
+    time::Duration,
+};
+
+use clap::Parser;
+use telemetry::{phase_name, Decoder, Frame, Packet};
+
+use crate::{
+    csv::CsvWriter,
+    stats::{LinkStats, SeqCheck},
+};
+
+#[derive(Parser, Debug)]
+#[command(about, version)]
+struct Args {
+    /// The serial port of the LoRa receiver, such as /dev/ttyUSB0 or COM3.
+    #[arg(short, long, conflicts_with = "file", required_unless_present = "file")]
+    port: Option<String>,
+
+    /// Baud rate of the serial port.
+    #[arg(short, long, default_value_t = 115_200)]
+    baud: u32,
+
+    /// Read frames recorded to a file, instead of from a serial port.
+    #[arg(short, long)]
+    file: Option<PathBuf>,
+
+    /// Where to write the decoded frames.
+    #[arg(short, long, default_value = "telemetry.csv")]
+    csv: PathBuf,
+
+    /// Only print the summary, not every frame.
+    #[arg(short, long)]
+    quiet: bool,
+}
+
+fn open_source(args: &Args) -> io::Result<Box<dyn Read>> {
+    if let Some(path) = &args.file {
+        return Ok(Box::new(File::open(path)?));
+    }
+
+    let port = args
+        .port
+        .as_deref()
+        .expect("clap requires a port or a file");
+
+    let serial = serialport::new(port, args.baud)
+        .timeout(Duration::from_millis(100))
+        .open()
+        .map_err(io::Error::from)?;
+
+    Ok(serial)
+}
+
+fn print_frame(frame: &Frame) {
+    match &frame.packet {
+        Packet::State(state) => {
+            print!(
+                "#{:<5} {:>9.3}s {:<9}",
+                frame.seq,
+                frame.time_ms as f64 / 1000.0,
+                phase_name(state.phase)
+            );
+
+            if let Some(baro) = state.baro {
+                print!(
+                    " alt={:>7.2}m p={:>9.0}Pa t={:>5.1}C",
+                    baro.altitude, baro.pressure, baro.temperature
+                );
+            }
+
+            if let Some(imu) = state.imu {
+                let [x, y, z] = imu.acc;
+                print!(" acc=({x:>6.2}, {y:>6.2}, {z:>6.2})g");
+            }
+
+            if let Some(gps) = state.gps {
+                print!(
+                    " gps=({:.6}, {:.6}) {} sats",
+                    gps.latitude, gps.longitude, gps.satellites
+                );
+            }
+
+            println!(" health={:#06x}", state.health);
+        }
+    }
+}
+
+/// Decodes the frames read from `source` until it runs out, writing them to
+/// `csv` (and printing them, unless `quiet`), and returns how many were
+/// received, lost and corrupted on the way.
+fn decode_stream<W: Write>(
+    source: &mut dyn Read,
+    csv: &mut CsvWriter<W>,
+    quiet: bool,
+) -> io::Result<LinkStats> {
+    let mut decoder = Decoder::new();
+    let mut stats = LinkStats::new();
+    let mut buf = [0; 1024];
+
+    loop {
+        let len = match source.read(&mut buf) {
+            // end of a recording
+            Ok(0) => break,
+            Ok(len) => len,
+            // nothing received from the serial port yet
+            Err(e) if e.kind() == ErrorKind::TimedOut || e.kind() == ErrorKind::Interrupted => {
+                continue
+            }
+            Err(e) => return Err(e),
+        };
+
+        for &byte in &buf[..len] {
+            decoder.push(byte);
+
+            while let Some(result) = decoder.next_frame() {
+                match result {
+                    Ok(frame) => {
+                        match stats.frame(frame.seq) {
+                            SeqCheck::InOrder => {}
+                            SeqCheck::Gap(missed) => eprintln!("{missed} frame(s) lost"),
+                            SeqCheck::OutOfOrder => {
+                                eprintln!("frame #{} arrived out of order", frame.seq)
+                            }
+                        }
+
+                        if !quiet {
+                            print_frame(&frame);
+                        }
+
+                        csv.write_frame(&frame)?;
+                    }
+                    Err(e) => {
+                        stats.error(e);
+                        eprintln!("bad frame: {e}");
+                    }
+                }
+            }
+        }
+
+        csv.flush()?;
+    }
+
+    csv.flush()?;
+
+    if decoder.buffered() > 0 {
+        eprintln!(
+            "{} trailing bytes weren't a whole frame",
+            decoder.buffered()
+        );
+    }
+
+    Ok(stats)
+}
+
+fn main() -> Result<(), Box<dyn std::error::Error>> {
+    let args = Args::parse();
+
+    let mut source = open_source(&args)?;
+    let mut csv = CsvWriter::new(BufWriter::new(File::create(&args.csv)?))?;
+
+    let stats = decode_stream(&mut source, &mut csv, args.quiet)?;
+
+    println!("{stats}");
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use telemetry::{Baro, StateFrame, MAX_FRAME_LEN};
+
+    use super::*;
+
+    fn frame(seq: u16) -> Frame {
+        Frame {
+            seq,
+            time_ms: seq as u32 * 200,
+            packet: Packet::State(StateFrame {
+                baro: Some(Baro {
+                    pressure: 95_000.0,
+                    temperature: 15.5,
+                    altitude: seq as f32,
+                }),
+                phase: 1,
+                health: 0x01ff,
+                ..Default::default()
+            }),
+        }
+    }
+
+    fn record(recording: &mut Vec<u8>, frame: &Frame) {
+        let mut buf = [0; MAX_FRAME_LEN];
+        let len = frame.encode(&mut buf).unwrap();
+        recording.extend_from_slice(&buf[..len]);
+    }
+
+    /// Hands out a recording a few bytes at a time, timing out in between like
+    /// a serial port which hasn't received anything yet.
+    struct Trickle<'a> {
+        bytes: &'a [u8],
+        timed_out: bool,
+    }
+
+    impl Read for Trickle<'_> {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            self.timed_out = !self.timed_out;
+            if self.timed_out {
+                return Err(ErrorKind::TimedOut.into());
+            }
+
+            let len = buf.len().min(self.bytes.len()).min(7);
+            buf[..len].copy_from_slice(&self.bytes[..len]);
+            self.bytes = &self.bytes[len..];
+
+            Ok(len)
+        }
+    }
+
+    /// A recording of frames 0 to 9, with 3 and 4 lost, 7 arriving after 8, a
+    /// corrupted copy of 9 before the real one, and noise in between.
+    fn recording() -> Vec<u8> {
+        let mut recording = b"noise before the first frame".to_vec();
+
+        for seq in [0, 1, 2, 5, 6, 8, 7] {
+            record(&mut recording, &frame(seq));
+        }
+
+        let mut corrupted = Vec::new();
+        record(&mut corrupted, &frame(9));
+        corrupted[20] ^= 0x40;
+        recording.extend_from_slice(&corrupted);
+        recording.extend_from_slice(&[0x00, 0xCA, 0xFF]);
+
+        record(&mut recording, &frame(9));
+
+        // the end of a frame cut off by the recording stopping
+        recording.extend_from_slice(&corrupted[..10]);
+
+        recording
+    }
+
+    fn decode_recording(source: &mut dyn Read) -> (LinkStats, String) {
+        let mut out = Vec::new();
+        let mut csv = CsvWriter::new(&mut out).unwrap();
+
+        let stats = decode_stream(source, &mut csv, true).unwrap();
+
+        (stats, String::from_utf8(out).unwrap())
+    }
+
+    fn assert_decoded(stats: &LinkStats, csv: &str) {
+        assert_eq!(stats.received, 8);
+        assert_eq!(stats.dropped, 3);
+        assert_eq!(stats.out_of_order, 1);
+        assert_eq!(stats.crc_errors, 1);
+        assert_eq!(stats.invalid, 0);
+
+        let mut lines = csv.lines();
+        assert!(lines
+            .next()
+            .unwrap()
+            .starts_with("seq,time_ms,phase,health,"));
+
+        let rows: Vec<&str> = lines.collect();
+        let seqs: Vec<&str> = rows
+            .iter()
+            .map(|row| row.split(',').next().unwrap())
+            .collect();
+        assert_eq!(seqs, ["0", "1", "2", "5", "6", "8", "7", "9"]);
+
+        assert_eq!(rows[3], "5,1000,Ascent,0x01ff,,,,,,,95000,15.5,5,,,,,,,");
+    }
+
+    #[test]
+    fn decodes_a_recording_to_csv() {
+        let recording = recording();
+        let (stats, csv) = decode_recording(&mut recording.as_slice());
+
+        assert_decoded(&stats, &csv);
+        assert_eq!(
+            stats.to_string(),
+            "8 received, 3 dropped, 1 out of order, 1 corrupt, 0 invalid (72.7% received)"
+        );
+    }
+
+    #[test]
+    fn decodes_frames_split_across_reads() {
+        let recording = recording();
+        let (stats, csv) = decode_recording(&mut Trickle {
+            bytes: &recording,
+            timed_out: false,
+        });
+
+        assert_decoded(&stats, &csv);
+    }
+
+    #[test]
+    fn decodes_an_empty_recording() {
+        let (stats, csv) = decode_recording(&mut io::empty());
+
+        assert_eq!(stats.received, 0);
+        assert_eq!(csv.lines().count(), 1);
+    }
+}
@@ -0,0 +1,98 @@
+use std::fmt;
+
+use telemetry::FrameError;
+
+/// Keeps count of the frames received, and of the ones that were lost or
+/// arrived out of order, from their sequence numbers.
+#[derive(Clone, Debug, Default)]
+pub struct LinkStats {
+    /// The sequence number expected next.
+    next_seq: Option<u16>,
+    pub received: u64,
+    /// Frames skipped over in the sequence - including any which turn up late.
+    pub dropped: u64,
+    /// Frames which arrived after a later one, or twice.
+    pub out_of_order: u64,
+    /// Frames which arrived corrupted.
+    pub crc_errors: u64,
+    /// Frames which had a valid CRC but couldn't be decoded.
+    pub invalid: u64,
+}
+
+/// What a frame's sequence number says about the frames before it.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum SeqCheck {
+    InOrder,
+    /// This many frames were skipped since the last one.
+    Gap(u16),
+    /// The frame is older than (or the same as) one already received.
+    OutOfOrder,
+}
+
+impl LinkStats {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Records a frame with sequence number `seq`.
+    pub fn frame(&mut self, seq: u16) -> SeqCheck {
+        self.received += 1;
+
+        let Some(expected) = self.next_seq else {
+            self.next_seq = Some(seq.wrapping_add(1));
+            return SeqCheck::InOrder;
+        };
+
+        let gap = seq.wrapping_sub(expected);
+
+        // sequence numbers wrap, so anything more than half way round is
+        // taken to be from the past
+        if gap >= u16::MAX / 2 {
+            self.out_of_order += 1;
+            return SeqCheck::OutOfOrder;
+        }
+
+        self.next_seq = Some(seq.wrapping_add(1));
+
+        if gap == 0 {
+            SeqCheck::InOrder
+        } else {
+            self.dropped += gap as u64;
+            SeqCheck::Gap(gap)
+        }
+    }
+
+    /// Records a frame which couldn't be decoded.
+    pub fn error(&mut self, error: FrameError) {
+        match error {
+            FrameError::BadCrc => self.crc_errors += 1,
+            _ => self.invalid += 1,
+        }
+    }
+
+    /// The fraction of frames sent which were received intact.
+    pub fn success_rate(&self) -> f64 {
+        let sent = self.received + self.dropped;
+
+        if sent == 0 {
+            return 1.0;
+        }
+
+        self.received as f64 / sent as f64
+    }
+}
+
+impl fmt::Display for LinkStats {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} received, {} dropped, {} out of order, {} corrupt, {} invalid ({:.1}% received)",
+            self.received,
+            self.dropped,
+            self.out_of_order,
+            self.crc_errors,
+            self.invalid,
+            self.success_rate() * 100.0
+        )
+    }
+}
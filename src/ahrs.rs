@@ -0,0 +1,650 @@
+use libm::sqrtf;
+use nalgebra::{Quaternion, UnitQuaternion, Vector3};
+
+use crate::{
+    bus::{BusSubscriber, DataBus},
+    mpu6050::MPU_BUS,
+    prelude::*,
+    qmc5883l::{MagData, MAG_BUS},
+    sample::Sample,
+    watchdog::{self, TaskId},
+};
+
+pub type AttitudeBus = DataBus<Sample<AttitudeData>, 4, 8>;
+pub static ATTITUDE_BUS: AttitudeBus = DataBus::new();
+pub type AttitudeSubscriber = BusSubscriber<'static, Sample<AttitudeData>, 4, 8>;
+
+/// Default filter gain - higher values trust the accelerometer and magnetometer
+/// more and the gyroscope less.
+pub const DEFAULT_BETA: f32 = 0.1;
+
+/// One of the magnetometer's axes, or its reverse.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum MagAxis {
+    X,
+    Y,
+    Z,
+    NegX,
+    NegY,
+    NegZ,
+}
+
+impl MagAxis {
+    fn pick(self, mag: Vector3<f32>) -> f32 {
+        match self {
+            Self::X => mag.x,
+            Self::Y => mag.y,
+            Self::Z => mag.z,
+            Self::NegX => -mag.x,
+            Self::NegY => -mag.y,
+            Self::NegZ => -mag.z,
+        }
+    }
+}
+
+/// Which of the magnetometer's axes points along each of the MPU6050's, as
+/// the two chips are mounted on the board.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct MagAxes {
+    pub x: MagAxis,
+    pub y: MagAxis,
+    pub z: MagAxis,
+}
+
+impl MagAxes {
+    /// The chips' axes already line up.
+    pub const ALIGNED: Self = Self {
+        x: MagAxis::X,
+        y: MagAxis::Y,
+        z: MagAxis::Z,
+    };
+
+    /// Turns a magnetometer reading into the MPU6050's frame.
+    pub fn to_mpu_frame(&self, mag: Vector3<f32>) -> Vector3<f32> {
+        Vector3::new(self.x.pick(mag), self.y.pick(mag), self.z.pick(mag))
+    }
+}
+
+impl Default for MagAxes {
+    fn default() -> Self {
+        Self::ALIGNED
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct AhrsConfig {
+    /// The filter gain.
+    ///
+    /// Defaults to [DEFAULT_BETA].
+    pub beta: f32,
+    /// How the magnetometer is mounted relative to the MPU6050.
+    ///
+    /// Defaults to [MagAxes::ALIGNED].
+    pub mag_axes: MagAxes,
+    /// How much older than a gyroscope sample the latest magnetometer reading
+    /// can be and still be fused with it - past this the filter uses the IMU
+    /// alone.
+    ///
+    /// Defaults to 300ms, three of the magnetometer's readings at 10Hz.
+    pub max_mag_age: Duration,
+}
+
+impl Default for AhrsConfig {
+    fn default() -> Self {
+        Self {
+            beta: DEFAULT_BETA,
+            mag_axes: MagAxes::default(),
+            max_mag_age: Duration::from_millis(300),
+        }
+    }
+}
+
+impl AhrsConfig {
+    /// The magnetometer reading to fuse with a gyroscope sample captured at
+    /// `time`, in the MPU6050's frame - or zeros, which leave the heading to the
+    /// gyroscope, if there is no valid reading recent enough.
+    pub fn mag_at(&self, time: Instant, mag: Option<Sample<MagData>>) -> Vector3<f32> {
+        mag.filter(|mag| time.saturating_duration_since(mag.time) <= self.max_mag_age)
+            .and_then(|mag| mag.data.mag.valid())
+            .map(|mag| self.mag_axes.to_mpu_frame(mag))
+            .unwrap_or_default()
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct AttitudeData {
+    /// rotates vectors from the body frame into the earth frame (x north, z up)
+    pub quaternion: UnitQuaternion<f32>,
+    /// roll, pitch and yaw in degrees
+    pub euler: Vector3<f32>,
+}
+
+impl Default for AttitudeData {
+    fn default() -> Self {
+        Self {
+            quaternion: UnitQuaternion::identity(),
+            euler: Vector3::zeros(),
+        }
+    }
+}
+
+impl From<UnitQuaternion<f32>> for AttitudeData {
+    fn from(quaternion: UnitQuaternion<f32>) -> Self {
+        let (roll, pitch, yaw) = quaternion.euler_angles();
+
+        Self {
+            quaternion,
+            euler: Vector3::new(roll, pitch, yaw).map(|x| x.to_degrees()),
+        }
+    }
+}
+
+/// Madgwick's gradient descent orientation filter.
+///
+/// See <https://x-io.co.uk/open-source-imu-and-ahrs-algorithms/>.
+#[derive(Clone, Copy, Debug)]
+pub struct Madgwick {
+    beta: f32,
+    /// `(w, x, y, z)`
+    q: [f32; 4],
+}
+
+impl Madgwick {
+    pub const fn new(beta: f32) -> Self {
+        Self {
+            beta,
+            q: [1.0, 0.0, 0.0, 0.0],
+        }
+    }
+
+    pub fn beta(&self) -> f32 {
+        self.beta
+    }
+
+    pub fn set_beta(&mut self, beta: f32) {
+        self.beta = beta;
+    }
+
+    pub fn quaternion(&self) -> UnitQuaternion<f32> {
+        let [w, x, y, z] = self.q;
+        UnitQuaternion::new_unchecked(Quaternion::new(w, x, y, z))
+    }
+
+    pub fn attitude(&self) -> AttitudeData {
+        self.quaternion().into()
+    }
+
+    /// Fuses a gyroscope (in radians per second), accelerometer and magnetometer
+    /// reading taken `dt` seconds after the previous one.
+    ///
+    /// The accelerometer and magnetometer can be in any unit, as only their
+    /// direction is used. If the magnetometer reading is all zeros, this falls
+    /// back to [Madgwick::update_imu].
+    pub fn update(&mut self, dt: f32, gyro: Vector3<f32>, acc: Vector3<f32>, mag: Vector3<f32>) {
+        if mag.x == 0.0 && mag.y == 0.0 && mag.z == 0.0 {
+            self.update_imu(dt, gyro, acc);
+            return;
+        }
+
+        let [q0, q1, q2, q3] = self.q;
+        let (gx, gy, gz) = (gyro.x, gyro.y, gyro.z);
+
+        // Rate of change of quaternion from gyroscope
+        let mut q_dot = [
+            0.5 * (-q1 * gx - q2 * gy - q3 * gz),
+            0.5 * (q0 * gx + q2 * gz - q3 * gy),
+            0.5 * (q0 * gy - q1 * gz + q3 * gx),
+            0.5 * (q0 * gz + q1 * gy - q2 * gx),
+        ];
+
+        if let (Some((ax, ay, az)), Some((mx, my, mz))) = (normalise(acc), normalise(mag)) {
+            // Auxiliary variables to avoid repeated arithmetic
+            let _2q0mx = 2.0 * q0 * mx;
+            let _2q0my = 2.0 * q0 * my;
+            let _2q0mz = 2.0 * q0 * mz;
+            let _2q1mx = 2.0 * q1 * mx;
+            let _2q0 = 2.0 * q0;
+            let _2q1 = 2.0 * q1;
+            let _2q2 = 2.0 * q2;
+            let _2q3 = 2.0 * q3;
+            let _2q0q2 = 2.0 * q0 * q2;
+            let _2q2q3 = 2.0 * q2 * q3;
+            let q0q0 = q0 * q0;
+            let q0q1 = q0 * q1;
+            let q0q2 = q0 * q2;
+            let q0q3 = q0 * q3;
+            let q1q1 = q1 * q1;
+            let q1q2 = q1 * q2;
+            let q1q3 = q1 * q3;
+            let q2q2 = q2 * q2;
+            let q2q3 = q2 * q3;
+            let q3q3 = q3 * q3;
+
+            // Reference direction of Earth's magnetic field
+            let hx =
+                mx * q0q0 - _2q0my * q3 + _2q0mz * q2 + mx * q1q1 + _2q1 * my * q2 + _2q1 * mz * q3
+                    - mx * q2q2
+                    - mx * q3q3;
+            let hy = _2q0mx * q3 + my * q0q0 - _2q0mz * q1 + _2q1mx * q2 - my * q1q1
+                + my * q2q2
+                + _2q2 * mz * q3
+                - my * q3q3;
+            let _2bx = sqrtf(hx * hx + hy * hy);
+            let _2bz = -_2q0mx * q2 + _2q0my * q1 + mz * q0q0 + _2q1mx * q3 - mz * q1q1
+                + _2q2 * my * q3
+                - mz * q2q2
+                + mz * q3q3;
+            let _4bx = 2.0 * _2bx;
+            let _4bz = 2.0 * _2bz;
+
+            // Objective function terms shared by each step
+            let f_ax = 2.0 * q1q3 - _2q0q2 - ax;
+            let f_ay = 2.0 * q0q1 + _2q2q3 - ay;
+            let f_az = 1.0 - 2.0 * q1q1 - 2.0 * q2q2 - az;
+            let f_mx = _2bx * (0.5 - q2q2 - q3q3) + _2bz * (q1q3 - q0q2) - mx;
+            let f_my = _2bx * (q1q2 - q0q3) + _2bz * (q0q1 + q2q3) - my;
+            let f_mz = _2bx * (q0q2 + q1q3) + _2bz * (0.5 - q1q1 - q2q2) - mz;
+
+            // Gradient descent algorithm corrective step
+            let step = [
+                -_2q2 * f_ax + _2q1 * f_ay - _2bz * q2 * f_mx
+                    + (-_2bx * q3 + _2bz * q1) * f_my
+                    + _2bx * q2 * f_mz,
+                _2q3 * f_ax + _2q0 * f_ay - 4.0 * q1 * f_az
+                    + _2bz * q3 * f_mx
+                    + (_2bx * q2 + _2bz * q0) * f_my
+                    + (_2bx * q3 - _4bz * q1) * f_mz,
+                -_2q0 * f_ax + _2q3 * f_ay - 4.0 * q2 * f_az
+                    + (-_4bx * q2 - _2bz * q0) * f_mx
+                    + (_2bx * q1 + _2bz * q3) * f_my
+                    + (_2bx * q0 - _4bz * q2) * f_mz,
+                _2q1 * f_ax
+                    + _2q2 * f_ay
+                    + (-_4bx * q3 + _2bz * q1) * f_mx
+                    + (-_2bx * q0 + _2bz * q2) * f_my
+                    + _2bx * q1 * f_mz,
+            ];
+
+            self.apply_feedback(&mut q_dot, step);
+        }
+
+        self.integrate(dt, q_dot);
+    }
+
+    /// Fuses a gyroscope (in radians per second) and accelerometer reading taken
+    /// `dt` seconds after the previous one, leaving yaw to drift.
+    pub fn update_imu(&mut self, dt: f32, gyro: Vector3<f32>, acc: Vector3<f32>) {
+        let [q0, q1, q2, q3] = self.q;
+        let (gx, gy, gz) = (gyro.x, gyro.y, gyro.z);
+
+        // Rate of change of quaternion from gyroscope
+        let mut q_dot = [
+            0.5 * (-q1 * gx - q2 * gy - q3 * gz),
+            0.5 * (q0 * gx + q2 * gz - q3 * gy),
+            0.5 * (q0 * gy - q1 * gz + q3 * gx),
+            0.5 * (q0 * gz + q1 * gy - q2 * gx),
+        ];
+
+        if let Some((ax, ay, az)) = normalise(acc) {
+            // Auxiliary variables to avoid repeated arithmetic
+            let _2q0 = 2.0 * q0;
+            let _2q1 = 2.0 * q1;
+            let _2q2 = 2.0 * q2;
+            let _2q3 = 2.0 * q3;
+            let _4q0 = 4.0 * q0;
+            let _4q1 = 4.0 * q1;
+            let _4q2 = 4.0 * q2;
+            let _8q1 = 8.0 * q1;
+            let _8q2 = 8.0 * q2;
+            let q0q0 = q0 * q0;
+            let q1q1 = q1 * q1;
+            let q2q2 = q2 * q2;
+            let q3q3 = q3 * q3;
+
+            // Gradient descent algorithm corrective step
+            let step = [
+                _4q0 * q2q2 + _2q2 * ax + _4q0 * q1q1 - _2q1 * ay,
+                _4q1 * q3q3 - _2q3 * ax + 4.0 * q0q0 * q1 - _2q0 * ay - _4q1
+                    + _8q1 * q1q1
+                    + _8q1 * q2q2
+                    + _4q1 * az,
+                4.0 * q0q0 * q2 + _2q0 * ax + _4q2 * q3q3 - _2q3 * ay - _4q2
+                    + _8q2 * q1q1
+                    + _8q2 * q2q2
+                    + _4q2 * az,
+                4.0 * q1q1 * q3 - _2q1 * ax + 4.0 * q2q2 * q3 - _2q2 * ay,
+            ];
+
+            self.apply_feedback(&mut q_dot, step);
+        }
+
+        self.integrate(dt, q_dot);
+    }
+
+    fn apply_feedback(&self, q_dot: &mut [f32; 4], step: [f32; 4]) {
+        let norm = sqrtf(step.iter().map(|s| s * s).sum());
+
+        if norm == 0.0 {
+            return;
+        }
+
+        for (q_dot, s) in q_dot.iter_mut().zip(step) {
+            *q_dot -= self.beta * s / norm;
+        }
+    }
+
+    fn integrate(&mut self, dt: f32, q_dot: [f32; 4]) {
+        for (q, q_dot) in self.q.iter_mut().zip(q_dot) {
+            *q += q_dot * dt;
+        }
+
+        let norm = sqrtf(self.q.iter().map(|q| q * q).sum());
+
+        if norm == 0.0 || !norm.is_finite() {
+            warn!("AHRS quaternion collapsed, resetting");
+            self.q = [1.0, 0.0, 0.0, 0.0];
+            return;
+        }
+
+        self.q.iter_mut().for_each(|q| *q /= norm);
+    }
+}
+
+impl Default for Madgwick {
+    fn default() -> Self {
+        Self::new(DEFAULT_BETA)
+    }
+}
+
+/// Normalises `v`, or returns [None] if it has no direction.
+fn normalise(v: Vector3<f32>) -> Option<(f32, f32, f32)> {
+    let norm = sqrtf(v.x * v.x + v.y * v.y + v.z * v.z);
+
+    if norm == 0.0 || !norm.is_finite() {
+        return None;
+    }
+
+    Some((v.x / norm, v.y / norm, v.z / norm))
+}
+
+#[task]
+pub async fn ahrs_stream(config: AhrsConfig) {
+    let heartbeat = watchdog::register(TaskId::Ahrs, Duration::from_secs(1));
+    let mut filter = Madgwick::new(config.beta);
+
+    let mut mpu_bus = MPU_BUS.subscribe().expect("no MPU_BUS subscribers left");
+
+    let mut last_sample: Option<Sample<_>> = None;
+
+    loop {
+        let sample = mpu_bus.next().await;
+        heartbeat.beat();
+
+        // without the gyroscope there is nothing to integrate, so wait for the
+        // next sample and integrate over the gap then
+        let Some(gyro) = sample.data.gyro.valid() else {
+            continue;
+        };
+
+        // a zero vector tells the filter not to correct with that sensor
+        let acc = sample.data.acc.valid().unwrap_or_default();
+
+        let mag = config.mag_at(sample.time, MAG_BUS.latest());
+
+        let dt = match last_sample {
+            Some(last) => sample.since(&last).as_micros() as f32 / 1_000_000.0,
+            None => 0.0,
+        };
+        last_sample = Some(sample);
+
+        // MpuData stores the gyroscope in degrees per second
+        filter.update(dt, gyro.map(|x| x.to_radians()), acc, mag);
+
+        let attitude = sample.map(|_| filter.attitude());
+
+        trace!("{attitude:?}");
+
+        ATTITUDE_BUS.publish(attitude);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sample::{Reading, SourceId};
+
+    /// 100Hz, as the MPU6050 can be read at.
+    const DT: f32 = 0.01;
+
+    /// The earth's magnetic field (x north, z up), pointing 66 degrees down as
+    /// it does in the UK.
+    fn earth_field() -> Vector3<f32> {
+        let dip = 66.0_f32.to_radians();
+        Vector3::new(dip.cos(), 0.0, -dip.sin())
+    }
+
+    fn attitude(roll: f32, pitch: f32, yaw: f32) -> UnitQuaternion<f32> {
+        UnitQuaternion::from_euler_angles(roll.to_radians(), pitch.to_radians(), yaw.to_radians())
+    }
+
+    /// What the accelerometer and magnetometer read at rest in `attitude`.
+    fn readings(attitude: &UnitQuaternion<f32>) -> (Vector3<f32>, Vector3<f32>) {
+        let to_body = attitude.inverse();
+        (to_body * Vector3::z(), to_body * earth_field())
+    }
+
+    /// The angle between two attitudes, in degrees.
+    fn error(a: &UnitQuaternion<f32>, b: &UnitQuaternion<f32>) -> f32 {
+        a.angle_to(b).to_degrees()
+    }
+
+    fn assert_euler(attitude: AttitudeData, expected: [f32; 3], tolerance: f32) {
+        for (angle, expected) in attitude.euler.iter().zip(expected) {
+            // yaw wraps around
+            let difference = (angle - expected + 540.0).rem_euclid(360.0) - 180.0;
+            assert!(
+                difference.abs() <= tolerance,
+                "{:?} != {expected:?}",
+                attitude.euler
+            );
+        }
+    }
+
+    #[test]
+    fn stays_put_when_still() {
+        let mut filter = Madgwick::default();
+        let truth = UnitQuaternion::identity();
+        let (acc, mag) = readings(&truth);
+
+        for _ in 0..1_000 {
+            filter.update(DT, Vector3::zeros(), acc, mag);
+        }
+
+        assert!(error(&filter.quaternion(), &truth) < 0.1);
+        assert_euler(filter.attitude(), [0.0, 0.0, 0.0], 0.1);
+    }
+
+    #[test]
+    fn converges_on_a_still_attitude() {
+        let mut filter = Madgwick::default();
+        let truth = attitude(30.0, -20.0, 60.0);
+        let (acc, mag) = readings(&truth);
+
+        let initial_error = error(&filter.quaternion(), &truth);
+
+        for _ in 0..500 {
+            filter.update(DT, Vector3::zeros(), acc, mag);
+        }
+        assert!(error(&filter.quaternion(), &truth) < initial_error);
+
+        // with the default gain it takes around a minute to settle
+        for _ in 0..5_500 {
+            filter.update(DT, Vector3::zeros(), acc, mag);
+        }
+
+        assert!(error(&filter.quaternion(), &truth) < 1.0);
+        assert_euler(filter.attitude(), [30.0, -20.0, 60.0], 1.0);
+    }
+
+    #[test]
+    fn follows_a_constant_rate_rotation() {
+        let mut filter = Madgwick::default();
+
+        // spinning around a tilted axis, as a can under a parachute does
+        let axis = Vector3::new(0.2, -0.1, 1.0).normalize();
+        let rate = 45.0_f32.to_radians();
+        let spin = |t: f32| UnitQuaternion::from_scaled_axis(axis * rate * t);
+
+        for step in 1..=2_000 {
+            let truth = spin(step as f32 * DT);
+            let (acc, mag) = readings(&truth);
+
+            // the axis is fixed in the body as well as the earth
+            filter.update(DT, axis * rate, acc, mag);
+
+            assert!(error(&filter.quaternion(), &truth) < 1.0, "step {step}");
+        }
+
+        let truth = spin(2_000.0 * DT);
+        let (roll, pitch, yaw) = truth.euler_angles();
+        assert_euler(
+            filter.attitude(),
+            [roll, pitch, yaw].map(|angle| angle.to_degrees()),
+            1.0,
+        );
+    }
+
+    #[test]
+    fn converges_while_rotating() {
+        let mut filter = Madgwick::default();
+
+        let start = attitude(-40.0, 10.0, 0.0);
+        let rate = 20.0_f32.to_radians();
+
+        for step in 1..=6_000 {
+            let yaw = UnitQuaternion::from_euler_angles(0.0, 0.0, rate * step as f32 * DT);
+            let truth = yaw * start;
+            let (acc, mag) = readings(&truth);
+
+            // spinning about the earth's vertical, seen from the tilted body
+            let gyro = truth.inverse() * Vector3::z() * rate;
+
+            filter.update(DT, gyro, acc, mag);
+        }
+
+        let yaw = UnitQuaternion::from_euler_angles(0.0, 0.0, rate * 6_000.0 * DT);
+        assert!(error(&filter.quaternion(), &(yaw * start)) < 1.0);
+    }
+
+    #[test]
+    fn integrates_the_gyroscope_alone() {
+        let mut filter = Madgwick::default();
+
+        // without the accelerometer or magnetometer there is nothing to correct
+        // towards, so a quarter turn at 90 degrees per second...
+        for _ in 0..100 {
+            filter.update_imu(
+                DT,
+                Vector3::new(90.0_f32.to_radians(), 0.0, 0.0),
+                Vector3::zeros(),
+            );
+        }
+
+        // ...is a roll of 90 degrees
+        assert!(error(&filter.quaternion(), &attitude(90.0, 0.0, 0.0)) < 0.5);
+    }
+
+    #[test]
+    fn levels_without_the_magnetometer() {
+        let mut filter = Madgwick::default();
+        let truth = attitude(-25.0, 15.0, 0.0);
+        let (acc, _) = readings(&truth);
+
+        // a zero magnetometer reading falls back to the accelerometer alone
+        for _ in 0..3_000 {
+            filter.update(DT, Vector3::zeros(), acc, Vector3::zeros());
+        }
+
+        let euler = filter.attitude().euler;
+        assert!((euler.x - -25.0).abs() < 1.0, "{euler:?}");
+        assert!((euler.y - 15.0).abs() < 1.0, "{euler:?}");
+    }
+
+    fn mag_sample(time: Instant, mag: Reading<Vector3<f32>>) -> Sample<MagData> {
+        Sample {
+            time,
+            seq: 0,
+            source: SourceId::Qmc5883l,
+            data: MagData {
+                mag,
+                temp: Reading::Valid(20.0),
+            },
+        }
+    }
+
+    #[test]
+    fn maps_the_magnetometer_onto_the_mpu_frame() {
+        let mag = Vector3::new(1.0, 2.0, 3.0);
+
+        assert_eq!(MagAxes::ALIGNED.to_mpu_frame(mag), mag);
+
+        // rotated a quarter turn about z and mounted upside down
+        let axes = MagAxes {
+            x: MagAxis::NegY,
+            y: MagAxis::X,
+            z: MagAxis::NegZ,
+        };
+        assert_eq!(axes.to_mpu_frame(mag), Vector3::new(-2.0, 1.0, -3.0));
+    }
+
+    #[test]
+    fn fuses_recent_magnetometer_readings() {
+        let config = AhrsConfig {
+            mag_axes: MagAxes {
+                x: MagAxis::Y,
+                y: MagAxis::NegX,
+                z: MagAxis::Z,
+            },
+            ..Default::default()
+        };
+        let time = Instant::from_secs(10);
+        let mag = mag_sample(
+            time - config.max_mag_age,
+            Reading::Valid(Vector3::new(0.2, 0.1, -0.4)),
+        );
+
+        assert_eq!(
+            config.mag_at(time, Some(mag)),
+            Vector3::new(0.1, -0.2, -0.4)
+        );
+        // a reading taken just after the gyroscope's is still current
+        assert_eq!(
+            config.mag_at(time - Duration::from_millis(1), Some(mag)),
+            Vector3::new(0.1, -0.2, -0.4)
+        );
+    }
+
+    #[test]
+    fn falls_back_to_the_imu_without_a_current_magnetometer_reading() {
+        let config = AhrsConfig::default();
+        let time = Instant::from_secs(10);
+        let field = Reading::Valid(Vector3::new(0.2, 0.1, -0.4));
+
+        let stale = mag_sample(time - config.max_mag_age - Duration::from_millis(1), field);
+        assert_eq!(config.mag_at(time, Some(stale)), Vector3::zeros());
+
+        let invalid = mag_sample(time, Reading::Invalid(1));
+        assert_eq!(config.mag_at(time, Some(invalid)), Vector3::zeros());
+
+        assert_eq!(config.mag_at(time, None), Vector3::zeros());
+    }
+
+    #[test]
+    fn recovers_from_a_collapsed_quaternion() {
+        let mut filter = Madgwick::default();
+
+        filter.update_imu(DT, Vector3::repeat(f32::NAN), Vector3::z());
+
+        assert_eq!(filter.quaternion(), UnitQuaternion::identity());
+    }
+}
@@ -0,0 +1,291 @@
+use libm::powf;
+
+use crate::{
+    bme280::BmeData,
+    bus::{BusSubscriber, DataBus},
+    prelude::*,
+    sample::Sample,
+};
+
+pub type AltitudeBus = DataBus<Sample<AltitudeData>, 4, 8>;
+pub static ALTITUDE_BUS: AltitudeBus = DataBus::new();
+pub type AltitudeSubscriber = BusSubscriber<'static, Sample<AltitudeData>, 4, 8>;
+
+/// How many pressure/temperature readings are averaged at boot to form the
+/// [GroundReference].
+pub const GROUND_REFERENCE_SAMPLES: u16 = 16;
+
+/// International standard atmosphere sea level pressure, in pascals.
+pub const SEA_LEVEL_PRESSURE: f32 = 101_325.0;
+/// International standard atmosphere sea level temperature, in kelvin.
+pub const SEA_LEVEL_TEMPERATURE: f32 = 288.15;
+/// Temperature lapse rate of the troposphere, in kelvin per metre.
+pub const LAPSE_RATE: f32 = 0.0065;
+/// `R * L / (g * M)` for dry air, the exponent of the barometric formula.
+pub const BAROMETRIC_EXPONENT: f32 = 0.190_263;
+
+const CELSIUS_TO_KELVIN: f32 = 273.15;
+
+/// The conditions on the ground that altitude above ground is measured against.
+#[derive(Copy, Clone, Debug, Default, PartialEq)]
+pub struct GroundReference {
+    /// pressure in pascals
+    pub pressure: f32,
+    /// temperature in degrees celsius
+    pub temperature: f32,
+}
+
+#[derive(Copy, Clone, Debug, Default, PartialEq)]
+pub struct AltitudeData {
+    /// altitude above mean sea level in metres, assuming a standard atmosphere
+    pub absolute: f32,
+    /// altitude above the ground reference in metres
+    pub agl: f32,
+    /// the ground reference used to compute [AltitudeData::agl]
+    pub reference: GroundReference,
+}
+
+/// Altitude above mean sea level of `pressure` (in pascals), using the
+/// international standard atmosphere.
+pub fn pressure_altitude(pressure: f32) -> f32 {
+    (SEA_LEVEL_TEMPERATURE / LAPSE_RATE)
+        * (1.0 - powf(pressure / SEA_LEVEL_PRESSURE, BAROMETRIC_EXPONENT))
+}
+
+/// Altitude of `pressure` (in pascals) above the point where `reference` was
+/// measured, using the hypsometric formula.
+///
+/// The temperature is taken to fall off at the standard lapse rate from the
+/// one measured on the ground, so with a standard atmosphere reference this
+/// agrees with [pressure_altitude].
+pub fn hypsometric_altitude(pressure: f32, reference: &GroundReference) -> f32 {
+    (1.0 - powf(pressure / reference.pressure, BAROMETRIC_EXPONENT))
+        * (reference.temperature + CELSIUS_TO_KELVIN)
+        / LAPSE_RATE
+}
+
+/// Converts [BmeData] into [AltitudeData], capturing a [GroundReference] from
+/// the first few valid readings it is given.
+#[derive(Clone, Debug)]
+pub struct Altimeter {
+    /// How many readings to average before the reference is fixed.
+    calibration_samples: u16,
+    /// How many readings have been averaged so far.
+    count: u16,
+    pressure_sum: f32,
+    temperature_sum: f32,
+    reference: Option<GroundReference>,
+}
+
+impl Altimeter {
+    pub const fn new(calibration_samples: u16) -> Self {
+        Self {
+            calibration_samples,
+            count: 0,
+            pressure_sum: 0.0,
+            temperature_sum: 0.0,
+            reference: None,
+        }
+    }
+
+    /// Skips calibration, measuring altitude above `reference` straight away.
+    pub const fn with_reference(reference: GroundReference) -> Self {
+        Self {
+            calibration_samples: 0,
+            count: 0,
+            pressure_sum: 0.0,
+            temperature_sum: 0.0,
+            reference: Some(reference),
+        }
+    }
+
+    /// The ground reference, or [None] if it is still being calibrated.
+    pub fn reference(&self) -> Option<GroundReference> {
+        self.reference
+    }
+
+    pub fn is_calibrated(&self) -> bool {
+        self.reference.is_some()
+    }
+
+    /// Throws away the current ground reference and starts averaging again.
+    pub fn recalibrate(&mut self) {
+        *self = Self::new(self.calibration_samples);
+    }
+
+    /// Feeds a new reading in, returning the altitude once the ground reference
+    /// is known.
+    ///
+    /// Readings with a non-physical pressure are ignored.
+    pub fn update(&mut self, data: &BmeData) -> Option<AltitudeData> {
+        if !data.pressure.is_finite() || data.pressure <= 0.0 {
+            return None;
+        }
+
+        let reference = match self.reference {
+            Some(reference) => reference,
+            None => {
+                self.pressure_sum += data.pressure;
+                self.temperature_sum += data.temperature;
+                self.count += 1;
+
+                if self.count < self.calibration_samples {
+                    return None;
+                }
+
+                let reference = GroundReference {
+                    pressure: self.pressure_sum / self.count as f32,
+                    temperature: self.temperature_sum / self.count as f32,
+                };
+
+                debug!("Ground reference captured: {reference:?}");
+
+                self.reference = Some(reference);
+                reference
+            }
+        };
+
+        Some(AltitudeData {
+            absolute: pressure_altitude(data.pressure),
+            agl: hypsometric_altitude(data.pressure, &reference),
+            reference,
+        })
+    }
+}
+
+impl Default for Altimeter {
+    fn default() -> Self {
+        Self::new(GROUND_REFERENCE_SAMPLES)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_utils::assert_close;
+
+    /// Pressures (in pascals) at geopotential altitudes (in metres) from the
+    /// international standard atmosphere tables.
+    const ISA_TABLE: [(f32, f32); 7] = [
+        (-500.0, 107_478.0),
+        (0.0, 101_325.0),
+        (500.0, 95_461.0),
+        (1_000.0, 89_875.0),
+        (2_000.0, 79_495.0),
+        (3_000.0, 70_108.0),
+        (5_000.0, 54_020.0),
+    ];
+
+    /// The standard atmosphere at sea level.
+    const ISA_SEA_LEVEL: GroundReference = GroundReference {
+        pressure: SEA_LEVEL_PRESSURE,
+        temperature: SEA_LEVEL_TEMPERATURE - CELSIUS_TO_KELVIN,
+    };
+
+    fn bme(pressure: f32, temperature: f32) -> BmeData {
+        BmeData {
+            temperature,
+            pressure,
+            humidity: 0.0,
+        }
+    }
+
+    #[test]
+    fn pressure_altitude_matches_isa_table() {
+        for (altitude, pressure) in ISA_TABLE {
+            assert_close(pressure_altitude(pressure), altitude, 2.0);
+        }
+    }
+
+    #[test]
+    fn hypsometric_altitude_matches_isa_table_from_sea_level() {
+        for (altitude, pressure) in ISA_TABLE {
+            assert_close(
+                hypsometric_altitude(pressure, &ISA_SEA_LEVEL),
+                altitude,
+                2.0,
+            );
+        }
+    }
+
+    #[test]
+    fn hypsometric_altitude_is_relative_to_the_reference() {
+        // standing at 1000m in the standard atmosphere
+        let reference = GroundReference {
+            pressure: 89_875.0,
+            temperature: 8.5,
+        };
+
+        assert_close(hypsometric_altitude(89_875.0, &reference), 0.0, 0.01);
+
+        for (altitude, pressure) in ISA_TABLE {
+            assert_close(
+                hypsometric_altitude(pressure, &reference),
+                altitude - 1_000.0,
+                2.0,
+            );
+        }
+    }
+
+    #[test]
+    fn ground_reference_averages_the_first_samples() {
+        let mut altimeter = Altimeter::new(4);
+
+        let samples = [
+            bme(100_000.0, 20.0),
+            bme(100_010.0, 21.0),
+            bme(99_990.0, 19.0),
+        ];
+
+        for sample in &samples {
+            assert_eq!(altimeter.update(sample), None);
+        }
+
+        // not counted towards the average
+        assert_eq!(altimeter.update(&bme(f32::NAN, 20.0)), None);
+        assert_eq!(altimeter.update(&bme(0.0, 20.0)), None);
+        assert!(!altimeter.is_calibrated());
+
+        let altitude = altimeter.update(&bme(100_020.0, 24.0)).unwrap();
+
+        let reference = GroundReference {
+            pressure: 100_005.0,
+            temperature: 21.0,
+        };
+        assert_eq!(altimeter.reference(), Some(reference));
+        assert_eq!(altitude.reference, reference);
+
+        // the last sample was slightly below the average
+        assert!(altitude.agl < 0.0 && altitude.agl > -2.0);
+        assert_close(altitude.absolute, pressure_altitude(100_020.0), 0.001);
+
+        // later readings don't move the reference
+        let altitude = altimeter.update(&bme(89_875.0, 0.0)).unwrap();
+        assert_eq!(altitude.reference, reference);
+        assert_eq!(altitude.agl, hypsometric_altitude(89_875.0, &reference));
+    }
+
+    #[test]
+    fn recalibrate_starts_averaging_again() {
+        let mut altimeter = Altimeter::new(2);
+
+        altimeter.update(&bme(100_000.0, 20.0));
+        altimeter.update(&bme(100_000.0, 20.0));
+        assert!(altimeter.is_calibrated());
+
+        altimeter.recalibrate();
+        assert_eq!(altimeter.update(&bme(90_000.0, 10.0)), None);
+
+        let altitude = altimeter.update(&bme(90_000.0, 10.0)).unwrap();
+        assert_eq!(altitude.agl, 0.0);
+        assert_eq!(altitude.reference.pressure, 90_000.0);
+    }
+
+    #[test]
+    fn with_reference_skips_calibration() {
+        let mut altimeter = Altimeter::with_reference(ISA_SEA_LEVEL);
+
+        let altitude = altimeter.update(&bme(89_875.0, 8.5)).unwrap();
+        assert_close(altitude.agl, 1_000.0, 2.0);
+    }
+}
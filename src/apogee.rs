@@ -0,0 +1,356 @@
+use crate::{
+    bus::{BusSubscriber, DataBus},
+    prelude::*,
+    sample::Sample,
+    vertical::{VerticalState, VERTICAL_BUS},
+};
+
+pub type ApogeeBus = DataBus<ApogeeEvent, 4, 8>;
+pub static APOGEE_BUS: ApogeeBus = DataBus::new();
+pub type ApogeeSubscriber = BusSubscriber<'static, ApogeeEvent, 4, 8>;
+
+/// The highest point of the flight.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Apogee {
+    /// altitude above the ground reference in metres
+    pub altitude: f32,
+    /// When the can was at [Apogee::altitude] - earlier than when it was
+    /// detected.
+    pub time: Instant,
+    /// When the can had been descending for long enough to be sure.
+    pub detected_at: Instant,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct DescentRate {
+    /// speed of descent in metres per second, positive downwards
+    pub rate: f32,
+    /// altitude above the ground reference in metres
+    pub altitude: f32,
+    pub time: Instant,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ApogeeEvent {
+    /// Sent once, when apogee is detected.
+    Apogee(Apogee),
+    /// Sent every [ApogeeConfig::descent_rate_interval] after apogee.
+    DescentRate(DescentRate),
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct ApogeeConfig {
+    /// Vertical velocity (in metres per second) the can must be falling faster
+    /// than for a sample to count as descending.
+    ///
+    /// Defaults to 1 m/s.
+    pub descending_velocity: f32,
+    /// How long the can must keep descending before apogee is detected, so
+    /// it isn't set off by noise in the velocity estimate.
+    ///
+    /// Defaults to 1s, about ten updates of the vertical filter at the
+    /// MPU6050's 10Hz.
+    pub descending_time: Duration,
+    /// How far (in metres) the can must climb above where it started before
+    /// apogee can be detected, so it isn't set off on the ground.
+    ///
+    /// Defaults to 10m.
+    pub min_altitude_gain: f32,
+    /// How often the descent rate is sent after apogee.
+    ///
+    /// Defaults to 500ms.
+    pub descent_rate_interval: Duration,
+}
+
+impl Default for ApogeeConfig {
+    fn default() -> Self {
+        Self {
+            descending_velocity: 1.0,
+            descending_time: Duration::from_secs(1),
+            min_altitude_gain: 10.0,
+            descent_rate_interval: Duration::from_millis(500),
+        }
+    }
+}
+
+/// Detects apogee, and then tracks the descent rate, from a stream of vertical
+/// state estimates.
+#[derive(Clone, Debug)]
+pub struct ApogeeDetector {
+    config: ApogeeConfig,
+    /// The altitude of the first sample.
+    start_altitude: Option<f32>,
+    /// The highest altitude so far, and when it was reached.
+    max: Option<(f32, Instant)>,
+    /// When the current run of descending samples started.
+    descending_since: Option<Instant>,
+    apogee: Option<Apogee>,
+    descent_rate: Option<DescentRate>,
+    last_descent_rate_event: Option<Instant>,
+}
+
+impl ApogeeDetector {
+    pub fn new(config: ApogeeConfig) -> Self {
+        Self {
+            config,
+            start_altitude: None,
+            max: None,
+            descending_since: None,
+            apogee: None,
+            descent_rate: None,
+            last_descent_rate_event: None,
+        }
+    }
+
+    pub fn config(&self) -> &ApogeeConfig {
+        &self.config
+    }
+
+    /// The apogee, once it has been detected.
+    pub fn apogee(&self) -> Option<Apogee> {
+        self.apogee
+    }
+
+    /// The highest altitude so far, and when it was reached.
+    pub fn max_altitude(&self) -> Option<(f32, Instant)> {
+        self.max
+    }
+
+    /// The descent rate as of the latest sample.
+    pub fn descent_rate(&self) -> Option<DescentRate> {
+        self.descent_rate
+    }
+
+    /// Feeds a new sample in, handing any events it causes to `emit`.
+    pub fn update(&mut self, sample: &Sample<VerticalState>, mut emit: impl FnMut(ApogeeEvent)) {
+        let state = &sample.data;
+
+        if !state.altitude.is_finite() || !state.velocity.is_finite() {
+            return;
+        }
+
+        let start_altitude = *self.start_altitude.get_or_insert(state.altitude);
+
+        if !matches!(self.max, Some((max, _)) if max >= state.altitude) {
+            self.max = Some((state.altitude, sample.time));
+        }
+
+        let descent_rate = DescentRate {
+            rate: -state.velocity,
+            altitude: state.altitude,
+            time: sample.time,
+        };
+        self.descent_rate = Some(descent_rate);
+
+        if self.apogee.is_some() {
+            let interval = self.config.descent_rate_interval;
+
+            if !matches!(self.last_descent_rate_event, Some(last)
+                if sample.time.saturating_duration_since(last) < interval)
+            {
+                self.last_descent_rate_event = Some(sample.time);
+                emit(ApogeeEvent::DescentRate(descent_rate));
+            }
+
+            return;
+        }
+
+        let Some((max_altitude, max_time)) = self.max else {
+            return;
+        };
+
+        let climbed = max_altitude - start_altitude >= self.config.min_altitude_gain;
+
+        if !climbed || state.velocity >= -self.config.descending_velocity {
+            self.descending_since = None;
+            return;
+        }
+
+        let descending_since = *self.descending_since.get_or_insert(sample.time);
+
+        if sample.time.saturating_duration_since(descending_since) >= self.config.descending_time {
+            let apogee = Apogee {
+                altitude: max_altitude,
+                time: max_time,
+                detected_at: sample.time,
+            };
+
+            self.apogee = Some(apogee);
+            self.last_descent_rate_event = Some(sample.time);
+
+            emit(ApogeeEvent::Apogee(apogee));
+            emit(ApogeeEvent::DescentRate(descent_rate));
+        }
+    }
+}
+
+impl Default for ApogeeDetector {
+    fn default() -> Self {
+        Self::new(ApogeeConfig::default())
+    }
+}
+
+#[task]
+pub async fn apogee_detector(config: ApogeeConfig) {
+    let mut detector = ApogeeDetector::new(config);
+
+    let mut vertical_bus = VERTICAL_BUS
+        .subscribe()
+        .expect("no VERTICAL_BUS subscribers left");
+
+    loop {
+        let sample = vertical_bus.next().await;
+
+        detector.update(&sample, |event| {
+            match &event {
+                ApogeeEvent::Apogee(apogee) => info!(
+                    "Apogee: {:.1}m at {}ms",
+                    apogee.altitude,
+                    apogee.time.as_millis()
+                ),
+                ApogeeEvent::DescentRate(descent) => debug!(
+                    "Descending at {:.1}m/s from {:.1}m",
+                    descent.rate, descent.altitude
+                ),
+            }
+
+            APOGEE_BUS.publish(event);
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use heapless::Vec;
+
+    use super::*;
+    use crate::{sample::SourceId, test_utils::assert_close};
+
+    const STANDARD_GRAVITY: f32 = 9.806_65;
+
+    /// Launched straight up at 30m/s, peaking at 3.06s - the highest sample is
+    /// the one at 3.1s, and it is falling faster than 1m/s from 3.2s.
+    fn ballistic(ms: u64) -> VerticalState {
+        let t = ms as f32 / 1000.0;
+
+        VerticalState {
+            altitude: 30.0 * t - 0.5 * STANDARD_GRAVITY * t * t,
+            velocity: 30.0 - STANDARD_GRAVITY * t,
+            acceleration: -STANDARD_GRAVITY,
+        }
+    }
+
+    fn sample(ms: u64, data: VerticalState) -> Sample<VerticalState> {
+        Sample {
+            time: Instant::from_millis(ms),
+            seq: (ms / 100) as u32,
+            source: SourceId::Mpu6050,
+            data,
+        }
+    }
+
+    /// Feeds `state` in at 10Hz from 0 until `end_ms`, collecting the events.
+    fn run(
+        detector: &mut ApogeeDetector,
+        end_ms: u64,
+        state: impl Fn(u64) -> VerticalState,
+    ) -> Vec<ApogeeEvent, 32> {
+        let mut events = Vec::new();
+
+        for ms in (0..end_ms).step_by(100) {
+            detector.update(&sample(ms, state(ms)), |event| {
+                events.push(event).unwrap();
+            });
+        }
+
+        events
+    }
+
+    #[test]
+    fn finds_the_highest_point_once_descending_for_long_enough() {
+        let mut detector = ApogeeDetector::default();
+        let events = run(&mut detector, 4_300, ballistic);
+
+        let [ApogeeEvent::Apogee(apogee), ApogeeEvent::DescentRate(descent)] = events[..] else {
+            panic!("expected apogee then the descent rate, got {events:?}");
+        };
+
+        assert_close(apogee.altitude, ballistic(3_100).altitude, 1e-6);
+        assert_eq!(apogee.time, Instant::from_millis(3_100));
+        // a second after the first descending sample
+        assert_eq!(apogee.detected_at, Instant::from_millis(4_200));
+
+        assert_eq!(descent.time, apogee.detected_at);
+        assert_close(descent.rate, -ballistic(4_200).velocity, 1e-6);
+
+        assert_eq!(detector.apogee(), Some(apogee));
+        assert_eq!(
+            detector.max_altitude(),
+            Some((apogee.altitude, apogee.time))
+        );
+    }
+
+    #[test]
+    fn nothing_is_detected_until_the_altitude_gain_is_reached() {
+        let mut detector = ApogeeDetector::default();
+
+        // a hop to 7.3m, then falling for seconds
+        let events = run(&mut detector, 6_000, |ms| {
+            let t = ms as f32 / 1000.0;
+
+            VerticalState {
+                altitude: 12.0 * t - 0.5 * STANDARD_GRAVITY * t * t,
+                velocity: 12.0 - STANDARD_GRAVITY * t,
+                acceleration: -STANDARD_GRAVITY,
+            }
+        });
+
+        assert!(events.is_empty(), "{events:?}");
+        assert_eq!(detector.apogee(), None);
+    }
+
+    #[test]
+    fn a_short_dip_in_velocity_is_not_apogee() {
+        let mut detector = ApogeeDetector::default();
+
+        // climbing at 20m/s, apart from 0.9s of noise at 2s
+        let events = run(&mut detector, 6_000, |ms| VerticalState {
+            altitude: 20.0 * ms as f32 / 1000.0,
+            velocity: if (2_000..2_900).contains(&ms) {
+                -5.0
+            } else {
+                20.0
+            },
+            acceleration: 0.0,
+        });
+
+        assert!(events.is_empty(), "{events:?}");
+        assert_eq!(detector.apogee(), None);
+    }
+
+    #[test]
+    fn sends_the_descent_rate_every_interval_after_apogee() {
+        let mut detector = ApogeeDetector::default();
+        let events = run(&mut detector, 7_000, ballistic);
+
+        let mut times: Vec<u64, 16> = Vec::new();
+
+        for event in &events[1..] {
+            let ApogeeEvent::DescentRate(descent) = event else {
+                panic!("apogee sent more than once");
+            };
+
+            let ms = descent.time.as_millis();
+            assert_close(descent.rate, -ballistic(ms).velocity, 1e-6);
+            assert_close(descent.altitude, ballistic(ms).altitude, 1e-6);
+
+            times.push(ms).unwrap();
+        }
+
+        assert_eq!(times, [4_200, 4_700, 5_200, 5_700, 6_200, 6_700]);
+        assert_eq!(
+            detector.descent_rate().map(|descent| descent.time),
+            Some(Instant::from_millis(6_900))
+        );
+    }
+}
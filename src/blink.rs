@@ -1,4 +1,17 @@
-use crate::prelude::*;
+use crate::{
+    health::{Status, HEALTH_BUS},
+    prelude::*,
+};
+
+/// How long the LED stays on (and off) for, showing the worst [Status] of
+/// anything on the can - the faster it blinks, the worse things are.
+fn blink_interval(status: Status) -> Duration {
+    match status {
+        Status::Ok => Duration::from_millis(1000),
+        Status::Unknown | Status::Degraded => Duration::from_millis(500),
+        Status::Failed => Duration::from_millis(100),
+    }
+}
 
 #[task]
 pub async fn blink(mut led: AnyPin<Output<PushPull>>) {
@@ -9,10 +22,15 @@ pub async fn blink(mut led: AnyPin<Output<PushPull>>) {
         on = !on;
 
         if on {
-            info!("ON!")
+            trace!("ON!")
         } else {
-            info!("OFF!")
+            trace!("OFF!")
         }
-        Timer::after(Duration::from_millis(1000)).await;
+
+        let status = HEALTH_BUS
+            .latest()
+            .map_or(Status::Unknown, |health| health.status());
+
+        Timer::after(blink_interval(status)).await;
     }
 }
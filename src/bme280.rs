@@ -1,10 +1,20 @@
 use bme280::Measurements;
-use embassy_sync::blocking_mutex::raw::CriticalSectionRawMutex;
 
-use crate::prelude::*;
-
-pub type BmeSignal = Signal<CriticalSectionRawMutex, BmeData>;
-pub static BME_SIGNAL: BmeSignal = Signal::new();
+#[cfg(target_arch = "xtensa")]
+use crate::{
+    altitude::{Altimeter, ALTITUDE_BUS},
+    sample::{Sequencer, SourceId},
+    watchdog::{self, TaskId},
+};
+use crate::{
+    bus::{BusSubscriber, DataBus},
+    prelude::*,
+    sample::{Reading, Sample},
+};
+
+pub type BmeBus = DataBus<Sample<Reading<BmeData>>, 4, 8>;
+pub static BME_BUS: BmeBus = DataBus::new();
+pub type BmeSubscriber = BusSubscriber<'static, Sample<Reading<BmeData>>, 4, 8>;
 // pub type BmeData = bme280::Measurements<hal::i2c::Error>;
 
 #[derive(Clone, Copy, ErrorCategory)]
@@ -19,8 +29,8 @@ pub enum BMEError {
     Ack,
 }
 
-impl From<bme280::Error<hal::i2c::Error>> for BMEError {
-    fn from(value: bme280::Error<hal::i2c::Error>) -> Self {
+impl<E> From<bme280::Error<E>> for BMEError {
+    fn from(value: bme280::Error<E>) -> Self {
         match value {
             bme280::Error::CompensationFailed => Self::DataErr,
             bme280::Error::Bus(_) => Self::InterfaceError,
@@ -33,28 +43,38 @@ impl From<bme280::Error<hal::i2c::Error>> for BMEError {
     }
 }
 
+#[cfg(target_arch = "xtensa")]
 type Result<T> = core::result::Result<T, BMEError>;
 
+#[cfg(target_arch = "xtensa")]
 pub struct BME280 {
     pub bme: bme280::i2c::BME280<SharedI2C>,
 }
 
+#[cfg(target_arch = "xtensa")]
 impl BME280 {
-    pub fn new(i2c: SharedI2C) -> Result<Self> {
+    pub async fn new(i2c: SharedI2C) -> Result<Self> {
         let mut bme280 = Self {
             bme: bme280::i2c::BME280::new_primary(i2c),
         };
 
-        bme280.init()?;
-
-        // bme280.try_init().await?;
+        bme280.try_init().await?;
 
         Ok(bme280)
     }
 
+    /// Initialises the BME280, retrying while it can't be talked to - it can
+    /// take a moment to answer after power on.
     pub async fn try_init(&mut self) -> Result<()> {
-        Backoff::new(|| self.init()).retry().await?;
-        Ok(())
+        Backoff::new_async(async || self.init())
+            .with_retry_if(|e| {
+                matches!(
+                    e,
+                    BMEError::InterfaceError | BMEError::Ack | BMEError::TimeoutError
+                )
+            })
+            .retry()
+            .await
     }
 
     pub fn init(&mut self) -> Result<()> {
@@ -92,6 +112,7 @@ impl<E: embedded_hal::i2c::Error> From<Measurements<E>> for BmeData {
     }
 }
 
+#[cfg(target_arch = "xtensa")]
 #[task]
 pub async fn bme280_stream(
     mut bme: BME280,
@@ -99,15 +120,21 @@ pub async fn bme280_stream(
 ) {
     let mut delay: Delay = Delay;
 
-    bme.init().print_warn();
+    let mut altimeter = Altimeter::default();
+    let mut sequencer = Sequencer::new(SourceId::Bme280);
+
+    let heartbeat = watchdog::register(TaskId::Bme, Duration::from_secs(3));
 
     loop {
-        let bme_data: BmeData = bme
-            .bme
-            .measure(&mut delay)
-            .map(Into::<BmeData>::into)
-            .map_err(|e| error!("{e:?}"))
-            .unwrap_or_default();
+        heartbeat.beat();
+
+        let time = Instant::now();
+
+        // the warning has the underlying error, bus or data
+        let bme_data = Reading::from_result(
+            bme.bme.measure(&mut delay).map(BmeData::from),
+            BMEError::DataErr,
+        );
 
         // let mpu_data = MpuData {
         //     roll_pitch: mpu.get_acc_angles().unwrap(),
@@ -116,9 +143,19 @@ pub async fn bme280_stream(
         //     acc: mpu.get_acc().unwrap(),
         // };
 
-        info!("{bme_data:?}");
+        let sample = sequencer.stamp_at(time, bme_data);
 
-        BME_SIGNAL.signal(bme_data);
+        trace!("{sample:?}");
+
+        BME_BUS.publish(sample);
+
+        if let Some(altitude) = bme_data.valid().and_then(|data| altimeter.update(&data)) {
+            let altitude = sample.map(|_| altitude);
+
+            trace!("{altitude:?}");
+
+            ALTITUDE_BUS.publish(altitude);
+        }
 
         Timer::after_millis(1000).await;
     }
@@ -0,0 +1,192 @@
+use core::cell::Cell;
+
+use embassy_sync::{
+    blocking_mutex::raw::CriticalSectionRawMutex,
+    pubsub::{PubSubChannel, Subscriber, WaitResult},
+};
+
+use crate::prelude::*;
+
+#[derive(Clone, Copy, ErrorCategory)]
+#[repr(u8)]
+pub enum BusError {
+    /// Every subscriber slot of the bus is already in use
+    TooManySubscribers,
+}
+
+/// A publish/subscribe channel for one kind of data, which any number of
+/// tasks (up to `SUBS`) can receive every published value from.
+///
+/// Publishing never waits: each subscriber has a queue of up to `CAP` values,
+/// and once a subscriber falls that far behind the oldest values are
+/// overwritten. The subscriber finds out how many it missed the next time it
+/// receives, and keeps a running total in [BusSubscriber::dropped].
+///
+/// The most recently published value is also kept, for code which only needs
+/// to peek at it with [DataBus::latest] rather than receive every value.
+pub struct DataBus<T: Copy, const CAP: usize, const SUBS: usize> {
+    channel: PubSubChannel<CriticalSectionRawMutex, T, CAP, SUBS, 1>,
+    latest: Mutex<Cell<Option<T>>>,
+}
+
+impl<T: Copy, const CAP: usize, const SUBS: usize> DataBus<T, CAP, SUBS> {
+    pub const fn new() -> Self {
+        Self {
+            channel: PubSubChannel::new(),
+            latest: Mutex::new(Cell::new(None)),
+        }
+    }
+
+    /// Sends `value` to every subscriber.
+    pub fn publish(&self, value: T) {
+        critical_section::with(|cs| self.latest.borrow(cs).set(Some(value)));
+
+        self.channel.immediate_publisher().publish_immediate(value);
+    }
+
+    /// The most recently published value, or [None] if nothing has been
+    /// published yet.
+    pub fn latest(&self) -> Option<T> {
+        critical_section::with(|cs| self.latest.borrow(cs).get())
+    }
+
+    /// Starts receiving every value published from now on.
+    pub fn subscribe(&self) -> Result<BusSubscriber<'_, T, CAP, SUBS>, BusError> {
+        let subscriber = self
+            .channel
+            .subscriber()
+            .map_err(|_| BusError::TooManySubscribers)?;
+
+        Ok(BusSubscriber {
+            subscriber,
+            dropped: 0,
+        })
+    }
+}
+
+impl<T: Copy, const CAP: usize, const SUBS: usize> Default for DataBus<T, CAP, SUBS> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+pub struct BusSubscriber<'a, T: Copy, const CAP: usize, const SUBS: usize> {
+    subscriber: Subscriber<'a, CriticalSectionRawMutex, T, CAP, SUBS, 1>,
+    /// How many values were overwritten before this subscriber received them.
+    dropped: u64,
+}
+
+impl<'a, T: Copy, const CAP: usize, const SUBS: usize> BusSubscriber<'a, T, CAP, SUBS> {
+    /// Waits for the next value, skipping over any that were overwritten.
+    pub async fn next(&mut self) -> T {
+        loop {
+            match self.subscriber.next_message().await {
+                WaitResult::Message(value) => return value,
+                WaitResult::Lagged(missed) => self.record_lag(missed),
+            }
+        }
+    }
+
+    /// The next value if one is waiting, skipping over any that were
+    /// overwritten.
+    pub fn try_next(&mut self) -> Option<T> {
+        loop {
+            match self.subscriber.try_next_message()? {
+                WaitResult::Message(value) => return Some(value),
+                WaitResult::Lagged(missed) => self.record_lag(missed),
+            }
+        }
+    }
+
+    /// How many values have been overwritten before this subscriber received
+    /// them, since it subscribed.
+    pub fn dropped(&self) -> u64 {
+        self.dropped
+    }
+
+    fn record_lag(&mut self, missed: u64) {
+        debug!("Subscriber lagged, {missed} values dropped");
+
+        self.dropped = self.dropped.saturating_add(missed);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use embassy_futures::block_on;
+
+    use super::*;
+
+    type TestBus = DataBus<u32, 4, 2>;
+
+    #[test]
+    fn every_subscriber_receives_every_value() {
+        let bus = TestBus::new();
+        let mut first = bus.subscribe().unwrap();
+        let mut second = bus.subscribe().unwrap();
+
+        bus.publish(1);
+        bus.publish(2);
+
+        assert_eq!(first.try_next(), Some(1));
+        assert_eq!(block_on(first.next()), 2);
+        assert_eq!(first.try_next(), None);
+
+        assert_eq!(second.try_next(), Some(1));
+        assert_eq!(second.try_next(), Some(2));
+    }
+
+    #[test]
+    fn keeps_the_latest_value() {
+        let bus = TestBus::new();
+        assert_eq!(bus.latest(), None);
+
+        bus.publish(1);
+        bus.publish(2);
+        assert_eq!(bus.latest(), Some(2));
+    }
+
+    #[test]
+    fn limits_the_subscribers() {
+        let bus = TestBus::new();
+        let _first = bus.subscribe().unwrap();
+        let _second = bus.subscribe().unwrap();
+
+        assert!(matches!(bus.subscribe(), Err(BusError::TooManySubscribers)));
+    }
+
+    #[test]
+    fn counts_the_values_a_lagging_subscriber_missed() {
+        let bus = TestBus::new();
+        let mut lagging = bus.subscribe().unwrap();
+        let mut keeping_up = bus.subscribe().unwrap();
+
+        for value in 0..10 {
+            bus.publish(value);
+            assert_eq!(keeping_up.try_next(), Some(value));
+        }
+
+        // only the last 4 are left
+        assert_eq!(lagging.try_next(), Some(6));
+        assert_eq!(lagging.dropped(), 6);
+
+        for value in 10..15 {
+            bus.publish(value);
+        }
+
+        assert_eq!(block_on(lagging.next()), 11);
+        assert_eq!(lagging.dropped(), 10);
+        assert_eq!(keeping_up.dropped(), 0);
+    }
+
+    #[test]
+    fn the_dropped_count_saturates() {
+        let bus = TestBus::new();
+        let mut subscriber = bus.subscribe().unwrap();
+
+        subscriber.dropped = u64::MAX - 1;
+        subscriber.record_lag(5);
+
+        assert_eq!(subscriber.dropped(), u64::MAX);
+    }
+}
@@ -0,0 +1,215 @@
+use core::fmt;
+
+use libm::{atan2f, cosf, sinf, sqrtf};
+use nalgebra::Vector3;
+
+/// A compass heading clockwise from north, always within `[0, 360)` degrees.
+#[derive(Clone, Copy, Debug, Default, PartialEq, PartialOrd)]
+pub struct Heading {
+    degrees: f32,
+}
+
+impl Heading {
+    pub const NORTH: Self = Self { degrees: 0.0 };
+
+    pub fn from_degrees(degrees: f32) -> Self {
+        Self {
+            degrees: wrap_degrees(degrees),
+        }
+    }
+
+    pub fn from_radians(radians: f32) -> Self {
+        Self::from_degrees(radians.to_degrees())
+    }
+
+    pub fn degrees(&self) -> f32 {
+        self.degrees
+    }
+
+    pub fn radians(&self) -> f32 {
+        self.degrees.to_radians()
+    }
+}
+
+impl fmt::Display for Heading {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{:.1} degrees", self.degrees)
+    }
+}
+
+/// Wraps any angle in degrees into `[0, 360)`.
+pub fn wrap_degrees(degrees: f32) -> f32 {
+    if !degrees.is_finite() {
+        return 0.0;
+    }
+
+    let mut wrapped = degrees % 360.0;
+
+    if wrapped < 0.0 {
+        wrapped += 360.0;
+    }
+
+    // adding 360 to a tiny negative number rounds up to exactly 360
+    if wrapped >= 360.0 {
+        wrapped -= 360.0;
+    }
+
+    wrapped
+}
+
+/// The heading of a magnetometer reading taken with the board perfectly level.
+///
+/// `declination` (in radians) is added to turn magnetic north into true north.
+pub fn level_heading(mag: Vector3<f32>, declination: f32) -> Heading {
+    Heading::from_radians(atan2f(mag.y, mag.x) + declination)
+}
+
+/// The heading of a magnetometer reading, corrected for the board's tilt using
+/// the direction of gravity from the accelerometer.
+///
+/// Both readings must use the same axes, and can be in any unit. If the
+/// accelerometer reading has no direction (such as in free fall, or after a
+/// failed read), this falls back to [level_heading].
+pub fn tilt_compensated_heading(acc: Vector3<f32>, mag: Vector3<f32>, declination: f32) -> Heading {
+    let norm = sqrtf(acc.x * acc.x + acc.y * acc.y + acc.z * acc.z);
+
+    if norm == 0.0 || !norm.is_finite() {
+        return level_heading(mag, declination);
+    }
+
+    let acc = acc / norm;
+
+    let roll = atan2f(acc.y, acc.z);
+    let pitch = atan2f(-acc.x, sqrtf(acc.y * acc.y + acc.z * acc.z));
+
+    let (sin_roll, cos_roll) = (sinf(roll), cosf(roll));
+    let (sin_pitch, cos_pitch) = (sinf(pitch), cosf(pitch));
+
+    // project the magnetic field back onto the horizontal plane
+    let x = mag.x * cos_pitch + mag.y * sin_roll * sin_pitch + mag.z * cos_roll * sin_pitch;
+    let y = mag.y * cos_roll - mag.z * sin_roll;
+
+    Heading::from_radians(atan2f(y, x) + declination)
+}
+
+#[cfg(test)]
+mod tests {
+    use core::f32::consts::FRAC_PI_2;
+
+    use nalgebra::Rotation3;
+
+    use super::*;
+    use crate::test_utils::assert_close;
+
+    #[test]
+    fn wraps_negative_angles() {
+        assert_eq!(wrap_degrees(-90.0), 270.0);
+        assert_eq!(wrap_degrees(-450.0), 270.0);
+        assert_eq!(wrap_degrees(-360.0), 0.0);
+    }
+
+    #[test]
+    fn wraps_whole_turns_to_zero() {
+        assert_eq!(wrap_degrees(360.0), 0.0);
+        assert_eq!(wrap_degrees(720.0), 0.0);
+        assert_eq!(wrap_degrees(361.5), 1.5);
+    }
+
+    #[test]
+    fn angles_just_below_zero_never_wrap_to_360() {
+        // -1e-6 + 360 rounds to exactly 360
+        for degrees in [-1e-6, -f32::EPSILON, -f32::MIN_POSITIVE] {
+            let wrapped = wrap_degrees(degrees);
+
+            assert!((0.0..360.0).contains(&wrapped), "{degrees} -> {wrapped}");
+        }
+
+        assert!(Heading::from_degrees(-1e-6).degrees() < 360.0);
+    }
+
+    #[test]
+    fn angles_that_are_not_finite_wrap_to_zero() {
+        assert_eq!(wrap_degrees(f32::NAN), 0.0);
+        assert_eq!(wrap_degrees(f32::INFINITY), 0.0);
+        assert_eq!(wrap_degrees(f32::NEG_INFINITY), 0.0);
+    }
+
+    #[test]
+    fn level_heading_adds_the_declination() {
+        assert_close(
+            level_heading(Vector3::new(0.0, 1.0, 0.5), 0.0).degrees(),
+            90.0,
+            1e-4,
+        );
+        assert_close(
+            level_heading(Vector3::new(0.0, 1.0, 0.5), 10f32.to_radians()).degrees(),
+            100.0,
+            1e-4,
+        );
+        assert_close(
+            level_heading(Vector3::new(1.0, 0.0, 0.5), -5f32.to_radians()).degrees(),
+            355.0,
+            1e-4,
+        );
+    }
+
+    /// Accelerometer and magnetometer readings of a board pointing at
+    /// `heading` degrees, tilted by `roll` then `pitch` (in radians), in a
+    /// field dipping down at 65 degrees.
+    fn tilted(heading: f32, roll: f32, pitch: f32) -> (Vector3<f32>, Vector3<f32>) {
+        let heading = heading.to_radians();
+        let dip = 65f32.to_radians();
+
+        let level_acc = Vector3::z();
+        let level_mag = Vector3::new(
+            cosf(dip) * cosf(heading),
+            cosf(dip) * sinf(heading),
+            -sinf(dip),
+        );
+
+        let tilt = Rotation3::from_axis_angle(&Vector3::x_axis(), -roll)
+            * Rotation3::from_axis_angle(&Vector3::y_axis(), -pitch);
+
+        (tilt * level_acc, tilt * level_mag)
+    }
+
+    #[test]
+    fn compensates_for_tilt() {
+        for heading in [30.0, 135.0, 250.0] {
+            for (roll, pitch) in [(0.0, 0.0), (0.4, 0.0), (0.0, -0.6), (-0.5, 0.3), (1.2, 1.0)] {
+                let (acc, mag) = tilted(heading, roll, pitch);
+
+                assert_close(
+                    tilt_compensated_heading(acc, mag, 0.0).degrees(),
+                    heading,
+                    1e-2,
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn tilt_compensation_works_in_any_unit() {
+        let (acc, mag) = tilted(135.0, -0.5, 0.3);
+
+        assert_close(
+            tilt_compensated_heading(acc * 9.81, mag * 1_200.0, FRAC_PI_2).degrees(),
+            225.0,
+            1e-2,
+        );
+    }
+
+    #[test]
+    fn falls_back_to_level_without_a_direction_for_gravity() {
+        let mag = Vector3::new(0.3, -0.2, -0.4);
+        let level = level_heading(mag, 0.1);
+
+        for acc in [
+            Vector3::zeros(),
+            Vector3::new(f32::NAN, 0.0, 1.0),
+            Vector3::new(0.0, f32::INFINITY, 1.0),
+        ] {
+            assert_eq!(tilt_compensated_heading(acc, mag, 0.1), level, "{acc:?}");
+        }
+    }
+}
@@ -1,15 +1,26 @@
-use crate::{mpu6050::MPU_SIGNAL, prelude::*};
+use crate::prelude::*;
+#[cfg(target_arch = "xtensa")]
+use crate::{
+    health::{SystemHealth, HEALTH_BUS},
+    mpu6050::{MpuData, MPU_BUS},
+    watchdog::{self, TaskId},
+};
 
+#[cfg(target_arch = "xtensa")]
 use core::fmt::{self, Write};
 
+use ssd1306::mode::TerminalModeError;
+#[cfg(target_arch = "xtensa")]
 use ssd1306::{
-    mode::{TerminalDisplaySize, TerminalMode, TerminalModeError},
+    mode::{TerminalDisplaySize, TerminalMode},
     prelude::*,
     I2CDisplayInterface, Ssd1306,
 };
 
+#[cfg(target_arch = "xtensa")]
 type DisplayInternals<SIZE> = Ssd1306<I2CInterface<SharedI2C>, SIZE, TerminalMode>;
 
+#[cfg(target_arch = "xtensa")]
 type Result<T> = core::result::Result<T, Error<DisplayError>>;
 
 #[derive(Clone, Copy, ErrorCategory)]
@@ -48,10 +59,12 @@ impl From<core::fmt::Error> for DisplayError {
     }
 }
 
+#[cfg(target_arch = "xtensa")]
 pub struct Display<SIZE> {
     display: DisplayInternals<SIZE>,
 }
 
+#[cfg(target_arch = "xtensa")]
 impl<SIZE: DisplaySize + TerminalDisplaySize> Display<SIZE> {
     pub async fn new(i2c: SharedI2C, display_size: SIZE) -> Result<Self>
 // where I: Write
@@ -104,8 +117,51 @@ impl<SIZE: DisplaySize + TerminalDisplaySize> Display<SIZE> {
             .map_err(DisplayError::from)?;
         Ok(())
     }
+
+    /// Writes out every field of `mpu_data`, with `--` in place of any that
+    /// failed to be read.
+    pub fn write_mpu_data(&mut self, mpu_data: &MpuData) -> Result<()> {
+        match mpu_data.temp.valid() {
+            Some(temp) => self.write_fmt(format_args!("temp: {:.4}c\n", temp))?,
+            None => self.write_str("temp: --\n")?,
+        }
+
+        self.write_str("acc: (x,y,z)\n")?;
+        match mpu_data.acc.valid() {
+            Some(acc) => {
+                self.write_fmt(format_args!("{:.1}, {:.1}, {:.1}\n", acc.x, acc.y, acc.z))?
+            }
+            None => self.write_str("--\n")?,
+        }
+
+        self.write_str("gyro:\n")?;
+        match mpu_data.gyro.valid() {
+            Some(gyro) => self.write_fmt(format_args!(
+                "{:.0}, {:.0}, {:.0}\n",
+                gyro.x, gyro.y, gyro.z
+            ))?,
+            None => self.write_str("--\n")?,
+        }
+
+        self.write_str("roll/pitch:\n")?;
+        match mpu_data.roll_pitch.valid() {
+            Some(roll_pitch) => {
+                self.write_fmt(format_args!("{:.2}, {:.2}", roll_pitch.x, roll_pitch.y))?
+            }
+            None => self.write_str("--")?,
+        }
+
+        Ok(())
+    }
+
+    /// Writes the worst status of anything on the can, on the bottom line.
+    pub fn write_health(&mut self, health: &SystemHealth) -> Result<()> {
+        self.set_position(0, 7)?;
+        self.write_fmt(format_args!("health {:<8}", health.status().name()))
+    }
 }
 
+#[cfg(target_arch = "xtensa")]
 #[task]
 pub async fn screen_counter(mut display: Display<DisplaySize128x64>) {
     let mut counter: u16 = 0;
@@ -113,8 +169,10 @@ pub async fn screen_counter(mut display: Display<DisplaySize128x64>) {
     loop {
         Timer::after_millis(1).await;
 
-        display.reset_pos().print_warn();
-        display.write_fmt(format_args!("{}", counter)).print_warn();
+        display.reset_pos().print_warn_limited();
+        display
+            .write_fmt(format_args!("{}", counter))
+            .print_warn_limited();
 
         counter = match counter.checked_add(1) {
             Some(next) => next,
@@ -126,40 +184,39 @@ pub async fn screen_counter(mut display: Display<DisplaySize128x64>) {
     }
 }
 
+#[cfg(target_arch = "xtensa")]
 #[task]
 pub async fn display_numerical_data(
     mut display: Display<DisplaySize128x64>,
     //  control: &'static MpuSignal
 ) {
+    let heartbeat = watchdog::register(TaskId::Display, Duration::from_secs(1));
+    let mut mpu_bus = MPU_BUS.subscribe().expect("no MPU_BUS subscribers left");
+
     loop {
-        let mpu_data = MPU_SIGNAL.wait().await;
-
-        let success = display
-        .write_fmt(format_args!(
-            "temp: {:.4}c\nacc: (x,y,z)\n{:.1}, {:.1}, {:.1}\ngyro:\n{:.0}, {:.0}, {:.0}\nroll/pitch:\n{:.2}, {:.2}",
-            mpu_data.temp,
-            mpu_data.acc.x,
-            mpu_data.acc.y,
-            mpu_data.acc.z,
-            mpu_data.gyro.x,
-            mpu_data.gyro.y,
-            mpu_data.gyro.z,
-            mpu_data.roll_pitch.x,
-            mpu_data.roll_pitch.y
-        ));
-
-        if let Err(e) = success {
-            warn!("{e:?}");
-            //try and just clear the display normally
+        let mpu_data = mpu_bus.next().await.data;
+        heartbeat.beat();
+
+        let written = display
+            .write_mpu_data(&mpu_data)
+            .map(|()| true)
+            .print_warn_limited();
 
+        if !written {
+            //try and just clear the display normally, carrying on without it if
+            //it is gone rather than taking the can down with it
             Backoff::new(|| display.clear())
                 .with_log_level(log::Level::Trace)
                 .with_max_elapsed_time(Duration::from_millis(500))
                 .retry()
                 .await
-                .unwrap();
+                .print_warn_limited();
         };
 
-        display.reset_pos().print_warn();
+        if let Some(health) = HEALTH_BUS.latest() {
+            display.write_health(&health).print_warn_limited();
+        }
+
+        display.reset_pos().print_warn_limited();
     }
 }
@@ -0,0 +1,83 @@
+//! Packing the state of the can into [telemetry] frames, to be sent to the
+//! ground.
+
+use telemetry::{Baro, Encoder, FrameError, Gps, Imu, Packet, StateFrame};
+
+use crate::{
+    altitude::ALTITUDE_BUS,
+    bme280::BME_BUS,
+    flight::{FlightPhase, FLIGHT_PHASE_BUS},
+    gps::GPS_BUS,
+    health::HEALTH_BUS,
+    mpu6050::MPU_BUS,
+    prelude::*,
+    qmc5883l::MAG_BUS,
+    sample::Sample,
+};
+
+/// Readings older than this are left out of the state, rather than being sent
+/// as if they were current.
+pub const MAX_READING_AGE: Duration = Duration::from_secs(2);
+
+/// `sample`, if it was taken recently enough to be sent.
+fn fresh<T>(sample: Option<Sample<T>>) -> Option<Sample<T>> {
+    sample.filter(|sample| sample.time.elapsed() <= MAX_READING_AGE)
+}
+
+/// The latest reading from every sensor, as a [StateFrame].
+pub fn current_state() -> StateFrame {
+    let imu = fresh(MPU_BUS.latest()).and_then(|sample| {
+        Some(Imu {
+            acc: sample.data.acc.valid()?.into(),
+            gyro: sample.data.gyro.valid()?.into(),
+        })
+    });
+
+    // left out until the ground reference is known too, rather than sending
+    // a made up altitude
+    let baro = fresh(BME_BUS.latest()).and_then(|sample| {
+        let data = sample.data.valid()?;
+
+        Some(Baro {
+            pressure: data.pressure,
+            temperature: data.temperature,
+            altitude: fresh(ALTITUDE_BUS.latest())?.data.agl,
+        })
+    });
+
+    let mag = fresh(MAG_BUS.latest())
+        .and_then(|sample| sample.data.mag.valid())
+        .map(Into::into);
+
+    let gps = fresh(GPS_BUS.latest())
+        .filter(|sample| sample.data.has_fix())
+        .map(|sample| Gps {
+            latitude: sample.data.latitude,
+            longitude: sample.data.longitude,
+            altitude: sample.data.altitude,
+            satellites: sample.data.satellites,
+        });
+
+    let phase = FLIGHT_PHASE_BUS
+        .latest()
+        .map_or(FlightPhase::default(), |transition| transition.to);
+
+    StateFrame {
+        imu,
+        baro,
+        mag,
+        gps,
+        phase: phase as u8,
+        health: HEALTH_BUS.latest().map_or(0, |health| health.flags()),
+    }
+}
+
+/// Encodes the [current_state] as the next frame from `encoder`, stamped with
+/// the time since boot, returning the length of the frame.
+pub fn encode_state(encoder: &mut Encoder, buf: &mut [u8]) -> Result<usize, FrameError> {
+    encoder.encode(
+        Instant::now().as_millis() as u32,
+        Packet::State(current_state()),
+        buf,
+    )
+}
@@ -1,10 +1,232 @@
-use crate::prelude::*;
+//! The firmware-wide error hierarchy.
+//!
+//! Every subsystem keeps its own [ErrorCategory], and each of them links into
+//! [CansatError] - directly, or through one of the groups below it (there can
+//! only be six links per category):
+//!
+//! ```text
+//! CansatError
+//! ├── SensorError: MpuError, MagError, BMEError, CalibrationError
+//! ├── GpsError: NmeaError, UbxError
+//! ├── LoggerError: BlackBoxError, SdError
+//! ├── LoRaError
+//! ├── DisplayError
+//! └── BusError
+//! ```
+//!
+//! Any of them can also be turned into a [FaultCode] with [Fault::fault_code],
+//! which is small and stable enough to send in telemetry or write to the logs,
+//! and is what the [journal] records.
+
+pub mod journal;
+
+use core::fmt;
+
+use crate::{
+    bme280::BMEError,
+    bus::BusError,
+    display::DisplayError,
+    gps::{nmea::NmeaError, ubx::UbxError},
+    logger::{blackbox::BlackBoxError, sd::SdError},
+    lora::LoRaError,
+    mag_calibration::CalibrationError,
+    mpu6050::MpuError,
+    prelude::*,
+    qmc5883l::MagError,
+};
 
 #[derive(Clone, Copy, ErrorCategory)]
+#[error_category(links(SensorError, GpsError, LoggerError, LoRaError, DisplayError, BusError))]
 #[repr(u8)]
 pub enum CansatError {
     I2C,
     Unknown,
     IntegerOverflow,
     InterfaceError,
+    /// A sensor failed, see [SensorError]
+    Sensor,
+    /// The GPS receiver failed, see [GpsError]
+    Gps,
+    /// Logging to flash or the SD card failed, see [LoggerError]
+    Logger,
+    /// The LoRa radio failed
+    Radio,
+    /// The display failed
+    Display,
+    /// A data bus had no subscriber slots left
+    Bus,
+}
+
+#[derive(Clone, Copy, ErrorCategory)]
+#[error_category(links(MpuError, MagError, BMEError, CalibrationError))]
+#[repr(u8)]
+pub enum SensorError {
+    Mpu,
+    Mag,
+    Bme,
+    /// The magnetometer calibration couldn't be made or loaded
+    Calibration,
+}
+
+#[derive(Clone, Copy, ErrorCategory)]
+#[error_category(links(NmeaError, UbxError))]
+#[repr(u8)]
+pub enum GpsError {
+    Nmea,
+    Ubx,
+    /// Reading from the receiver's UART failed, whichever protocol it was
+    /// sending
+    Uart,
+}
+
+#[derive(Clone, Copy, ErrorCategory)]
+#[error_category(links(BlackBoxError, SdError))]
+#[repr(u8)]
+pub enum LoggerError {
+    BlackBox,
+    Sd,
+}
+
+/// Where an error came from - the high byte of its [FaultCode].
+///
+/// These are sent in telemetry and written to the logs, so existing values
+/// must never change - new subsystems go on the end.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+#[repr(u8)]
+pub enum Subsystem {
+    Cansat,
+    Sensor,
+    Gps,
+    Logger,
+    Mpu,
+    Mag,
+    Bme,
+    Calibration,
+    Nmea,
+    Ubx,
+    BlackBox,
+    Sd,
+    LoRa,
+    Display,
+    Bus,
+    /// A task stopped checking in with the watchdog - the code is its
+    /// [TaskId](crate::watchdog::TaskId)
+    Watchdog,
+}
+
+impl Subsystem {
+    pub fn from_u8(value: u8) -> Option<Self> {
+        Some(match value {
+            0 => Self::Cansat,
+            1 => Self::Sensor,
+            2 => Self::Gps,
+            3 => Self::Logger,
+            4 => Self::Mpu,
+            5 => Self::Mag,
+            6 => Self::Bme,
+            7 => Self::Calibration,
+            8 => Self::Nmea,
+            9 => Self::Ubx,
+            10 => Self::BlackBox,
+            11 => Self::Sd,
+            12 => Self::LoRa,
+            13 => Self::Display,
+            14 => Self::Bus,
+            15 => Self::Watchdog,
+            _ => return None,
+        })
+    }
+}
+
+/// An error as a number - the [Subsystem] in the high byte, and the error's
+/// variant within its category in the low byte.
+///
+/// Variants are only ever added to the end of a category, so a code means the
+/// same thing in every build.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct FaultCode(pub u16);
+
+impl FaultCode {
+    pub const fn new(subsystem: Subsystem, code: u8) -> Self {
+        Self(((subsystem as u16) << 8) | code as u16)
+    }
+
+    /// The subsystem the error came from, or [None] if it is from a newer
+    /// build than this one.
+    pub fn subsystem(self) -> Option<Subsystem> {
+        Subsystem::from_u8((self.0 >> 8) as u8)
+    }
+
+    /// The error's variant within its category.
+    pub fn code(self) -> u8 {
+        self.0 as u8
+    }
+}
+
+impl fmt::Display for FaultCode {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.subsystem() {
+            Some(subsystem) => write!(f, "{subsystem:?}:{}", self.code()),
+            None => write!(f, "{:#06x}", self.0),
+        }
+    }
+}
+
+/// An error category with a place in the hierarchy.
+pub trait Fault: Copy {
+    const SUBSYSTEM: Subsystem;
+
+    fn fault_code(self) -> FaultCode;
+
+    /// Chains the error up to the top of the hierarchy.
+    fn into_cansat(self) -> Error<CansatError>;
+}
+
+/// Implements [Fault] for each category, chaining it through its group (if it
+/// has one) to [CansatError].
+macro_rules! faults {
+    ($($category:ty => $subsystem:ident: $($link:expr),+;)+) => {
+        $(
+            impl Fault for $category {
+                const SUBSYSTEM: Subsystem = Subsystem::$subsystem;
+
+                fn fault_code(self) -> FaultCode {
+                    FaultCode::new(Self::SUBSYSTEM, self as u8)
+                }
+
+                fn into_cansat(self) -> Error<CansatError> {
+                    Error::new(self)$(.chain($link))+
+                }
+            }
+        )+
+    };
+}
+
+faults! {
+    SensorError => Sensor: CansatError::Sensor;
+    GpsError => Gps: CansatError::Gps;
+    LoggerError => Logger: CansatError::Logger;
+    MpuError => Mpu: SensorError::Mpu, CansatError::Sensor;
+    MagError => Mag: SensorError::Mag, CansatError::Sensor;
+    BMEError => Bme: SensorError::Bme, CansatError::Sensor;
+    CalibrationError => Calibration: SensorError::Calibration, CansatError::Sensor;
+    NmeaError => Nmea: GpsError::Nmea, CansatError::Gps;
+    UbxError => Ubx: GpsError::Ubx, CansatError::Gps;
+    BlackBoxError => BlackBox: LoggerError::BlackBox, CansatError::Logger;
+    SdError => Sd: LoggerError::Sd, CansatError::Logger;
+    LoRaError => LoRa: CansatError::Radio;
+    DisplayError => Display: CansatError::Display;
+    BusError => Bus: CansatError::Bus;
+}
+
+impl Fault for CansatError {
+    const SUBSYSTEM: Subsystem = Subsystem::Cansat;
+
+    fn fault_code(self) -> FaultCode {
+        FaultCode::new(Self::SUBSYSTEM, self as u8)
+    }
+
+    fn into_cansat(self) -> Error<CansatError> {
+        Error::new(self)
+    }
 }
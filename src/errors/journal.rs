@@ -0,0 +1,291 @@
+//! A timestamped record of the errors during a flight.
+//!
+//! [record] adds an error to a fixed-size RAM journal, which the health
+//! monitor and anything else can look back through with [entries]. Repeats of
+//! an error that is still happening are folded into one entry, so one failing
+//! sensor can't push everything else out.
+//!
+//! Each new entry is also sent on [FAULT_BUS], which the loggers write to the
+//! black box and SD card - so the journal can be downloaded after the flight,
+//! along with everything else, even if the can has been power cycled since.
+
+use core::cell::RefCell;
+
+use heapless::{Deque, Vec};
+
+use super::{Fault, FaultCode};
+use crate::{
+    bus::{BusSubscriber, DataBus},
+    prelude::*,
+};
+
+pub type FaultBus = DataBus<JournalEntry, 8, 4>;
+pub static FAULT_BUS: FaultBus = DataBus::new();
+pub type FaultSubscriber = BusSubscriber<'static, JournalEntry, 8, 4>;
+
+/// How many entries the journal holds, before the oldest are overwritten.
+pub const JOURNAL_CAPACITY: usize = 64;
+
+/// How soon an error must happen again to count as a repeat, rather than a
+/// new entry.
+pub const REPEAT_WINDOW: Duration = Duration::from_secs(1);
+
+static ERROR_JOURNAL: Mutex<RefCell<ErrorJournal<JOURNAL_CAPACITY>>> =
+    Mutex::new(RefCell::new(ErrorJournal::new(REPEAT_WINDOW)));
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct JournalEntry {
+    pub code: FaultCode,
+    /// When the error first happened.
+    pub first: Instant,
+    /// When the error last happened, within [REPEAT_WINDOW] of the time before.
+    pub last: Instant,
+    /// How many times the error happened between [JournalEntry::first] and
+    /// [JournalEntry::last].
+    pub count: u16,
+}
+
+/// A ring of the most recent errors.
+#[derive(Clone, Debug)]
+pub struct ErrorJournal<const N: usize> {
+    entries: Deque<JournalEntry, N>,
+    repeat_window: Duration,
+    /// How many entries have been pushed out of the ring by newer ones.
+    overwritten: u32,
+    /// How many errors have been recorded, including repeats.
+    total: u32,
+}
+
+impl<const N: usize> ErrorJournal<N> {
+    pub const fn new(repeat_window: Duration) -> Self {
+        Self {
+            entries: Deque::new(),
+            repeat_window,
+            overwritten: 0,
+            total: 0,
+        }
+    }
+
+    /// Adds an error which happened at `time`, returning the new entry, or
+    /// [None] if it was a repeat of one already in the journal.
+    pub fn record(&mut self, code: FaultCode, time: Instant) -> Option<JournalEntry> {
+        self.total = self.total.saturating_add(1);
+
+        let window = self.repeat_window;
+
+        let repeat = self
+            .entries
+            .iter_mut()
+            .rev()
+            .find(|entry| entry.code == code);
+
+        if let Some(entry) = repeat {
+            if time.saturating_duration_since(entry.last) <= window {
+                entry.last = time;
+                entry.count = entry.count.saturating_add(1);
+                return None;
+            }
+        }
+
+        let entry = JournalEntry {
+            code,
+            first: time,
+            last: time,
+            count: 1,
+        };
+
+        if self.entries.is_full() {
+            self.entries.pop_front();
+            self.overwritten = self.overwritten.saturating_add(1);
+        }
+
+        // there is always room, after the pop
+        let _ = self.entries.push_back(entry);
+
+        Some(entry)
+    }
+
+    /// Every entry, oldest first.
+    pub fn iter(&self) -> impl Iterator<Item = &JournalEntry> {
+        self.entries.iter()
+    }
+
+    /// The entry for the latest error.
+    pub fn latest(&self) -> Option<&JournalEntry> {
+        self.entries.back()
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    /// How many entries have been pushed out by newer ones.
+    pub fn overwritten(&self) -> u32 {
+        self.overwritten
+    }
+
+    /// How many errors have been recorded, including repeats.
+    pub fn total(&self) -> u32 {
+        self.total
+    }
+
+    pub fn clear(&mut self) {
+        self.entries.clear();
+        self.overwritten = 0;
+        self.total = 0;
+    }
+}
+
+/// Records `fault` in the journal as happening now, and sends it on
+/// [FAULT_BUS] if it isn't a repeat.
+pub fn record(fault: impl Fault) {
+    record_at(fault.fault_code(), Instant::now());
+}
+
+/// Records the error `code` in the journal as happening at `time`.
+pub fn record_at(code: FaultCode, time: Instant) {
+    let entry = critical_section::with(|cs| ERROR_JOURNAL.borrow_ref_mut(cs).record(code, time));
+
+    if let Some(entry) = entry {
+        FAULT_BUS.publish(entry);
+    }
+}
+
+/// A copy of every entry in the journal, oldest first.
+pub fn entries() -> Vec<JournalEntry, JOURNAL_CAPACITY> {
+    critical_section::with(|cs| ERROR_JOURNAL.borrow_ref(cs).iter().copied().collect())
+}
+
+/// The entry for the latest error.
+pub fn latest() -> Option<JournalEntry> {
+    critical_section::with(|cs| ERROR_JOURNAL.borrow_ref(cs).latest().copied())
+}
+
+/// How many errors have been recorded since boot, including repeats.
+pub fn total() -> u32 {
+    critical_section::with(|cs| ERROR_JOURNAL.borrow_ref(cs).total())
+}
+
+/// Prints the journal over UART, oldest first.
+pub fn dump() {
+    println!("# first_ms,last_ms,count,fault");
+
+    for entry in entries() {
+        println!(
+            "{},{},{},{}",
+            entry.first.as_millis(),
+            entry.last.as_millis(),
+            entry.count,
+            entry.code
+        );
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn at(ms: u64) -> Instant {
+        Instant::from_millis(ms)
+    }
+
+    fn code(code: u8) -> FaultCode {
+        FaultCode::new(Subsystem::Sensor, code)
+    }
+
+    fn codes<const N: usize>(journal: &ErrorJournal<N>) -> Vec<FaultCode, N> {
+        journal.iter().map(|entry| entry.code).collect()
+    }
+
+    #[test]
+    fn folds_repeats_into_one_entry() {
+        let mut journal: ErrorJournal<4> = ErrorJournal::new(REPEAT_WINDOW);
+
+        assert!(journal.record(code(1), at(0)).is_some());
+        assert!(journal.record(code(1), at(500)).is_none());
+        // within the window of the last repeat, not the first
+        assert!(journal.record(code(1), at(1_400)).is_none());
+
+        assert_eq!(journal.len(), 1);
+        assert_eq!(
+            journal.latest(),
+            Some(&JournalEntry {
+                code: code(1),
+                first: at(0),
+                last: at(1_400),
+                count: 3,
+            })
+        );
+        assert_eq!(journal.total(), 3);
+    }
+
+    #[test]
+    fn repeats_after_the_window_are_new_entries() {
+        let mut journal: ErrorJournal<4> = ErrorJournal::new(REPEAT_WINDOW);
+
+        journal.record(code(1), at(0));
+        let entry = journal.record(code(1), at(1_001));
+
+        assert_eq!(
+            entry,
+            Some(JournalEntry {
+                code: code(1),
+                first: at(1_001),
+                last: at(1_001),
+                count: 1,
+            })
+        );
+        assert_eq!(journal.len(), 2);
+        assert_eq!(journal.total(), 2);
+    }
+
+    #[test]
+    fn repeats_fold_in_past_other_errors() {
+        let mut journal: ErrorJournal<4> = ErrorJournal::new(REPEAT_WINDOW);
+
+        journal.record(code(1), at(0));
+        journal.record(code(2), at(100));
+        assert!(journal.record(code(1), at(200)).is_none());
+
+        assert_eq!(codes(&journal), [code(1), code(2)]);
+        assert_eq!(journal.iter().next().map(|entry| entry.count), Some(2));
+        assert_eq!(journal.latest().map(|entry| entry.code), Some(code(2)));
+    }
+
+    #[test]
+    fn overwrites_the_oldest_entries_once_full() {
+        let mut journal: ErrorJournal<4> = ErrorJournal::new(REPEAT_WINDOW);
+
+        for i in 0..6 {
+            journal.record(code(i), at(i as u64 * 100));
+        }
+
+        assert_eq!(codes(&journal), [code(2), code(3), code(4), code(5)]);
+        assert_eq!(journal.overwritten(), 2);
+        assert_eq!(journal.total(), 6);
+
+        // the entry it would have been a repeat of has gone
+        assert!(journal.record(code(0), at(700)).is_some());
+        assert_eq!(codes(&journal), [code(3), code(4), code(5), code(0)]);
+        assert_eq!(journal.overwritten(), 3);
+    }
+
+    #[test]
+    fn clearing_starts_again() {
+        let mut journal: ErrorJournal<4> = ErrorJournal::new(REPEAT_WINDOW);
+
+        for i in 0..6 {
+            journal.record(code(i), at(0));
+        }
+        journal.clear();
+
+        assert!(journal.is_empty());
+        assert_eq!(journal.latest(), None);
+        assert_eq!(journal.overwritten(), 0);
+        assert_eq!(journal.total(), 0);
+    }
+}
@@ -0,0 +1,584 @@
+use embassy_futures::select::{select, Either};
+use libm::fabsf;
+
+use crate::{
+    altitude::{AltitudeData, ALTITUDE_BUS},
+    bus::{BusSubscriber, DataBus},
+    logger::pretrigger::{self, RESUMED_FLIGHT_TIMEOUT},
+    mpu6050::{MpuData, MPU_BUS},
+    prelude::*,
+    watchdog::{self, TaskId},
+};
+
+pub type FlightPhaseBus = DataBus<PhaseTransition, 4, 8>;
+pub static FLIGHT_PHASE_BUS: FlightPhaseBus = DataBus::new();
+pub type FlightPhaseSubscriber = BusSubscriber<'static, PhaseTransition, 4, 8>;
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, PartialOrd, Ord)]
+#[repr(u8)]
+pub enum FlightPhase {
+    /// Sitting on the pad (or in the hand) waiting to be launched.
+    #[default]
+    PreLaunch,
+    /// Launch has been detected and the can is still climbing.
+    Ascent,
+    /// The can has stopped climbing, but has not yet fallen far enough to be
+    /// sure it is descending.
+    Apogee,
+    /// Falling back to the ground.
+    Descent,
+    /// On the ground and no longer moving.
+    Landed,
+}
+
+impl FlightPhase {
+    /// The phase with discriminant `value`, such as one stored with
+    /// `phase as u8`.
+    pub fn from_u8(value: u8) -> Option<Self> {
+        match value {
+            0 => Some(Self::PreLaunch),
+            1 => Some(Self::Ascent),
+            2 => Some(Self::Apogee),
+            3 => Some(Self::Descent),
+            4 => Some(Self::Landed),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct PhaseTransition {
+    pub from: FlightPhase,
+    pub to: FlightPhase,
+    /// When the sample that caused the transition was taken.
+    pub time: Instant,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct FlightConfig {
+    /// Acceleration magnitude (in g) which counts as the can being launched.
+    ///
+    /// Defaults to 2.5g.
+    pub launch_acceleration: f32,
+    /// How long the launch acceleration must be sustained for.
+    ///
+    /// Defaults to 100ms.
+    pub launch_duration: Duration,
+    /// Altitude above ground (in metres) which counts as the can being launched,
+    /// in case the acceleration was missed.
+    ///
+    /// Defaults to 15m.
+    pub launch_altitude: f32,
+    /// How far below the maximum altitude (in metres) a reading must be to count
+    /// as descending.
+    ///
+    /// Defaults to 2m.
+    pub apogee_drop: f32,
+    /// How many consecutive barometric readings are needed before launch or
+    /// apogee are detected from altitude alone.
+    ///
+    /// Defaults to 3.
+    pub debounce_samples: u8,
+    /// How far below the maximum altitude (in metres) the can must fall before
+    /// it is considered to be descending.
+    ///
+    /// Defaults to 10m.
+    pub descent_drop: f32,
+    /// How far (in metres) the altitude may wander while still being considered
+    /// stationary.
+    ///
+    /// Defaults to 1m.
+    pub landed_altitude_window: f32,
+    /// How far (in g) the acceleration magnitude may be from 1g while still being
+    /// considered stationary.
+    ///
+    /// Defaults to 0.2g.
+    pub landed_acceleration_tolerance: f32,
+    /// How long the can must be stationary before it is considered landed.
+    ///
+    /// Defaults to 5 seconds.
+    pub landed_duration: Duration,
+}
+
+impl Default for FlightConfig {
+    fn default() -> Self {
+        Self {
+            launch_acceleration: 2.5,
+            launch_duration: Duration::from_millis(100),
+            launch_altitude: 15.0,
+            apogee_drop: 2.0,
+            debounce_samples: 3,
+            descent_drop: 10.0,
+            landed_altitude_window: 1.0,
+            landed_acceleration_tolerance: 0.2,
+            landed_duration: Duration::from_secs(5),
+        }
+    }
+}
+
+/// Works out which [FlightPhase] the can is in from a stream of IMU and
+/// barometric samples.
+#[derive(Clone, Debug)]
+pub struct FlightStateMachine {
+    config: FlightConfig,
+    phase: FlightPhase,
+    /// When the acceleration first went above the launch threshold.
+    launch_start: Option<Instant>,
+    /// Consecutive readings above the launch altitude.
+    launch_altitude_count: u8,
+    /// Highest altitude above ground seen since launch.
+    max_altitude: f32,
+    /// Consecutive readings below the maximum altitude.
+    descending_count: u8,
+    /// When the altitude settled, and the altitude it settled at.
+    altitude_still_since: Option<(Instant, f32)>,
+    /// When the acceleration settled at 1g.
+    acceleration_still_since: Option<Instant>,
+}
+
+impl FlightStateMachine {
+    pub fn new(config: FlightConfig) -> Self {
+        Self {
+            config,
+            phase: FlightPhase::PreLaunch,
+            launch_start: None,
+            launch_altitude_count: 0,
+            max_altitude: f32::MIN,
+            descending_count: 0,
+            altitude_still_since: None,
+            acceleration_still_since: None,
+        }
+    }
+
+    pub fn phase(&self) -> FlightPhase {
+        self.phase
+    }
+
+    pub fn config(&self) -> &FlightConfig {
+        &self.config
+    }
+
+    /// The highest altitude above ground seen since launch, if the can has
+    /// launched.
+    pub fn max_altitude(&self) -> Option<f32> {
+        (self.phase > FlightPhase::PreLaunch && self.max_altitude > f32::MIN)
+            .then_some(self.max_altitude)
+    }
+
+    /// Feeds in an IMU sample taken at `time`, returning the transition it caused
+    /// (if any).
+    pub fn update_imu(&mut self, time: Instant, data: &MpuData) -> Option<PhaseTransition> {
+        let acceleration = data.acc.valid()?.norm();
+
+        match self.phase {
+            FlightPhase::PreLaunch => {
+                if acceleration < self.config.launch_acceleration {
+                    self.launch_start = None;
+                    return None;
+                }
+
+                let start = *self.launch_start.get_or_insert(time);
+
+                if time.saturating_duration_since(start) >= self.config.launch_duration {
+                    return self.transition(time, FlightPhase::Ascent);
+                }
+            }
+            FlightPhase::Descent => {
+                if fabsf(acceleration - 1.0) > self.config.landed_acceleration_tolerance {
+                    self.acceleration_still_since = None;
+                    return None;
+                }
+
+                self.acceleration_still_since.get_or_insert(time);
+
+                return self.check_landed(time);
+            }
+            _ => (),
+        }
+
+        None
+    }
+
+    /// Feeds in a barometric sample taken at `time`, returning the transition it
+    /// caused (if any).
+    pub fn update_baro(&mut self, time: Instant, data: &AltitudeData) -> Option<PhaseTransition> {
+        let altitude = data.agl;
+
+        if self.phase > FlightPhase::PreLaunch && altitude > self.max_altitude {
+            self.max_altitude = altitude;
+        }
+
+        match self.phase {
+            FlightPhase::PreLaunch => {
+                if altitude < self.config.launch_altitude {
+                    self.launch_altitude_count = 0;
+                    return None;
+                }
+
+                self.launch_altitude_count = self.launch_altitude_count.saturating_add(1);
+
+                if self.launch_altitude_count >= self.config.debounce_samples {
+                    self.max_altitude = altitude;
+                    return self.transition(time, FlightPhase::Ascent);
+                }
+            }
+            FlightPhase::Ascent => {
+                if altitude > self.max_altitude - self.config.apogee_drop {
+                    self.descending_count = 0;
+                    return None;
+                }
+
+                self.descending_count = self.descending_count.saturating_add(1);
+
+                if self.descending_count >= self.config.debounce_samples {
+                    return self.transition(time, FlightPhase::Apogee);
+                }
+            }
+            FlightPhase::Apogee => {
+                if altitude <= self.max_altitude - self.config.descent_drop {
+                    return self.transition(time, FlightPhase::Descent);
+                }
+            }
+            FlightPhase::Descent => {
+                match self.altitude_still_since {
+                    Some((_, settled))
+                        if fabsf(altitude - settled) <= self.config.landed_altitude_window => {}
+                    _ => self.altitude_still_since = Some((time, altitude)),
+                }
+
+                return self.check_landed(time);
+            }
+            FlightPhase::Landed => (),
+        }
+
+        None
+    }
+
+    fn check_landed(&mut self, time: Instant) -> Option<PhaseTransition> {
+        let (Some((altitude_since, _)), Some(acceleration_since)) =
+            (self.altitude_still_since, self.acceleration_still_since)
+        else {
+            return None;
+        };
+
+        let still_since = altitude_since.max(acceleration_since);
+
+        if time.saturating_duration_since(still_since) >= self.config.landed_duration {
+            return self.transition(time, FlightPhase::Landed);
+        }
+
+        None
+    }
+
+    fn transition(&mut self, time: Instant, to: FlightPhase) -> Option<PhaseTransition> {
+        let transition = PhaseTransition {
+            from: self.phase,
+            to,
+            time,
+        };
+
+        self.phase = to;
+
+        Some(transition)
+    }
+}
+
+impl Default for FlightStateMachine {
+    fn default() -> Self {
+        Self::new(FlightConfig::default())
+    }
+}
+
+#[task]
+pub async fn flight_state(config: FlightConfig) {
+    let heartbeat = watchdog::register(TaskId::Flight, Duration::from_secs(1));
+    let mut state_machine = FlightStateMachine::new(config);
+    // the state machine can't see the landing of a flight it was reset in
+    let mut resumed_in_flight = pretrigger::in_flight();
+    let resume_deadline = Instant::now() + RESUMED_FLIGHT_TIMEOUT;
+
+    let mut mpu_bus = MPU_BUS.subscribe().expect("no MPU_BUS subscribers left");
+    let mut altitude_bus = ALTITUDE_BUS
+        .subscribe()
+        .expect("no ALTITUDE_BUS subscribers left");
+
+    loop {
+        let transition = match select(mpu_bus.next(), altitude_bus.next()).await {
+            Either::First(mpu_sample) => {
+                state_machine.update_imu(mpu_sample.time, &mpu_sample.data)
+            }
+            Either::Second(altitude_sample) => {
+                state_machine.update_baro(altitude_sample.time, &altitude_sample.data)
+            }
+        };
+        heartbeat.beat();
+
+        if let Some(transition) = transition {
+            info!("Flight phase: {:?} -> {:?}", transition.from, transition.to);
+
+            pretrigger::set_in_flight(transition.to != FlightPhase::Landed);
+            resumed_in_flight = false;
+
+            FLIGHT_PHASE_BUS.publish(transition);
+        } else if resumed_in_flight && Instant::now() >= resume_deadline {
+            info!("No launch since resetting in flight, the flight must be over");
+
+            pretrigger::set_in_flight(false);
+            resumed_in_flight = false;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use nalgebra::{Vector2, Vector3};
+
+    use super::*;
+    use crate::{altitude::GroundReference, sample::Reading, test_utils::Noise};
+
+    const STANDARD_GRAVITY: f32 = 9.806_65;
+
+    fn at(ms: u64) -> Instant {
+        Instant::from_millis(ms)
+    }
+
+    /// An IMU reading of `g` straight up, as the can reads when accelerating
+    /// at `g - 1` g.
+    fn imu(g: f32) -> MpuData {
+        MpuData {
+            roll_pitch: Reading::Valid(Vector2::zeros()),
+            temp: Reading::Valid(20.0),
+            gyro: Reading::Valid(Vector3::zeros()),
+            acc: Reading::Valid(Vector3::new(0.0, 0.0, g)),
+        }
+    }
+
+    fn baro(agl: f32) -> AltitudeData {
+        AltitudeData {
+            absolute: agl + 100.0,
+            agl,
+            reference: GroundReference::default(),
+        }
+    }
+
+    /// A flight which launches at 1s, boosts at 4g for 1s, coasts up to apogee
+    /// and comes down under a parachute at 5m/s, landing at `LANDING_MS`.
+    ///
+    /// Returns the altitude (in metres) and acceleration reading (in g) at
+    /// `ms`.
+    fn synthetic_flight(ms: u64) -> (f32, f32) {
+        const LAUNCH: f32 = 1.0;
+        const BURNOUT: f32 = 2.0;
+        const BOOST: f32 = 4.0 * STANDARD_GRAVITY;
+        const DESCENT_RATE: f32 = 5.0;
+
+        let t = ms as f32 / 1000.0;
+
+        let burnout_velocity = BOOST * (BURNOUT - LAUNCH);
+        let burnout_altitude = 0.5 * BOOST * (BURNOUT - LAUNCH).powi(2);
+        let apogee_time = BURNOUT + burnout_velocity / STANDARD_GRAVITY;
+        let apogee = burnout_altitude + burnout_velocity.powi(2) / (2.0 * STANDARD_GRAVITY);
+        let landing_time = apogee_time + apogee / DESCENT_RATE;
+
+        if t < LAUNCH {
+            (0.0, 1.0)
+        } else if t < BURNOUT {
+            let dt = t - LAUNCH;
+            (0.5 * BOOST * dt * dt, 1.0 + BOOST / STANDARD_GRAVITY)
+        } else if t < apogee_time {
+            // in free fall, the accelerometer reads nothing
+            let dt = t - BURNOUT;
+            (
+                burnout_altitude + burnout_velocity * dt - 0.5 * STANDARD_GRAVITY * dt * dt,
+                0.0,
+            )
+        } else if t < landing_time {
+            (apogee - DESCENT_RATE * (t - apogee_time), 1.0)
+        } else {
+            (0.0, 1.0)
+        }
+    }
+
+    /// Apogee and landing times of [synthetic_flight], in milliseconds.
+    const APOGEE_MS: u64 = 6_000;
+    const LANDING_MS: u64 = 25_613;
+
+    /// Runs the state machine through [synthetic_flight], with the IMU at
+    /// 100Hz and the barometer at 10Hz, collecting the transitions.
+    fn fly(mut noise: Option<&mut Noise>) -> [Option<PhaseTransition>; 4] {
+        let mut state_machine = FlightStateMachine::default();
+        let mut transitions = [None; 4];
+        let mut count = 0;
+
+        for ms in (0..60_000).step_by(10) {
+            let (altitude, g) = synthetic_flight(ms);
+
+            let (altitude_noise, g_noise) = match &mut noise {
+                Some(noise) => (noise.next(0.5), noise.next(0.05)),
+                None => (0.0, 0.0),
+            };
+
+            let mut record = |transition| {
+                if let Some(transition) = transition {
+                    transitions[count] = Some(transition);
+                    count += 1;
+                }
+            };
+
+            record(state_machine.update_imu(at(ms), &imu(g + g_noise)));
+
+            if ms % 100 == 0 {
+                record(state_machine.update_baro(at(ms), &baro(altitude + altitude_noise)));
+            }
+        }
+
+        assert_eq!(state_machine.phase(), FlightPhase::Landed);
+
+        transitions
+    }
+
+    fn assert_flight(transitions: [Option<PhaseTransition>; 4]) {
+        let [launch, apogee, descent, landed] = transitions.map(Option::unwrap);
+
+        assert_eq!(
+            (launch.from, launch.to),
+            (FlightPhase::PreLaunch, FlightPhase::Ascent)
+        );
+        assert_eq!(
+            (apogee.from, apogee.to),
+            (FlightPhase::Ascent, FlightPhase::Apogee)
+        );
+        assert_eq!(
+            (descent.from, descent.to),
+            (FlightPhase::Apogee, FlightPhase::Descent)
+        );
+        assert_eq!(
+            (landed.from, landed.to),
+            (FlightPhase::Descent, FlightPhase::Landed)
+        );
+
+        // the acceleration has to last 100ms
+        assert!((1_100..=1_120).contains(&launch.time.as_millis()));
+        // 2m down and 3 readings to be sure
+        assert!((APOGEE_MS..=APOGEE_MS + 1_500).contains(&apogee.time.as_millis()));
+        // 10m down under the parachute
+        assert!((APOGEE_MS + 1_800..=APOGEE_MS + 2_500).contains(&descent.time.as_millis()));
+        // still for 5 seconds, from when it was within a metre of the ground
+        assert!((LANDING_MS + 4_500..=LANDING_MS + 6_500).contains(&landed.time.as_millis()));
+    }
+
+    #[test]
+    fn synthetic_flight_is_as_described() {
+        let (apogee, _) = synthetic_flight(APOGEE_MS);
+        assert!(apogee > synthetic_flight(APOGEE_MS - 100).0);
+        assert!(apogee > synthetic_flight(APOGEE_MS + 100).0);
+        assert!(synthetic_flight(LANDING_MS - 100).0 > 0.0);
+        assert_eq!(synthetic_flight(LANDING_MS + 100).0, 0.0);
+    }
+
+    #[test]
+    fn follows_a_clean_flight() {
+        assert_flight(fly(None));
+    }
+
+    #[test]
+    fn follows_a_noisy_flight() {
+        assert_flight(fly(Some(&mut Noise(1))));
+        assert_flight(fly(Some(&mut Noise(0xdead_beef))));
+    }
+
+    #[test]
+    fn launch_acceleration_is_debounced() {
+        let mut state_machine = FlightStateMachine::default();
+
+        // a knock on the pad
+        assert_eq!(state_machine.update_imu(at(0), &imu(4.0)), None);
+        assert_eq!(state_machine.update_imu(at(50), &imu(4.0)), None);
+        assert_eq!(state_machine.update_imu(at(60), &imu(1.0)), None);
+        assert_eq!(state_machine.update_imu(at(200), &imu(4.0)), None);
+        assert_eq!(state_machine.phase(), FlightPhase::PreLaunch);
+
+        // failed readings don't count either way
+        let mut failed = imu(4.0);
+        failed.acc = Reading::Invalid(0);
+        assert_eq!(state_machine.update_imu(at(250), &failed), None);
+
+        let transition = state_machine.update_imu(at(300), &imu(4.0)).unwrap();
+        assert_eq!(transition.to, FlightPhase::Ascent);
+        assert_eq!(transition.time, at(300));
+    }
+
+    #[test]
+    fn launch_altitude_is_debounced() {
+        let mut state_machine = FlightStateMachine::default();
+
+        // a gust of wind over the barometer
+        assert_eq!(state_machine.update_baro(at(0), &baro(20.0)), None);
+        assert_eq!(state_machine.update_baro(at(100), &baro(20.0)), None);
+        assert_eq!(state_machine.update_baro(at(200), &baro(1.0)), None);
+        assert_eq!(state_machine.update_baro(at(300), &baro(20.0)), None);
+        assert_eq!(state_machine.update_baro(at(400), &baro(21.0)), None);
+        assert_eq!(state_machine.phase(), FlightPhase::PreLaunch);
+        assert_eq!(state_machine.max_altitude(), None);
+
+        let transition = state_machine.update_baro(at(500), &baro(22.0)).unwrap();
+        assert_eq!(transition.to, FlightPhase::Ascent);
+        assert_eq!(state_machine.max_altitude(), Some(22.0));
+    }
+
+    #[test]
+    fn apogee_ignores_single_low_readings() {
+        let mut state_machine = FlightStateMachine::default();
+
+        for ms in (0..=100).step_by(10) {
+            state_machine.update_imu(at(ms), &imu(4.0));
+        }
+        assert_eq!(state_machine.phase(), FlightPhase::Ascent);
+
+        for (i, altitude) in [10.0, 20.0, 30.0, 5.0, 35.0, 25.0, 26.0, 40.0]
+            .into_iter()
+            .enumerate()
+        {
+            assert_eq!(
+                state_machine.update_baro(at(200 + i as u64 * 100), &baro(altitude)),
+                None
+            );
+        }
+
+        assert_eq!(state_machine.phase(), FlightPhase::Ascent);
+        assert_eq!(state_machine.max_altitude(), Some(40.0));
+    }
+
+    #[test]
+    fn landing_needs_both_sensors_still() {
+        let mut state_machine = FlightStateMachine::default();
+
+        state_machine.update_baro(at(0), &baro(50.0));
+        state_machine.update_baro(at(100), &baro(50.0));
+        state_machine.update_baro(at(200), &baro(50.0));
+        for (i, altitude) in [45.0, 44.0, 43.0, 30.0].into_iter().enumerate() {
+            state_machine.update_baro(at(300 + i as u64 * 100), &baro(altitude));
+        }
+        assert_eq!(state_machine.phase(), FlightPhase::Descent);
+
+        // swinging under the parachute, at a standstill
+        for ms in (1_000..10_000).step_by(100) {
+            let g = if (ms / 100) % 2 == 1 { 1.5 } else { 1.0 };
+            assert_eq!(state_machine.update_imu(at(ms), &imu(g)), None);
+            assert_eq!(state_machine.update_baro(at(ms), &baro(0.0)), None);
+        }
+
+        // and then lying still
+        for ms in (10_000..15_000).step_by(100) {
+            assert_eq!(state_machine.update_imu(at(ms), &imu(1.0)), None);
+            assert_eq!(state_machine.update_baro(at(ms), &baro(0.0)), None);
+        }
+
+        let transition = state_machine.update_imu(at(15_000), &imu(1.0)).unwrap();
+        assert_eq!(transition.to, FlightPhase::Landed);
+
+        // nothing after landing
+        assert_eq!(state_machine.update_baro(at(16_000), &baro(100.0)), None);
+        assert_eq!(state_machine.update_imu(at(16_000), &imu(5.0)), None);
+    }
+}
@@ -0,0 +1,461 @@
+//! Position, speed and time from a GPS receiver on a UART.
+
+pub mod nmea;
+pub mod ubx;
+
+use core::fmt;
+
+use embassy_time::with_timeout;
+use embedded_io_async::{Read, Write};
+
+use self::{
+    nmea::{NmeaParser, Sentence},
+    ubx::{
+        Message, MessageId, UbxConfig, UbxError, UbxParser, CFG_MSG, CFG_NAV5, CFG_PRT, CFG_RATE,
+        NAV_DOP, NAV_PVT,
+    },
+};
+use crate::{
+    bus::{BusSubscriber, DataBus},
+    errors::journal,
+    prelude::*,
+    sample::Sample,
+    watchdog::Heartbeat,
+};
+#[cfg(target_arch = "xtensa")]
+use crate::{
+    sample::{Sequencer, SourceId},
+    watchdog::{self, TaskId},
+};
+
+pub type GpsBus = DataBus<Sample<GpsFix>, 4, 8>;
+pub static GPS_BUS: GpsBus = DataBus::new();
+pub type GpsSubscriber = BusSubscriber<'static, Sample<GpsFix>, 4, 8>;
+
+#[cfg(target_arch = "xtensa")]
+pub type GpsUart = hal::Uart<'static, hal::peripherals::UART2>;
+
+/// What most receivers talk at out of the box.
+pub const GPS_BAUD_RATE: u32 = 9600;
+
+/// How long to wait for a u-blox receiver to acknowledge a configuration
+/// message.
+const ACK_TIMEOUT: Duration = Duration::from_secs(1);
+/// How long to give a u-blox receiver to switch baud rate.
+const BAUD_RATE_SWITCH_DELAY: Duration = Duration::from_millis(100);
+
+/// How long to wait before reading the UART again after an error. Most are
+/// one-off framing or overrun errors, so the first wait is short, but one that
+/// keeps failing is only tried every second or so.
+const UART_RETRY_POLICY: BackoffPolicy = BackoffPolicy {
+    initial_interval: Duration::from_millis(5),
+    multiplier: 2.0,
+    max_interval: Duration::from_secs(1),
+    randomization_factor: DEFAULT_RANDOMIZATION_FACTOR,
+    max_elapsed_time: None,
+};
+
+/// The longest a read waits for the receiver, so the task keeps checking in
+/// with the watchdog while it is silent - a receiver which has stopped sending
+/// is the GPS failing, not the task.
+const READ_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// What the receiver is sending.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum GpsProtocol {
+    Nmea,
+    /// Only after the receiver has been set up with [configure_ubx]
+    Ubx,
+}
+
+/// Reported by receivers when they don't know the dilution of precision.
+const UNKNOWN_DOP: f32 = 99.99;
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, PartialOrd, Ord)]
+#[repr(u8)]
+pub enum FixType {
+    #[default]
+    NoFix,
+    /// Latitude and longitude only, the altitude can't be trusted
+    Fix2D,
+    Fix3D,
+}
+
+impl FixType {
+    pub fn from_u8(value: u8) -> Option<Self> {
+        match value {
+            0 => Some(Self::NoFix),
+            1 => Some(Self::Fix2D),
+            2 => Some(Self::Fix3D),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct UtcTime {
+    pub hours: u8,
+    pub minutes: u8,
+    pub seconds: u8,
+    pub millis: u16,
+}
+
+impl UtcTime {
+    /// Milliseconds since midnight.
+    pub fn millis_of_day(&self) -> u32 {
+        ((self.hours as u32 * 60 + self.minutes as u32) * 60 + self.seconds as u32) * 1000
+            + self.millis as u32
+    }
+
+    pub fn from_millis_of_day(millis: u32) -> Self {
+        Self {
+            hours: (millis / 3_600_000) as u8,
+            minutes: (millis / 60_000 % 60) as u8,
+            seconds: (millis / 1000 % 60) as u8,
+            millis: (millis % 1000) as u16,
+        }
+    }
+}
+
+impl fmt::Display for UtcTime {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{:02}:{:02}:{:02}.{:03}",
+            self.hours, self.minutes, self.seconds, self.millis
+        )
+    }
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Date {
+    pub year: u16,
+    pub month: u8,
+    pub day: u8,
+}
+
+impl fmt::Display for Date {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{:04}-{:02}-{:02}", self.year, self.month, self.day)
+    }
+}
+
+/// Everything the receiver knows at the end of one navigation epoch.
+///
+/// The position, altitude and speed are only meaningful when
+/// [GpsFix::has_fix].
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct GpsFix {
+    pub fix_type: FixType,
+    pub time: Option<UtcTime>,
+    pub date: Option<Date>,
+    /// degrees, north is positive
+    pub latitude: f64,
+    /// degrees, east is positive
+    pub longitude: f64,
+    /// metres above mean sea level
+    pub altitude: f32,
+    /// speed over ground in metres per second
+    pub speed: f32,
+    /// course over ground in degrees from true north
+    pub course: f32,
+    /// number of satellites used in the fix
+    pub satellites: u8,
+    /// horizontal dilution of precision
+    pub hdop: f32,
+}
+
+impl GpsFix {
+    pub fn has_fix(&self) -> bool {
+        self.fix_type != FixType::NoFix
+    }
+}
+
+impl Default for GpsFix {
+    fn default() -> Self {
+        Self {
+            fix_type: FixType::NoFix,
+            time: None,
+            date: None,
+            latitude: 0.0,
+            longitude: 0.0,
+            altitude: 0.0,
+            speed: 0.0,
+            course: 0.0,
+            satellites: 0,
+            hdop: UNKNOWN_DOP,
+        }
+    }
+}
+
+/// Builds up a [GpsFix] from the different sentences sent each epoch.
+///
+/// A GGA sentence finishes the fix, as every receiver sends one per epoch and
+/// it has the position and altitude. Whatever RMC, GSA or VTG sentences came
+/// before it fill in the rest.
+#[derive(Clone, Debug, Default)]
+pub struct FixAssembler {
+    fix: GpsFix,
+    /// The fix type from the latest GSA sentence, if there has been one.
+    gsa_fix_type: Option<FixType>,
+}
+
+impl FixAssembler {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Takes in a sentence, returning the fix if it finished one.
+    pub fn update(&mut self, sentence: Sentence) -> Option<GpsFix> {
+        match sentence {
+            Sentence::Gga(gga) => {
+                let fix = &mut self.fix;
+
+                fix.time = gga.time.or(fix.time);
+                fix.satellites = gga.satellites;
+                fix.hdop = gga.hdop.unwrap_or(UNKNOWN_DOP);
+
+                fix.fix_type = match (gga.quality, gga.latitude, gga.longitude) {
+                    (0, _, _) | (_, None, _) | (_, _, None) => FixType::NoFix,
+                    // without a GSA sentence, an altitude means a 3D fix
+                    _ => self.gsa_fix_type.unwrap_or(match gga.altitude {
+                        Some(_) => FixType::Fix3D,
+                        None => FixType::Fix2D,
+                    }),
+                };
+
+                if let (Some(latitude), Some(longitude)) = (gga.latitude, gga.longitude) {
+                    fix.latitude = latitude;
+                    fix.longitude = longitude;
+                }
+
+                if let Some(altitude) = gga.altitude {
+                    fix.altitude = altitude;
+                }
+
+                return Some(*fix);
+            }
+            Sentence::Rmc(rmc) => {
+                let fix = &mut self.fix;
+
+                fix.time = rmc.time.or(fix.time);
+                fix.date = rmc.date.or(fix.date);
+
+                if rmc.valid {
+                    fix.speed = rmc.speed.unwrap_or(fix.speed);
+                    fix.course = rmc.course.unwrap_or(fix.course);
+                }
+            }
+            Sentence::Gsa(gsa) => {
+                self.gsa_fix_type = Some(gsa.fix_type);
+
+                if let Some(hdop) = gsa.hdop {
+                    self.fix.hdop = hdop;
+                }
+            }
+            Sentence::Vtg(vtg) => {
+                self.fix.speed = vtg.speed.unwrap_or(self.fix.speed);
+                self.fix.course = vtg.course.unwrap_or(self.fix.course);
+            }
+        }
+
+        None
+    }
+}
+
+/// Calls `f` with every byte read from `reader`, forever, beating `heartbeat`
+/// at least every [READ_TIMEOUT].
+///
+/// A UART which has failed returns straight away, so after an error this waits
+/// as [UART_RETRY_POLICY] says before reading again, rather than spinning.
+async fn for_each_byte<R: Read>(mut reader: R, heartbeat: &Heartbeat, mut f: impl FnMut(u8)) -> ! {
+    let mut buf = [0; 64];
+    let mut jitter = Jitter::from_global_seed();
+    let mut failures = 0;
+
+    loop {
+        heartbeat.beat();
+
+        let Ok(result) = with_timeout(READ_TIMEOUT, reader.read(&mut buf)).await else {
+            continue;
+        };
+
+        let Some(len) = result.map(Some).print_warn_limited() else {
+            journal::record(GpsError::Uart);
+
+            let policy = UART_RETRY_POLICY;
+            Timer::after(policy.randomise(policy.interval(failures), jitter.next_f32())).await;
+
+            failures += 1;
+            continue;
+        };
+
+        failures = 0;
+        buf[..len].iter().for_each(|&byte| f(byte));
+    }
+}
+
+/// Reads NMEA sentences from `reader` forever, calling `on_fix` with every
+/// complete fix.
+pub async fn read_nmea_fixes<R: Read>(
+    reader: R,
+    heartbeat: &Heartbeat,
+    mut on_fix: impl FnMut(GpsFix),
+) -> ! {
+    let mut parser = NmeaParser::new();
+    let mut assembler = FixAssembler::new();
+
+    for_each_byte(reader, heartbeat, |byte| match parser.push(byte) {
+        Some(Ok(sentence)) => {
+            if let Some(fix) = assembler.update(sentence) {
+                on_fix(fix);
+            }
+        }
+        Some(Err(nmea::NmeaError::Unsupported)) | None => {}
+        Some(Err(e)) => debug!("Bad NMEA sentence: {e:?}"),
+    })
+    .await
+}
+
+/// Reads UBX messages from `reader` forever, calling `on_fix` with every
+/// NAV-PVT solution.
+pub async fn read_ubx_fixes<R: Read>(
+    reader: R,
+    heartbeat: &Heartbeat,
+    mut on_fix: impl FnMut(GpsFix),
+) -> ! {
+    let mut parser = UbxParser::new();
+    let mut hdop = UNKNOWN_DOP;
+
+    for_each_byte(reader, heartbeat, |byte| match parser.push(byte) {
+        // sent before NAV-PVT in each epoch
+        Some(Ok(Message::NavDop(dop))) => hdop = dop.hdop as f32 / 100.0,
+        Some(Ok(Message::NavPvt(pvt))) => on_fix(pvt.fix(hdop)),
+        Some(Ok(_)) | None => {}
+        Some(Err(e)) => debug!("Bad UBX message: {e:?}"),
+    })
+    .await
+}
+
+/// Waits for the receiver to acknowledge (or reject) `message`.
+async fn wait_for_ack<R: Read>(uart: &mut R, message: MessageId) -> Result<(), UbxError> {
+    let mut parser = UbxParser::new();
+    let mut buf = [0; 64];
+
+    loop {
+        let len = uart.read(&mut buf).await.map_err(|_| UbxError::UartError)?;
+
+        for &byte in &buf[..len] {
+            match parser.push(byte) {
+                Some(Ok(Message::Ack(acked))) if acked == message => return Ok(()),
+                Some(Ok(Message::Nak(rejected))) if rejected == message => {
+                    return Err(UbxError::Nak)
+                }
+                _ => {}
+            }
+        }
+    }
+}
+
+async fn send_ubx<W: Write>(
+    uart: &mut W,
+    message: MessageId,
+    payload: &[u8],
+) -> Result<(), UbxError> {
+    let mut buf = [0; 64];
+    let len = ubx::encode(message, payload, &mut buf)?;
+
+    uart.write_all(&buf[..len])
+        .await
+        .map_err(|_| UbxError::UartError)
+}
+
+/// Sends a configuration message, and waits for it to be acknowledged.
+async fn send_ubx_config<U: Read + Write>(
+    uart: &mut U,
+    message: MessageId,
+    payload: &[u8],
+) -> Result<(), UbxError> {
+    send_ubx(uart, message, payload).await?;
+
+    with_timeout(ACK_TIMEOUT, wait_for_ack(uart, message))
+        .await
+        .map_err(|_| UbxError::NoResponse)?
+}
+
+/// Everything but the port settings, which have to come last.
+async fn send_navigation_config<U: Read + Write>(
+    uart: &mut U,
+    config: &UbxConfig,
+) -> Result<(), UbxError> {
+    send_ubx_config(uart, CFG_NAV5, &ubx::cfg_nav5(config.dynamic_model)).await?;
+    send_ubx_config(uart, CFG_RATE, &ubx::cfg_rate(config.nav_rate_hz)).await?;
+    send_ubx_config(uart, CFG_MSG, &ubx::cfg_msg(NAV_DOP, 1)).await?;
+    send_ubx_config(uart, CFG_MSG, &ubx::cfg_msg(NAV_PVT, 1)).await
+}
+
+/// Sets up a u-blox receiver as `config` says, then switches it over to
+/// sending only UBX at the new baud rate. `set_baud_rate` changes the baud rate
+/// of the UART to match.
+///
+/// If only the esp32 was reset, the receiver will have kept its settings, so
+/// both its default baud rate and the new one are tried. If neither works, the
+/// UART is left at [GPS_BAUD_RATE] to carry on with NMEA.
+pub async fn configure_ubx<U: Read + Write>(
+    uart: &mut U,
+    config: &UbxConfig,
+    mut set_baud_rate: impl FnMut(&mut U, u32),
+) -> Result<(), UbxError> {
+    let mut result = Err(UbxError::NoResponse);
+
+    for baud_rate in [GPS_BAUD_RATE, config.baud_rate] {
+        set_baud_rate(uart, baud_rate);
+
+        result = send_navigation_config(uart, config).await;
+
+        match &result {
+            Ok(()) => break,
+            Err(e) => debug!("u-blox configuration at {baud_rate} baud failed: {e:?}"),
+        }
+    }
+
+    if let Err(e) = result {
+        set_baud_rate(uart, GPS_BAUD_RATE);
+        return Err(e);
+    }
+
+    // the receiver switches straight away, so its acknowledgement could arrive
+    // at either baud rate - it isn't waited for
+    send_ubx(uart, CFG_PRT, &ubx::cfg_prt_uart(config.baud_rate)).await?;
+    uart.flush().await.map_err(|_| UbxError::UartError)?;
+
+    Timer::after(BAUD_RATE_SWITCH_DELAY).await;
+    set_baud_rate(uart, config.baud_rate);
+
+    info!(
+        "u-blox receiver configured: {:?} at {} Hz, {} baud",
+        config.dynamic_model, config.nav_rate_hz, config.baud_rate
+    );
+
+    Ok(())
+}
+
+#[cfg(target_arch = "xtensa")]
+#[task]
+pub async fn gps_stream(uart: GpsUart, protocol: GpsProtocol) {
+    let heartbeat = watchdog::register(TaskId::Gps, Duration::from_secs(3));
+    let mut sequencer = Sequencer::new(SourceId::Gps);
+
+    let publish = |fix| {
+        let sample = sequencer.stamp(fix);
+
+        trace!("{sample:?}");
+
+        GPS_BUS.publish(sample);
+    };
+
+    match protocol {
+        GpsProtocol::Nmea => read_nmea_fixes(uart, &heartbeat, publish).await,
+        GpsProtocol::Ubx => read_ubx_fixes(uart, &heartbeat, publish).await,
+    }
+}
@@ -0,0 +1,778 @@
+//! A parser for the NMEA 0183 sentences GPS receivers send.
+//!
+//! Only GGA, RMC, GSA and VTG are understood, from any talker (GP, GN, GL...).
+//! This doesn't touch any hardware, so it runs just as well on the host.
+
+use core::str;
+
+use embedded_error_chain::ErrorCategory;
+
+use super::{Date, FixType, UtcTime};
+
+/// NMEA says 82, but some receivers go over.
+pub const MAX_SENTENCE_LEN: usize = 128;
+
+const KNOTS_TO_METRES_PER_SECOND: f32 = 0.514_444;
+const KPH_TO_METRES_PER_SECOND: f32 = 1.0 / 3.6;
+
+#[derive(Clone, Copy, PartialEq, Eq, ErrorCategory)]
+#[repr(u8)]
+pub enum NmeaError {
+    /// The sentence didn't start with `$`, or had no `*` before the checksum
+    Malformed,
+    /// The sentence was corrupted on the way
+    BadChecksum,
+    /// The sentence was longer than [MAX_SENTENCE_LEN]
+    TooLong,
+    /// A sentence type other than GGA, RMC, GSA or VTG
+    Unsupported,
+    /// A field couldn't be parsed
+    InvalidField,
+}
+
+type Result<T> = core::result::Result<T, NmeaError>;
+
+/// Position fix data.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct Gga {
+    pub time: Option<UtcTime>,
+    pub latitude: Option<f64>,
+    pub longitude: Option<f64>,
+    /// 0 for no fix, 1 for GPS, 2 for DGPS...
+    pub quality: u8,
+    pub satellites: u8,
+    pub hdop: Option<f32>,
+    /// altitude above mean sea level in metres
+    pub altitude: Option<f32>,
+}
+
+/// Recommended minimum data.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct Rmc {
+    pub time: Option<UtcTime>,
+    /// whether the receiver says the data is valid (`A`), rather than a
+    /// warning (`V`)
+    pub valid: bool,
+    pub latitude: Option<f64>,
+    pub longitude: Option<f64>,
+    /// speed over ground in metres per second
+    pub speed: Option<f32>,
+    /// course over ground in degrees from true north
+    pub course: Option<f32>,
+    pub date: Option<Date>,
+}
+
+/// Dilution of precision and active satellites.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct Gsa {
+    pub fix_type: FixType,
+    pub pdop: Option<f32>,
+    pub hdop: Option<f32>,
+    pub vdop: Option<f32>,
+}
+
+/// Course and speed over ground.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct Vtg {
+    /// course over ground in degrees from true north
+    pub course: Option<f32>,
+    /// speed over ground in metres per second
+    pub speed: Option<f32>,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Sentence {
+    Gga(Gga),
+    Rmc(Rmc),
+    Gsa(Gsa),
+    Vtg(Vtg),
+}
+
+fn hex_digit(byte: u8) -> Option<u8> {
+    match byte {
+        b'0'..=b'9' => Some(byte - b'0'),
+        b'A'..=b'F' => Some(byte - b'A' + 10),
+        b'a'..=b'f' => Some(byte - b'a' + 10),
+        _ => None,
+    }
+}
+
+/// Checks the checksum of a sentence (from `$` up to, but not including, the
+/// line ending), returning the part between `$` and `*`.
+pub fn verify_checksum(sentence: &[u8]) -> Result<&[u8]> {
+    let body = sentence.strip_prefix(b"$").ok_or(NmeaError::Malformed)?;
+
+    let star = body
+        .iter()
+        .rposition(|&byte| byte == b'*')
+        .ok_or(NmeaError::Malformed)?;
+    let (data, checksum) = (&body[..star], &body[star + 1..]);
+
+    let [high, low] = checksum else {
+        return Err(NmeaError::Malformed);
+    };
+    let expected = (hex_digit(*high).ok_or(NmeaError::Malformed)? << 4)
+        | hex_digit(*low).ok_or(NmeaError::Malformed)?;
+
+    let actual = data.iter().fold(0, |checksum, byte| checksum ^ byte);
+
+    if actual != expected {
+        return Err(NmeaError::BadChecksum);
+    }
+
+    Ok(data)
+}
+
+/// Parses one sentence, from `$` up to (optionally) the line ending.
+pub fn parse_sentence(sentence: &[u8]) -> Result<Sentence> {
+    let end = sentence
+        .iter()
+        .rposition(|byte| !byte.is_ascii_whitespace())
+        .map_or(0, |last| last + 1);
+    let data = verify_checksum(&sentence[..end])?;
+    let data = str::from_utf8(data).map_err(|_| NmeaError::Malformed)?;
+
+    let mut fields = Fields(data.split(','));
+
+    let address = fields.next_str();
+
+    // two letters of talker, then the sentence type
+    if address.len() != 5 {
+        return Err(NmeaError::Malformed);
+    }
+
+    match address.get(2..).unwrap_or("") {
+        "GGA" => parse_gga(&mut fields).map(Sentence::Gga),
+        "RMC" => parse_rmc(&mut fields).map(Sentence::Rmc),
+        "GSA" => parse_gsa(&mut fields).map(Sentence::Gsa),
+        "VTG" => parse_vtg(&mut fields).map(Sentence::Vtg),
+        _ => Err(NmeaError::Unsupported),
+    }
+}
+
+/// The comma separated fields of a sentence. Missing fields at the end are
+/// treated as empty.
+struct Fields<'a>(str::Split<'a, char>);
+
+impl<'a> Fields<'a> {
+    fn next_str(&mut self) -> &'a str {
+        self.0.next().unwrap_or("")
+    }
+
+    fn skip(&mut self, n: usize) {
+        for _ in 0..n {
+            self.next_str();
+        }
+    }
+
+    /// An empty field is [None], anything unparseable is an error.
+    fn next<T: str::FromStr>(&mut self) -> Result<Option<T>> {
+        match self.next_str() {
+            "" => Ok(None),
+            field => field.parse().map(Some).map_err(|_| NmeaError::InvalidField),
+        }
+    }
+
+    fn next_char(&mut self) -> Option<u8> {
+        self.next_str().bytes().next()
+    }
+
+    /// `hhmmss.ss`
+    fn next_time(&mut self) -> Result<Option<UtcTime>> {
+        let field = self.next_str();
+
+        if field.is_empty() {
+            return Ok(None);
+        }
+
+        let digits = |range: core::ops::Range<usize>| -> Result<u8> {
+            field
+                .get(range)
+                .and_then(|digits| digits.parse().ok())
+                .ok_or(NmeaError::InvalidField)
+        };
+
+        let hours = digits(0..2)?;
+        let minutes = digits(2..4)?;
+        let seconds: f32 = field
+            .get(4..)
+            .and_then(|seconds| seconds.parse().ok())
+            .ok_or(NmeaError::InvalidField)?;
+
+        if hours > 23 || minutes > 59 || !(0.0..61.0).contains(&seconds) {
+            return Err(NmeaError::InvalidField);
+        }
+
+        Ok(Some(UtcTime {
+            hours,
+            minutes,
+            seconds: seconds as u8,
+            millis: ((seconds % 1.0) * 1000.0) as u16,
+        }))
+    }
+
+    /// `ddmmyy`
+    fn next_date(&mut self) -> Result<Option<Date>> {
+        let field = self.next_str();
+
+        if field.is_empty() {
+            return Ok(None);
+        }
+
+        let digits = |range: core::ops::Range<usize>| -> Result<u8> {
+            field
+                .get(range)
+                .and_then(|digits| digits.parse().ok())
+                .ok_or(NmeaError::InvalidField)
+        };
+
+        let day = digits(0..2)?;
+        let month = digits(2..4)?;
+        let year = digits(4..6)?;
+
+        if field.len() != 6 || !(1..=31).contains(&day) || !(1..=12).contains(&month) {
+            return Err(NmeaError::InvalidField);
+        }
+
+        Ok(Some(Date {
+            year: 2000 + year as u16,
+            month,
+            day,
+        }))
+    }
+
+    /// `(d)ddmm.mmmm,H`, turned into signed decimal degrees.
+    fn next_coordinate(&mut self, negative: u8) -> Result<Option<f64>> {
+        let value = self.next_str();
+        let hemisphere = self.next_char();
+
+        if value.is_empty() {
+            return Ok(None);
+        }
+
+        let dot = value.find('.').unwrap_or(value.len());
+
+        if dot < 3 {
+            return Err(NmeaError::InvalidField);
+        }
+
+        let field = |part: Option<&str>| -> Result<f64> {
+            part.and_then(|part| part.parse().ok())
+                .ok_or(NmeaError::InvalidField)
+        };
+
+        // split by bytes, which might not be on a character boundary in a
+        // corrupted sentence
+        let degrees = field(value.get(..dot - 2))?;
+        let minutes = field(value.get(dot - 2..))?;
+
+        if minutes >= 60.0 {
+            return Err(NmeaError::InvalidField);
+        }
+
+        let coordinate = degrees + minutes / 60.0;
+
+        Ok(Some(if hemisphere == Some(negative) {
+            -coordinate
+        } else {
+            coordinate
+        }))
+    }
+}
+
+fn parse_gga(fields: &mut Fields) -> Result<Gga> {
+    let time = fields.next_time()?;
+    let latitude = fields.next_coordinate(b'S')?;
+    let longitude = fields.next_coordinate(b'W')?;
+    let quality = fields.next()?.unwrap_or(0);
+    let satellites = fields.next()?.unwrap_or(0);
+    let hdop = fields.next()?;
+    let altitude = fields.next()?;
+
+    Ok(Gga {
+        time,
+        latitude,
+        longitude,
+        quality,
+        satellites,
+        hdop,
+        altitude,
+    })
+}
+
+fn parse_rmc(fields: &mut Fields) -> Result<Rmc> {
+    let time = fields.next_time()?;
+    let valid = fields.next_char() == Some(b'A');
+    let latitude = fields.next_coordinate(b'S')?;
+    let longitude = fields.next_coordinate(b'W')?;
+    let speed = fields
+        .next::<f32>()?
+        .map(|knots| knots * KNOTS_TO_METRES_PER_SECOND);
+    let course = fields.next()?;
+    let date = fields.next_date()?;
+
+    Ok(Rmc {
+        time,
+        valid,
+        latitude,
+        longitude,
+        speed,
+        course,
+        date,
+    })
+}
+
+fn parse_gsa(fields: &mut Fields) -> Result<Gsa> {
+    // selection mode (automatic/manual)
+    fields.skip(1);
+
+    let fix_type = match fields.next::<u8>()? {
+        Some(2) => FixType::Fix2D,
+        Some(3) => FixType::Fix3D,
+        _ => FixType::NoFix,
+    };
+
+    // the ids of the satellites used
+    fields.skip(12);
+
+    Ok(Gsa {
+        fix_type,
+        pdop: fields.next()?,
+        hdop: fields.next()?,
+        // NMEA 4.1 adds a system id after this, which `next` ignores
+        vdop: fields.next()?,
+    })
+}
+
+fn parse_vtg(fields: &mut Fields) -> Result<Vtg> {
+    let course = fields.next()?;
+    // T, magnetic course, M, speed in knots, N
+    fields.skip(5);
+    let speed = fields
+        .next::<f32>()?
+        .map(|kph| kph * KPH_TO_METRES_PER_SECOND);
+
+    Ok(Vtg { course, speed })
+}
+
+/// Splits a stream of bytes into sentences.
+///
+/// Anything before a `$` is ignored, so it can be started part way through a
+/// sentence.
+pub struct NmeaParser {
+    buf: [u8; MAX_SENTENCE_LEN],
+    len: usize,
+    /// Whether a `$` has been seen, and the bytes since are being kept.
+    in_sentence: bool,
+}
+
+impl NmeaParser {
+    pub const fn new() -> Self {
+        Self {
+            buf: [0; MAX_SENTENCE_LEN],
+            len: 0,
+            in_sentence: false,
+        }
+    }
+
+    /// Adds a received byte, returning the sentence it finished (if any).
+    pub fn push(&mut self, byte: u8) -> Option<Result<Sentence>> {
+        match byte {
+            b'$' => {
+                self.in_sentence = true;
+                self.buf[0] = byte;
+                self.len = 1;
+                None
+            }
+            b'\r' | b'\n' if self.in_sentence => {
+                self.in_sentence = false;
+                Some(parse_sentence(&self.buf[..self.len]))
+            }
+            _ if self.in_sentence => {
+                if self.len == self.buf.len() {
+                    self.in_sentence = false;
+                    return Some(Err(NmeaError::TooLong));
+                }
+
+                self.buf[self.len] = byte;
+                self.len += 1;
+                None
+            }
+            _ => None,
+        }
+    }
+}
+
+impl Default for NmeaParser {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use heapless::Vec;
+
+    use super::*;
+    use crate::test_utils::{assert_close, Noise};
+
+    const GGA: &str = "$GPGGA,123519,4807.038,N,01131.000,E,1,08,0.9,545.4,M,46.9,M,,*47";
+    const RMC: &str = "$GPRMC,123519,A,4807.038,N,01131.000,E,022.4,084.4,230324,003.1,W*61";
+    const GSA: &str = "$GPGSA,A,3,04,05,,09,12,,,24,,,,,2.5,1.3,2.1*39";
+    const VTG: &str = "$GPVTG,054.7,T,034.4,M,005.5,N,010.2,K*48";
+
+    /// What a u-blox receiver sends before it has a fix.
+    const NO_FIX: [&str; 4] = [
+        "$GNRMC,092751.000,V,,,,,,,,,,N*5B",
+        "$GNVTG,,T,,M,0.021,N,0.039,K,A*34",
+        "$GNGSA,A,1,,,,,,,,,,,,,99.99,99.99,99.99,1*33",
+        "$GNGGA,,,,,,0,00,99.99,,,,,,*56",
+    ];
+
+    const GSV: &str = "$GPGSV,3,1,11,03,03,111,00,04,15,270,00,06,01,010,00,13,06,292,00*74";
+
+    fn time(hours: u8, minutes: u8, seconds: u8, millis: u16) -> Option<UtcTime> {
+        Some(UtcTime {
+            hours,
+            minutes,
+            seconds,
+            millis,
+        })
+    }
+
+    /// `$body*hh`, with the checksum worked out.
+    fn with_checksum(body: &[u8]) -> Vec<u8, 256> {
+        let checksum = body.iter().fold(0, |checksum, byte| checksum ^ byte);
+        let hex = |digit: u8| b"0123456789ABCDEF"[digit as usize];
+
+        let mut sentence = Vec::new();
+        sentence.push(b'$').unwrap();
+        sentence.extend_from_slice(body).unwrap();
+        sentence
+            .extend_from_slice(&[b'*', hex(checksum >> 4), hex(checksum & 0xF)])
+            .unwrap();
+        sentence
+    }
+
+    /// Everything between `$` and `*`.
+    fn body(sentence: &str) -> &[u8] {
+        &sentence.as_bytes()[1..sentence.len() - 3]
+    }
+
+    fn parse(sentence: &str) -> Result<Sentence> {
+        parse_sentence(sentence.as_bytes())
+    }
+
+    #[test]
+    fn parses_gga() {
+        let Ok(Sentence::Gga(gga)) = parse(GGA) else {
+            panic!("not parsed as GGA");
+        };
+
+        assert_eq!(gga.time, time(12, 35, 19, 0));
+        assert_close(gga.latitude.unwrap(), 48.1173, 1e-6);
+        assert_close(gga.longitude.unwrap(), 11.516_666_7, 1e-6);
+        assert_eq!(gga.quality, 1);
+        assert_eq!(gga.satellites, 8);
+        assert_eq!(gga.hdop, Some(0.9));
+        assert_eq!(gga.altitude, Some(545.4));
+    }
+
+    #[test]
+    fn parses_southern_and_western_coordinates() {
+        let Ok(Sentence::Gga(gga)) =
+            parse("$GNGGA,235959.50,3352.1280,S,15112.6200,W,2,12,0.8,-12.5,M,,M,,*7A")
+        else {
+            panic!("not parsed as GGA");
+        };
+
+        assert_eq!(gga.time, time(23, 59, 59, 500));
+        assert_close(gga.latitude.unwrap(), -33.868_8, 1e-6);
+        assert_close(gga.longitude.unwrap(), -151.210_333_3, 1e-6);
+        assert_eq!(gga.altitude, Some(-12.5));
+    }
+
+    #[test]
+    fn parses_rmc() {
+        let Ok(Sentence::Rmc(rmc)) = parse(RMC) else {
+            panic!("not parsed as RMC");
+        };
+
+        assert_eq!(rmc.time, time(12, 35, 19, 0));
+        assert!(rmc.valid);
+        assert_close(rmc.latitude.unwrap(), 48.1173, 1e-6);
+        assert_close(rmc.longitude.unwrap(), 11.516_666_7, 1e-6);
+        assert_close(rmc.speed.unwrap() as f64, 22.4 * 0.514_444, 1e-4);
+        assert_eq!(rmc.course, Some(84.4));
+        assert_eq!(
+            rmc.date,
+            Some(Date {
+                year: 2024,
+                month: 3,
+                day: 23
+            })
+        );
+    }
+
+    #[test]
+    fn parses_gsa() {
+        assert_eq!(
+            parse(GSA),
+            Ok(Sentence::Gsa(Gsa {
+                fix_type: FixType::Fix3D,
+                pdop: Some(2.5),
+                hdop: Some(1.3),
+                vdop: Some(2.1),
+            }))
+        );
+    }
+
+    #[test]
+    fn parses_vtg() {
+        let Ok(Sentence::Vtg(vtg)) = parse(VTG) else {
+            panic!("not parsed as VTG");
+        };
+
+        assert_eq!(vtg.course, Some(54.7));
+        assert_close(vtg.speed.unwrap() as f64, 10.2 / 3.6, 1e-5);
+    }
+
+    #[test]
+    fn parses_sentences_without_a_fix() {
+        let sentences: Vec<Sentence, 4> = NO_FIX.iter().map(|s| parse(s).unwrap()).collect();
+
+        assert_eq!(
+            sentences[0],
+            Sentence::Rmc(Rmc {
+                time: time(9, 27, 51, 0),
+                ..Default::default()
+            })
+        );
+        assert_eq!(
+            sentences[1],
+            Sentence::Vtg(Vtg {
+                course: None,
+                speed: Some(0.039 * KPH_TO_METRES_PER_SECOND),
+            })
+        );
+        assert_eq!(
+            sentences[2],
+            Sentence::Gsa(Gsa {
+                fix_type: FixType::NoFix,
+                pdop: Some(99.99),
+                hdop: Some(99.99),
+                vdop: Some(99.99),
+            })
+        );
+        assert_eq!(
+            sentences[3],
+            Sentence::Gga(Gga {
+                hdop: Some(99.99),
+                ..Default::default()
+            })
+        );
+    }
+
+    #[test]
+    fn ignores_trailing_line_endings() {
+        let mut sentence: Vec<u8, 128> = Vec::from_slice(GGA.as_bytes()).unwrap();
+        sentence.extend_from_slice(b"\r\n").unwrap();
+
+        assert_eq!(parse_sentence(&sentence), parse(GGA));
+    }
+
+    #[test]
+    fn rejects_bad_checksums() {
+        assert_eq!(
+            parse("$GPGGA,123519,4807.038,N,01131.000,E,1,08,0.9,545.4,M,46.9,M,,*48"),
+            Err(NmeaError::BadChecksum)
+        );
+
+        // a digit changed on the way
+        assert_eq!(
+            parse("$GPGGA,123519,4807.038,N,01131.000,E,1,08,0.9,545.5,M,46.9,M,,*47"),
+            Err(NmeaError::BadChecksum)
+        );
+
+        // lower case hex is fine, anything else isn't
+        let southern = "$GNGGA,235959.50,3352.1280,S,15112.6200,W,2,12,0.8,-12.5,M,,M,,*7A";
+        let lower_case = "$GNGGA,235959.50,3352.1280,S,15112.6200,W,2,12,0.8,-12.5,M,,M,,*7a";
+        let not_hex = "$GNGGA,235959.50,3352.1280,S,15112.6200,W,2,12,0.8,-12.5,M,,M,,*7G";
+
+        assert!(parse(southern).is_ok());
+        assert_eq!(parse(lower_case), parse(southern));
+        assert_eq!(parse(not_hex), Err(NmeaError::Malformed));
+    }
+
+    #[test]
+    fn rejects_truncated_sentences() {
+        // cut off before the checksum
+        assert_eq!(parse(&GGA[..30]), Err(NmeaError::Malformed));
+        assert_eq!(parse(&GGA[..GGA.len() - 1]), Err(NmeaError::Malformed));
+        assert_eq!(parse(&GGA[..GGA.len() - 3]), Err(NmeaError::Malformed));
+        assert_eq!(parse("$"), Err(NmeaError::Malformed));
+        assert_eq!(parse(""), Err(NmeaError::Malformed));
+
+        // the start missing
+        assert_eq!(parse(&GGA[1..]), Err(NmeaError::Malformed));
+    }
+
+    #[test]
+    fn rejects_invalid_fields() {
+        for body in [
+            "GPGGA,253519,4807.038,N,01131.000,E,1,08,0.9,545.4,M,46.9,M,,",
+            "GPGGA,123519,4867.038,N,01131.000,E,1,08,0.9,545.4,M,46.9,M,,",
+            "GPGGA,123519,07.038,N,01131.000,E,1,08,0.9,545.4,M,46.9,M,,",
+            "GPGGA,123519,4807.038,N,01131.000,E,1,eight,0.9,545.4,M,46.9,M,,",
+            "GPRMC,123519,A,4807.038,N,01131.000,E,022.4,084.4,231324,003.1,W",
+            "GPRMC,123519,A,4807.038,N,01131.000,E,022.4,084.4,2303245,003.1,W",
+        ] {
+            assert_eq!(
+                parse_sentence(&with_checksum(body.as_bytes())),
+                Err(NmeaError::InvalidField),
+                "{body}"
+            );
+        }
+    }
+
+    #[test]
+    fn only_understands_some_sentences() {
+        assert_eq!(parse(GSV), Err(NmeaError::Unsupported));
+        assert_eq!(
+            parse_sentence(&with_checksum(b"GPGGAX,123519")),
+            Err(NmeaError::Malformed)
+        );
+    }
+
+    #[test]
+    fn accepts_any_talker() {
+        let body = body(GGA);
+
+        for talker in [b"GN", b"GL", b"GA", b"BD"] {
+            let mut other: Vec<u8, 128> = Vec::from_slice(talker).unwrap();
+            other.extend_from_slice(&body[2..]).unwrap();
+
+            assert_eq!(parse_sentence(&with_checksum(&other)), parse(GGA));
+        }
+    }
+
+    #[test]
+    fn parser_splits_a_stream_into_sentences() {
+        let mut parser = NmeaParser::new();
+        let mut sentences: Vec<Result<Sentence>, 8> = Vec::new();
+
+        // starting part way through a sentence
+        let stream = [
+            &GGA[20..],
+            "\r\n",
+            RMC,
+            "\r\n",
+            GSV,
+            "\r\n",
+            GSA,
+            "\n",
+            VTG,
+            "\r\n",
+        ];
+
+        for &byte in stream.iter().flat_map(|part| part.as_bytes()) {
+            if let Some(result) = parser.push(byte) {
+                sentences.push(result).unwrap();
+            }
+        }
+
+        assert_eq!(
+            sentences,
+            [
+                parse(RMC),
+                Err(NmeaError::Unsupported),
+                parse(GSA),
+                parse(VTG)
+            ]
+        );
+    }
+
+    #[test]
+    fn parser_drops_sentences_cut_off_by_the_next() {
+        let mut parser = NmeaParser::new();
+        let mut sentences: Vec<Result<Sentence>, 8> = Vec::new();
+
+        for &byte in [&RMC[..25], GGA, "\r\n"]
+            .iter()
+            .flat_map(|part| part.as_bytes())
+        {
+            if let Some(result) = parser.push(byte) {
+                sentences.push(result).unwrap();
+            }
+        }
+
+        assert_eq!(sentences, [parse(GGA)]);
+    }
+
+    #[test]
+    fn parser_rejects_sentences_too_long_to_buffer() {
+        let mut parser = NmeaParser::new();
+        let mut results: Vec<Result<Sentence>, 8> = Vec::new();
+
+        parser.push(b'$');
+        for _ in 0..200 {
+            if let Some(result) = parser.push(b'A') {
+                results.push(result).unwrap();
+            }
+        }
+
+        // and carries on with the next one
+        for &byte in GGA.as_bytes().iter().chain(b"\r\n") {
+            if let Some(result) = parser.push(byte) {
+                results.push(result).unwrap();
+            }
+        }
+
+        assert_eq!(results, [Err(NmeaError::TooLong), parse(GGA)]);
+    }
+
+    #[test]
+    fn survives_garbage() {
+        let mut parser = NmeaParser::new();
+        let mut noise = Noise(1);
+
+        // mostly the characters sentences are made of, so some of it gets
+        // past the start of a sentence
+        let alphabet = b"$$**,,,,..--0123456789ABCDEFGNPRMCSTVAW\r\n\xC3\xA9\x00\xFF";
+
+        for _ in 0..200_000 {
+            let byte = alphabet[noise.byte() as usize % alphabet.len()];
+            let _ = parser.push(byte);
+        }
+
+        for _ in 0..200_000 {
+            let _ = parser.push(noise.byte());
+        }
+    }
+
+    #[test]
+    fn survives_corrupted_sentences_with_good_checksums() {
+        let replacements = b"$*,.-09AENSW \xC3\xA9\x00\xFF";
+
+        for sentence in [GGA, RMC, GSA, VTG, GSV].iter().chain(&NO_FIX) {
+            let body = body(sentence);
+
+            for i in 0..body.len() {
+                for &replacement in replacements {
+                    let mut corrupted: Vec<u8, 128> = Vec::from_slice(body).unwrap();
+                    corrupted[i] = replacement;
+                    let _ = parse_sentence(&with_checksum(&corrupted));
+                }
+
+                // a two byte character in the middle of a field
+                let mut corrupted: Vec<u8, 128> = Vec::from_slice(&body[..i]).unwrap();
+                corrupted.extend_from_slice("é".as_bytes()).unwrap();
+                corrupted.extend_from_slice(&body[i..]).unwrap();
+                let _ = parse_sentence(&with_checksum(&corrupted));
+
+                // cut short, but with a good checksum
+                let _ = parse_sentence(&with_checksum(&body[..i]));
+            }
+        }
+    }
+}
@@ -0,0 +1,577 @@
+//! A central view of whether every part of the can is working.
+//!
+//! Sensors and filters are watched through their buses by [health_monitor],
+//! and tasks without a bus (the radio and the loggers) report in with
+//! [report_success] and [report_error]. Every [HealthConfig::interval] it all
+//! gets summed up as a [SystemHealth] on [HEALTH_BUS], for the display, the
+//! telemetry and the status LED.
+
+use core::cell::RefCell;
+
+use crate::{
+    ahrs::ATTITUDE_BUS,
+    bme280::BME_BUS,
+    bus::{BusSubscriber, DataBus},
+    errors::journal,
+    gps::GPS_BUS,
+    mpu6050::MPU_BUS,
+    prelude::*,
+    qmc5883l::MAG_BUS,
+    vertical::VERTICAL_BUS,
+    watchdog::{self, TaskId},
+};
+
+pub type HealthBus = DataBus<SystemHealth, 2, 4>;
+pub static HEALTH_BUS: HealthBus = DataBus::new();
+pub type HealthSubscriber = BusSubscriber<'static, SystemHealth, 2, 4>;
+
+/// How often the buses are checked for new samples - often enough that none
+/// are overwritten before they are counted.
+const POLL_INTERVAL: Duration = Duration::from_millis(250);
+
+static HEALTH_TRACKER: Mutex<RefCell<HealthTracker>> =
+    Mutex::new(RefCell::new(HealthTracker::new()));
+
+/// Every part of the can whose health is tracked. Each is one bit of
+/// [SystemHealth::flags], so existing values must never change.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+#[repr(u8)]
+pub enum Component {
+    Mpu,
+    Mag,
+    Bme,
+    Gps,
+    Attitude,
+    Vertical,
+    Radio,
+    BlackBox,
+    Sd,
+}
+
+pub const COMPONENT_COUNT: usize = 9;
+
+impl Component {
+    pub const ALL: [Self; COMPONENT_COUNT] = [
+        Self::Mpu,
+        Self::Mag,
+        Self::Bme,
+        Self::Gps,
+        Self::Attitude,
+        Self::Vertical,
+        Self::Radio,
+        Self::BlackBox,
+        Self::Sd,
+    ];
+
+    /// How long the component can go without a success before its data is
+    /// stale - a few of its usual intervals.
+    pub fn max_age(self) -> Duration {
+        match self {
+            Self::Mpu | Self::Attitude | Self::Vertical => Duration::from_millis(500),
+            Self::Mag | Self::BlackBox => Duration::from_secs(1),
+            Self::Radio => Duration::from_secs(2),
+            Self::Bme | Self::Gps => Duration::from_secs(3),
+            Self::Sd => Duration::from_secs(5),
+        }
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Self::Mpu => "mpu",
+            Self::Mag => "mag",
+            Self::Bme => "bme",
+            Self::Gps => "gps",
+            Self::Attitude => "ahrs",
+            Self::Vertical => "vert",
+            Self::Radio => "lora",
+            Self::BlackBox => "bbox",
+            Self::Sd => "sd",
+        }
+    }
+}
+
+/// Worst last, so statuses can be compared with `max`.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Status {
+    /// Working, with no errors since the last check.
+    Ok,
+    /// Nothing has been heard from it yet.
+    #[default]
+    Unknown,
+    /// Still working, but there have been errors since the last check.
+    Degraded,
+    /// Its data is stale, or it has failed too many times in a row.
+    Failed,
+}
+
+impl Status {
+    pub fn name(self) -> &'static str {
+        match self {
+            Self::Ok => "Ok",
+            Self::Unknown => "Unknown",
+            Self::Degraded => "Degraded",
+            Self::Failed => "Failed",
+        }
+    }
+
+    /// Whether the component is doing its job, even if not perfectly.
+    pub fn is_working(self) -> bool {
+        matches!(self, Self::Ok | Self::Degraded)
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct HealthConfig {
+    /// How often the health of every component is worked out and published.
+    ///
+    /// Defaults to 1s.
+    pub interval: Duration,
+    /// How many errors in a row a component can have before it has failed,
+    /// even if its last success isn't stale yet.
+    ///
+    /// Defaults to 5.
+    pub max_consecutive_errors: u16,
+}
+
+impl Default for HealthConfig {
+    fn default() -> Self {
+        Self {
+            interval: Duration::from_secs(1),
+            max_consecutive_errors: 5,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct ComponentHealth {
+    pub status: Status,
+    pub last_success: Option<Instant>,
+    pub last_error: Option<Instant>,
+    pub consecutive_errors: u16,
+    pub total_errors: u32,
+    /// Successes and errors per second, over the last [HealthConfig::interval].
+    pub rate: f32,
+    /// Successes and errors since the last check.
+    count: u32,
+    /// Whether there have been any errors since the last check.
+    errored: bool,
+}
+
+impl ComponentHealth {
+    fn success(&mut self, time: Instant) {
+        self.last_success = Some(time);
+        self.consecutive_errors = 0;
+        self.count = self.count.saturating_add(1);
+    }
+
+    fn error(&mut self, time: Instant) {
+        self.last_error = Some(time);
+        self.consecutive_errors = self.consecutive_errors.saturating_add(1);
+        self.total_errors = self.total_errors.saturating_add(1);
+        self.count = self.count.saturating_add(1);
+        self.errored = true;
+    }
+
+    /// Works out the status and rate as of `now`, `elapsed` after the last
+    /// check, and starts counting again.
+    fn check(&mut self, now: Instant, elapsed: Duration, max_age: Duration, config: &HealthConfig) {
+        let stale = !matches!(self.last_success,
+            Some(last) if now.saturating_duration_since(last) <= max_age);
+
+        self.status = if self.last_success.is_none() && self.last_error.is_none() {
+            Status::Unknown
+        } else if stale || self.consecutive_errors >= config.max_consecutive_errors {
+            Status::Failed
+        } else if self.errored {
+            Status::Degraded
+        } else {
+            Status::Ok
+        };
+
+        let seconds = elapsed.as_micros() as f32 / 1_000_000.0;
+
+        if seconds > 0.0 {
+            self.rate = self.count as f32 / seconds;
+        }
+
+        self.count = 0;
+        self.errored = false;
+    }
+}
+
+/// The health of the whole can, at one point in time.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct SystemHealth {
+    pub time: Instant,
+    pub components: [ComponentHealth; COMPONENT_COUNT],
+    /// How many errors have been handled by [PrintErr] since boot.
+    pub logged_errors: u32,
+    /// How many errors have been recorded in the [journal] since boot.
+    pub faults: u32,
+}
+
+impl SystemHealth {
+    pub fn get(&self, component: Component) -> &ComponentHealth {
+        &self.components[component as usize]
+    }
+
+    /// One bit per [Component], set if it is working - as sent in telemetry.
+    pub fn flags(&self) -> u16 {
+        Component::ALL
+            .iter()
+            .filter(|&&component| self.get(component).status.is_working())
+            .fold(0, |flags, &component| flags | (1 << component as u16))
+    }
+
+    /// The worst status of any component.
+    pub fn status(&self) -> Status {
+        self.components
+            .iter()
+            .map(|component| component.status)
+            .max()
+            .unwrap_or_default()
+    }
+
+    /// The components with `status`.
+    pub fn with_status(&self, status: Status) -> impl Iterator<Item = Component> + '_ {
+        Component::ALL
+            .into_iter()
+            .filter(move |&component| self.get(component).status == status)
+    }
+}
+
+/// Keeps count of the successes and errors of every component.
+#[derive(Clone, Debug)]
+pub struct HealthTracker {
+    components: [ComponentHealth; COMPONENT_COUNT],
+    last_check: Option<Instant>,
+}
+
+impl HealthTracker {
+    pub const fn new() -> Self {
+        const UNKNOWN: ComponentHealth = ComponentHealth {
+            status: Status::Unknown,
+            last_success: None,
+            last_error: None,
+            consecutive_errors: 0,
+            total_errors: 0,
+            rate: 0.0,
+            count: 0,
+            errored: false,
+        };
+
+        Self {
+            components: [UNKNOWN; COMPONENT_COUNT],
+            last_check: None,
+        }
+    }
+
+    pub fn success(&mut self, component: Component, time: Instant) {
+        self.components[component as usize].success(time);
+    }
+
+    pub fn error(&mut self, component: Component, time: Instant) {
+        self.components[component as usize].error(time);
+    }
+
+    pub fn report(&mut self, component: Component, success: bool, time: Instant) {
+        if success {
+            self.success(component, time);
+        } else {
+            self.error(component, time);
+        }
+    }
+
+    /// Works out the health of every component as of `now`, and starts
+    /// counting the rates again.
+    pub fn check(&mut self, now: Instant, config: &HealthConfig) -> SystemHealth {
+        let elapsed = self
+            .last_check
+            .map_or(config.interval, |last| now.saturating_duration_since(last));
+        self.last_check = Some(now);
+
+        for component in Component::ALL {
+            self.components[component as usize].check(now, elapsed, component.max_age(), config);
+        }
+
+        SystemHealth {
+            time: now,
+            components: self.components,
+            logged_errors: 0,
+            faults: 0,
+        }
+    }
+}
+
+impl Default for HealthTracker {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Tells the health monitor that `component` just did its job.
+pub fn report_success(component: Component) {
+    let now = Instant::now();
+    critical_section::with(|cs| HEALTH_TRACKER.borrow_ref_mut(cs).success(component, now));
+}
+
+/// Tells the health monitor that `component` just failed.
+pub fn report_error(component: Component) {
+    let now = Instant::now();
+    critical_section::with(|cs| HEALTH_TRACKER.borrow_ref_mut(cs).error(component, now));
+}
+
+/// Tells the health monitor whether `component` just did its job.
+pub fn report<T, E>(component: Component, result: &Result<T, E>) {
+    let now = Instant::now();
+    critical_section::with(|cs| {
+        HEALTH_TRACKER
+            .borrow_ref_mut(cs)
+            .report(component, result.is_ok(), now)
+    });
+}
+
+#[task]
+pub async fn health_monitor(config: HealthConfig) {
+    let mut mpu_bus = MPU_BUS.subscribe().expect("no MPU_BUS subscribers left");
+    let mut mag_bus = MAG_BUS.subscribe().expect("no MAG_BUS subscribers left");
+    let mut bme_bus = BME_BUS.subscribe().expect("no BME_BUS subscribers left");
+    let mut gps_bus = GPS_BUS.subscribe().expect("no GPS_BUS subscribers left");
+    let mut attitude_bus = ATTITUDE_BUS
+        .subscribe()
+        .expect("no ATTITUDE_BUS subscribers left");
+    let mut vertical_bus = VERTICAL_BUS
+        .subscribe()
+        .expect("no VERTICAL_BUS subscribers left");
+
+    let mut ticker = Ticker::every(POLL_INTERVAL);
+    let mut last_check = Instant::now();
+    let mut last_status = Status::Unknown;
+
+    let heartbeat = watchdog::register(TaskId::Health, Duration::from_secs(2));
+
+    loop {
+        ticker.next().await;
+        heartbeat.beat();
+
+        critical_section::with(|cs| {
+            let mut tracker = HEALTH_TRACKER.borrow_ref_mut(cs);
+
+            while let Some(sample) = mpu_bus.try_next() {
+                let valid = sample.data.acc.is_valid() && sample.data.gyro.is_valid();
+                tracker.report(Component::Mpu, valid, sample.time);
+            }
+            while let Some(sample) = mag_bus.try_next() {
+                tracker.report(Component::Mag, sample.data.mag.is_valid(), sample.time);
+            }
+            while let Some(sample) = bme_bus.try_next() {
+                tracker.report(Component::Bme, sample.data.is_valid(), sample.time);
+            }
+            // a receiver without a fix isn't doing its job, even if it is talking
+            while let Some(sample) = gps_bus.try_next() {
+                tracker.report(Component::Gps, sample.data.has_fix(), sample.time);
+            }
+            while let Some(sample) = attitude_bus.try_next() {
+                tracker.success(Component::Attitude, sample.time);
+            }
+            while let Some(sample) = vertical_bus.try_next() {
+                tracker.success(Component::Vertical, sample.time);
+            }
+        });
+
+        if last_check.elapsed() < config.interval {
+            continue;
+        }
+        last_check = Instant::now();
+
+        // sum up errors that were held back and haven't been repeated since
+        log_error_repeats();
+
+        let mut health = critical_section::with(|cs| {
+            HEALTH_TRACKER.borrow_ref_mut(cs).check(last_check, &config)
+        });
+        health.logged_errors = error_total();
+        health.faults = journal::total();
+
+        let status = health.status();
+
+        if status != last_status {
+            match status {
+                Status::Failed => {
+                    warn!("Health: {}", status.name());
+                    for component in health.with_status(Status::Failed) {
+                        warn!("{} has failed", component.name());
+                    }
+                }
+                _ => info!("Health: {}", status.name()),
+            }
+            last_status = status;
+        }
+
+        trace!("{health:?}");
+
+        HEALTH_BUS.publish(health);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn at(ms: u64) -> Instant {
+        Instant::from_millis(ms)
+    }
+
+    fn status(health: &SystemHealth, component: Component) -> Status {
+        health.get(component).status
+    }
+
+    #[test]
+    fn unknown_until_heard_from() {
+        let mut tracker = HealthTracker::new();
+        let health = tracker.check(at(1_000), &HealthConfig::default());
+
+        assert!(health
+            .components
+            .iter()
+            .all(|c| c.status == Status::Unknown));
+        assert_eq!(health.status(), Status::Unknown);
+        assert_eq!(health.flags(), 0);
+    }
+
+    #[test]
+    fn goes_stale_without_successes() {
+        let config = HealthConfig::default();
+        let mut tracker = HealthTracker::new();
+
+        tracker.success(Component::Mpu, at(1_000));
+        let health = tracker.check(at(1_500), &config);
+        assert_eq!(status(&health, Component::Mpu), Status::Ok);
+
+        // just past the MPU's 500ms
+        let health = tracker.check(at(1_501), &config);
+        assert_eq!(status(&health, Component::Mpu), Status::Failed);
+
+        tracker.success(Component::Mpu, at(1_600));
+        let health = tracker.check(at(1_700), &config);
+        assert_eq!(status(&health, Component::Mpu), Status::Ok);
+    }
+
+    #[test]
+    fn errors_since_the_last_check_degrade() {
+        let config = HealthConfig::default();
+        let mut tracker = HealthTracker::new();
+
+        tracker.success(Component::Bme, at(1_000));
+        tracker.error(Component::Bme, at(1_100));
+        tracker.success(Component::Bme, at(1_200));
+
+        let health = tracker.check(at(1_300), &config);
+        let bme = health.get(Component::Bme);
+        assert_eq!(bme.status, Status::Degraded);
+        assert_eq!(bme.last_error, Some(at(1_100)));
+        assert_eq!(bme.consecutive_errors, 0);
+        assert_eq!(bme.total_errors, 1);
+
+        // a clean interval recovers it
+        tracker.success(Component::Bme, at(2_000));
+        let health = tracker.check(at(2_300), &config);
+        assert_eq!(status(&health, Component::Bme), Status::Ok);
+        assert_eq!(health.get(Component::Bme).total_errors, 1);
+    }
+
+    #[test]
+    fn too_many_errors_in_a_row_fail() {
+        let config = HealthConfig::default();
+        let mut tracker = HealthTracker::new();
+
+        tracker.success(Component::Sd, at(1_000));
+        for ms in [1_100, 1_200, 1_300, 1_400] {
+            tracker.error(Component::Sd, at(ms));
+        }
+
+        // the last success is recent, but the errors keep coming
+        let health = tracker.check(at(1_500), &config);
+        assert_eq!(status(&health, Component::Sd), Status::Degraded);
+
+        tracker.error(Component::Sd, at(1_600));
+        let health = tracker.check(at(1_700), &config);
+        assert_eq!(status(&health, Component::Sd), Status::Failed);
+        assert_eq!(health.get(Component::Sd).consecutive_errors, 5);
+
+        tracker.success(Component::Sd, at(1_800));
+        let health = tracker.check(at(1_900), &config);
+        assert_eq!(status(&health, Component::Sd), Status::Ok);
+    }
+
+    #[test]
+    fn only_errors_is_failed() {
+        let mut tracker = HealthTracker::new();
+        tracker.report(Component::Radio, false, at(1_000));
+
+        let health = tracker.check(at(1_100), &HealthConfig::default());
+        assert_eq!(status(&health, Component::Radio), Status::Failed);
+    }
+
+    #[test]
+    fn counts_the_rate_since_the_last_check() {
+        let config = HealthConfig::default();
+        let mut tracker = HealthTracker::new();
+        tracker.check(at(0), &config);
+
+        for ms in (0..500).step_by(100) {
+            tracker.report(Component::Mag, ms != 200, at(ms));
+        }
+
+        let health = tracker.check(at(500), &config);
+        assert_eq!(health.get(Component::Mag).rate, 10.0);
+
+        let health = tracker.check(at(1_500), &config);
+        assert_eq!(health.get(Component::Mag).rate, 0.0);
+    }
+
+    #[test]
+    fn the_worst_status_is_the_overall_one() {
+        assert!(Status::Ok < Status::Unknown);
+        assert!(Status::Unknown < Status::Degraded);
+        assert!(Status::Degraded < Status::Failed);
+
+        let config = HealthConfig::default();
+        let mut tracker = HealthTracker::new();
+
+        for component in Component::ALL {
+            tracker.success(component, at(1_000));
+        }
+        let health = tracker.check(at(1_100), &config);
+        assert_eq!(health.status(), Status::Ok);
+        assert_eq!(health.flags(), (1 << COMPONENT_COUNT) - 1);
+
+        for component in Component::ALL {
+            tracker.success(component, at(1_200));
+        }
+        tracker.error(Component::Gps, at(1_250));
+        let health = tracker.check(at(1_300), &config);
+        assert_eq!(health.status(), Status::Degraded);
+        // degraded still counts as working
+        assert_eq!(health.flags(), (1 << COMPONENT_COUNT) - 1);
+
+        // by 2.3s only the GPS, BME280, radio and SD card aren't stale
+        tracker.success(Component::Gps, at(2_200));
+        let health = tracker.check(at(2_300), &config);
+        assert_eq!(health.status(), Status::Failed);
+        assert!(health.with_status(Status::Failed).eq([
+            Component::Mpu,
+            Component::Mag,
+            Component::Attitude,
+            Component::Vertical,
+            Component::BlackBox,
+        ]));
+        assert_eq!(
+            health.flags(),
+            1 << Component::Bme as u16
+                | 1 << Component::Gps as u16
+                | 1 << Component::Radio as u16
+                | 1 << Component::Sd as u16
+        );
+    }
+}
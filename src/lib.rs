@@ -1,15 +1,34 @@
-#![no_std]
-#![no_main]
+#![cfg_attr(not(test), no_std)]
+#![cfg_attr(not(test), no_main)]
 #![feature(type_alias_impl_trait)]
 // #![allow(unused)]
 #![allow(clippy::unused_unit)]
 
+pub mod ahrs;
+pub mod altitude;
+pub mod apogee;
+#[cfg(target_arch = "xtensa")]
 pub mod blink;
 pub mod bme280;
+pub mod bus;
+pub mod compass;
 pub mod display;
+pub mod downlink;
 pub mod errors;
+pub mod flight;
+pub mod gps;
+pub mod health;
+pub mod logger;
+pub mod lora;
+pub mod mag_calibration;
 pub mod mpu6050;
+pub mod qmc5883l;
+pub mod sample;
+#[cfg(test)]
+mod test_utils;
 pub mod utils;
+pub mod vertical;
+pub mod watchdog;
 
 #[cfg(feature = "alloc")]
 pub mod alloc {
@@ -33,6 +52,7 @@ pub mod alloc {
 
 pub mod prelude {
 
+    #[cfg(target_arch = "xtensa")]
     pub type SharedI2C = embedded_hal_bus::i2c::CriticalSectionDevice<
         'static,
         hal::i2c::I2C<'static, hal::peripherals::I2C0>,
@@ -52,10 +72,14 @@ pub mod prelude {
 
     pub use critical_section::Mutex;
 
+    #[cfg(target_arch = "xtensa")]
     pub use esp32_hal as hal;
+    #[cfg(target_arch = "xtensa")]
     #[allow(unused)]
     pub use esp_backtrace as _;
 
+    //on the host (where only the tests are built) std's own are used
+    #[cfg(target_arch = "xtensa")]
     pub use esp_println::{print, println};
 
     pub use embedded_error_chain::prelude::*;
@@ -64,6 +88,7 @@ pub mod prelude {
 
     pub use embassy_sync::signal::Signal;
 
+    #[cfg(target_arch = "xtensa")]
     pub use hal::{
         embassy,
         gpio::{AnyPin, Output, PushPull},
@@ -0,0 +1,357 @@
+//! Recording of every sensor reading, for analysis after the flight.
+//!
+//! Readings are collected from the data buses as [LogRecord]s, formatted as
+//! lines of CSV and queued in a [LogBuffer] until a storage backend (such as
+//! [sd]) is ready to take a whole chunk of them at once.
+
+pub mod blackbox;
+pub mod pretrigger;
+pub mod sd;
+
+use core::fmt::{self, Write};
+
+use crate::{
+    ahrs::{AttitudeData, AttitudeSubscriber, ATTITUDE_BUS},
+    altitude::{AltitudeData, AltitudeSubscriber, ALTITUDE_BUS},
+    bme280::{BmeData, BmeSubscriber, BME_BUS},
+    bus::BusError,
+    errors::journal::{FaultSubscriber, JournalEntry, FAULT_BUS},
+    flight::{FlightPhaseSubscriber, PhaseTransition, FLIGHT_PHASE_BUS},
+    gps::{GpsFix, GpsSubscriber, GPS_BUS},
+    mpu6050::{MpuData, MpuSubscriber, MPU_BUS},
+    prelude::*,
+    qmc5883l::{MagData, MagSubscriber, MAG_BUS},
+    sample::{Reading, Sample},
+};
+
+/// The longest a single formatted [LogRecord] can be.
+pub const MAX_LINE_LEN: usize = 192;
+
+/// Written at the start of every log file, describing the columns of each kind
+/// of record. Every line starts with the record kind and the time in
+/// milliseconds since boot, and fields of readings which failed are left empty.
+pub const CSV_HEADER: &str = "\
+# cansat flight log v1
+# M,time_ms,seq,acc_x_g,acc_y_g,acc_z_g,gyro_x_dps,gyro_y_dps,gyro_z_dps,temp_c,roll_rad,pitch_rad
+# B,time_ms,seq,temp_c,pressure_pa,humidity_pct
+# A,time_ms,seq,absolute_m,agl_m
+# Q,time_ms,seq,mag_x_gauss,mag_y_gauss,mag_z_gauss,temp_c
+# O,time_ms,seq,q_w,q_x,q_y,q_z,roll_deg,pitch_deg,yaw_deg
+# G,time_ms,seq,fix,satellites,latitude,longitude,altitude_m,speed_mps,course_deg,hdop,utc_date,utc_time
+# F,time_ms,from,to
+# E,time_ms,code,fault
+";
+
+/// Anything which can be written to the flight log.
+#[derive(Clone, Copy, Debug)]
+pub enum LogRecord {
+    Mpu(Sample<MpuData>),
+    Baro(Sample<Reading<BmeData>>),
+    Altitude(Sample<AltitudeData>),
+    Mag(Sample<MagData>),
+    Attitude(Sample<AttitudeData>),
+    Gps(Sample<GpsFix>),
+    Phase(PhaseTransition),
+    /// An error from the [journal](crate::errors::journal), when it first
+    /// happened.
+    Fault(JournalEntry),
+}
+
+impl LogRecord {
+    /// When the reading (or transition) happened.
+    pub fn time(&self) -> Instant {
+        match self {
+            Self::Mpu(sample) => sample.time,
+            Self::Baro(sample) => sample.time,
+            Self::Altitude(sample) => sample.time,
+            Self::Mag(sample) => sample.time,
+            Self::Attitude(sample) => sample.time,
+            Self::Gps(sample) => sample.time,
+            Self::Phase(transition) => transition.time,
+            Self::Fault(entry) => entry.first,
+        }
+    }
+
+    /// Writes the record as one line of CSV, in the format described by
+    /// [CSV_HEADER].
+    pub fn write_csv<W: Write>(&self, w: &mut W) -> fmt::Result {
+        match self {
+            Self::Mpu(sample) => {
+                write!(w, "M,{},{}", sample.time.as_millis(), sample.seq)?;
+                write_fields(w, sample.data.acc.as_valid().map(|v| v.as_slice()), 3)?;
+                write_fields(w, sample.data.gyro.as_valid().map(|v| v.as_slice()), 3)?;
+                write_fields(w, sample.data.temp.as_valid().map(core::slice::from_ref), 1)?;
+                write_fields(
+                    w,
+                    sample.data.roll_pitch.as_valid().map(|v| v.as_slice()),
+                    2,
+                )?;
+            }
+            Self::Baro(sample) => {
+                write!(w, "B,{},{}", sample.time.as_millis(), sample.seq)?;
+                let values = sample
+                    .data
+                    .map(|data| [data.temperature, data.pressure, data.humidity]);
+                write_fields(w, values.as_valid().map(|v| v.as_slice()), 3)?;
+            }
+            Self::Altitude(sample) => {
+                write!(
+                    w,
+                    "A,{},{},{:.2},{:.2}",
+                    sample.time.as_millis(),
+                    sample.seq,
+                    sample.data.absolute,
+                    sample.data.agl
+                )?;
+            }
+            Self::Mag(sample) => {
+                write!(w, "Q,{},{}", sample.time.as_millis(), sample.seq)?;
+                write_fields(w, sample.data.mag.as_valid().map(|v| v.as_slice()), 3)?;
+                write_fields(w, sample.data.temp.as_valid().map(core::slice::from_ref), 1)?;
+            }
+            Self::Attitude(sample) => {
+                let q = sample.data.quaternion.quaternion();
+                let euler = sample.data.euler;
+
+                write!(
+                    w,
+                    "O,{},{},{:.4},{:.4},{:.4},{:.4},{:.2},{:.2},{:.2}",
+                    sample.time.as_millis(),
+                    sample.seq,
+                    q.w,
+                    q.i,
+                    q.j,
+                    q.k,
+                    euler.x,
+                    euler.y,
+                    euler.z
+                )?;
+            }
+            Self::Gps(sample) => {
+                let fix = &sample.data;
+
+                write!(
+                    w,
+                    "G,{},{},{:?},{},{:.7},{:.7},{:.2},{:.2},{:.1},{:.2},",
+                    sample.time.as_millis(),
+                    sample.seq,
+                    fix.fix_type,
+                    fix.satellites,
+                    fix.latitude,
+                    fix.longitude,
+                    fix.altitude,
+                    fix.speed,
+                    fix.course,
+                    fix.hdop
+                )?;
+
+                if let Some(date) = fix.date {
+                    write!(w, "{date}")?;
+                }
+                w.write_char(',')?;
+                if let Some(time) = fix.time {
+                    write!(w, "{time}")?;
+                }
+            }
+            Self::Phase(transition) => {
+                write!(
+                    w,
+                    "F,{},{:?},{:?}",
+                    transition.time.as_millis(),
+                    transition.from,
+                    transition.to
+                )?;
+            }
+            Self::Fault(entry) => {
+                write!(
+                    w,
+                    "E,{},{:#06x},{}",
+                    entry.first.as_millis(),
+                    entry.code.0,
+                    entry.code
+                )?;
+            }
+        }
+
+        w.write_char('\n')
+    }
+}
+
+/// Writes `count` comma separated fields from `values`, or leaves them empty if
+/// the reading failed.
+fn write_fields<W: Write>(w: &mut W, values: Option<&[f32]>, count: usize) -> fmt::Result {
+    for i in 0..count {
+        w.write_char(',')?;
+
+        if let Some(value) = values.and_then(|values| values.get(i)) {
+            write!(w, "{value:.4}")?;
+        }
+    }
+
+    Ok(())
+}
+
+/// A subscription to every bus that gets logged.
+pub struct RecordCollector {
+    mpu: MpuSubscriber,
+    baro: BmeSubscriber,
+    altitude: AltitudeSubscriber,
+    mag: MagSubscriber,
+    attitude: AttitudeSubscriber,
+    gps: GpsSubscriber,
+    phase: FlightPhaseSubscriber,
+    fault: FaultSubscriber,
+}
+
+impl RecordCollector {
+    pub fn new() -> Result<Self, BusError> {
+        Ok(Self {
+            mpu: MPU_BUS.subscribe()?,
+            baro: BME_BUS.subscribe()?,
+            altitude: ALTITUDE_BUS.subscribe()?,
+            mag: MAG_BUS.subscribe()?,
+            attitude: ATTITUDE_BUS.subscribe()?,
+            gps: GPS_BUS.subscribe()?,
+            phase: FLIGHT_PHASE_BUS.subscribe()?,
+            fault: FAULT_BUS.subscribe()?,
+        })
+    }
+
+    /// Hands every record waiting on any of the buses to `f`, without waiting
+    /// for more.
+    ///
+    /// Records are grouped by bus rather than strictly ordered by time - each
+    /// one carries its own timestamp.
+    pub fn drain(&mut self, mut f: impl FnMut(LogRecord)) {
+        while let Some(transition) = self.phase.try_next() {
+            f(LogRecord::Phase(transition));
+        }
+        while let Some(entry) = self.fault.try_next() {
+            f(LogRecord::Fault(entry));
+        }
+        while let Some(sample) = self.mpu.try_next() {
+            f(LogRecord::Mpu(sample));
+        }
+        while let Some(sample) = self.baro.try_next() {
+            f(LogRecord::Baro(sample));
+        }
+        while let Some(sample) = self.altitude.try_next() {
+            f(LogRecord::Altitude(sample));
+        }
+        while let Some(sample) = self.mag.try_next() {
+            f(LogRecord::Mag(sample));
+        }
+        while let Some(sample) = self.attitude.try_next() {
+            f(LogRecord::Attitude(sample));
+        }
+        while let Some(sample) = self.gps.try_next() {
+            f(LogRecord::Gps(sample));
+        }
+    }
+
+    /// How many records were overwritten on the buses before they could be
+    /// collected.
+    pub fn dropped(&self) -> u64 {
+        self.mpu.dropped()
+            + self.baro.dropped()
+            + self.altitude.dropped()
+            + self.mag.dropped()
+            + self.attitude.dropped()
+            + self.gps.dropped()
+            + self.phase.dropped()
+            + self.fault.dropped()
+    }
+}
+
+/// A fixed size FIFO of bytes waiting to be written to storage.
+///
+/// Lines are only ever added whole, so if storage can't keep up (or has been
+/// removed) whole lines are dropped rather than the log being corrupted.
+pub struct LogBuffer<const N: usize> {
+    data: [u8; N],
+    /// Index of the oldest byte.
+    start: usize,
+    len: usize,
+    /// How many lines didn't fit.
+    dropped: u32,
+}
+
+impl<const N: usize> LogBuffer<N> {
+    pub const fn new() -> Self {
+        Self {
+            data: [0; N],
+            start: 0,
+            len: 0,
+            dropped: 0,
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    /// How many more bytes will fit.
+    pub fn free(&self) -> usize {
+        N - self.len
+    }
+
+    /// How many lines have been dropped because the buffer was full.
+    pub fn dropped(&self) -> u32 {
+        self.dropped
+    }
+
+    /// Appends `line`, returning `false` (and dropping all of it) if there
+    /// isn't room for the whole line.
+    pub fn push(&mut self, line: &[u8]) -> bool {
+        if line.len() > self.free() {
+            self.dropped = self.dropped.saturating_add(1);
+            return false;
+        }
+
+        let end = (self.start + self.len) % N;
+        let first = line.len().min(N - end);
+
+        self.data[end..end + first].copy_from_slice(&line[..first]);
+        self.data[..line.len() - first].copy_from_slice(&line[first..]);
+
+        self.len += line.len();
+
+        true
+    }
+
+    /// The oldest bytes in the buffer which are stored contiguously - call
+    /// again after [LogBuffer::consume] to get the rest.
+    pub fn peek(&self) -> &[u8] {
+        let end = (self.start + self.len).min(N);
+
+        &self.data[self.start..end]
+    }
+
+    /// Removes the oldest `n` bytes, once they have been written to storage.
+    pub fn consume(&mut self, n: usize) {
+        let n = n.min(self.len);
+
+        self.start = (self.start + n) % N;
+        self.len -= n;
+
+        if self.len == 0 {
+            self.start = 0;
+        }
+    }
+
+    /// Throws away everything in the buffer.
+    pub fn clear(&mut self) {
+        self.start = 0;
+        self.len = 0;
+    }
+}
+
+impl<const N: usize> Default for LogBuffer<N> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
@@ -0,0 +1,1221 @@
+//! A black box recorder in the ESP32's own flash, for when there is no SD card
+//! (or it didn't survive).
+//!
+//! The `blackbox` partition is used as a ring of sectors. Each sector starts
+//! with a header holding an ever increasing sequence number, followed by
+//! records packed one after another. Records are only ever appended, and a
+//! sector is only erased when the ring wraps around onto it, so every sector is
+//! worn evenly.
+//!
+//! A record is laid out as:
+//!
+//! | bytes   | contents                                     |
+//! |---------|----------------------------------------------|
+//! | 0       | length of the record, including the CRC      |
+//! | 1       | [RecordKind]                                 |
+//! | 2..6    | time in milliseconds since boot              |
+//! | 6..10   | sequence number of the sample                |
+//! | 10..    | the data, mostly as little endian `f32`s     |
+//! | last 2  | CRC-16 of everything before it               |
+//!
+//! and padded with `0xFF` to a multiple of [RECORD_ALIGN]. A length of `0xFF`
+//! is erased flash, which marks the end of the records in a sector, so the
+//! write head can be found again after a reset.
+//!
+//! [BlackBox] works on any [NorFlash], so it can be run against a RAM-backed
+//! flash as well as the real thing.
+
+use crc::{Crc, CRC_16_IBM_3740};
+use embedded_storage::nor_flash::{NorFlash, ReadNorFlash};
+#[cfg(target_arch = "xtensa")]
+use esp_storage::FlashStorage;
+use heapless::String;
+use nalgebra::{Quaternion, UnitQuaternion, Vector2, Vector3};
+
+#[cfg(target_arch = "xtensa")]
+use super::{
+    pretrigger::{PreTrigger, PRE_TRIGGER_CAPACITY, PRE_TRIGGER_DURATION},
+    RecordCollector,
+};
+use super::{LogRecord, CSV_HEADER, MAX_LINE_LEN};
+use crate::{
+    ahrs::AttitudeData,
+    altitude::{AltitudeData, GroundReference},
+    bme280::BmeData,
+    errors::journal::JournalEntry,
+    flight::{FlightPhase, PhaseTransition},
+    gps::{Date, FixType, GpsFix, UtcTime},
+    mpu6050::MpuData,
+    prelude::*,
+    qmc5883l::MagData,
+    sample::{Reading, Sample, SourceId},
+};
+#[cfg(target_arch = "xtensa")]
+use crate::{
+    errors::journal,
+    health::{self, Component},
+    watchdog::{self, TaskId},
+};
+
+/// Where the black box is stored - the `blackbox` partition in
+/// `partitions.csv`.
+pub const BLACKBOX_OFFSET: u32 = 0x22_0000;
+pub const BLACKBOX_LEN: u32 = 0x1E_0000;
+
+/// How often new records are collected from the buses and written.
+pub const RECORD_INTERVAL: Duration = Duration::from_millis(100);
+
+/// Records start on, and are padded to, a multiple of this many bytes - the
+/// write size of the ESP32's flash.
+pub const RECORD_ALIGN: u32 = 4;
+/// The longest a record can be, before padding.
+pub const MAX_RECORD_LEN: usize = 64;
+
+const RECORD_HEADER_LEN: usize = 10;
+const CRC_LEN: usize = 2;
+const MIN_RECORD_LEN: usize = RECORD_HEADER_LEN + CRC_LEN;
+
+const SECTOR_MAGIC: [u8; 4] = *b"BBX1";
+const SECTOR_HEADER_LEN: u32 = 8;
+
+/// Marks a [Reading] that was valid - anything else is the error code.
+const VALID: u8 = 0xFF;
+/// Stands in for a GPS time or date the receiver didn't send.
+const NO_GPS_TIME: u32 = u32::MAX;
+const ERASED: u8 = 0xFF;
+
+const CRC: Crc<u16> = Crc::<u16>::new(&CRC_16_IBM_3740);
+
+#[derive(Clone, Copy, ErrorCategory)]
+#[repr(u8)]
+pub enum BlackBoxError {
+    /// The partition doesn't line up with the flash's sectors
+    InvalidPartition,
+    /// An error while reading, writing or erasing the flash
+    StorageError,
+}
+
+type Result<T> = core::result::Result<T, BlackBoxError>;
+
+/// What kind of [LogRecord] a black box record holds.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[repr(u8)]
+pub enum RecordKind {
+    Mpu = 1,
+    Baro,
+    Altitude,
+    Mag,
+    Attitude,
+    Phase,
+    Gps,
+    Fault,
+}
+
+impl RecordKind {
+    fn from_u8(value: u8) -> Option<Self> {
+        match value {
+            1 => Some(Self::Mpu),
+            2 => Some(Self::Baro),
+            3 => Some(Self::Altitude),
+            4 => Some(Self::Mag),
+            5 => Some(Self::Attitude),
+            6 => Some(Self::Phase),
+            7 => Some(Self::Gps),
+            8 => Some(Self::Fault),
+            _ => None,
+        }
+    }
+}
+
+/// Appends values to a record being encoded.
+struct Encoder<'a> {
+    buf: &'a mut [u8; MAX_RECORD_LEN],
+    len: usize,
+}
+
+impl Encoder<'_> {
+    fn bytes(&mut self, bytes: &[u8]) {
+        self.buf[self.len..self.len + bytes.len()].copy_from_slice(bytes);
+        self.len += bytes.len();
+    }
+
+    fn f32s(&mut self, values: &[f32]) {
+        for value in values {
+            self.bytes(&value.to_le_bytes());
+        }
+    }
+
+    /// The error code (or [VALID]), then `count` values - zeros if the reading
+    /// failed.
+    fn reading(&mut self, reading: Reading<&[f32]>, count: usize) {
+        match reading {
+            Reading::Valid(values) => {
+                self.bytes(&[VALID]);
+                self.f32s(&values[..count]);
+            }
+            Reading::Invalid(code) => {
+                self.bytes(&[code]);
+                for _ in 0..count {
+                    self.f32s(&[0.0]);
+                }
+            }
+        }
+    }
+}
+
+/// Takes values back out of an encoded record, in the order they were added.
+struct Decoder<'a> {
+    bytes: &'a [u8],
+}
+
+impl Decoder<'_> {
+    fn array<const N: usize>(&mut self) -> Option<[u8; N]> {
+        let (value, rest) = self.bytes.split_first_chunk::<N>()?;
+        self.bytes = rest;
+        Some(*value)
+    }
+
+    fn u8(&mut self) -> Option<u8> {
+        self.array::<1>().map(|[value]| value)
+    }
+
+    fn u32(&mut self) -> Option<u32> {
+        self.array().map(u32::from_le_bytes)
+    }
+
+    fn u16(&mut self) -> Option<u16> {
+        self.array().map(u16::from_le_bytes)
+    }
+
+    fn f64(&mut self) -> Option<f64> {
+        self.array().map(f64::from_le_bytes)
+    }
+
+    fn f32s<const N: usize>(&mut self) -> Option<[f32; N]> {
+        let mut values = [0.0; N];
+
+        for value in &mut values {
+            *value = f32::from_le_bytes(self.array()?);
+        }
+
+        Some(values)
+    }
+
+    fn reading<const N: usize>(&mut self) -> Option<Reading<[f32; N]>> {
+        let code = self.u8()?;
+        let values = self.f32s::<N>()?;
+
+        Some(match code {
+            VALID => Reading::Valid(values),
+            code => Reading::Invalid(code),
+        })
+    }
+}
+
+fn vector_reading<T>(reading: &Reading<T>, as_slice: impl FnOnce(&T) -> &[f32]) -> Reading<&[f32]> {
+    match reading {
+        Reading::Valid(value) => Reading::Valid(as_slice(value)),
+        Reading::Invalid(code) => Reading::Invalid(*code),
+    }
+}
+
+/// Packs `record` into `buf`, returning the length of the record.
+pub fn encode(record: &LogRecord, buf: &mut [u8; MAX_RECORD_LEN]) -> usize {
+    let (kind, time, seq) = match record {
+        LogRecord::Mpu(sample) => (RecordKind::Mpu, sample.time, sample.seq),
+        LogRecord::Baro(sample) => (RecordKind::Baro, sample.time, sample.seq),
+        LogRecord::Altitude(sample) => (RecordKind::Altitude, sample.time, sample.seq),
+        LogRecord::Mag(sample) => (RecordKind::Mag, sample.time, sample.seq),
+        LogRecord::Attitude(sample) => (RecordKind::Attitude, sample.time, sample.seq),
+        LogRecord::Gps(sample) => (RecordKind::Gps, sample.time, sample.seq),
+        LogRecord::Phase(transition) => (RecordKind::Phase, transition.time, 0),
+        LogRecord::Fault(entry) => (RecordKind::Fault, entry.first, 0),
+    };
+
+    let mut encoder = Encoder { buf, len: 0 };
+
+    // the length is filled in at the end
+    encoder.bytes(&[0, kind as u8]);
+    encoder.bytes(&(time.as_millis() as u32).to_le_bytes());
+    encoder.bytes(&seq.to_le_bytes());
+
+    match record {
+        LogRecord::Mpu(sample) => {
+            let data = &sample.data;
+            encoder.reading(vector_reading(&data.acc, |v| v.as_slice()), 3);
+            encoder.reading(vector_reading(&data.gyro, |v| v.as_slice()), 3);
+            encoder.reading(vector_reading(&data.temp, core::slice::from_ref), 1);
+            encoder.reading(vector_reading(&data.roll_pitch, |v| v.as_slice()), 2);
+        }
+        LogRecord::Baro(sample) => {
+            let values = sample
+                .data
+                .map(|data| [data.temperature, data.pressure, data.humidity]);
+            encoder.reading(vector_reading(&values, |v| v.as_slice()), 3);
+        }
+        LogRecord::Altitude(sample) => {
+            let data = &sample.data;
+            encoder.f32s(&[
+                data.absolute,
+                data.agl,
+                data.reference.pressure,
+                data.reference.temperature,
+            ]);
+        }
+        LogRecord::Mag(sample) => {
+            encoder.reading(vector_reading(&sample.data.mag, |v| v.as_slice()), 3);
+            encoder.reading(vector_reading(&sample.data.temp, core::slice::from_ref), 1);
+        }
+        LogRecord::Attitude(sample) => {
+            encoder.f32s(sample.data.quaternion.coords.as_slice());
+        }
+        LogRecord::Gps(sample) => {
+            let fix = &sample.data;
+            encoder.bytes(&[fix.fix_type as u8, fix.satellites]);
+            encoder.bytes(&fix.latitude.to_le_bytes());
+            encoder.bytes(&fix.longitude.to_le_bytes());
+            encoder.f32s(&[fix.altitude, fix.speed, fix.course, fix.hdop]);
+
+            let time = fix.time.map_or(NO_GPS_TIME, |time| time.millis_of_day());
+            encoder.bytes(&time.to_le_bytes());
+
+            let date = fix.date.unwrap_or_default();
+            encoder.bytes(&date.year.to_le_bytes());
+            encoder.bytes(&[date.month, date.day]);
+        }
+        LogRecord::Phase(transition) => {
+            encoder.bytes(&[transition.from as u8, transition.to as u8]);
+        }
+        LogRecord::Fault(entry) => {
+            encoder.bytes(&entry.code.0.to_le_bytes());
+        }
+    }
+
+    let len = encoder.len + CRC_LEN;
+    encoder.buf[0] = len as u8;
+
+    let crc = CRC.checksum(&encoder.buf[..encoder.len]);
+    encoder.bytes(&crc.to_le_bytes());
+
+    len
+}
+
+/// Unpacks a record written by [encode], or [None] if it is corrupt.
+pub fn decode(bytes: &[u8]) -> Option<LogRecord> {
+    let len = *bytes.first()? as usize;
+
+    if !(MIN_RECORD_LEN..=MAX_RECORD_LEN).contains(&len) || bytes.len() < len {
+        return None;
+    }
+
+    let (data, crc) = bytes[..len].split_at(len - CRC_LEN);
+
+    if CRC.checksum(data).to_le_bytes() != crc {
+        return None;
+    }
+
+    let mut decoder = Decoder { bytes: &data[1..] };
+
+    let kind = RecordKind::from_u8(decoder.u8()?)?;
+    let time = Instant::from_millis(decoder.u32()? as u64);
+    let seq = decoder.u32()?;
+
+    let sample = |source| Sample {
+        time,
+        seq,
+        source,
+        data: (),
+    };
+
+    let record = match kind {
+        RecordKind::Mpu => {
+            let data = MpuData {
+                acc: decoder.reading::<3>()?.map(Vector3::from),
+                gyro: decoder.reading::<3>()?.map(Vector3::from),
+                temp: decoder.reading::<1>()?.map(|[temp]| temp),
+                roll_pitch: decoder.reading::<2>()?.map(Vector2::from),
+            };
+
+            LogRecord::Mpu(sample(SourceId::Mpu6050).map(|_| data))
+        }
+        RecordKind::Baro => {
+            let data = decoder
+                .reading::<3>()?
+                .map(|[temperature, pressure, humidity]| BmeData {
+                    temperature,
+                    pressure,
+                    humidity,
+                });
+
+            LogRecord::Baro(sample(SourceId::Bme280).map(|_| data))
+        }
+        RecordKind::Altitude => {
+            let [absolute, agl, pressure, temperature] = decoder.f32s()?;
+            let data = AltitudeData {
+                absolute,
+                agl,
+                reference: GroundReference {
+                    pressure,
+                    temperature,
+                },
+            };
+
+            LogRecord::Altitude(sample(SourceId::Bme280).map(|_| data))
+        }
+        RecordKind::Mag => {
+            let data = MagData {
+                mag: decoder.reading::<3>()?.map(Vector3::from),
+                temp: decoder.reading::<1>()?.map(|[temp]| temp),
+            };
+
+            LogRecord::Mag(sample(SourceId::Qmc5883l).map(|_| data))
+        }
+        RecordKind::Attitude => {
+            // stored as the quaternion's coordinates, i, j, k, w
+            let [i, j, k, w] = decoder.f32s()?;
+            let quaternion = UnitQuaternion::new_normalize(Quaternion::new(w, i, j, k));
+
+            LogRecord::Attitude(sample(SourceId::Mpu6050).map(|_| AttitudeData::from(quaternion)))
+        }
+        RecordKind::Gps => {
+            let fix_type = FixType::from_u8(decoder.u8()?)?;
+            let satellites = decoder.u8()?;
+            let latitude = decoder.f64()?;
+            let longitude = decoder.f64()?;
+            let [altitude, speed, course, hdop] = decoder.f32s()?;
+            let time = decoder.u32()?;
+            let year = decoder.u16()?;
+            let [month, day] = decoder.array()?;
+
+            let data = GpsFix {
+                fix_type,
+                time: (time != NO_GPS_TIME).then(|| UtcTime::from_millis_of_day(time)),
+                // a year of 0 is a date the receiver didn't send
+                date: (year != 0).then_some(Date { year, month, day }),
+                latitude,
+                longitude,
+                altitude,
+                speed,
+                course,
+                satellites,
+                hdop,
+            };
+
+            LogRecord::Gps(sample(SourceId::Gps).map(|_| data))
+        }
+        RecordKind::Phase => LogRecord::Phase(PhaseTransition {
+            from: FlightPhase::from_u8(decoder.u8()?)?,
+            to: FlightPhase::from_u8(decoder.u8()?)?,
+            time,
+        }),
+        RecordKind::Fault => LogRecord::Fault(JournalEntry {
+            code: FaultCode(decoder.u16()?),
+            first: time,
+            last: time,
+            count: 1,
+        }),
+    };
+
+    Some(record)
+}
+
+fn padded(len: usize) -> u32 {
+    (len as u32).next_multiple_of(RECORD_ALIGN)
+}
+
+/// A ring buffer of records in a region of [NorFlash].
+pub struct BlackBox<F: NorFlash> {
+    flash: F,
+    /// Start of the region.
+    offset: u32,
+    sector_len: u32,
+    sectors: u32,
+    /// The sector being written to.
+    head: u32,
+    /// Where in the head sector the next record goes.
+    head_offset: u32,
+    /// The sequence number of the head sector.
+    sequence: u32,
+}
+
+impl<F: NorFlash> BlackBox<F> {
+    /// Finds where the last record was written in the `len` bytes at `offset`,
+    /// so recording carries on after it.
+    ///
+    /// If nothing has been recorded there yet, the first sector is erased and
+    /// recording starts from there.
+    pub fn recover(flash: F, offset: u32, len: u32) -> Result<Self> {
+        let sector_len = F::ERASE_SIZE as u32;
+
+        if offset % sector_len != 0 || len % sector_len != 0 || len < 2 * sector_len {
+            return Err(BlackBoxError::InvalidPartition);
+        }
+
+        let mut blackbox = Self {
+            flash,
+            offset,
+            sector_len,
+            sectors: len / sector_len,
+            head: 0,
+            head_offset: SECTOR_HEADER_LEN,
+            sequence: 0,
+        };
+
+        let mut newest = None;
+
+        for sector in 0..blackbox.sectors {
+            if let Some(sequence) = blackbox.sector_sequence(sector)? {
+                if !matches!(newest, Some((_, newest)) if newest >= sequence) {
+                    newest = Some((sector, sequence));
+                }
+            }
+        }
+
+        match newest {
+            Some((sector, sequence)) => {
+                blackbox.head = sector;
+                blackbox.sequence = sequence;
+                blackbox.head_offset = blackbox.scan_sector(sector, |_| ())?.0;
+
+                info!(
+                    "Black box recovered: sector {sector} (sequence {sequence}), offset {}",
+                    blackbox.head_offset
+                );
+            }
+            None => {
+                info!("Black box is empty");
+                blackbox.start_sector(0, 0)?;
+            }
+        }
+
+        Ok(blackbox)
+    }
+
+    pub fn into_inner(self) -> F {
+        self.flash
+    }
+
+    /// How many bytes the black box can hold, including headers and padding.
+    pub fn capacity(&self) -> u32 {
+        self.sectors * self.sector_len
+    }
+
+    fn sector_address(&self, sector: u32) -> u32 {
+        self.offset + sector * self.sector_len
+    }
+
+    /// The sequence number of `sector`, or [None] if it has been erased (or
+    /// was never part of the ring).
+    fn sector_sequence(&mut self, sector: u32) -> Result<Option<u32>> {
+        let mut header = [0; SECTOR_HEADER_LEN as usize];
+
+        self.flash
+            .read(self.sector_address(sector), &mut header)
+            .map_err(|_| BlackBoxError::StorageError)?;
+
+        let (magic, sequence) = header.split_at(SECTOR_MAGIC.len());
+
+        if magic != SECTOR_MAGIC {
+            return Ok(None);
+        }
+
+        Ok(Some(u32::from_le_bytes([
+            sequence[0],
+            sequence[1],
+            sequence[2],
+            sequence[3],
+        ])))
+    }
+
+    /// Erases `sector` and makes it the head of the ring.
+    fn start_sector(&mut self, sector: u32, sequence: u32) -> Result<()> {
+        let address = self.sector_address(sector);
+
+        self.flash
+            .erase(address, address + self.sector_len)
+            .map_err(|_| BlackBoxError::StorageError)?;
+
+        let mut header = [0; SECTOR_HEADER_LEN as usize];
+        header[..SECTOR_MAGIC.len()].copy_from_slice(&SECTOR_MAGIC);
+        header[SECTOR_MAGIC.len()..].copy_from_slice(&sequence.to_le_bytes());
+
+        self.flash
+            .write(address, &header)
+            .map_err(|_| BlackBoxError::StorageError)?;
+
+        self.head = sector;
+        self.head_offset = SECTOR_HEADER_LEN;
+        self.sequence = sequence;
+
+        Ok(())
+    }
+
+    /// Hands every record in `sector` to `f` - [None] for ones which are
+    /// corrupt.
+    ///
+    /// Returns the offset of the end of the records (where the next one would
+    /// go), and how many were corrupt.
+    fn scan_sector(
+        &mut self,
+        sector: u32,
+        mut f: impl FnMut(Option<LogRecord>),
+    ) -> Result<(u32, u32)> {
+        let address = self.sector_address(sector);
+        let mut offset = SECTOR_HEADER_LEN;
+        let mut corrupt = 0;
+        let mut buf = [0; MAX_RECORD_LEN];
+
+        while offset + (MIN_RECORD_LEN as u32) <= self.sector_len {
+            let read_len = (MAX_RECORD_LEN as u32).min(self.sector_len - offset) as usize;
+
+            self.flash
+                .read(address + offset, &mut buf[..read_len])
+                .map_err(|_| BlackBoxError::StorageError)?;
+
+            let len = buf[0];
+
+            if len == ERASED {
+                break;
+            }
+
+            if !(MIN_RECORD_LEN..=read_len).contains(&(len as usize)) {
+                // not even the length was written properly, so there is no
+                // telling where the next record starts - give up on the rest of
+                // the sector
+                corrupt += 1;
+                offset = self.sector_len;
+                break;
+            }
+
+            let record = decode(&buf[..read_len]);
+
+            if record.is_none() {
+                corrupt += 1;
+            }
+
+            f(record);
+
+            offset += padded(len as usize);
+        }
+
+        Ok((offset, corrupt))
+    }
+
+    /// Appends `record` to the ring, erasing the oldest sector if the head one
+    /// is full.
+    pub fn write(&mut self, record: &LogRecord) -> Result<()> {
+        let mut buf = [ERASED; MAX_RECORD_LEN];
+        let len = padded(encode(record, &mut buf));
+
+        if self.head_offset + len > self.sector_len {
+            let next = (self.head + 1) % self.sectors;
+            self.start_sector(next, self.sequence.wrapping_add(1))?;
+        }
+
+        self.flash
+            .write(
+                self.sector_address(self.head) + self.head_offset,
+                &buf[..len as usize],
+            )
+            .map_err(|_| BlackBoxError::StorageError)?;
+
+        self.head_offset += len;
+
+        Ok(())
+    }
+
+    /// Hands every intact record to `f`, oldest first, returning how many were
+    /// corrupt.
+    pub fn for_each_record(&mut self, mut f: impl FnMut(LogRecord)) -> Result<u32> {
+        let mut corrupt = 0;
+
+        // the sector after the head is the oldest
+        for i in 1..=self.sectors {
+            let sector = (self.head + i) % self.sectors;
+
+            if self.sector_sequence(sector)?.is_none() {
+                continue;
+            }
+
+            corrupt += self
+                .scan_sector(sector, |record| {
+                    if let Some(record) = record {
+                        f(record);
+                    }
+                })?
+                .1;
+        }
+
+        Ok(corrupt)
+    }
+
+    /// Erases everything that has been recorded.
+    pub fn clear(&mut self) -> Result<()> {
+        for sector in 0..self.sectors {
+            if self.sector_sequence(sector)?.is_some() {
+                let address = self.sector_address(sector);
+
+                self.flash
+                    .erase(address, address + self.sector_len)
+                    .map_err(|_| BlackBoxError::StorageError)?;
+            }
+        }
+
+        self.start_sector(0, 0)
+    }
+}
+
+/// Prints everything in the black box over UART, as CSV in the same format as
+/// the SD card logs.
+pub fn dump<F: NorFlash>(blackbox: &mut BlackBox<F>) -> Result<()> {
+    let mut line: String<MAX_LINE_LEN> = String::new();
+    let mut count = 0u32;
+
+    print!("{CSV_HEADER}");
+
+    let corrupt = blackbox.for_each_record(|record| {
+        line.clear();
+
+        if record.write_csv(&mut line).is_ok() {
+            print!("{line}");
+            count += 1;
+        }
+    })?;
+
+    println!("# {count} records, {corrupt} corrupt");
+
+    Ok(())
+}
+
+/// Records every reading into the black box.
+///
+/// Writing a record takes only microseconds, but erasing the next sector when
+/// one fills up blocks for tens of milliseconds - the buses hold enough
+/// readings to cover it.
+///
+/// Nothing is recorded until launch is detected (see [super::pretrigger]), so
+/// the flight isn't overwritten while sitting on the pad.
+#[cfg(target_arch = "xtensa")]
+#[task]
+pub async fn blackbox_recorder(mut blackbox: BlackBox<FlashStorage>) {
+    let mut collector = RecordCollector::new().expect("no bus subscribers left for the black box");
+    let mut pretrigger: PreTrigger<PRE_TRIGGER_CAPACITY> = PreTrigger::resume(PRE_TRIGGER_DURATION);
+    let mut ticker = Ticker::every(RECORD_INTERVAL);
+
+    // erasing a sector blocks for a while
+    let heartbeat = watchdog::register(TaskId::BlackBox, Duration::from_secs(2));
+
+    loop {
+        ticker.next().await;
+        heartbeat.beat();
+
+        let mut failed = false;
+
+        collector.drain(|record| {
+            pretrigger.push(record, |record| {
+                blackbox
+                    .write(&record)
+                    .map_err(|e| {
+                        journal::record(e);
+                        failed = true;
+                        e
+                    })
+                    .print_warn_limited()
+            })
+        });
+
+        // nothing is written before launch, but the recorder is still alive
+        if failed {
+            health::report_error(Component::BlackBox);
+        } else {
+            health::report_success(Component::BlackBox);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use embedded_storage::nor_flash::{
+        check_erase, check_read, check_write, ErrorType, NorFlashErrorKind,
+    };
+
+    use super::*;
+    use crate::test_utils::assert_close;
+
+    type Vec<T> = heapless::Vec<T, 128>;
+
+    const SECTOR_LEN: usize = 256;
+    const FLASH_SECTORS: usize = 5;
+
+    /// The black box is given every sector but the first, to check it stays
+    /// inside its partition.
+    const OFFSET: u32 = SECTOR_LEN as u32;
+    const LEN: u32 = ((FLASH_SECTORS - 1) * SECTOR_LEN) as u32;
+
+    /// Fault records are the same length whatever is in them.
+    const FAULT_LEN: u32 = 16;
+    const FAULTS_PER_SECTOR: u32 = (SECTOR_LEN as u32 - SECTOR_HEADER_LEN) / FAULT_LEN;
+
+    /// NOR flash held in RAM - writes can only clear bits, and erases set whole
+    /// sectors back to `0xFF`.
+    struct RamFlash {
+        data: [u8; SECTOR_LEN * FLASH_SECTORS],
+        erases: [u32; FLASH_SECTORS],
+    }
+
+    impl RamFlash {
+        fn new() -> Self {
+            let mut data = [ERASED; SECTOR_LEN * FLASH_SECTORS];
+            // something else's data, in front of the partition
+            data[..SECTOR_LEN].fill(0x5A);
+
+            Self {
+                data,
+                erases: [0; FLASH_SECTORS],
+            }
+        }
+    }
+
+    impl ErrorType for RamFlash {
+        type Error = NorFlashErrorKind;
+    }
+
+    impl ReadNorFlash for RamFlash {
+        const READ_SIZE: usize = 1;
+
+        fn read(&mut self, offset: u32, bytes: &mut [u8]) -> core::result::Result<(), Self::Error> {
+            check_read(self, offset, bytes.len())?;
+
+            let offset = offset as usize;
+            bytes.copy_from_slice(&self.data[offset..offset + bytes.len()]);
+
+            Ok(())
+        }
+
+        fn capacity(&self) -> usize {
+            self.data.len()
+        }
+    }
+
+    impl NorFlash for RamFlash {
+        const WRITE_SIZE: usize = RECORD_ALIGN as usize;
+        const ERASE_SIZE: usize = SECTOR_LEN;
+
+        fn erase(&mut self, from: u32, to: u32) -> core::result::Result<(), Self::Error> {
+            check_erase(self, from, to)?;
+
+            self.data[from as usize..to as usize].fill(ERASED);
+
+            for sector in from as usize / SECTOR_LEN..to as usize / SECTOR_LEN {
+                self.erases[sector] += 1;
+            }
+
+            Ok(())
+        }
+
+        fn write(&mut self, offset: u32, bytes: &[u8]) -> core::result::Result<(), Self::Error> {
+            check_write(self, offset, bytes.len())?;
+
+            let offset = offset as usize;
+            for (byte, new) in self.data[offset..offset + bytes.len()]
+                .iter_mut()
+                .zip(bytes)
+            {
+                assert_eq!(*byte, ERASED, "written twice at {offset}");
+                *byte &= new;
+            }
+
+            Ok(())
+        }
+    }
+
+    /// A record that can be told apart from the others by its time.
+    fn fault(index: u32) -> LogRecord {
+        let time = Instant::from_millis(index as u64);
+
+        LogRecord::Fault(JournalEntry {
+            code: FaultCode(index as u16),
+            first: time,
+            last: time,
+            count: 1,
+        })
+    }
+
+    fn index(record: &LogRecord) -> u32 {
+        record.time().as_millis() as u32
+    }
+
+    /// Every intact record, oldest first, along with how many were corrupt.
+    fn contents(blackbox: &mut BlackBox<RamFlash>) -> (Vec<u32>, u32) {
+        let mut indices = Vec::new();
+        let corrupt = blackbox
+            .for_each_record(|record| indices.push(index(&record)).unwrap())
+            .unwrap();
+
+        (indices, corrupt)
+    }
+
+    fn record_faults(blackbox: &mut BlackBox<RamFlash>, indices: core::ops::Range<u32>) {
+        for i in indices {
+            blackbox.write(&fault(i)).unwrap();
+        }
+    }
+
+    fn range(indices: core::ops::Range<u32>) -> Vec<u32> {
+        indices.collect()
+    }
+
+    #[test]
+    fn fault_records_are_the_expected_length() {
+        let mut buf = [ERASED; MAX_RECORD_LEN];
+        assert_eq!(padded(encode(&fault(0), &mut buf)), FAULT_LEN);
+    }
+
+    #[test]
+    fn records_round_trip() {
+        let mut buf = [ERASED; MAX_RECORD_LEN];
+        let len = encode(&fault(1234), &mut buf);
+
+        let Some(LogRecord::Fault(entry)) = decode(&buf[..len]) else {
+            panic!("not decoded as a fault");
+        };
+
+        assert_eq!(entry.code, FaultCode(1234));
+        assert_eq!(entry.first.as_millis(), 1234);
+        assert_eq!(entry.count, 1);
+    }
+
+    /// Encodes and decodes `record`.
+    fn round_trip(record: LogRecord) -> LogRecord {
+        let mut buf = [ERASED; MAX_RECORD_LEN];
+        let len = encode(&record, &mut buf);
+
+        decode(&buf[..len]).expect("not decoded")
+    }
+
+    fn sample<T>(source: SourceId, data: T) -> Sample<T> {
+        Sample {
+            time: Instant::from_millis(1234),
+            seq: 56,
+            source,
+            data,
+        }
+    }
+
+    #[test]
+    fn mpu_records_round_trip() {
+        let original = sample(
+            SourceId::Mpu6050,
+            MpuData {
+                acc: Reading::Valid(Vector3::new(0.1, -0.2, 1.3)),
+                gyro: Reading::Invalid(3),
+                temp: Reading::Valid(21.5),
+                roll_pitch: Reading::Valid(Vector2::new(0.25, -0.5)),
+            },
+        );
+
+        let LogRecord::Mpu(decoded) = round_trip(LogRecord::Mpu(original)) else {
+            panic!("not decoded as an MPU record");
+        };
+
+        assert_eq!(decoded.map(|_| ()), original.map(|_| ()));
+        assert_eq!(decoded.data.acc, original.data.acc);
+        assert_eq!(decoded.data.gyro, original.data.gyro);
+        assert_eq!(decoded.data.temp, original.data.temp);
+        assert_eq!(decoded.data.roll_pitch, original.data.roll_pitch);
+    }
+
+    #[test]
+    fn baro_records_round_trip() {
+        for data in [
+            Reading::Valid(BmeData {
+                temperature: 18.25,
+                pressure: 101_325.5,
+                humidity: 40.5,
+            }),
+            Reading::Invalid(2),
+        ] {
+            let original = sample(SourceId::Bme280, data);
+
+            let LogRecord::Baro(decoded) = round_trip(LogRecord::Baro(original)) else {
+                panic!("not decoded as a barometer record");
+            };
+
+            let values = |sample: Sample<Reading<BmeData>>| {
+                sample.map(|data| data.map(|data| [data.temperature, data.pressure, data.humidity]))
+            };
+            assert_eq!(values(decoded), values(original));
+        }
+    }
+
+    #[test]
+    fn altitude_records_round_trip() {
+        let original = sample(
+            SourceId::Bme280,
+            AltitudeData {
+                absolute: 152.5,
+                agl: 52.25,
+                reference: GroundReference {
+                    pressure: 100_120.0,
+                    temperature: 15.5,
+                },
+            },
+        );
+
+        let LogRecord::Altitude(decoded) = round_trip(LogRecord::Altitude(original)) else {
+            panic!("not decoded as an altitude record");
+        };
+
+        assert_eq!(decoded, original);
+    }
+
+    #[test]
+    fn mag_records_round_trip() {
+        let original = sample(
+            SourceId::Qmc5883l,
+            MagData {
+                mag: Reading::Valid(Vector3::new(0.2, -0.05, 0.4)),
+                temp: Reading::Invalid(1),
+            },
+        );
+
+        let LogRecord::Mag(decoded) = round_trip(LogRecord::Mag(original)) else {
+            panic!("not decoded as a magnetometer record");
+        };
+
+        assert_eq!(decoded.map(|_| ()), original.map(|_| ()));
+        assert_eq!(decoded.data.mag, original.data.mag);
+        assert_eq!(decoded.data.temp, original.data.temp);
+    }
+
+    #[test]
+    fn attitude_records_round_trip() {
+        // every component different, so they can't be mixed up
+        let quaternion = UnitQuaternion::from_euler_angles(0.1, -0.2, 0.3);
+        let original = sample(SourceId::Mpu6050, AttitudeData::from(quaternion));
+
+        let LogRecord::Attitude(decoded) = round_trip(LogRecord::Attitude(original)) else {
+            panic!("not decoded as an attitude record");
+        };
+
+        assert_eq!(decoded.map(|_| ()), original.map(|_| ()));
+
+        let (q, expected) = (decoded.data.quaternion, quaternion);
+        assert_close(q.w, expected.w, 1e-6);
+        assert_close(q.i, expected.i, 1e-6);
+        assert_close(q.j, expected.j, 1e-6);
+        assert_close(q.k, expected.k, 1e-6);
+
+        for (axis, &angle) in decoded.data.euler.iter().enumerate() {
+            assert_close(angle, original.data.euler[axis], 1e-4);
+        }
+    }
+
+    #[test]
+    fn gps_records_round_trip() {
+        let fix = GpsFix {
+            fix_type: FixType::Fix3D,
+            time: Some(UtcTime {
+                hours: 13,
+                minutes: 45,
+                seconds: 30,
+                millis: 250,
+            }),
+            date: Some(Date {
+                year: 2024,
+                month: 6,
+                day: 21,
+            }),
+            latitude: 51.477_928_123,
+            longitude: -0.001_545_456,
+            altitude: 312.5,
+            speed: 4.25,
+            course: 271.5,
+            satellites: 9,
+            hdop: 0.9,
+        };
+
+        for data in [
+            fix,
+            // before the receiver knows the time
+            GpsFix {
+                fix_type: FixType::NoFix,
+                time: None,
+                date: None,
+                ..fix
+            },
+        ] {
+            let original = sample(SourceId::Gps, data);
+
+            let LogRecord::Gps(decoded) = round_trip(LogRecord::Gps(original)) else {
+                panic!("not decoded as a GPS record");
+            };
+
+            assert_eq!(decoded, original);
+        }
+    }
+
+    #[test]
+    fn phase_records_round_trip() {
+        let original = PhaseTransition {
+            from: FlightPhase::Apogee,
+            to: FlightPhase::Descent,
+            time: Instant::from_millis(98_765),
+        };
+
+        let LogRecord::Phase(decoded) = round_trip(LogRecord::Phase(original)) else {
+            panic!("not decoded as a phase record");
+        };
+
+        assert_eq!(decoded, original);
+    }
+
+    #[test]
+    fn decode_rejects_every_single_bit_flip() {
+        let mut buf = [ERASED; MAX_RECORD_LEN];
+        let len = encode(&fault(42), &mut buf);
+
+        for byte in 0..len {
+            for bit in 0..8 {
+                let mut corrupted = buf;
+                corrupted[byte] ^= 1 << bit;
+
+                assert!(
+                    decode(&corrupted).is_none(),
+                    "bit {bit} of byte {byte} flipped"
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn decode_rejects_a_truncated_record() {
+        let mut buf = [ERASED; MAX_RECORD_LEN];
+        let len = encode(&fault(42), &mut buf);
+
+        assert!(decode(&buf[..len - 1]).is_none());
+        assert!(decode(&[]).is_none());
+    }
+
+    #[test]
+    fn rejects_partitions_not_on_sector_boundaries() {
+        for (offset, len) in [
+            (1, LEN - SECTOR_LEN as u32),
+            (OFFSET, LEN - 1),
+            (OFFSET, SECTOR_LEN as u32),
+        ] {
+            assert!(matches!(
+                BlackBox::recover(RamFlash::new(), offset, len),
+                Err(BlackBoxError::InvalidPartition)
+            ));
+        }
+    }
+
+    #[test]
+    fn starts_empty_on_blank_flash() {
+        let mut blackbox = BlackBox::recover(RamFlash::new(), OFFSET, LEN).unwrap();
+
+        assert_eq!(blackbox.capacity(), LEN);
+        assert_eq!(contents(&mut blackbox), (range(0..0), 0));
+
+        record_faults(&mut blackbox, 0..5);
+        assert_eq!(contents(&mut blackbox), (range(0..5), 0));
+    }
+
+    #[test]
+    fn skips_corrupt_records() {
+        let mut blackbox = BlackBox::recover(RamFlash::new(), OFFSET, LEN).unwrap();
+        record_faults(&mut blackbox, 0..5);
+
+        let mut flash = blackbox.into_inner();
+        // a bit of the third record's payload
+        flash.data[(OFFSET + SECTOR_HEADER_LEN + 2 * FAULT_LEN) as usize + 10] ^= 0x04;
+
+        let mut blackbox = BlackBox::recover(flash, OFFSET, LEN).unwrap();
+        assert_eq!(
+            contents(&mut blackbox),
+            (Vec::from_slice(&[0, 1, 3, 4]).unwrap(), 1)
+        );
+    }
+
+    #[test]
+    fn wraps_around_erasing_the_oldest_sector() {
+        let mut blackbox = BlackBox::recover(RamFlash::new(), OFFSET, LEN).unwrap();
+
+        // into the 7th sector of the ring, which is its 3rd time round
+        let count = 6 * FAULTS_PER_SECTOR + 10;
+        record_faults(&mut blackbox, 0..count);
+
+        // the head sector and the 3 full ones before it are all that is left
+        let oldest = 3 * FAULTS_PER_SECTOR;
+        assert_eq!(contents(&mut blackbox), (range(oldest..count), 0));
+
+        let flash = blackbox.into_inner();
+
+        // every sector is erased in turn
+        assert_eq!(flash.erases, [0, 2, 2, 2, 1]);
+        assert!(flash.data[..SECTOR_LEN].iter().all(|&byte| byte == 0x5A));
+    }
+
+    #[test]
+    fn carries_on_after_a_reset() {
+        let mut blackbox = BlackBox::recover(RamFlash::new(), OFFSET, LEN).unwrap();
+        record_faults(&mut blackbox, 0..20);
+
+        let mut blackbox = BlackBox::recover(blackbox.into_inner(), OFFSET, LEN).unwrap();
+        assert_eq!(contents(&mut blackbox), (range(0..20), 0));
+
+        record_faults(&mut blackbox, 20..25);
+        assert_eq!(contents(&mut blackbox), (range(0..25), 0));
+    }
+
+    #[test]
+    fn carries_on_after_a_reset_once_wrapped() {
+        let mut blackbox = BlackBox::recover(RamFlash::new(), OFFSET, LEN).unwrap();
+        let count = 5 * FAULTS_PER_SECTOR + 3;
+        record_faults(&mut blackbox, 0..count);
+
+        // the head is in the second sector, with the oldest records after it
+        let mut blackbox = BlackBox::recover(blackbox.into_inner(), OFFSET, LEN).unwrap();
+        record_faults(&mut blackbox, count..count + 20);
+
+        let oldest = 3 * FAULTS_PER_SECTOR;
+        assert_eq!(contents(&mut blackbox), (range(oldest..count + 20), 0));
+    }
+
+    #[test]
+    fn carries_on_after_a_reset_mid_record() {
+        let mut blackbox = BlackBox::recover(RamFlash::new(), OFFSET, LEN).unwrap();
+        record_faults(&mut blackbox, 0..5);
+
+        // the power is cut part way through writing the next record
+        let mut buf = [ERASED; MAX_RECORD_LEN];
+        encode(&fault(5), &mut buf);
+
+        let mut flash = blackbox.into_inner();
+        let head = (OFFSET + SECTOR_HEADER_LEN + 5 * FAULT_LEN) as usize;
+        flash.data[head..head + 8].copy_from_slice(&buf[..8]);
+
+        let mut blackbox = BlackBox::recover(flash, OFFSET, LEN).unwrap();
+        assert_eq!(contents(&mut blackbox), (range(0..5), 1));
+
+        // new records go after the broken one, rather than on top of it
+        record_faults(&mut blackbox, 6..10);
+
+        let mut expected = range(0..5);
+        expected.extend(6..10);
+        assert_eq!(contents(&mut blackbox), (expected, 1));
+    }
+
+    #[test]
+    fn clear_erases_everything() {
+        let mut blackbox = BlackBox::recover(RamFlash::new(), OFFSET, LEN).unwrap();
+        record_faults(&mut blackbox, 0..40);
+
+        blackbox.clear().unwrap();
+        assert_eq!(contents(&mut blackbox), (range(0..0), 0));
+
+        let mut blackbox = BlackBox::recover(blackbox.into_inner(), OFFSET, LEN).unwrap();
+        assert_eq!(contents(&mut blackbox), (range(0..0), 0));
+    }
+}
@@ -0,0 +1,331 @@
+//! Holding back readings until launch, so hours on the pad don't fill up the
+//! logs, without losing the start of the flight.
+//!
+//! While the can is waiting to be launched, motion and barometer readings (and
+//! errors) are kept in a RAM ring of the last [PRE_TRIGGER_DURATION], and everything else
+//! is thrown away. As soon as the flight state machine leaves
+//! [FlightPhase::PreLaunch], the ring is flushed to the log and every record
+//! is passed straight through from then on.
+//!
+//! Launch is remembered in RTC memory (which survives a reset) until landing,
+//! so a can that resets in flight goes straight back to logging everything
+//! rather than waiting for a launch that has already happened. The flight
+//! state machine starts again in [FlightPhase::PreLaunch] after a reset, so it
+//! may never see that landing - if it hasn't seen a launch either by
+//! [RESUMED_FLIGHT_TIMEOUT] after boot, the flight must be over and the flag is
+//! cleared, so the next reset waits for launch again.
+
+use core::ptr::{addr_of, addr_of_mut};
+
+#[cfg(target_arch = "xtensa")]
+use hal::macros::ram;
+use heapless::HistoryBuffer;
+
+use super::LogRecord;
+use crate::{flight::FlightPhase, prelude::*};
+
+/// Written to [IN_FLIGHT] between launch and landing, so it can't be mistaken
+/// for whatever was there when the can was powered on.
+const IN_FLIGHT_MAGIC: u32 = 0x464C_5954;
+
+#[cfg_attr(target_arch = "xtensa", ram(rtc_fast, uninitialized))]
+static mut IN_FLIGHT: u32 = 0;
+
+/// Whether the can was last launched and hasn't landed since, even if it has
+/// reset in between.
+pub fn in_flight() -> bool {
+    // SAFETY: a single word, only ever written whole
+    unsafe { addr_of!(IN_FLIGHT).read_volatile() == IN_FLIGHT_MAGIC }
+}
+
+/// Remembers whether the can is between launch and landing - called by the
+/// flight state machine's task on every transition.
+pub fn set_in_flight(in_flight: bool) {
+    let value = if in_flight { IN_FLIGHT_MAGIC } else { 0 };
+
+    // SAFETY: as in `in_flight`
+    unsafe {
+        addr_of_mut!(IN_FLIGHT).write_volatile(value);
+    }
+}
+
+/// How long after a reset in flight the flight is assumed to be over if no
+/// launch has been seen again - much longer than any flight lasts.
+pub const RESUMED_FLIGHT_TIMEOUT: Duration = Duration::from_secs(15 * 60);
+
+/// How long before launch is detected readings are kept for.
+pub const PRE_TRIGGER_DURATION: Duration = Duration::from_secs(5);
+
+/// How many records the ring can hold - enough for [PRE_TRIGGER_DURATION] of
+/// the MPU6050 at 10Hz and the BME280 at 1Hz, with room to spare.
+pub const PRE_TRIGGER_CAPACITY: usize = 128;
+
+/// Sits between the record collector and a log, holding records back until
+/// launch.
+///
+/// It lives in the logging task's future, which embassy allocates statically,
+/// so the ring never touches the stack.
+pub struct PreTrigger<const N: usize> {
+    ring: HistoryBuffer<LogRecord, N>,
+    window: Duration,
+    triggered: bool,
+}
+
+impl<const N: usize> PreTrigger<N> {
+    /// Keeps records from up to `window` before launch.
+    pub const fn new(window: Duration) -> Self {
+        Self {
+            ring: HistoryBuffer::new(),
+            window,
+            triggered: false,
+        }
+    }
+
+    /// Passes every record straight through, for when logging should start
+    /// immediately (such as when testing on the ground).
+    pub const fn triggered() -> Self {
+        Self {
+            ring: HistoryBuffer::new(),
+            window: Duration::from_ticks(0),
+            triggered: true,
+        }
+    }
+
+    /// Keeps records from up to `window` before launch, unless the can reset
+    /// in flight - then everything is passed straight through, as the launch
+    /// won't be seen again.
+    pub fn resume(window: Duration) -> Self {
+        if !in_flight() {
+            return Self::new(window);
+        }
+
+        warn!("Reset in flight, logging without waiting for launch");
+        Self::triggered()
+    }
+
+    /// Whether launch has been detected, and records are being passed through.
+    pub fn is_triggered(&self) -> bool {
+        self.triggered
+    }
+
+    /// How many records are being held back.
+    pub fn len(&self) -> usize {
+        self.ring.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.ring.len() == 0
+    }
+
+    /// Feeds a record in, handing anything that should now be logged to
+    /// `log`, oldest first.
+    pub fn push(&mut self, record: LogRecord, mut log: impl FnMut(LogRecord)) {
+        if self.triggered {
+            log(record);
+            return;
+        }
+
+        match record {
+            LogRecord::Mpu(_)
+            | LogRecord::Baro(_)
+            | LogRecord::Altitude(_)
+            | LogRecord::Fault(_) => {
+                self.ring.write(record);
+            }
+            LogRecord::Phase(transition) if transition.to > FlightPhase::PreLaunch => {
+                self.trigger(transition.time, &mut log);
+                log(record);
+            }
+            _ => {}
+        }
+    }
+
+    /// Flushes the records from the `window` before `time` to `log`, and
+    /// passes everything through from now on.
+    pub fn trigger(&mut self, time: Instant, mut log: impl FnMut(LogRecord)) {
+        if self.triggered {
+            return;
+        }
+
+        let start = time
+            .checked_sub(self.window)
+            .unwrap_or(Instant::from_ticks(0));
+
+        debug!(
+            "Launch detected, flushing {} pre-launch records",
+            self.ring.len()
+        );
+
+        for record in self.ring.oldest_ordered() {
+            if record.time() >= start {
+                log(*record);
+            }
+        }
+
+        self.ring.clear();
+        self.triggered = true;
+    }
+}
+
+impl<const N: usize> Default for PreTrigger<N> {
+    fn default() -> Self {
+        Self::new(PRE_TRIGGER_DURATION)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use nalgebra::Vector3;
+
+    use super::*;
+    use crate::{
+        errors::journal::JournalEntry,
+        flight::PhaseTransition,
+        qmc5883l::MagData,
+        sample::{Reading, Sample, SourceId},
+    };
+
+    type Vec<T> = heapless::Vec<T, 64>;
+
+    fn at(ms: u64) -> Instant {
+        Instant::from_millis(ms)
+    }
+
+    /// A record that is held back before launch.
+    fn fault(ms: u64) -> LogRecord {
+        LogRecord::Fault(JournalEntry {
+            code: FaultCode(1),
+            first: at(ms),
+            last: at(ms),
+            count: 1,
+        })
+    }
+
+    /// A record that is thrown away before launch.
+    fn mag(ms: u64) -> LogRecord {
+        LogRecord::Mag(Sample {
+            time: at(ms),
+            seq: 0,
+            source: SourceId::Qmc5883l,
+            data: MagData {
+                mag: Reading::Valid(Vector3::zeros()),
+                temp: Reading::Valid(20.0),
+            },
+        })
+    }
+
+    fn phase(ms: u64, from: FlightPhase, to: FlightPhase) -> LogRecord {
+        LogRecord::Phase(PhaseTransition {
+            from,
+            to,
+            time: at(ms),
+        })
+    }
+
+    /// Pushes `record`, returning the times of the records logged because of
+    /// it.
+    fn push<const N: usize>(pretrigger: &mut PreTrigger<N>, record: LogRecord) -> Vec<u64> {
+        let mut logged = Vec::new();
+        pretrigger.push(record, |record| {
+            logged.push(record.time().as_millis()).unwrap();
+        });
+
+        logged
+    }
+
+    fn times(times: &[u64]) -> Vec<u64> {
+        Vec::from_slice(times).unwrap()
+    }
+
+    #[test]
+    fn holds_back_motion_and_faults_until_launch() {
+        let mut pretrigger: PreTrigger<8> = PreTrigger::new(Duration::from_secs(5));
+
+        assert!(push(&mut pretrigger, fault(100)).is_empty());
+        assert!(push(&mut pretrigger, mag(200)).is_empty());
+        assert!(push(&mut pretrigger, fault(300)).is_empty());
+
+        assert!(!pretrigger.is_triggered());
+        // the magnetometer reading was thrown away
+        assert_eq!(pretrigger.len(), 2);
+    }
+
+    #[test]
+    fn launch_flushes_the_ring_oldest_first_then_the_transition() {
+        let mut pretrigger: PreTrigger<8> = PreTrigger::new(Duration::from_secs(5));
+
+        for ms in [100, 200, 300] {
+            push(&mut pretrigger, fault(ms));
+        }
+
+        let logged = push(
+            &mut pretrigger,
+            phase(400, FlightPhase::PreLaunch, FlightPhase::Ascent),
+        );
+
+        assert_eq!(logged, times(&[100, 200, 300, 400]));
+        assert!(pretrigger.is_triggered());
+        assert!(pretrigger.is_empty());
+    }
+
+    #[test]
+    fn only_the_window_before_launch_is_flushed() {
+        let mut pretrigger: PreTrigger<8> = PreTrigger::new(Duration::from_secs(1));
+
+        for ms in [1_000, 1_900, 2_000, 2_500] {
+            push(&mut pretrigger, fault(ms));
+        }
+
+        let mut logged = Vec::new();
+        pretrigger.trigger(at(3_000), |record| {
+            logged.push(record.time().as_millis()).unwrap();
+        });
+
+        assert_eq!(logged, times(&[2_000, 2_500]));
+    }
+
+    #[test]
+    fn a_full_ring_keeps_the_newest_records() {
+        let mut pretrigger: PreTrigger<4> = PreTrigger::new(Duration::from_secs(5));
+
+        for ms in (100..=1_000).step_by(100) {
+            push(&mut pretrigger, fault(ms));
+        }
+        assert_eq!(pretrigger.len(), 4);
+
+        let logged = push(
+            &mut pretrigger,
+            phase(1_100, FlightPhase::PreLaunch, FlightPhase::Ascent),
+        );
+
+        assert_eq!(logged, times(&[700, 800, 900, 1_000, 1_100]));
+    }
+
+    #[test]
+    fn passes_everything_through_once_triggered() {
+        let mut pretrigger: PreTrigger<8> = PreTrigger::new(Duration::from_secs(5));
+        push(
+            &mut pretrigger,
+            phase(100, FlightPhase::PreLaunch, FlightPhase::Ascent),
+        );
+
+        assert_eq!(push(&mut pretrigger, mag(200)), times(&[200]));
+        assert_eq!(push(&mut pretrigger, fault(300)), times(&[300]));
+        assert!(pretrigger.is_empty());
+
+        // a second trigger has nothing left to flush
+        let mut logged = Vec::new();
+        pretrigger.trigger(at(400), |record| {
+            logged.push(record.time().as_millis()).unwrap();
+        });
+        assert!(logged.is_empty());
+    }
+
+    #[test]
+    fn triggered_passes_everything_through_from_the_start() {
+        let mut pretrigger: PreTrigger<8> = PreTrigger::triggered();
+
+        assert!(pretrigger.is_triggered());
+        assert_eq!(push(&mut pretrigger, mag(100)), times(&[100]));
+    }
+}
@@ -0,0 +1,976 @@
+//! Logging to a FAT32 formatted SD card, over SPI.
+//!
+//! Records are written to `LOGnnnnn.CSV` files in the root of the card, each
+//! starting with [CSV_HEADER]. A new file is started every boot, and whenever
+//! the current one reaches [MAX_FILE_LEN] (or [SdLogger::with_max_file_len]).
+//!
+//! [SdLogger] is generic over the [BlockDevice] it writes to, so the file
+//! handling can be run against an in-memory disk image as well as a real card.
+
+use core::fmt::{Debug, Write};
+
+#[cfg(target_arch = "xtensa")]
+use embedded_sdmmc::SdCard;
+use embedded_sdmmc::{
+    BlockDevice, Mode, RawDirectory, RawFile, RawVolume, TimeSource, Timestamp, VolumeIdx,
+    VolumeManager,
+};
+#[cfg(target_arch = "xtensa")]
+use hal::{clock::Clocks, peripherals::SPI3, spi::master::Spi, spi::FullDuplexMode};
+use heapless::String;
+
+#[cfg(target_arch = "xtensa")]
+use super::{
+    pretrigger::{PreTrigger, PRE_TRIGGER_CAPACITY, PRE_TRIGGER_DURATION},
+    RecordCollector,
+};
+use super::{LogBuffer, CSV_HEADER, MAX_LINE_LEN};
+use crate::prelude::*;
+#[cfg(target_arch = "xtensa")]
+use crate::{
+    errors::journal,
+    health::{self, Component},
+    watchdog::{self, TaskId},
+};
+
+/// The SPI bus of the SD card, with its chip select pin.
+#[cfg(target_arch = "xtensa")]
+pub type SdSpiDevice = embedded_hal_bus::spi::ExclusiveDevice<
+    Spi<'static, SPI3, FullDuplexMode>,
+    AnyPin<Output<PushPull>>,
+    Delay,
+>;
+#[cfg(target_arch = "xtensa")]
+pub type SdCardDevice = SdCard<SdSpiDevice, Delay>;
+
+/// Files are rotated once they grow past this, in bytes.
+pub const MAX_FILE_LEN: u32 = 4 * 1024 * 1024;
+
+/// How many bytes of records can be waiting while the card is busy or missing.
+pub const LOG_BUFFER_LEN: usize = 16 * 1024;
+/// The most written to the card in one go - a full [LOG_BUFFER_LEN] is
+/// written out over several flushes, rather than holding up every other task
+/// until it all is.
+pub const MAX_WRITE_LEN: usize = 4 * 1024;
+
+/// How often new records are collected from the buses.
+pub const COLLECT_INTERVAL: Duration = Duration::from_millis(50);
+/// Records are written once this many bytes are waiting - one SD card block.
+pub const FLUSH_THRESHOLD: usize = 512;
+/// ...or once they have been waiting this long.
+pub const FLUSH_INTERVAL: Duration = Duration::from_secs(1);
+/// How long to wait before trying to mount the card again after an error.
+pub const REMOUNT_INTERVAL: Duration = Duration::from_secs(2);
+
+/// Cards have to be clocked at no more than this while they are initialised.
+pub const INIT_FREQUENCY_KHZ: u32 = 400;
+/// ...and can be run at up to 25MHz once they have been.
+pub const FREQUENCY_MHZ: u32 = 20;
+
+#[derive(Clone, Copy, ErrorCategory)]
+#[repr(u8)]
+pub enum SdError {
+    /// The card didn't respond, or has no FAT partition
+    MountFailed,
+    /// Every log file name is already taken
+    NoFreeFileName,
+    /// A log file couldn't be opened or closed
+    FileError,
+    WriteFailed,
+}
+
+/// Logs the underlying error, and turns it into an [SdError].
+fn sd_err<E: Debug>(error: SdError) -> impl FnOnce(embedded_sdmmc::Error<E>) -> SdError {
+    move |e| {
+        warn!("SD card error: {e:?}");
+        error
+    }
+}
+
+/// Timestamps files with the time since boot, from midnight on 2024-01-01 -
+/// there is no real time clock to get the date from.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct UptimeClock;
+
+impl TimeSource for UptimeClock {
+    fn get_timestamp(&self) -> Timestamp {
+        let secs = Instant::now().as_secs();
+
+        Timestamp {
+            year_since_1970: 54,
+            zero_indexed_month: 0,
+            zero_indexed_day: ((secs / 86_400) % 31) as u8,
+            hours: ((secs / 3_600) % 24) as u8,
+            minutes: ((secs / 60) % 60) as u8,
+            seconds: (secs % 60) as u8,
+        }
+    }
+}
+
+/// The volume and log file currently in use.
+struct Mounted {
+    volume: RawVolume,
+    root: RawDirectory,
+    /// Kept open between writes - opening a file walks its whole cluster chain
+    /// to find the end, which takes longer the bigger the file gets.
+    file: Option<RawFile>,
+    file_index: u32,
+    file_len: u32,
+}
+
+/// Writes log files to a FAT formatted [BlockDevice].
+///
+/// The log file is kept open, and its directory entry is updated at the end of
+/// every [SdLogger::write_buffer], so the file on the card is complete after
+/// every flush and pulling the card out loses at most what was still buffered.
+pub struct SdLogger<D: BlockDevice, T: TimeSource> {
+    volume_mgr: VolumeManager<D, T>,
+    mounted: Option<Mounted>,
+    /// Files are rotated once they grow past this, in bytes.
+    max_file_len: u32,
+}
+
+impl<D: BlockDevice, T: TimeSource> SdLogger<D, T> {
+    pub fn new(device: D, time_source: T) -> Self {
+        Self {
+            volume_mgr: VolumeManager::new(device, time_source),
+            mounted: None,
+            max_file_len: MAX_FILE_LEN,
+        }
+    }
+
+    /// Rotates files once they grow past `max_file_len` bytes, rather than
+    /// [MAX_FILE_LEN].
+    pub fn with_max_file_len(mut self, max_file_len: u32) -> Self {
+        self.max_file_len = max_file_len;
+        self
+    }
+
+    pub fn device(&mut self) -> &mut D {
+        self.volume_mgr.device()
+    }
+
+    pub fn is_mounted(&self) -> bool {
+        self.mounted.is_some()
+    }
+
+    /// The name of the log file being written to, if the card is mounted.
+    pub fn file_name(&self) -> Option<String<12>> {
+        self.mounted
+            .as_ref()
+            .map(|mounted| log_file_name(mounted.file_index))
+    }
+
+    /// Opens the first partition of the card, and starts a new log file after
+    /// any already on it.
+    pub fn mount(&mut self) -> Result<(), SdError> {
+        self.unmount();
+
+        let volume = self
+            .volume_mgr
+            .open_raw_volume(VolumeIdx(0))
+            .map_err(sd_err(SdError::MountFailed))?;
+
+        let root = match self.volume_mgr.open_root_dir(volume) {
+            Ok(root) => root,
+            Err(e) => {
+                let _ = self.volume_mgr.close_volume(volume);
+                return Err(sd_err(SdError::MountFailed)(e));
+            }
+        };
+
+        let mut last_index = None;
+
+        let found = self.volume_mgr.iterate_dir(root, |entry| {
+            if let Some(index) = parse_log_file_name(entry.name.base_name(), entry.name.extension())
+            {
+                last_index = last_index.max(Some(index));
+            }
+        });
+
+        let file_index = match found {
+            Ok(()) => last_index.map_or(0, |index| index + 1),
+            Err(e) => {
+                let _ = self.volume_mgr.close_dir(root);
+                let _ = self.volume_mgr.close_volume(volume);
+                return Err(sd_err(SdError::MountFailed)(e));
+            }
+        };
+
+        self.mounted = Some(Mounted {
+            volume,
+            root,
+            file: None,
+            file_index,
+            file_len: 0,
+        });
+
+        self.start_file(file_index)
+    }
+
+    /// Closes everything that is open on the card. Errors are ignored, as this
+    /// is also how the logger cleans up after the card has been removed.
+    pub fn unmount(&mut self) {
+        if let Some(mounted) = self.mounted.take() {
+            if let Some(file) = mounted.file {
+                let _ = self.volume_mgr.close_file(file);
+            }
+            let _ = self.volume_mgr.close_dir(mounted.root);
+            let _ = self.volume_mgr.close_volume(mounted.volume);
+        }
+    }
+
+    /// Closes the current log file, creates log file `index` and writes the
+    /// header to it.
+    fn start_file(&mut self, index: u32) -> Result<(), SdError> {
+        if index > MAX_LOG_FILE_INDEX {
+            self.unmount();
+            return Err(SdError::NoFreeFileName);
+        }
+
+        let mut header: String<MAX_LINE_LEN> = String::new();
+        // only fails if the line is too long, which it can't be
+        let _ = writeln!(
+            header,
+            "# file {index}, started {} ms after boot",
+            Instant::now().as_millis()
+        );
+
+        let mounted = self.mounted.as_mut().ok_or(SdError::MountFailed)?;
+        let root = mounted.root;
+
+        // closing is what updates the old file's size on the card
+        let closed = match mounted.file.take() {
+            Some(file) => self
+                .volume_mgr
+                .close_file(file)
+                .map_err(sd_err(SdError::FileError)),
+            None => Ok(()),
+        };
+
+        let opened = closed.and_then(|()| {
+            self.volume_mgr
+                .open_file_in_dir(
+                    root,
+                    log_file_name(index).as_str(),
+                    Mode::ReadWriteCreateOrAppend,
+                )
+                .map_err(sd_err(SdError::FileError))
+        });
+
+        let file = match opened {
+            Ok(file) => file,
+            Err(e) => {
+                self.unmount();
+                return Err(e);
+            }
+        };
+
+        if let Some(mounted) = self.mounted.as_mut() {
+            mounted.file = Some(file);
+            mounted.file_index = index;
+            mounted.file_len = 0;
+        }
+
+        info!("Logging to {}", log_file_name(index));
+
+        self.append(CSV_HEADER.as_bytes())?;
+        self.append(header.as_bytes())?;
+        self.flush()
+    }
+
+    /// Writes one chunk to the end of the current log file.
+    fn append(&mut self, bytes: &[u8]) -> Result<(), SdError> {
+        let Some(file) = self.mounted.as_ref().and_then(|mounted| mounted.file) else {
+            return Err(SdError::MountFailed);
+        };
+
+        match self.volume_mgr.write(file, bytes) {
+            Ok(_) => {
+                if let Some(mounted) = self.mounted.as_mut() {
+                    mounted.file_len = mounted.file_len.saturating_add(bytes.len() as u32);
+                }
+                Ok(())
+            }
+            Err(e) => {
+                self.unmount();
+                Err(sd_err(SdError::WriteFailed)(e))
+            }
+        }
+    }
+
+    /// Updates the current log file's directory entry, so everything written
+    /// to it so far is there if the card is pulled out.
+    fn flush(&mut self) -> Result<(), SdError> {
+        let Some(file) = self.mounted.as_ref().and_then(|mounted| mounted.file) else {
+            return Err(SdError::MountFailed);
+        };
+
+        let result = self
+            .volume_mgr
+            .flush_file(file)
+            .map_err(sd_err(SdError::FileError));
+
+        if result.is_err() {
+            self.unmount();
+        }
+
+        result
+    }
+
+    /// Writes up to [MAX_WRITE_LEN] bytes from `buffer` to the card, starting a
+    /// new file whenever the current one gets too big, so a full buffer is
+    /// written out over several calls rather than blocking for all of it at
+    /// once.
+    ///
+    /// Bytes are only removed from `buffer` once they have been written, so
+    /// after an error the rest can be written again once the card has been
+    /// remounted.
+    pub fn write_buffer<const N: usize>(
+        &mut self,
+        buffer: &mut LogBuffer<N>,
+    ) -> Result<(), SdError> {
+        let mut budget = MAX_WRITE_LEN;
+
+        while !buffer.is_empty() && budget > 0 {
+            let (file_index, file_len) = match &self.mounted {
+                Some(mounted) => (mounted.file_index, mounted.file_len),
+                None => return Err(SdError::MountFailed),
+            };
+
+            if file_len >= self.max_file_len {
+                self.start_file(file_index + 1)?;
+            }
+
+            let chunk = buffer.peek();
+            let chunk = &chunk[..chunk.len().min(budget)];
+            self.append(chunk)?;
+            let written = chunk.len();
+
+            buffer.consume(written);
+            budget -= written;
+        }
+
+        self.flush()
+    }
+}
+
+/// Log files are numbered with 5 digits, to fit in an 8.3 file name.
+const MAX_LOG_FILE_INDEX: u32 = 99_999;
+
+/// `LOGnnnnn.CSV`
+pub fn log_file_name(index: u32) -> String<12> {
+    let mut name = String::new();
+    // at most 12 characters, as the index is at most 5 digits
+    let _ = write!(name, "LOG{:05}.CSV", index.min(MAX_LOG_FILE_INDEX));
+    name
+}
+
+/// The index of a file named by [log_file_name], or [None] for any other file.
+pub fn parse_log_file_name(base_name: &[u8], extension: &[u8]) -> Option<u32> {
+    let digits = base_name.strip_prefix(b"LOG")?;
+
+    if extension != b"CSV" || digits.len() != 5 || !digits.iter().all(u8::is_ascii_digit) {
+        return None;
+    }
+
+    Some(
+        digits
+            .iter()
+            .fold(0, |index, digit| index * 10 + (digit - b'0') as u32),
+    )
+}
+
+/// Logs every reading to the SD card.
+///
+/// The card is written to in the same thread as the sensors are read, so
+/// records are batched up and written at most once per [FLUSH_INTERVAL] (or
+/// every [FLUSH_THRESHOLD] bytes) to keep the time spent blocked on the card
+/// short. If the card is missing or fails, records are kept in the buffer
+/// (until it fills up) while the card is remounted every [REMOUNT_INTERVAL].
+///
+/// Nothing is written until launch is detected - see [super::pretrigger].
+///
+/// The card is only clocked at [INIT_FREQUENCY_KHZ] while it is (re)mounted,
+/// which is when it is initialised, and at [FREQUENCY_MHZ] the rest of the
+/// time.
+#[cfg(target_arch = "xtensa")]
+#[task]
+pub async fn sd_logger(sd_card: SdCardDevice, clocks: &'static Clocks<'static>) {
+    let set_frequency = |card: &mut SdCardDevice, frequency| {
+        card.spi(|spi| spi.bus_mut().change_bus_frequency(frequency, clocks));
+    };
+
+    let mut logger = SdLogger::new(sd_card, UptimeClock);
+    let mut collector = RecordCollector::new().expect("no bus subscribers left for the logger");
+
+    let mut pretrigger: PreTrigger<PRE_TRIGGER_CAPACITY> = PreTrigger::resume(PRE_TRIGGER_DURATION);
+    let mut buffer: LogBuffer<LOG_BUFFER_LEN> = LogBuffer::new();
+    let mut line: String<MAX_LINE_LEN> = String::new();
+
+    let mut ticker = Ticker::every(COLLECT_INTERVAL);
+    let mut last_flush = Instant::now();
+    let mut next_mount = Instant::now();
+
+    // mounting a card goes through its whole initialisation, which is slow
+    let heartbeat = watchdog::register(TaskId::Sd, Duration::from_secs(10));
+
+    loop {
+        ticker.next().await;
+        heartbeat.beat();
+
+        collector.drain(|record| {
+            pretrigger.push(record, |record| {
+                line.clear();
+
+                match record.write_csv(&mut line) {
+                    Ok(()) => {
+                        buffer.push(line.as_bytes());
+                    }
+                    Err(_) => warn!("Log record too long: {record:?}"),
+                }
+            })
+        });
+
+        if buffer.len() < FLUSH_THRESHOLD && last_flush.elapsed() < FLUSH_INTERVAL {
+            continue;
+        }
+
+        if !logger.is_mounted() {
+            if Instant::now() < next_mount {
+                continue;
+            }
+
+            set_frequency(logger.device(), INIT_FREQUENCY_KHZ.kHz());
+            let mounted = logger.mount();
+            set_frequency(logger.device(), FREQUENCY_MHZ.MHz());
+
+            if let Err(e) = mounted {
+                warn!("SD card not mounted: {e:?}");
+                journal::record(e);
+                health::report_error(Component::Sd);
+
+                // make the card go through its whole initialisation again, in
+                // case it has been swapped
+                logger.device().mark_card_uninit();
+                next_mount = Instant::now() + REMOUNT_INTERVAL;
+                continue;
+            }
+        }
+
+        let result = logger.write_buffer(&mut buffer);
+        health::report(Component::Sd, &result);
+
+        if let Err(e) = result {
+            warn!("Writing to the SD card failed: {e:?}");
+            journal::record(e);
+
+            logger.device().mark_card_uninit();
+            next_mount = Instant::now() + REMOUNT_INTERVAL;
+        }
+
+        last_flush = Instant::now();
+
+        trace!(
+            "Log flushed: {} lines dropped, {} records missed",
+            buffer.dropped(),
+            collector.dropped()
+        );
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate std;
+
+    use core::cell::{Cell, RefCell};
+    use std::{collections::BTreeMap, vec::Vec};
+
+    use embedded_sdmmc::{Block, BlockCount, BlockIdx};
+
+    use super::*;
+
+    /// Where the partition starts, as on a real card.
+    const PARTITION_START: u32 = 63;
+
+    /// Just big enough for FAT16, at a block per cluster.
+    const FAT16_BLOCKS: u32 = 8_192;
+    const FAT16_RESERVED_BLOCKS: u16 = 1;
+    const FAT16_FAT_BLOCKS: u16 = 32;
+    const FAT16_ROOT_ENTRIES: u16 = 512;
+
+    /// Just big enough for FAT32 (which needs at least 65,525 clusters), at a
+    /// block per cluster.
+    const FAT32_BLOCKS: u32 = 67_000;
+    const FAT32_RESERVED_BLOCKS: u16 = 32;
+    const FAT32_FAT_BLOCKS: u32 = 520;
+
+    #[derive(Debug)]
+    struct CardRemoved;
+
+    /// An SD card image held in memory, which can be made to fail every access
+    /// as if it had been pulled out. Only blocks which have been written to are
+    /// stored - the rest read as zeroes.
+    struct MemDisk {
+        blocks: RefCell<BTreeMap<u32, [u8; 512]>>,
+        len: u32,
+        failing: Cell<bool>,
+    }
+
+    impl MemDisk {
+        /// A card with one partition of `blocks` blocks, which isn't formatted
+        /// yet.
+        fn partitioned(partition_type: u8, blocks: u32) -> Self {
+            let disk = Self {
+                blocks: RefCell::new(BTreeMap::new()),
+                len: PARTITION_START + blocks,
+                failing: Cell::new(false),
+            };
+
+            disk.edit(0, |mbr| {
+                let partition = &mut mbr[446..462];
+                partition[4] = partition_type;
+                partition[8..12].copy_from_slice(&PARTITION_START.to_le_bytes());
+                partition[12..16].copy_from_slice(&blocks.to_le_bytes());
+                mbr[510..512].copy_from_slice(&[0x55, 0xAA]);
+            });
+
+            disk
+        }
+
+        /// A freshly formatted card, with one empty FAT16 partition.
+        fn fat16() -> Self {
+            let disk = Self::partitioned(0x06, FAT16_BLOCKS);
+
+            disk.edit(PARTITION_START, |boot| {
+                boot[0..3].copy_from_slice(&[0xEB, 0x3C, 0x90]);
+                boot[3..11].copy_from_slice(b"CANSAT  ");
+                boot[11..13].copy_from_slice(&512u16.to_le_bytes());
+                boot[13] = 1;
+                boot[14..16].copy_from_slice(&FAT16_RESERVED_BLOCKS.to_le_bytes());
+                boot[16] = 2;
+                boot[17..19].copy_from_slice(&FAT16_ROOT_ENTRIES.to_le_bytes());
+                boot[19..21].copy_from_slice(&(FAT16_BLOCKS as u16).to_le_bytes());
+                boot[21] = 0xF8;
+                boot[22..24].copy_from_slice(&FAT16_FAT_BLOCKS.to_le_bytes());
+                boot[38] = 0x29;
+                boot[43..54].copy_from_slice(b"NO NAME    ");
+                boot[54..62].copy_from_slice(b"FAT16   ");
+                boot[510..512].copy_from_slice(&[0x55, 0xAA]);
+            });
+
+            // the first two entries of each FAT are reserved
+            for fat in 0..2 {
+                let first =
+                    PARTITION_START + FAT16_RESERVED_BLOCKS as u32 + fat * FAT16_FAT_BLOCKS as u32;
+                disk.edit(first, |block| {
+                    block[0..4].copy_from_slice(&[0xF8, 0xFF, 0xFF, 0xFF]);
+                });
+            }
+
+            disk
+        }
+
+        /// A freshly formatted card, with one empty FAT32 partition.
+        fn fat32() -> Self {
+            let disk = Self::partitioned(0x0C, FAT32_BLOCKS);
+
+            disk.edit(PARTITION_START, |boot| {
+                boot[0..3].copy_from_slice(&[0xEB, 0x58, 0x90]);
+                boot[3..11].copy_from_slice(b"CANSAT  ");
+                boot[11..13].copy_from_slice(&512u16.to_le_bytes());
+                boot[13] = 1;
+                boot[14..16].copy_from_slice(&FAT32_RESERVED_BLOCKS.to_le_bytes());
+                boot[16] = 2;
+                // no fixed root directory, and the sizes are all 32 bit
+                boot[21] = 0xF8;
+                boot[32..36].copy_from_slice(&FAT32_BLOCKS.to_le_bytes());
+                boot[36..40].copy_from_slice(&FAT32_FAT_BLOCKS.to_le_bytes());
+                // the root directory starts in the first cluster, and the info
+                // sector comes straight after this one
+                boot[44..48].copy_from_slice(&2u32.to_le_bytes());
+                boot[48..50].copy_from_slice(&1u16.to_le_bytes());
+                boot[50..52].copy_from_slice(&6u16.to_le_bytes());
+                boot[64] = 0x80;
+                boot[66] = 0x29;
+                boot[71..82].copy_from_slice(b"NO NAME    ");
+                boot[82..90].copy_from_slice(b"FAT32   ");
+                boot[510..512].copy_from_slice(&[0x55, 0xAA]);
+            });
+
+            disk.edit(PARTITION_START + 1, |info| {
+                info[0..4].copy_from_slice(b"RRaA");
+                info[484..488].copy_from_slice(b"rrAa");
+                // the free cluster count and next free cluster aren't known
+                info[488..496].fill(0xFF);
+                info[510..512].copy_from_slice(&[0x55, 0xAA]);
+            });
+
+            // the first two entries of each FAT are reserved, and the root
+            // directory is a single cluster
+            for fat in 0..2 {
+                let first = PARTITION_START + FAT32_RESERVED_BLOCKS as u32 + fat * FAT32_FAT_BLOCKS;
+                disk.edit(first, |block| {
+                    block[0..4].copy_from_slice(&0x0FFF_FFF8u32.to_le_bytes());
+                    block[4..8].copy_from_slice(&0x0FFF_FFFFu32.to_le_bytes());
+                    block[8..12].copy_from_slice(&0x0FFF_FFFFu32.to_le_bytes());
+                });
+            }
+
+            disk
+        }
+
+        fn edit(&self, block: u32, f: impl FnOnce(&mut [u8; 512])) {
+            f(self.blocks.borrow_mut().entry(block).or_insert([0; 512]));
+        }
+
+        fn set_failing(&self, failing: bool) {
+            self.failing.set(failing);
+        }
+
+        fn check(&self) -> Result<(), CardRemoved> {
+            if self.failing.get() {
+                Err(CardRemoved)
+            } else {
+                Ok(())
+            }
+        }
+    }
+
+    impl BlockDevice for &MemDisk {
+        type Error = CardRemoved;
+
+        fn read(
+            &self,
+            blocks: &mut [Block],
+            start_block_idx: BlockIdx,
+            _reason: &str,
+        ) -> Result<(), Self::Error> {
+            self.check()?;
+
+            let disk = self.blocks.borrow();
+            for (i, block) in blocks.iter_mut().enumerate() {
+                block.contents = disk
+                    .get(&(start_block_idx.0 + i as u32))
+                    .copied()
+                    .unwrap_or([0; 512]);
+            }
+
+            Ok(())
+        }
+
+        fn write(&self, blocks: &[Block], start_block_idx: BlockIdx) -> Result<(), Self::Error> {
+            self.check()?;
+
+            let mut disk = self.blocks.borrow_mut();
+            for (i, block) in blocks.iter().enumerate() {
+                disk.insert(start_block_idx.0 + i as u32, block.contents);
+            }
+
+            Ok(())
+        }
+
+        fn num_blocks(&self) -> Result<BlockCount, Self::Error> {
+            self.check()?;
+
+            Ok(BlockCount(self.len))
+        }
+    }
+
+    /// Opens the card separately from the logger, as a computer reading it back
+    /// would.
+    fn with_root<R>(
+        disk: &MemDisk,
+        f: impl FnOnce(&mut VolumeManager<&MemDisk, UptimeClock>, RawDirectory) -> R,
+    ) -> R {
+        let mut volume_mgr = VolumeManager::new(disk, UptimeClock);
+        let volume = volume_mgr.open_raw_volume(VolumeIdx(0)).unwrap();
+        let root = volume_mgr.open_root_dir(volume).unwrap();
+
+        let result = f(&mut volume_mgr, root);
+
+        volume_mgr.close_dir(root).unwrap();
+        volume_mgr.close_volume(volume).unwrap();
+
+        result
+    }
+
+    fn read_file(disk: &MemDisk, name: &str) -> Vec<u8> {
+        with_root(disk, |volume_mgr, root| {
+            let file = volume_mgr
+                .open_file_in_dir(root, name, Mode::ReadOnly)
+                .unwrap();
+
+            let mut contents = Vec::new();
+            let mut chunk = [0; 512];
+
+            loop {
+                let read = volume_mgr.read(file, &mut chunk).unwrap();
+                if read == 0 {
+                    break;
+                }
+                contents.extend_from_slice(&chunk[..read]);
+            }
+
+            volume_mgr.close_file(file).unwrap();
+
+            contents
+        })
+    }
+
+    fn create_file(disk: &MemDisk, name: &str, contents: &[u8]) {
+        with_root(disk, |volume_mgr, root| {
+            let file = volume_mgr
+                .open_file_in_dir(root, name, Mode::ReadWriteCreate)
+                .unwrap();
+
+            volume_mgr.write(file, contents).unwrap();
+            volume_mgr.close_file(file).unwrap();
+        })
+    }
+
+    /// The log files on the card, in order.
+    fn log_files(disk: &MemDisk) -> Vec<u32> {
+        let mut indices = with_root(disk, |volume_mgr, root| {
+            let mut indices = Vec::new();
+
+            volume_mgr
+                .iterate_dir(root, |entry| {
+                    indices.extend(parse_log_file_name(
+                        entry.name.base_name(),
+                        entry.name.extension(),
+                    ))
+                })
+                .unwrap();
+
+            indices
+        });
+
+        indices.sort();
+        indices
+    }
+
+    /// What comes after the header in a log file.
+    fn records(contents: &[u8]) -> &[u8] {
+        let contents = contents.strip_prefix(CSV_HEADER.as_bytes()).unwrap();
+        let line_end = contents.iter().position(|&b| b == b'\n').unwrap();
+
+        assert!(contents.starts_with(b"# file "));
+
+        &contents[line_end + 1..]
+    }
+
+    fn line(i: usize) -> String<MAX_LINE_LEN> {
+        let mut line = String::new();
+        writeln!(line, "M,{i},{i},0.0,0.0,1.0,0.0,0.0,0.0,20.0,0.0,0.0").unwrap();
+        line
+    }
+
+    #[test]
+    fn log_file_names_round_trip() {
+        assert_eq!(log_file_name(0), "LOG00000.CSV");
+        assert_eq!(log_file_name(42), "LOG00042.CSV");
+        assert_eq!(log_file_name(1_000_000), "LOG99999.CSV");
+
+        assert_eq!(parse_log_file_name(b"LOG00042", b"CSV"), Some(42));
+        assert_eq!(parse_log_file_name(b"LOG00042", b"TXT"), None);
+        assert_eq!(parse_log_file_name(b"LOG0042", b"CSV"), None);
+        assert_eq!(parse_log_file_name(b"LOGABCDE", b"CSV"), None);
+        assert_eq!(parse_log_file_name(b"DATA0001", b"CSV"), None);
+    }
+
+    #[test]
+    fn new_files_start_with_the_header() {
+        let disk = MemDisk::fat16();
+        let mut logger = SdLogger::new(&disk, UptimeClock);
+
+        logger.mount().unwrap();
+        assert_eq!(logger.file_name().unwrap(), "LOG00000.CSV");
+
+        let contents = read_file(&disk, "LOG00000.CSV");
+        assert_eq!(records(&contents), b"");
+        assert!(contents.ends_with(b"\n"));
+    }
+
+    #[test]
+    fn mounting_starts_after_the_last_log_file() {
+        let disk = MemDisk::fat16();
+        create_file(&disk, "LOG00003.CSV", b"old flight\n");
+        create_file(&disk, "LOG00001.CSV", b"older flight\n");
+        create_file(&disk, "NOTES.TXT", b"not a log\n");
+
+        let mut logger = SdLogger::new(&disk, UptimeClock);
+        logger.mount().unwrap();
+
+        assert_eq!(logger.file_name().unwrap(), "LOG00004.CSV");
+        assert_eq!(log_files(&disk), [1, 3, 4]);
+
+        // the old logs are left alone
+        assert_eq!(read_file(&disk, "LOG00003.CSV"), b"old flight\n");
+    }
+
+    fn writes_whole_buffer_in_order(disk: MemDisk) {
+        let mut logger = SdLogger::new(&disk, UptimeClock);
+        let mut buffer: LogBuffer<2048> = LogBuffer::new();
+        let mut expected = Vec::new();
+
+        logger.mount().unwrap();
+
+        for i in 0..100 {
+            let line = line(i);
+            assert!(buffer.push(line.as_bytes()));
+            expected.extend_from_slice(line.as_bytes());
+
+            if i % 10 == 9 {
+                logger.write_buffer(&mut buffer).unwrap();
+                assert!(buffer.is_empty());
+            }
+        }
+
+        assert_eq!(records(&read_file(&disk, "LOG00000.CSV")), expected);
+    }
+
+    fn rotates_files_once_they_are_full(disk: MemDisk) {
+        let mut logger = SdLogger::new(&disk, UptimeClock).with_max_file_len(1_024);
+        let mut buffer: LogBuffer<1024> = LogBuffer::new();
+        let mut expected = Vec::new();
+
+        logger.mount().unwrap();
+
+        for i in 0..100 {
+            let line = line(i);
+            assert!(buffer.push(line.as_bytes()));
+            expected.extend_from_slice(line.as_bytes());
+
+            logger.write_buffer(&mut buffer).unwrap();
+        }
+
+        let files = log_files(&disk);
+        assert!(files.len() > 3, "only rotated into {files:?}");
+        assert_eq!(
+            logger.file_name().unwrap(),
+            log_file_name(*files.last().unwrap())
+        );
+
+        // every file has its own header, and only goes past the limit by the
+        // last line written to it
+        let mut written = Vec::new();
+
+        for index in files {
+            let contents = read_file(&disk, &log_file_name(index));
+            assert!(contents.len() < 1_024 + line(99).len());
+
+            written.extend_from_slice(records(&contents));
+        }
+
+        assert_eq!(written, expected);
+    }
+
+    #[test]
+    fn writes_whole_buffer_in_order_on_fat16() {
+        writes_whole_buffer_in_order(MemDisk::fat16());
+    }
+
+    #[test]
+    fn writes_whole_buffer_in_order_on_fat32() {
+        writes_whole_buffer_in_order(MemDisk::fat32());
+    }
+
+    #[test]
+    fn rotates_files_on_fat16() {
+        rotates_files_once_they_are_full(MemDisk::fat16());
+    }
+
+    #[test]
+    fn rotates_files_on_fat32() {
+        rotates_files_once_they_are_full(MemDisk::fat32());
+    }
+
+    #[test]
+    fn long_flushes_are_split_up() {
+        let disk = MemDisk::fat32();
+        let mut logger = SdLogger::new(&disk, UptimeClock);
+        let mut buffer: LogBuffer<LOG_BUFFER_LEN> = LogBuffer::new();
+        let mut expected = Vec::new();
+
+        logger.mount().unwrap();
+
+        let mut i = 0;
+        while buffer.push(line(i).as_bytes()) {
+            expected.extend_from_slice(line(i).as_bytes());
+            i += 1;
+        }
+
+        let full = buffer.len();
+        assert!(full > 2 * MAX_WRITE_LEN);
+
+        logger.write_buffer(&mut buffer).unwrap();
+        assert_eq!(buffer.len(), full - MAX_WRITE_LEN);
+
+        // what has been written is already on the card
+        assert_eq!(
+            records(&read_file(&disk, "LOG00000.CSV")),
+            &expected[..MAX_WRITE_LEN]
+        );
+
+        let mut flushes = 1;
+        while !buffer.is_empty() {
+            logger.write_buffer(&mut buffer).unwrap();
+            flushes += 1;
+        }
+
+        assert_eq!(flushes, full.div_ceil(MAX_WRITE_LEN));
+        assert_eq!(records(&read_file(&disk, "LOG00000.CSV")), expected);
+    }
+
+    #[test]
+    fn recovers_from_card_errors() {
+        let disk = MemDisk::fat16();
+        let mut logger = SdLogger::new(&disk, UptimeClock);
+        let mut buffer: LogBuffer<2048> = LogBuffer::new();
+
+        logger.mount().unwrap();
+
+        buffer.push(line(0).as_bytes());
+        logger.write_buffer(&mut buffer).unwrap();
+
+        // the card is pulled out
+        disk.set_failing(true);
+        buffer.push(line(1).as_bytes());
+
+        assert!(matches!(
+            logger.write_buffer(&mut buffer),
+            Err(SdError::WriteFailed)
+        ));
+        assert!(!logger.is_mounted());
+        assert_eq!(logger.file_name(), None);
+
+        // nothing is lost while it is missing
+        assert_eq!(buffer.len(), line(1).len());
+        buffer.push(line(2).as_bytes());
+
+        assert!(matches!(logger.mount(), Err(SdError::MountFailed)));
+        assert!(matches!(
+            logger.write_buffer(&mut buffer),
+            Err(SdError::MountFailed)
+        ));
+
+        // and put back in
+        disk.set_failing(false);
+        logger.mount().unwrap();
+        assert_eq!(logger.file_name().unwrap(), "LOG00001.CSV");
+
+        logger.write_buffer(&mut buffer).unwrap();
+        assert!(buffer.is_empty());
+
+        assert_eq!(
+            records(&read_file(&disk, "LOG00000.CSV")),
+            line(0).as_bytes()
+        );
+
+        let mut expected = Vec::new();
+        expected.extend_from_slice(line(1).as_bytes());
+        expected.extend_from_slice(line(2).as_bytes());
+        assert_eq!(records(&read_file(&disk, "LOG00001.CSV")), expected);
+    }
+}
@@ -0,0 +1,899 @@
+//! A driver for the SX1276/77/78 LoRa radios, and the task which sends
+//! telemetry down to the ground station with one.
+//!
+//! The driver only needs an [SpiDevice], so it can share an async SPI bus with
+//! other devices - or be run against a mock one.
+
+#[cfg(target_arch = "xtensa")]
+use embassy_sync::blocking_mutex::raw::CriticalSectionRawMutex;
+use embedded_hal_async::spi::{Operation, SpiDevice};
+#[cfg(target_arch = "xtensa")]
+use hal::{
+    pdma::Spi2DmaChannel,
+    peripherals::SPI2,
+    spi::{master::dma::SpiDma, FullDuplexMode},
+};
+#[cfg(target_arch = "xtensa")]
+use telemetry::{Encoder, MAX_FRAME_LEN};
+
+use crate::prelude::*;
+#[cfg(target_arch = "xtensa")]
+use crate::{
+    downlink::encode_state,
+    errors::journal,
+    health::{self, Component},
+    watchdog::{self, TaskId},
+};
+
+/// The bus the radio is on, which other devices can share.
+#[cfg(target_arch = "xtensa")]
+pub type LoRaSpiBus = embassy_sync::mutex::Mutex<
+    CriticalSectionRawMutex,
+    SpiDma<'static, SPI2, Spi2DmaChannel, FullDuplexMode>,
+>;
+/// The radio's chip select on [LoRaSpiBus].
+#[cfg(target_arch = "xtensa")]
+pub type LoRaSpi = embassy_embedded_hal::shared_bus::asynch::spi::SpiDevice<
+    'static,
+    CriticalSectionRawMutex,
+    SpiDma<'static, SPI2, Spi2DmaChannel, FullDuplexMode>,
+    AnyPin<Output<PushPull>>,
+>;
+
+/// How often a telemetry frame is sent, unless told otherwise.
+pub const DEFAULT_TELEMETRY_INTERVAL: Duration = Duration::from_millis(500);
+
+/// The longest a transmission is waited on before giving up.
+const TX_TIMEOUT: Duration = Duration::from_secs(2);
+/// How often the radio is polled to see if a transmission has finished.
+const TX_POLL_INTERVAL: Duration = Duration::from_millis(5);
+
+/// How long to keep retrying a frame the radio couldn't be told to send - any
+/// longer, and the next frame would be fresher.
+#[cfg(target_arch = "xtensa")]
+const TX_RETRY_TIME: Duration = Duration::from_millis(100);
+
+/// The crystal frequency of every SX127x module.
+const OSCILLATOR_FREQUENCY: u64 = 32_000_000;
+
+/// What [Register::Version] reads on the SX1276/77/78/79.
+const SX127X_VERSION: u8 = 0x12;
+
+/// Set on a register address to write to it, rather than read from it.
+const WRITE: u8 = 0x80;
+
+/// The largest payload the FIFO can hold.
+pub const MAX_PAYLOAD_LEN: usize = 255;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[repr(u8)]
+pub enum Register {
+    Fifo = 0x00,
+    OpMode = 0x01,
+    FrfMsb = 0x06,
+    FrfMid = 0x07,
+    FrfLsb = 0x08,
+    PaConfig = 0x09,
+    Ocp = 0x0B,
+    Lna = 0x0C,
+    FifoAddrPtr = 0x0D,
+    FifoTxBaseAddr = 0x0E,
+    FifoRxBaseAddr = 0x0F,
+    IrqFlags = 0x12,
+    ModemConfig1 = 0x1D,
+    ModemConfig2 = 0x1E,
+    PreambleMsb = 0x20,
+    PreambleLsb = 0x21,
+    PayloadLength = 0x22,
+    ModemConfig3 = 0x26,
+    SyncWord = 0x39,
+    DioMapping1 = 0x40,
+    Version = 0x42,
+    PaDac = 0x4D,
+}
+
+/// [Register::OpMode] bits.
+mod op_mode {
+    pub const LONG_RANGE_MODE: u8 = 0x80;
+    pub const SLEEP: u8 = 0x00;
+    pub const STANDBY: u8 = 0x01;
+    pub const TX: u8 = 0x03;
+}
+
+/// [Register::IrqFlags] bits.
+const IRQ_TX_DONE: u8 = 0x08;
+
+#[derive(Clone, Copy, ErrorCategory)]
+#[repr(u8)]
+pub enum LoRaError {
+    /// Communicating with the radio over SPI failed
+    SpiError,
+    /// Something other than an SX127x answered
+    WrongVersion,
+    /// The frequency or transmit power is outside what the radio supports
+    InvalidConfig,
+    /// The radio never reported that it finished transmitting
+    TxTimeout,
+    /// The payload doesn't fit in the radio's FIFO
+    PayloadTooLong,
+}
+
+type Result<T> = core::result::Result<T, LoRaError>;
+
+/// Higher spreading factors reach further, but take longer to send.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[repr(u8)]
+pub enum SpreadingFactor {
+    Sf7 = 7,
+    Sf8 = 8,
+    #[default]
+    Sf9 = 9,
+    Sf10 = 10,
+    Sf11 = 11,
+    Sf12 = 12,
+}
+
+/// Narrower bandwidths reach further, but take longer to send.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Bandwidth {
+    Khz7_8,
+    Khz10_4,
+    Khz15_6,
+    Khz20_8,
+    Khz31_25,
+    Khz41_7,
+    Khz62_5,
+    #[default]
+    Khz125,
+    Khz250,
+    Khz500,
+}
+
+impl Bandwidth {
+    pub fn hz(&self) -> u32 {
+        match self {
+            Self::Khz7_8 => 7_800,
+            Self::Khz10_4 => 10_400,
+            Self::Khz15_6 => 15_600,
+            Self::Khz20_8 => 20_800,
+            Self::Khz31_25 => 31_250,
+            Self::Khz41_7 => 41_700,
+            Self::Khz62_5 => 62_500,
+            Self::Khz125 => 125_000,
+            Self::Khz250 => 250_000,
+            Self::Khz500 => 500_000,
+        }
+    }
+
+    /// The value of the bandwidth bits in [Register::ModemConfig1].
+    fn bits(&self) -> u8 {
+        *self as u8
+    }
+}
+
+/// How much error correction is added to each transmission.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum CodingRate {
+    #[default]
+    Cr4_5,
+    Cr4_6,
+    Cr4_7,
+    Cr4_8,
+}
+
+impl CodingRate {
+    /// The value of the coding rate bits in [Register::ModemConfig1].
+    fn bits(&self) -> u8 {
+        *self as u8 + 1
+    }
+}
+
+/// The ground station must be set up with the same frequency, spreading
+/// factor, bandwidth, coding rate and sync word to receive anything.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct LoRaConfig {
+    /// Carrier frequency in hertz.
+    ///
+    /// Defaults to 433MHz.
+    pub frequency: u32,
+    /// Defaults to 9.
+    pub spreading_factor: SpreadingFactor,
+    /// Defaults to 125kHz.
+    pub bandwidth: Bandwidth,
+    /// Defaults to 4/5.
+    pub coding_rate: CodingRate,
+    /// Transmit power in dBm, from 2 to 17, or 20 - sent through the PA_BOOST
+    /// pin, which is the only one wired up on most modules.
+    ///
+    /// Defaults to 10dBm (10mW), the limit for most CanSat competitions.
+    pub tx_power: u8,
+    /// Length of the preamble in symbols.
+    ///
+    /// Defaults to 8.
+    pub preamble_length: u16,
+    /// Defaults to 0x12, the private network sync word.
+    pub sync_word: u8,
+}
+
+impl Default for LoRaConfig {
+    fn default() -> Self {
+        Self {
+            frequency: 433_000_000,
+            spreading_factor: SpreadingFactor::default(),
+            bandwidth: Bandwidth::default(),
+            coding_rate: CodingRate::default(),
+            tx_power: 10,
+            preamble_length: 8,
+            sync_word: 0x12,
+        }
+    }
+}
+
+impl LoRaConfig {
+    /// The value of the frequency registers for [LoRaConfig::frequency].
+    fn frf(&self) -> u32 {
+        ((self.frequency as u64 * (1 << 19)) / OSCILLATOR_FREQUENCY) as u32
+    }
+
+    /// Whether symbols are long enough (over 16ms) that the radio needs to be
+    /// told to compensate for clock drift.
+    fn low_data_rate_optimize(&self) -> bool {
+        // in microseconds, as SF11 at 125kHz (16.384ms) is only just over
+        (1u32 << self.spreading_factor as u8) * 1_000_000 / self.bandwidth.hz() > 16_000
+    }
+
+    /// Checks the frequency and transmit power are ones the radio supports.
+    fn validate(&self) -> Result<()> {
+        if !(137_000_000..=1_020_000_000).contains(&self.frequency)
+            || !matches!(self.tx_power, 2..=17 | 20)
+        {
+            return Err(LoRaError::InvalidConfig);
+        }
+
+        Ok(())
+    }
+}
+
+/// An SX1276/77/78 radio, in LoRa mode.
+pub struct Sx127x<SPI: SpiDevice> {
+    spi: SPI,
+    config: LoRaConfig,
+}
+
+impl<SPI: SpiDevice> Sx127x<SPI> {
+    /// Checks there is an SX127x on `spi`, and configures it for LoRa.
+    pub async fn new(spi: SPI, config: LoRaConfig) -> Result<Self> {
+        let mut radio = Self { spi, config };
+
+        let version = radio.read_register(Register::Version).await?;
+
+        if version != SX127X_VERSION {
+            warn!("Expected an SX127x (version {SX127X_VERSION:#04x}), found {version:#04x}");
+            return Err(LoRaError::WrongVersion);
+        }
+
+        radio.configure().await?;
+
+        Ok(radio)
+    }
+
+    pub fn config(&self) -> &LoRaConfig {
+        &self.config
+    }
+
+    /// Writes `config` to the radio, only keeping it if that succeeds.
+    pub async fn set_config(&mut self, config: LoRaConfig) -> Result<()> {
+        self.write_config(config).await?;
+        self.config = config;
+
+        Ok(())
+    }
+
+    pub fn release(self) -> SPI {
+        self.spi
+    }
+
+    pub async fn read_register(&mut self, register: Register) -> Result<u8> {
+        let mut buf = [register as u8 & !WRITE, 0];
+
+        self.spi
+            .transfer_in_place(&mut buf)
+            .await
+            .map_err(|_| LoRaError::SpiError)?;
+
+        Ok(buf[1])
+    }
+
+    pub async fn write_register(&mut self, register: Register, value: u8) -> Result<()> {
+        self.spi
+            .write(&[register as u8 | WRITE, value])
+            .await
+            .map_err(|_| LoRaError::SpiError)
+    }
+
+    /// Writes `data` to consecutive registers starting at `register` (or
+    /// repeatedly to the FIFO), in one transaction.
+    async fn write_burst(&mut self, register: Register, data: &[u8]) -> Result<()> {
+        self.spi
+            .transaction(&mut [
+                Operation::Write(&[register as u8 | WRITE]),
+                Operation::Write(data),
+            ])
+            .await
+            .map_err(|_| LoRaError::SpiError)
+    }
+
+    async fn set_mode(&mut self, mode: u8) -> Result<()> {
+        self.write_register(Register::OpMode, op_mode::LONG_RANGE_MODE | mode)
+            .await
+    }
+
+    /// Puts the radio to sleep, using as little power as possible until the
+    /// next transmission.
+    pub async fn sleep(&mut self) -> Result<()> {
+        self.set_mode(op_mode::SLEEP).await
+    }
+
+    pub async fn standby(&mut self) -> Result<()> {
+        self.set_mode(op_mode::STANDBY).await
+    }
+
+    /// Writes the current config to the radio, leaving it in standby.
+    pub async fn configure(&mut self) -> Result<()> {
+        self.write_config(self.config).await
+    }
+
+    async fn write_config(&mut self, config: LoRaConfig) -> Result<()> {
+        config.validate()?;
+
+        // LoRa mode can only be switched on while asleep
+        self.write_register(Register::OpMode, op_mode::SLEEP)
+            .await?;
+        self.sleep().await?;
+
+        let frf = config.frf().to_be_bytes();
+        self.write_burst(Register::FrfMsb, &frf[1..]).await?;
+
+        // the whole FIFO is used for transmitting
+        self.write_register(Register::FifoTxBaseAddr, 0).await?;
+        self.write_register(Register::FifoRxBaseAddr, 0).await?;
+
+        // LNA boost, in case this is ever used to receive
+        let lna = self.read_register(Register::Lna).await?;
+        self.write_register(Register::Lna, lna | 0x03).await?;
+
+        if config.tx_power == 20 {
+            // +20dBm needs the high power DAC, and the current limit raising to
+            // 140mA
+            self.write_register(Register::PaDac, 0x87).await?;
+            self.write_register(Register::Ocp, 0x20 | 0x11).await?;
+            self.write_register(Register::PaConfig, 0x80 | (20 - 5))
+                .await?;
+        } else {
+            // default DAC, and a 100mA current limit
+            self.write_register(Register::PaDac, 0x84).await?;
+            self.write_register(Register::Ocp, 0x20 | 0x0B).await?;
+            self.write_register(Register::PaConfig, 0x80 | (config.tx_power - 2))
+                .await?;
+        }
+
+        // explicit header, so the receiver doesn't need to know the length
+        self.write_register(
+            Register::ModemConfig1,
+            (config.bandwidth.bits() << 4) | (config.coding_rate.bits() << 1),
+        )
+        .await?;
+        // payload CRC on
+        self.write_register(
+            Register::ModemConfig2,
+            ((config.spreading_factor as u8) << 4) | 0x04,
+        )
+        .await?;
+        // automatic gain control on
+        let low_data_rate_optimize = if config.low_data_rate_optimize() {
+            0x08
+        } else {
+            0x00
+        };
+        self.write_register(Register::ModemConfig3, 0x04 | low_data_rate_optimize)
+            .await?;
+
+        self.write_burst(Register::PreambleMsb, &config.preamble_length.to_be_bytes())
+            .await?;
+        self.write_register(Register::SyncWord, config.sync_word)
+            .await?;
+
+        // DIO0 signals TX done
+        self.write_register(Register::DioMapping1, 0x40).await?;
+
+        self.standby().await
+    }
+
+    /// Sends `payload`, waiting until it has finished transmitting.
+    pub async fn transmit(&mut self, payload: &[u8]) -> Result<()> {
+        if payload.len() > MAX_PAYLOAD_LEN {
+            return Err(LoRaError::PayloadTooLong);
+        }
+
+        self.standby().await?;
+
+        self.write_register(Register::FifoAddrPtr, 0).await?;
+        self.write_burst(Register::Fifo, payload).await?;
+        self.write_register(Register::PayloadLength, payload.len() as u8)
+            .await?;
+
+        self.write_register(Register::IrqFlags, 0xFF).await?;
+        self.set_mode(op_mode::TX).await?;
+
+        let deadline = Instant::now() + TX_TIMEOUT;
+
+        loop {
+            let flags = self.read_register(Register::IrqFlags).await?;
+
+            if flags & IRQ_TX_DONE != 0 {
+                self.write_register(Register::IrqFlags, IRQ_TX_DONE).await?;
+                return Ok(());
+            }
+
+            if Instant::now() >= deadline {
+                self.standby().await?;
+                return Err(LoRaError::TxTimeout);
+            }
+
+            Timer::after(TX_POLL_INTERVAL).await;
+        }
+    }
+}
+
+/// Sends the state of the can to the ground every `interval`.
+///
+/// If a frame takes longer than `interval` to send, the next one is sent as
+/// soon as it finishes.
+#[cfg(target_arch = "xtensa")]
+#[task]
+pub async fn lora_downlink(mut radio: Sx127x<LoRaSpi>, interval: Duration) {
+    let mut encoder = Encoder::new();
+    let mut buf = [0; MAX_FRAME_LEN];
+    let mut ticker = Ticker::every(interval);
+
+    // sending gives up after TX_TIMEOUT (plus any retries), and waking and
+    // sleeping the radio takes a little more
+    let heartbeat = watchdog::register(
+        TaskId::Radio,
+        interval + TX_RETRY_TIME + TX_TIMEOUT + Duration::from_secs(1),
+    );
+
+    loop {
+        ticker.next().await;
+        heartbeat.beat();
+
+        let len = match encode_state(&mut encoder, &mut buf) {
+            Ok(len) => len,
+            Err(e) => {
+                warn!("Couldn't encode telemetry: {e}");
+                continue;
+            }
+        };
+
+        let start = Instant::now();
+
+        // only a garbled SPI transfer is worth trying again - a frame that
+        // timed out would be stale by the time it was sent again
+        let result = Backoff::new_async(async || radio.transmit(&buf[..len]).await)
+            .with_interval(Duration::from_millis(10))
+            .with_max_elapsed_time(TX_RETRY_TIME)
+            .with_retry_if(|e| matches!(e, LoRaError::SpiError))
+            .retry()
+            .await;
+        health::report(Component::Radio, &result);
+
+        match result {
+            Ok(()) => trace!(
+                "Sent {len} byte telemetry frame in {}ms",
+                start.elapsed().as_millis()
+            ),
+            Err(e) => journal::record(e),
+        }
+        result.print_warn_limited();
+
+        radio.sleep().await.print_warn_limited();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use embassy_futures::block_on;
+    use embedded_hal_async::spi::{ErrorKind, ErrorType};
+    use heapless::Vec;
+
+    use super::*;
+
+    /// An SX127x on the end of an SPI bus, which records every register write.
+    struct MockRadio {
+        registers: [u8; 0x80],
+        fifo: Vec<u8, MAX_PAYLOAD_LEN>,
+        /// Every write, in order, as `(register, value)`.
+        writes: Vec<(u8, u8), 512>,
+        /// Whether transmissions finish straight away, or never.
+        tx_completes: bool,
+        /// Whether every transaction fails.
+        failing: bool,
+    }
+
+    impl MockRadio {
+        fn new() -> Self {
+            let mut registers = [0; 0x80];
+            registers[Register::Version as usize] = SX127X_VERSION;
+            registers[Register::Lna as usize] = 0x20;
+
+            Self {
+                registers,
+                fifo: Vec::new(),
+                writes: Vec::new(),
+                tx_completes: true,
+                failing: false,
+            }
+        }
+
+        /// Every value written to `register`, in order.
+        fn writes_to(&self, register: Register) -> impl Iterator<Item = u8> + '_ {
+            self.writes
+                .iter()
+                .filter(move |(written, _)| *written == register as u8)
+                .map(|(_, value)| *value)
+        }
+
+        fn last_write(&self, register: Register) -> Option<u8> {
+            self.writes_to(register).last()
+        }
+
+        fn write_register(&mut self, register: u8, value: u8) {
+            self.writes.push((register, value)).unwrap();
+
+            match register {
+                r if r == Register::Fifo as u8 => self.fifo.push(value).unwrap(),
+                // flags are cleared by writing 1s to them
+                r if r == Register::IrqFlags as u8 => self.registers[r as usize] &= !value,
+                r => {
+                    self.registers[r as usize] = value;
+
+                    if r == Register::OpMode as u8
+                        && value & 0x07 == op_mode::TX
+                        && self.tx_completes
+                    {
+                        self.registers[Register::IrqFlags as usize] |= IRQ_TX_DONE;
+                    }
+                }
+            }
+        }
+
+        /// Handles one byte of a transaction, returning what the radio sends
+        /// back. The first byte is the address, and the rest read or write
+        /// consecutive registers (or the FIFO, repeatedly).
+        fn byte(&mut self, address: &mut Option<u8>, byte: u8) -> u8 {
+            let Some(current) = *address else {
+                *address = Some(byte);
+                return 0;
+            };
+
+            let register = current & !WRITE;
+
+            if register != Register::Fifo as u8 {
+                *address = Some(current + 1);
+            }
+
+            if current & WRITE != 0 {
+                self.write_register(register, byte);
+                0
+            } else {
+                self.registers[register as usize]
+            }
+        }
+    }
+
+    impl ErrorType for MockRadio {
+        type Error = ErrorKind;
+    }
+
+    impl SpiDevice for MockRadio {
+        async fn transaction(
+            &mut self,
+            operations: &mut [Operation<'_, u8>],
+        ) -> core::result::Result<(), Self::Error> {
+            if self.failing {
+                return Err(ErrorKind::Other);
+            }
+
+            let mut address = None;
+
+            for operation in operations {
+                match operation {
+                    Operation::Write(bytes) => {
+                        for &byte in bytes.iter() {
+                            self.byte(&mut address, byte);
+                        }
+                    }
+                    Operation::TransferInPlace(bytes) => {
+                        for byte in bytes.iter_mut() {
+                            *byte = self.byte(&mut address, *byte);
+                        }
+                    }
+                    // not used by the driver
+                    _ => return Err(ErrorKind::Other),
+                }
+            }
+
+            Ok(())
+        }
+    }
+
+    fn radio(config: LoRaConfig) -> Sx127x<MockRadio> {
+        block_on(Sx127x::new(MockRadio::new(), config)).unwrap()
+    }
+
+    fn frf(mock: &MockRadio) -> [u8; 3] {
+        [
+            mock.last_write(Register::FrfMsb).unwrap(),
+            mock.last_write(Register::FrfMid).unwrap(),
+            mock.last_write(Register::FrfLsb).unwrap(),
+        ]
+    }
+
+    #[test]
+    fn rejects_other_chips() {
+        let mut mock = MockRadio::new();
+        mock.registers[Register::Version as usize] = 0x22;
+
+        assert!(matches!(
+            block_on(Sx127x::new(mock, LoRaConfig::default())),
+            Err(LoRaError::WrongVersion)
+        ));
+    }
+
+    #[test]
+    fn reports_spi_errors() {
+        let mut mock = MockRadio::new();
+        mock.failing = true;
+
+        assert!(matches!(
+            block_on(Sx127x::new(mock, LoRaConfig::default())),
+            Err(LoRaError::SpiError)
+        ));
+    }
+
+    #[test]
+    fn configures_the_defaults() {
+        let mock = radio(LoRaConfig::default()).release();
+
+        // into LoRa mode while asleep, then left in standby
+        assert!(mock.writes_to(Register::OpMode).eq([0x00, 0x80, 0x81]));
+
+        // 433MHz
+        assert_eq!(frf(&mock), [0x6C, 0x40, 0x00]);
+        // 125kHz, 4/5, explicit header
+        assert_eq!(mock.last_write(Register::ModemConfig1), Some(0x72));
+        // SF9, payload CRC
+        assert_eq!(mock.last_write(Register::ModemConfig2), Some(0x94));
+        // AGC, symbols are short enough without low data rate optimisation
+        assert_eq!(mock.last_write(Register::ModemConfig3), Some(0x04));
+        // 10dBm on PA_BOOST
+        assert_eq!(mock.last_write(Register::PaConfig), Some(0x88));
+        assert_eq!(mock.last_write(Register::PaDac), Some(0x84));
+        assert_eq!(mock.last_write(Register::Ocp), Some(0x2B));
+
+        assert_eq!(mock.last_write(Register::PreambleMsb), Some(0x00));
+        assert_eq!(mock.last_write(Register::PreambleLsb), Some(0x08));
+        assert_eq!(mock.last_write(Register::SyncWord), Some(0x12));
+        assert_eq!(mock.last_write(Register::DioMapping1), Some(0x40));
+        assert_eq!(mock.last_write(Register::FifoTxBaseAddr), Some(0x00));
+        // boost added to whatever else was set
+        assert_eq!(mock.last_write(Register::Lna), Some(0x23));
+    }
+
+    #[test]
+    fn sets_the_frequency() {
+        for (frequency, expected) in [
+            (433_000_000, [0x6C, 0x40, 0x00]),
+            (434_250_000, [0x6C, 0x90, 0x00]),
+            (868_000_000, [0xD9, 0x00, 0x00]),
+            (915_000_000, [0xE4, 0xC0, 0x00]),
+        ] {
+            let mock = radio(LoRaConfig {
+                frequency,
+                ..Default::default()
+            })
+            .release();
+
+            assert_eq!(frf(&mock), expected, "{frequency}Hz");
+        }
+    }
+
+    #[test]
+    fn sets_the_spreading_factor_and_bandwidth() {
+        let mock = radio(LoRaConfig {
+            spreading_factor: SpreadingFactor::Sf12,
+            bandwidth: Bandwidth::Khz500,
+            coding_rate: CodingRate::Cr4_8,
+            ..Default::default()
+        })
+        .release();
+
+        assert_eq!(mock.last_write(Register::ModemConfig1), Some(0x98));
+        assert_eq!(mock.last_write(Register::ModemConfig2), Some(0xC4));
+        // 8ms symbols
+        assert_eq!(mock.last_write(Register::ModemConfig3), Some(0x04));
+
+        let mock = radio(LoRaConfig {
+            spreading_factor: SpreadingFactor::Sf12,
+            bandwidth: Bandwidth::Khz125,
+            ..Default::default()
+        })
+        .release();
+
+        // 32ms symbols need low data rate optimisation
+        assert_eq!(mock.last_write(Register::ModemConfig3), Some(0x0C));
+
+        let mock = radio(LoRaConfig {
+            spreading_factor: SpreadingFactor::Sf11,
+            bandwidth: Bandwidth::Khz125,
+            ..Default::default()
+        })
+        .release();
+
+        // and so do 16.384ms ones
+        assert_eq!(mock.last_write(Register::ModemConfig3), Some(0x0C));
+
+        let mock = radio(LoRaConfig {
+            spreading_factor: SpreadingFactor::Sf7,
+            bandwidth: Bandwidth::Khz62_5,
+            ..Default::default()
+        })
+        .release();
+
+        assert_eq!(mock.last_write(Register::ModemConfig1), Some(0x62));
+        assert_eq!(mock.last_write(Register::ModemConfig2), Some(0x74));
+    }
+
+    #[test]
+    fn optimises_for_low_data_rates_only_above_16ms_symbols() {
+        for (spreading_factor, bandwidth, optimise) in [
+            (SpreadingFactor::Sf10, Bandwidth::Khz125, false),
+            (SpreadingFactor::Sf11, Bandwidth::Khz125, true),
+            (SpreadingFactor::Sf12, Bandwidth::Khz125, true),
+            (SpreadingFactor::Sf11, Bandwidth::Khz250, false),
+            (SpreadingFactor::Sf12, Bandwidth::Khz250, true),
+            (SpreadingFactor::Sf10, Bandwidth::Khz62_5, true),
+            (SpreadingFactor::Sf12, Bandwidth::Khz500, false),
+        ] {
+            let config = LoRaConfig {
+                spreading_factor,
+                bandwidth,
+                ..Default::default()
+            };
+
+            assert_eq!(
+                config.low_data_rate_optimize(),
+                optimise,
+                "{spreading_factor:?} at {bandwidth:?}"
+            );
+        }
+    }
+
+    #[test]
+    fn sets_the_transmit_power() {
+        for (tx_power, pa_config, pa_dac, ocp) in [
+            (2, 0x80, 0x84, 0x2B),
+            (17, 0x8F, 0x84, 0x2B),
+            (20, 0x8F, 0x87, 0x31),
+        ] {
+            let mock = radio(LoRaConfig {
+                tx_power,
+                ..Default::default()
+            })
+            .release();
+
+            assert_eq!(mock.last_write(Register::PaConfig), Some(pa_config));
+            assert_eq!(mock.last_write(Register::PaDac), Some(pa_dac));
+            assert_eq!(mock.last_write(Register::Ocp), Some(ocp));
+        }
+    }
+
+    #[test]
+    fn rejects_invalid_configs_without_writing_them() {
+        for config in [
+            LoRaConfig {
+                tx_power: 18,
+                ..Default::default()
+            },
+            LoRaConfig {
+                tx_power: 1,
+                ..Default::default()
+            },
+            LoRaConfig {
+                frequency: 100_000_000,
+                ..Default::default()
+            },
+        ] {
+            let mut radio = radio(LoRaConfig::default());
+            radio.spi.writes.clear();
+
+            assert!(matches!(
+                block_on(radio.set_config(config)),
+                Err(LoRaError::InvalidConfig)
+            ));
+            assert!(radio.spi.writes.is_empty());
+            assert_eq!(radio.config(), &LoRaConfig::default());
+        }
+    }
+
+    #[test]
+    fn keeps_the_old_config_if_it_cannot_be_written() {
+        let mut radio = radio(LoRaConfig::default());
+        radio.spi.failing = true;
+
+        assert!(matches!(
+            block_on(radio.set_config(LoRaConfig {
+                tx_power: 17,
+                ..Default::default()
+            })),
+            Err(LoRaError::SpiError)
+        ));
+        assert_eq!(radio.config(), &LoRaConfig::default());
+    }
+
+    #[test]
+    fn transmits_through_the_fifo() {
+        let mut radio = radio(LoRaConfig::default());
+        radio.spi.writes.clear();
+
+        block_on(radio.transmit(b"hello ground")).unwrap();
+
+        let mock = radio.release();
+        assert_eq!(mock.fifo, b"hello ground");
+
+        let writes: Vec<(u8, u8), 8> = mock
+            .writes
+            .iter()
+            .copied()
+            .filter(|(register, _)| *register != Register::Fifo as u8)
+            .collect();
+
+        assert_eq!(
+            writes,
+            [
+                (Register::OpMode, 0x81),
+                (Register::FifoAddrPtr, 0x00),
+                (Register::PayloadLength, 12),
+                (Register::IrqFlags, 0xFF),
+                (Register::OpMode, 0x83),
+                (Register::IrqFlags, IRQ_TX_DONE),
+            ]
+            .map(|(register, value)| (register as u8, value))
+        );
+        assert_eq!(mock.registers[Register::IrqFlags as usize], 0);
+    }
+
+    #[test]
+    fn rejects_payloads_too_long_for_the_fifo() {
+        let mut radio = radio(LoRaConfig::default());
+        radio.spi.writes.clear();
+
+        assert!(matches!(
+            block_on(radio.transmit(&[0; MAX_PAYLOAD_LEN + 1])),
+            Err(LoRaError::PayloadTooLong)
+        ));
+        assert!(radio.spi.writes.is_empty());
+    }
+
+    #[test]
+    fn gives_up_on_transmissions_that_never_finish() {
+        let mut radio = radio(LoRaConfig::default());
+        radio.spi.tx_completes = false;
+
+        assert!(matches!(
+            block_on(radio.transmit(b"lost")),
+            Err(LoRaError::TxTimeout)
+        ));
+        assert_eq!(radio.spi.last_write(Register::OpMode), Some(0x81));
+    }
+}
@@ -0,0 +1,412 @@
+use core::cell::Cell;
+
+use crc::{Crc, CRC_32_ISO_HDLC};
+use embedded_storage::{ReadStorage, Storage};
+use libm::{pow, sqrt};
+use nalgebra::{ArrayStorage, Matrix3, SMatrix, SVector, Vector3};
+
+use crate::prelude::*;
+
+/// Where the calibration is stored - the start of the `calib` partition in
+/// `partitions.csv`.
+pub const MAG_CALIBRATION_OFFSET: u32 = 0x21_0000;
+
+/// How long to collect samples for while the can is being rotated.
+pub const CALIBRATION_DURATION: Duration = Duration::from_secs(20);
+/// How often to sample the magnetometer while calibrating.
+pub const CALIBRATION_INTERVAL: Duration = Duration::from_millis(50);
+/// The fewest samples an ellipsoid will be fitted to.
+pub const MIN_CALIBRATION_SAMPLES: u32 = 50;
+
+/// Roughly the largest raw reading, used to keep the fitting well conditioned.
+const RAW_SCALE: f32 = i16::MAX as f32;
+
+const MAGIC: [u8; 4] = *b"MAGC";
+const RECORD_LEN: usize = MAGIC.len() + 12 * 4 + 4;
+const CRC: Crc<u32> = Crc::<u32>::new(&CRC_32_ISO_HDLC);
+
+static MAG_CALIBRATION: Mutex<Cell<MagCalibration>> =
+    Mutex::new(Cell::new(MagCalibration::IDENTITY));
+
+/// The calibration applied to every magnetometer reading.
+pub fn mag_calibration() -> MagCalibration {
+    critical_section::with(|cs| MAG_CALIBRATION.borrow(cs).get())
+}
+
+pub fn set_mag_calibration(calibration: MagCalibration) {
+    critical_section::with(|cs| MAG_CALIBRATION.borrow(cs).set(calibration));
+}
+
+#[derive(Clone, Copy, ErrorCategory)]
+#[repr(u8)]
+pub enum CalibrationError {
+    /// Too few samples were collected to fit an ellipsoid
+    NotEnoughSamples,
+    /// The samples didn't cover enough orientations to solve for every parameter
+    Singular,
+    /// The best fit through the samples wasn't an ellipsoid
+    NotAnEllipsoid,
+    /// Nothing valid was found in storage
+    NoCalibration,
+    /// An error while reading or writing to storage
+    StorageError,
+}
+
+/// Hard and soft-iron corrections for the magnetometer.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct MagCalibration {
+    /// hard-iron offset, in raw units
+    pub offset: Vector3<f32>,
+    /// soft-iron correction, which turns the ellipsoid of readings back into a
+    /// sphere
+    pub soft_iron: Matrix3<f32>,
+}
+
+impl MagCalibration {
+    /// A calibration which leaves readings unchanged.
+    pub const IDENTITY: Self = Self {
+        offset: Vector3::from_array_storage(ArrayStorage([[0.0; 3]; 1])),
+        soft_iron: Matrix3::from_array_storage(ArrayStorage([
+            [1.0, 0.0, 0.0],
+            [0.0, 1.0, 0.0],
+            [0.0, 0.0, 1.0],
+        ])),
+    };
+
+    /// Corrects a raw magnetometer reading.
+    pub fn apply(&self, raw: Vector3<f32>) -> Vector3<f32> {
+        self.soft_iron * (raw - self.offset)
+    }
+
+    pub fn to_bytes(&self) -> [u8; RECORD_LEN] {
+        let mut bytes = [0; RECORD_LEN];
+
+        bytes[..MAGIC.len()].copy_from_slice(&MAGIC);
+
+        let values = self.offset.iter().chain(self.soft_iron.iter());
+
+        for (chunk, value) in bytes[MAGIC.len()..RECORD_LEN - 4]
+            .chunks_exact_mut(4)
+            .zip(values)
+        {
+            chunk.copy_from_slice(&value.to_le_bytes());
+        }
+
+        let crc = CRC.checksum(&bytes[..RECORD_LEN - 4]);
+        bytes[RECORD_LEN - 4..].copy_from_slice(&crc.to_le_bytes());
+
+        bytes
+    }
+
+    pub fn from_bytes(bytes: &[u8; RECORD_LEN]) -> Result<Self, CalibrationError> {
+        let (data, crc) = bytes.split_at(RECORD_LEN - 4);
+
+        if data[..MAGIC.len()] != MAGIC || CRC.checksum(data).to_le_bytes() != crc {
+            return Err(CalibrationError::NoCalibration);
+        }
+
+        let mut values = data[MAGIC.len()..]
+            .chunks_exact(4)
+            .map(|chunk| f32::from_le_bytes([chunk[0], chunk[1], chunk[2], chunk[3]]));
+
+        let offset = Vector3::from_iterator(values.by_ref().take(3));
+        let soft_iron = Matrix3::from_iterator(values);
+
+        if offset
+            .iter()
+            .chain(soft_iron.iter())
+            .any(|x| !x.is_finite())
+        {
+            return Err(CalibrationError::NoCalibration);
+        }
+
+        Ok(Self { offset, soft_iron })
+    }
+
+    /// Reads a previously stored calibration from flash.
+    pub fn load<S: ReadStorage>(storage: &mut S) -> Result<Self, CalibrationError> {
+        let mut bytes = [0; RECORD_LEN];
+
+        storage
+            .read(MAG_CALIBRATION_OFFSET, &mut bytes)
+            .map_err(|_| CalibrationError::StorageError)?;
+
+        Self::from_bytes(&bytes)
+    }
+
+    /// Writes this calibration to flash, to be loaded again on the next boot.
+    pub fn store<S: Storage>(&self, storage: &mut S) -> Result<(), CalibrationError> {
+        storage
+            .write(MAG_CALIBRATION_OFFSET, &self.to_bytes())
+            .map_err(|_| CalibrationError::StorageError)
+    }
+}
+
+impl Default for MagCalibration {
+    fn default() -> Self {
+        Self::IDENTITY
+    }
+}
+
+/// Least-squares fit of an ellipsoid to magnetometer readings.
+///
+/// Readings are accumulated straight into the normal equations, so any number
+/// can be fed in without storing them.
+#[derive(Clone, Debug)]
+pub struct EllipsoidFit {
+    /// Readings are divided by this before fitting.
+    scale: f64,
+    ata: SMatrix<f64, 9, 9>,
+    atb: SVector<f64, 9>,
+    count: u32,
+}
+
+impl EllipsoidFit {
+    /// `scale` should be roughly the magnitude of the largest reading.
+    pub fn new(scale: f32) -> Self {
+        Self {
+            scale: scale as f64,
+            ata: SMatrix::zeros(),
+            atb: SVector::zeros(),
+            count: 0,
+        }
+    }
+
+    pub fn count(&self) -> u32 {
+        self.count
+    }
+
+    pub fn push(&mut self, sample: Vector3<f32>) {
+        let v = sample.cast::<f64>() / self.scale;
+        let (x, y, z) = (v.x, v.y, v.z);
+
+        // x^2, y^2, z^2, 2xy, 2xz, 2yz, 2x, 2y, 2z
+        let row = SVector::<f64, 9>::from([
+            x * x,
+            y * y,
+            z * z,
+            2.0 * x * y,
+            2.0 * x * z,
+            2.0 * y * z,
+            2.0 * x,
+            2.0 * y,
+            2.0 * z,
+        ]);
+
+        self.ata += row * row.transpose();
+        self.atb += row;
+        self.count += 1;
+    }
+
+    /// Fits `v^T Q v + 2 b^T v = 1` to the readings, and turns the result into
+    /// the offset and matrix which map it back onto a sphere.
+    pub fn fit(&self) -> Result<MagCalibration, CalibrationError> {
+        if self.count < MIN_CALIBRATION_SAMPLES {
+            return Err(CalibrationError::NotEnoughSamples);
+        }
+
+        let p = self
+            .ata
+            .cholesky()
+            .ok_or(CalibrationError::Singular)?
+            .solve(&self.atb);
+
+        let q = Matrix3::new(p[0], p[3], p[4], p[3], p[1], p[5], p[4], p[5], p[2]);
+        let b = Vector3::new(p[6], p[7], p[8]);
+
+        let center = -(q.try_inverse().ok_or(CalibrationError::Singular)? * b);
+
+        // (v - center)^T Q (v - center) = 1 + center^T Q center
+        let k = 1.0 + center.dot(&(q * center));
+
+        if k <= 0.0 {
+            return Err(CalibrationError::NotAnEllipsoid);
+        }
+
+        let eigen = (q / k).symmetric_eigen();
+
+        if eigen.eigenvalues.iter().any(|&l| l <= 0.0) {
+            return Err(CalibrationError::NotAnEllipsoid);
+        }
+
+        let sqrt_m = eigen.eigenvectors
+            * Matrix3::from_diagonal(&eigen.eigenvalues.map(sqrt))
+            * eigen.eigenvectors.transpose();
+
+        // keep the corrected readings the same size as the original field, by
+        // scaling to the geometric mean of the ellipsoid's radii
+        let radius = pow(eigen.eigenvalues.iter().product(), -1.0 / 6.0);
+
+        Ok(MagCalibration {
+            offset: (center * self.scale).cast::<f32>(),
+            soft_iron: (sqrt_m * radius).cast::<f32>(),
+        })
+    }
+}
+
+/// Samples `read` for `duration` while the can is rotated through as many
+/// orientations as possible, then fits a calibration to the readings.
+pub async fn calibrate<E: core::fmt::Debug>(
+    mut read: impl FnMut() -> Result<Vector3<f32>, E>,
+    duration: Duration,
+) -> Result<MagCalibration, CalibrationError> {
+    let mut fit = EllipsoidFit::new(RAW_SCALE);
+    let mut ticker = Ticker::every(CALIBRATION_INTERVAL);
+
+    info!("Magnetometer calibration started - slowly rotate the can in every direction");
+
+    let end = Instant::now() + duration;
+
+    while Instant::now() < end {
+        match read() {
+            Ok(sample) => fit.push(sample),
+            Err(e) => trace!("{e:?}"),
+        }
+
+        ticker.next().await;
+    }
+
+    info!("Collected {} samples, fitting", fit.count());
+
+    fit.fit()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_utils::{assert_close, Noise};
+
+    /// A typical field strength, in raw units.
+    const FIELD: f32 = 2_000.0;
+
+    const OFFSET: Vector3<f32> = Vector3::new(350.0, -1_200.0, 80.0);
+
+    /// Soft-iron distortion - symmetric, as the part of it that rotates the
+    /// readings can't be told apart from the can being rotated.
+    fn distortion() -> Matrix3<f32> {
+        Matrix3::new(1.25, 0.10, -0.05, 0.10, 0.85, 0.08, -0.05, 0.08, 1.05)
+    }
+
+    /// `count` directions spread evenly over a sphere.
+    fn sphere(count: usize) -> impl Iterator<Item = Vector3<f32>> {
+        let golden_angle = core::f32::consts::PI * (3.0 - 5.0_f32.sqrt());
+
+        (0..count).map(move |i| {
+            let z = 1.0 - 2.0 * (i as f32 + 0.5) / count as f32;
+            let r = (1.0 - z * z).sqrt();
+            let theta = golden_angle * i as f32;
+            Vector3::new(r * theta.cos(), r * theta.sin(), z)
+        })
+    }
+
+    /// Fits readings of a field distorted by [OFFSET] and [distortion], with
+    /// up to `noise` raw units of noise on each axis.
+    fn fit_distorted(count: usize, noise: f32) -> MagCalibration {
+        let mut fit = EllipsoidFit::new(RAW_SCALE);
+        let mut rng = Noise(7);
+
+        for direction in sphere(count) {
+            fit.push(distortion() * direction * FIELD + OFFSET + rng.vector(noise));
+        }
+
+        assert_eq!(fit.count(), count as u32);
+
+        fit.fit().unwrap()
+    }
+
+    /// The soft-iron correction that undoes [distortion], keeping the volume
+    /// of the ellipsoid.
+    fn expected_soft_iron() -> Matrix3<f32> {
+        distortion().try_inverse().unwrap() * distortion().determinant().cbrt()
+    }
+
+    #[test]
+    fn recovers_offset_and_soft_iron() {
+        let calibration = fit_distorted(500, 0.0);
+
+        for (fitted, expected) in calibration.offset.iter().zip(OFFSET.iter()) {
+            assert_close(*fitted, *expected, 0.5);
+        }
+
+        for (fitted, expected) in calibration
+            .soft_iron
+            .iter()
+            .zip(expected_soft_iron().iter())
+        {
+            assert_close(*fitted, *expected, 1e-3);
+        }
+    }
+
+    #[test]
+    fn recovers_offset_and_soft_iron_from_noisy_readings() {
+        // 1% noise
+        let calibration = fit_distorted(2_000, 20.0);
+
+        for (fitted, expected) in calibration.offset.iter().zip(OFFSET.iter()) {
+            assert_close(*fitted, *expected, 5.0);
+        }
+
+        for (fitted, expected) in calibration
+            .soft_iron
+            .iter()
+            .zip(expected_soft_iron().iter())
+        {
+            assert_close(*fitted, *expected, 0.01);
+        }
+    }
+
+    #[test]
+    fn corrected_readings_lie_on_a_sphere() {
+        let calibration = fit_distorted(500, 0.0);
+        let radius = FIELD * distortion().determinant().cbrt();
+
+        // including directions that weren't fitted to
+        for direction in sphere(37) {
+            let corrected = calibration.apply(distortion() * direction * FIELD + OFFSET);
+
+            assert_close(corrected.norm(), radius, radius * 1e-3);
+            assert!(corrected.normalize().dot(&direction) > 0.999);
+        }
+    }
+
+    #[test]
+    fn needs_enough_samples() {
+        let mut fit = EllipsoidFit::new(RAW_SCALE);
+
+        for direction in sphere(MIN_CALIBRATION_SAMPLES as usize - 1) {
+            fit.push(direction * FIELD);
+        }
+
+        assert!(matches!(fit.fit(), Err(CalibrationError::NotEnoughSamples)));
+    }
+
+    #[test]
+    fn needs_more_than_one_plane() {
+        let mut fit = EllipsoidFit::new(RAW_SCALE);
+
+        // only ever turned around one axis
+        for i in 0..100 {
+            let angle = i as f32 * 0.1;
+            fit.push(Vector3::new(angle.cos(), angle.sin(), 0.0) * FIELD + OFFSET);
+        }
+
+        assert!(matches!(
+            fit.fit(),
+            Err(CalibrationError::Singular | CalibrationError::NotAnEllipsoid)
+        ));
+    }
+
+    #[test]
+    fn round_trips_through_storage_bytes() {
+        let calibration = fit_distorted(200, 0.0);
+        let mut bytes = calibration.to_bytes();
+
+        assert_eq!(MagCalibration::from_bytes(&bytes).unwrap(), calibration);
+
+        bytes[10] ^= 1;
+        assert!(matches!(
+            MagCalibration::from_bytes(&bytes),
+            Err(CalibrationError::NoCalibration)
+        ));
+    }
+}
@@ -2,32 +2,56 @@
 #![no_main]
 #![feature(type_alias_impl_trait)]
 
-
 use cansat::{
+    ahrs::{ahrs_stream, AhrsConfig},
+    apogee::{apogee_detector, ApogeeConfig},
     blink::blink,
-    // bme280::{bme280_stream, BME280},
+    bme280::{bme280_stream, BME280},
+    compass::{level_heading, tilt_compensated_heading},
     display::{display_numerical_data, Display},
-    mpu6050::mpu6050_stream,
+    errors::journal,
+    flight::{flight_state, FlightConfig},
+    gps::{configure_ubx, gps_stream, ubx::UbxConfig, GpsProtocol, GPS_BAUD_RATE},
+    health::{self, health_monitor, Component, HealthConfig},
+    logger::{
+        blackbox::{blackbox_recorder, dump, BlackBox, BLACKBOX_LEN, BLACKBOX_OFFSET},
+        sd::{sd_logger, INIT_FREQUENCY_KHZ},
+    },
+    lora::{lora_downlink, LoRaConfig, LoRaSpiBus, Sx127x, DEFAULT_TELEMETRY_INTERVAL},
+    mag_calibration::{calibrate, set_mag_calibration, MagCalibration, CALIBRATION_DURATION},
+    mpu6050::{mpu6050_stream, MPU_BUS},
     prelude::*,
+    qmc5883l::{qmc5883l_stream, MagConfig, MAG_BUS, QMC5883L},
+    vertical::{vertical_stream, KalmanConfig},
+    watchdog::{self, watchdog_supervisor, TaskId, WatchdogConfig},
 };
 
 use hal::{
-    clock::ClockControl,
+    clock::{ClockControl, Clocks},
+    dma::DmaPriority,
     i2c::*,
-    peripherals::{Peripherals, I2C0},
+    interrupt::{self, Priority},
+    pdma::Dma,
+    peripherals::{Interrupt, Peripherals, I2C0},
+    spi::{
+        master::{prelude::*, Spi},
+        SpiMode,
+    },
     timer::TimerGroup,
+    uart::{config::Config as UartConfig, TxRxPins},
     xtensa_lx::singleton,
-    IO,
+    Rng, Uart, IO,
 };
 
 use mpu6050::Mpu6050;
-use qmc5883l::QMC5883L;
 
-use embedded_hal_bus::i2c::CriticalSectionDevice;
-use embassy_time::Ticker;
+use embassy_embedded_hal::shared_bus::asynch::spi::SpiDevice;
 use embassy_executor::Spawner;
+use embassy_time::Ticker;
+use embedded_hal_bus::{i2c::CriticalSectionDevice, spi::ExclusiveDevice};
+use embedded_sdmmc::SdCard;
 use esp_println::println;
-use libm::atan2;
+use esp_storage::FlashStorage;
 
 use core::cell::RefCell;
 
@@ -41,16 +65,25 @@ async fn main(spawner: Spawner) -> ! {
 
     let io = IO::new(peripherals.GPIO, peripherals.IO_MUX);
 
-    let clocks = ClockControl::boot_defaults(system.clock_control).freeze();
+    //kept forever, so the sd card logger can change its bus frequency
+    let clocks: &'static Clocks = singleton!(
+        : Clocks<'static> = ClockControl::boot_defaults(system.clock_control).freeze()
+    )
+    .unwrap();
+
+    let timer_group0 = TimerGroup::new(peripherals.TIMG0, clocks);
 
-    let timer_group0 = TimerGroup::new(peripherals.TIMG0, &clocks);
+    embassy::init(clocks, timer_group0);
 
-    embassy::init(&clocks, timer_group0);
+    let wdt = TimerGroup::new(peripherals.TIMG1, clocks).wdt;
 
     // To change the log_level change the env section in .cargo/config.toml or remove it and set ESP_LOGLEVEL manually before running cargo run this requires a clean rebuild because of https://github.com/rust-lang/cargo/issues/10358
     #[cfg(feature = "log")]
     esp_println::logger::init_logger_from_env();
     info!("Logger is setup");
+
+    //so retries from different tasks don't line up
+    seed_jitter(Rng::new(peripherals.RNG).random());
     println!("Hello world!");
 
     // #[cfg(feature = "net")]
@@ -68,7 +101,7 @@ async fn main(spawner: Spawner) -> ! {
     let scl = io.pins.gpio22;
     let sda = io.pins.gpio21;
 
-    let i2c = I2C::new(peripherals.I2C0, sda, scl, 400u32.kHz(), &clocks);
+    let i2c = I2C::new(peripherals.I2C0, sda, scl, 400u32.kHz(), clocks);
 
     //we must share the i2c bus between the two, as otherwise the functions want to "own" the i2c bus themselves
     let i2c_mutex =
@@ -83,46 +116,190 @@ async fn main(spawner: Spawner) -> ! {
 
     let mpu = Mpu6050::new(CriticalSectionDevice::new(i2c_mutex));
 
-    // let bme = BME280::new(shared_i2c.acquire_i2c()).map_err(|e| { error!("{e:?}") }).unwrap();
-
     spawner.spawn(blink(led.degrade())).unwrap();
     spawner.spawn(display_numerical_data(display)).unwrap();
 
     spawner.spawn(mpu6050_stream(mpu)).unwrap();
-    // spawner.spawn(bme280_stream(bme)).unwrap();
-
-    let mut ticker = Ticker::every(Duration::from_secs(1));
 
-    let mut qmc = QMC5883L::new(CriticalSectionDevice::new(i2c_mutex)).unwrap();
-
-    qmc.continuous().expect("init failed");
+    //without the barometer the vertical filter carries on from the IMU alone
+    match BME280::new(CriticalSectionDevice::new(i2c_mutex)).await {
+        Ok(bme) => spawner.spawn(bme280_stream(bme)).unwrap(),
+        Err(e) => {
+            error!("BME280 initialisation failed: {e:?}");
+            journal::record(e);
+        }
+    }
 
-    loop {
-        trace!("KeepAlive tick");
+    spawner.spawn(ahrs_stream(AhrsConfig::default())).unwrap();
+    spawner
+        .spawn(vertical_stream(KalmanConfig::default()))
+        .unwrap();
+    spawner
+        .spawn(apogee_detector(ApogeeConfig::default()))
+        .unwrap();
+    spawner
+        .spawn(flight_state(FlightConfig::default()))
+        .unwrap();
+    spawner
+        .spawn(health_monitor(HealthConfig::default()))
+        .unwrap();
+
+    //without the magnetometer there is no heading, and the AHRS carries on from the IMU alone
+    match QMC5883L::new(CriticalSectionDevice::new(i2c_mutex), MagConfig::default()) {
+        Ok(mut qmc) => {
+            let mut flash = FlashStorage::new();
+
+            //hold the BOOT button while starting up to recalibrate the magnetometer
+            let calibrate_button = io.pins.gpio0.into_pull_up_input();
+
+            if calibrate_button.is_low().unwrap() {
+                match calibrate(|| qmc.raw_mag(), CALIBRATION_DURATION).await {
+                    Ok(calibration) => {
+                        info!("{calibration:?}");
+                        calibration.store(&mut flash).print_warn();
+                        set_mag_calibration(calibration);
+                    }
+                    Err(e) => {
+                        error!("Magnetometer calibration failed: {e:?}");
+                        journal::record(e);
+                    }
+                }
+            } else {
+                match MagCalibration::load(&mut flash) {
+                    Ok(calibration) => set_mag_calibration(calibration),
+                    Err(e) => warn!("No magnetometer calibration loaded: {e:?}"),
+                }
+            }
 
-        if let Ok(temp) = qmc.temp() {
-            let temp = temp.wrapping_neg() / 128;
-            info!("Temperature: {:?}", temp);
+            spawner.spawn(qmc5883l_stream(qmc)).unwrap();
+        }
+        Err(e) => {
+            error!("Magnetometer initialisation failed: {e:?}");
+            journal::record(e);
+            health::report_error(Component::Mag);
         }
+    }
+
+    match BlackBox::recover(FlashStorage::new(), BLACKBOX_OFFSET, BLACKBOX_LEN) {
+        Ok(mut blackbox) => {
+            //ground GPIO4 while starting up to print the black box over uart after a flight,
+            //instead of recording over it
+            let dump_jumper = io.pins.gpio4.into_pull_up_input();
 
-        match qmc.mag() {
-            Ok((x, y, z)) => {
-                let mut heading = atan2(y as f64, x as f64) + DECLINATION_RADS;
+            if dump_jumper.is_low().unwrap() {
+                dump(&mut blackbox).print_error();
 
-                if heading < 0.0 {
-                    heading += 2.0 * PI;
-                } else if heading > 2.0 * PI {
-                    heading -= 2.0 * PI;
+                loop {
+                    Timer::after(Duration::from_secs(1)).await;
                 }
+            }
 
-                let heading_degrees = heading * 180.0 / PI;
+            spawner.spawn(blackbox_recorder(blackbox)).unwrap();
+        }
+        Err(e) => {
+            error!("Black box unavailable: {e:?}");
+            journal::record(e);
+        }
+    }
+
+    //the sd card has the VSPI bus to itself - it must start at <=400kHz to initialise, and is
+    //sped up once it has been
+    let sd_spi = Spi::new(
+        peripherals.SPI3,
+        INIT_FREQUENCY_KHZ.kHz(),
+        SpiMode::Mode0,
+        clocks,
+    )
+    .with_sck(io.pins.gpio18)
+    .with_mosi(io.pins.gpio23)
+    .with_miso(io.pins.gpio19);
+    let sd_cs = io.pins.gpio5.into_push_pull_output().degrade();
+
+    let sd_card = SdCard::new(ExclusiveDevice::new(sd_spi, sd_cs, Delay), Delay);
+
+    spawner.spawn(sd_logger(sd_card, clocks)).unwrap();
+
+    //the radio gets the HSPI bus, with MISO moved off GPIO12 as it is a strapping pin
+    let dma = Dma::new(system.dma);
+    let lora_descriptors = singleton!(: [u32; 8 * 3] = [0; 8 * 3]).unwrap();
+    let lora_rx_descriptors = singleton!(: [u32; 8 * 3] = [0; 8 * 3]).unwrap();
+
+    let lora_spi = Spi::new(peripherals.SPI2, 1u32.MHz(), SpiMode::Mode0, clocks)
+        .with_sck(io.pins.gpio14)
+        .with_mosi(io.pins.gpio13)
+        .with_miso(io.pins.gpio27)
+        .with_dma(dma.spi2channel.configure(
+            false,
+            lora_descriptors,
+            lora_rx_descriptors,
+            DmaPriority::Priority0,
+        ));
+
+    let lora_bus = singleton!(: LoRaSpiBus = embassy_sync::mutex::Mutex::new(lora_spi)).unwrap();
+    let lora_cs = io.pins.gpio26.into_push_pull_output().degrade();
+
+    match Sx127x::new(SpiDevice::new(lora_bus, lora_cs), LoRaConfig::default()).await {
+        Ok(radio) => spawner
+            .spawn(lora_downlink(radio, DEFAULT_TELEMETRY_INTERVAL))
+            .unwrap(),
+        Err(e) => {
+            error!("LoRa radio initialisation failed: {e:?}");
+            journal::record(e);
+        }
+    }
 
-                info!(
-                    "x={:.1}, y={:.1}, z={:.1}: heading={:.1} degrees",
-                    x, y, z, heading_degrees
-                );
+    //the gps starts out sending NMEA at 9600 baud, and is switched over to UBX if it is a u-blox
+    let mut gps_uart = Uart::new_with_config(
+        peripherals.UART2,
+        UartConfig::default().baudrate(GPS_BAUD_RATE),
+        Some(TxRxPins::new_tx_rx(
+            io.pins.gpio17.into_push_pull_output(),
+            io.pins.gpio16.into_floating_input(),
+        )),
+        clocks,
+    );
+    interrupt::enable(Interrupt::UART2, Priority::Priority1).unwrap();
+
+    let gps_protocol =
+        match configure_ubx(&mut gps_uart, &UbxConfig::default(), |uart, baud_rate| {
+            uart.change_baud(baud_rate, clocks)
+        })
+        .await
+        {
+            Ok(()) => GpsProtocol::Ubx,
+            Err(e) => {
+                warn!("GPS not configured, falling back to NMEA: {e:?}");
+                journal::record(e);
+                GpsProtocol::Nmea
             }
-            Err(e) => info!("Error {:?}", e),
+        };
+
+    spawner.spawn(gps_stream(gps_uart, gps_protocol)).unwrap();
+
+    // only now that the loggers are running, so it makes it into the logs
+    watchdog::report_last_reset();
+
+    let heartbeat = watchdog::register(TaskId::Main, Duration::from_secs(3));
+    spawner
+        .spawn(watchdog_supervisor(wdt, WatchdogConfig::default()))
+        .unwrap();
+
+    let mut ticker = Ticker::every(Duration::from_secs(1));
+
+    loop {
+        trace!("KeepAlive tick");
+        heartbeat.beat();
+
+        if let Some(mag) = MAG_BUS.latest().and_then(|sample| sample.data.mag.valid()) {
+            let heading = match MPU_BUS.latest().and_then(|sample| sample.data.acc.valid()) {
+                Some(acc) => tilt_compensated_heading(acc, mag, DECLINATION_RADS as f32),
+                None => level_heading(mag, DECLINATION_RADS as f32),
+            };
+
+            info!(
+                "mag=({:.3}, {:.3}, {:.3}) gauss: heading={}",
+                mag.x, mag.y, mag.z, heading
+            );
         }
 
         ticker.next().await;
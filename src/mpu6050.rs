@@ -1,15 +1,27 @@
 use core::fmt::Debug;
 
-use crate::prelude::*;
-
-use embassy_sync::{blocking_mutex::raw::CriticalSectionRawMutex, signal::Signal};
+use crate::{
+    bus::{BusSubscriber, DataBus},
+    prelude::*,
+    sample::{Reading, Sample},
+};
+#[cfg(target_arch = "xtensa")]
+use crate::{
+    errors::journal,
+    sample::{Sequencer, SourceId},
+    watchdog::{self, TaskId},
+};
+
+#[cfg(target_arch = "xtensa")]
 use embassy_time::Delay;
 
+#[cfg(target_arch = "xtensa")]
 use mpu6050::*;
 use nalgebra::{Vector2, Vector3};
 
-pub type MpuSignal = Signal<CriticalSectionRawMutex, MpuData>;
-pub static MPU_SIGNAL: MpuSignal = Signal::new();
+pub type MpuBus = DataBus<Sample<MpuData>, 8, 8>;
+pub static MPU_BUS: MpuBus = DataBus::new();
+pub type MpuSubscriber = BusSubscriber<'static, Sample<MpuData>, 8, 8>;
 
 #[derive(Clone, Copy, ErrorCategory)]
 #[repr(u8)]
@@ -18,14 +30,33 @@ pub enum MpuError {
     ReadoutFailed,
 }
 
-#[derive(Debug)]
+#[derive(Clone, Copy, Debug)]
 pub struct MpuData {
-    pub roll_pitch: Vector2<f32>,
-    pub temp: f32,
-    pub gyro: Vector3<f32>,
-    pub acc: Vector3<f32>,
+    /// roll and pitch estimated from the accelerometer, in radians
+    pub roll_pitch: Reading<Vector2<f32>>,
+    /// temperature in degrees celsius
+    pub temp: Reading<f32>,
+    /// angular rate in degrees per second
+    pub gyro: Reading<Vector3<f32>>,
+    /// acceleration in g
+    pub acc: Reading<Vector3<f32>>,
+}
+
+impl MpuData {
+    /// Every reading marked as failed with `error`.
+    pub fn invalid(error: MpuError) -> Self {
+        let code = error.fault_code().code();
+
+        Self {
+            roll_pitch: Reading::Invalid(code),
+            temp: Reading::Invalid(code),
+            gyro: Reading::Invalid(code),
+            acc: Reading::Invalid(code),
+        }
+    }
 }
 
+#[cfg(target_arch = "xtensa")]
 #[task]
 pub async fn get_sensor_data(mut mpu: Mpu6050<SharedI2C>) {
     let mut delay = Delay;
@@ -53,6 +84,7 @@ pub async fn get_sensor_data(mut mpu: Mpu6050<SharedI2C>) {
     }
 }
 
+#[cfg(target_arch = "xtensa")]
 #[task]
 pub async fn mpu6050_stream(
     mut mpu: Mpu6050<SharedI2C>,
@@ -60,19 +92,46 @@ pub async fn mpu6050_stream(
 ) {
     let mut delay = Delay;
 
-    mpu.init(&mut delay).unwrap();
+    // carries on without it, so everything downstream sees the readings as
+    // failed rather than missing
+    let initialised = Backoff::new(|| mpu.init(&mut delay))
+        .with_log_level(log::Level::Warn)
+        .retry()
+        .await
+        .map_err(|e| {
+            error!("MPU6050 initialisation failed: {e:?}");
+            journal::record(MpuError::InitFailed);
+        })
+        .is_ok();
+
+    // registered only now, as the retries can take a few seconds
+    let heartbeat = watchdog::register(TaskId::Mpu, Duration::from_secs(1));
+    let mut sequencer = Sequencer::new(SourceId::Mpu6050);
 
     loop {
-        let mpu_data = MpuData {
-            roll_pitch: mpu.get_acc_angles().print_warn(),
-            temp: mpu.get_temp().print_warn(),
-            gyro: mpu.get_gyro().print_warn().map(|x| x.to_degrees()),
-            acc: mpu.get_acc().print_warn(),
+        heartbeat.beat();
+
+        let time = Instant::now();
+
+        let failed = MpuError::ReadoutFailed;
+
+        let mpu_data = if initialised {
+            MpuData {
+                roll_pitch: Reading::from_result(mpu.get_acc_angles(), failed),
+                temp: Reading::from_result(mpu.get_temp(), failed),
+                gyro: Reading::from_result(mpu.get_gyro(), failed)
+                    .map(|gyro| gyro.map(|x| x.to_degrees())),
+                acc: Reading::from_result(mpu.get_acc(), failed),
+            }
+        } else {
+            MpuData::invalid(MpuError::InitFailed)
         };
 
-        trace!("{mpu_data:?}");
+        let sample = sequencer.stamp_at(time, mpu_data);
+
+        trace!("{sample:?}");
 
-        MPU_SIGNAL.signal(mpu_data);
+        MPU_BUS.publish(sample);
 
         Timer::after_millis(100).await;
     }
@@ -0,0 +1,252 @@
+use nalgebra::Vector3;
+use qmc5883l::{FieldRange, OutputDataRate, OversampleRate};
+
+use crate::{
+    bus::{BusSubscriber, DataBus},
+    prelude::*,
+    sample::{Reading, Sample},
+};
+#[cfg(target_arch = "xtensa")]
+use crate::{
+    mag_calibration::mag_calibration,
+    sample::{Sequencer, SourceId},
+    watchdog::{self, TaskId},
+};
+
+pub type MagBus = DataBus<Sample<MagData>, 4, 8>;
+pub static MAG_BUS: MagBus = DataBus::new();
+pub type MagSubscriber = BusSubscriber<'static, Sample<MagData>, 4, 8>;
+
+#[derive(Clone, Copy, ErrorCategory)]
+#[repr(u8)]
+pub enum MagError {
+    /// The QMC5883L didn't respond when it was created
+    InitFailed,
+    /// The data rate, range or oversampling couldn't be set
+    ConfigFailed,
+    ReadoutFailed,
+}
+
+#[cfg(target_arch = "xtensa")]
+type Result<T> = core::result::Result<T, MagError>;
+
+/// How often the QMC5883L takes a new measurement.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum DataRate {
+    #[default]
+    Hz10,
+    Hz50,
+    Hz100,
+    Hz200,
+}
+
+impl DataRate {
+    /// The time between two measurements.
+    pub fn interval(&self) -> Duration {
+        match self {
+            Self::Hz10 => Duration::from_millis(100),
+            Self::Hz50 => Duration::from_millis(20),
+            Self::Hz100 => Duration::from_millis(10),
+            Self::Hz200 => Duration::from_millis(5),
+        }
+    }
+}
+
+impl From<DataRate> for OutputDataRate {
+    fn from(value: DataRate) -> Self {
+        match value {
+            DataRate::Hz10 => Self::Rate10Hz,
+            DataRate::Hz50 => Self::Rate50Hz,
+            DataRate::Hz100 => Self::Rate100Hz,
+            DataRate::Hz200 => Self::Rate200Hz,
+        }
+    }
+}
+
+/// The largest field the QMC5883L can measure.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Range {
+    /// ±2 gauss - the earth's field is at most ~0.65 gauss, so this is the most
+    /// precise
+    #[default]
+    Gauss2,
+    /// ±8 gauss
+    Gauss8,
+}
+
+impl Range {
+    /// How many LSBs of a raw reading make up one gauss.
+    pub fn sensitivity(&self) -> f32 {
+        match self {
+            Self::Gauss2 => 12_000.0,
+            Self::Gauss8 => 3_000.0,
+        }
+    }
+}
+
+impl From<Range> for FieldRange {
+    fn from(value: Range) -> Self {
+        match value {
+            Range::Gauss2 => Self::Range2Gauss,
+            Range::Gauss8 => Self::Range8Gauss,
+        }
+    }
+}
+
+/// How many internal readings the QMC5883L filters into each measurement -
+/// higher values are less noisy but use more power.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Oversampling {
+    #[default]
+    X512,
+    X256,
+    X128,
+    X64,
+}
+
+impl From<Oversampling> for OversampleRate {
+    fn from(value: Oversampling) -> Self {
+        match value {
+            Oversampling::X512 => Self::Rate512,
+            Oversampling::X256 => Self::Rate256,
+            Oversampling::X128 => Self::Rate128,
+            Oversampling::X64 => Self::Rate64,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct MagConfig {
+    /// Defaults to 10Hz.
+    pub data_rate: DataRate,
+    /// Defaults to ±2 gauss.
+    ///
+    /// The stored calibration is in raw units, so the magnetometer must be
+    /// recalibrated after changing this.
+    pub range: Range,
+    /// Defaults to 512 times.
+    pub oversampling: Oversampling,
+    /// The QMC5883L's temperature sensor is only calibrated for its slope (100
+    /// LSB per degree), so this offset (in degrees celsius) has to be found for
+    /// each chip against a known temperature.
+    ///
+    /// Defaults to 0.
+    pub temperature_offset: f32,
+}
+
+impl Default for MagConfig {
+    fn default() -> Self {
+        Self {
+            data_rate: DataRate::default(),
+            range: Range::default(),
+            oversampling: Oversampling::default(),
+            temperature_offset: 0.0,
+        }
+    }
+}
+
+/// LSB per degree celsius of the temperature sensor.
+#[cfg(target_arch = "xtensa")]
+const TEMPERATURE_SENSITIVITY: f32 = 100.0;
+
+#[derive(Clone, Copy, Debug)]
+pub struct MagData {
+    /// calibrated magnetic field in gauss
+    pub mag: Reading<Vector3<f32>>,
+    /// temperature in degrees celsius
+    pub temp: Reading<f32>,
+}
+
+#[cfg(target_arch = "xtensa")]
+pub struct QMC5883L {
+    pub qmc: qmc5883l::QMC5883L<SharedI2C>,
+    config: MagConfig,
+}
+
+#[cfg(target_arch = "xtensa")]
+impl QMC5883L {
+    pub fn new(i2c: SharedI2C, config: MagConfig) -> Result<Self> {
+        let mut qmc5883l = Self {
+            qmc: qmc5883l::QMC5883L::new(i2c).map_err(|_| MagError::InitFailed)?,
+            config,
+        };
+
+        qmc5883l.configure()?;
+
+        Ok(qmc5883l)
+    }
+
+    pub fn config(&self) -> &MagConfig {
+        &self.config
+    }
+
+    pub fn set_config(&mut self, config: MagConfig) -> Result<()> {
+        self.config = config;
+        self.configure()
+    }
+
+    /// Writes the current config to the QMC5883L and starts it measuring
+    /// continuously.
+    pub fn configure(&mut self) -> Result<()> {
+        self.qmc
+            .set_output_data_rate(self.config.data_rate.into())
+            .map_err(|_| MagError::ConfigFailed)?;
+        self.qmc
+            .set_field_range(self.config.range.into())
+            .map_err(|_| MagError::ConfigFailed)?;
+        self.qmc
+            .set_oversample(self.config.oversampling.into())
+            .map_err(|_| MagError::ConfigFailed)?;
+        self.qmc.continuous().map_err(|_| MagError::ConfigFailed)?;
+
+        Ok(())
+    }
+
+    /// The uncalibrated reading, in raw units.
+    pub fn raw_mag(&mut self) -> Result<Vector3<f32>> {
+        let (x, y, z) = self.qmc.mag().map_err(|_| MagError::ReadoutFailed)?;
+
+        Ok(Vector3::new(x as f32, y as f32, z as f32))
+    }
+
+    /// The calibrated reading, in gauss.
+    pub fn mag(&mut self) -> Result<Vector3<f32>> {
+        let raw = self.raw_mag()?;
+
+        Ok(mag_calibration().apply(raw) / self.config.range.sensitivity())
+    }
+
+    /// The temperature in degrees celsius.
+    pub fn temp(&mut self) -> Result<f32> {
+        let raw = self.qmc.temp().map_err(|_| MagError::ReadoutFailed)?;
+
+        Ok(raw as f32 / TEMPERATURE_SENSITIVITY + self.config.temperature_offset)
+    }
+}
+
+#[cfg(target_arch = "xtensa")]
+#[task]
+pub async fn qmc5883l_stream(mut qmc: QMC5883L) {
+    let heartbeat = watchdog::register(TaskId::Mag, Duration::from_secs(1));
+    let mut ticker = Ticker::every(qmc.config().data_rate.interval());
+    let mut sequencer = Sequencer::new(SourceId::Qmc5883l);
+
+    loop {
+        heartbeat.beat();
+
+        let time = Instant::now();
+
+        let mag_data = MagData {
+            mag: Reading::from_result(qmc.mag(), MagError::ReadoutFailed),
+            temp: Reading::from_result(qmc.temp(), MagError::ReadoutFailed),
+        };
+
+        let sample = sequencer.stamp_at(time, mag_data);
+
+        trace!("{sample:?}");
+
+        MAG_BUS.publish(sample);
+
+        ticker.next().await;
+    }
+}
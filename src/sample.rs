@@ -0,0 +1,190 @@
+use core::fmt::Debug;
+
+use crate::{errors::journal, prelude::*};
+
+/// Which sensor a [Sample] was read from.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+#[repr(u8)]
+pub enum SourceId {
+    Mpu6050,
+    Bme280,
+    Qmc5883l,
+    Gps,
+}
+
+/// A single reading from a sensor, along with when it was taken.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Sample<T> {
+    /// When the reading was captured.
+    pub time: Instant,
+    /// Counts up by one for every reading from `source`, so gaps show where
+    /// readings were lost.
+    pub seq: u32,
+    pub source: SourceId,
+    pub data: T,
+}
+
+impl<T> Sample<T> {
+    /// Turns the data into something derived from it, keeping the capture time,
+    /// sequence number and source of the original reading.
+    pub fn map<U>(self, f: impl FnOnce(T) -> U) -> Sample<U> {
+        Sample {
+            time: self.time,
+            seq: self.seq,
+            source: self.source,
+            data: f(self.data),
+        }
+    }
+
+    /// How long after `earlier` this sample was captured.
+    pub fn since<U>(&self, earlier: &Sample<U>) -> Duration {
+        self.time.saturating_duration_since(earlier.time)
+    }
+}
+
+/// A value read from a sensor, or the reason it couldn't be read.
+///
+/// Failed reads are kept distinct from real readings, rather than being
+/// replaced with zeros, so consumers can throw them away.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Reading<T> {
+    Valid(T),
+    /// The read failed - holds the code of the sensor's error within its
+    /// category (the low byte of its [FaultCode]).
+    Invalid(u8),
+}
+
+impl<T> Reading<T> {
+    /// Takes the value out of `result`, or logs the error as a warning, records
+    /// `fault` in the [journal] and marks the reading as invalid with its code.
+    ///
+    /// Sensors are read every loop, so the warning is rate-limited per caller
+    /// (see [PrintErr]).
+    #[track_caller]
+    pub fn from_result<E: Debug>(result: Result<T, E>, fault: impl Fault) -> Self {
+        match result.map(Some).print_warn_limited() {
+            Some(value) => Self::Valid(value),
+            None => {
+                journal::record(fault);
+                Self::Invalid(fault.fault_code().code())
+            }
+        }
+    }
+
+    pub fn is_valid(&self) -> bool {
+        matches!(self, Self::Valid(_))
+    }
+
+    /// The value, or [None] if the read failed.
+    pub fn valid(self) -> Option<T> {
+        match self {
+            Self::Valid(value) => Some(value),
+            Self::Invalid(_) => None,
+        }
+    }
+
+    pub fn as_valid(&self) -> Option<&T> {
+        match self {
+            Self::Valid(value) => Some(value),
+            Self::Invalid(_) => None,
+        }
+    }
+
+    /// The error code, or [None] if the read succeeded.
+    pub fn error_code(&self) -> Option<u8> {
+        match self {
+            Self::Valid(_) => None,
+            Self::Invalid(code) => Some(*code),
+        }
+    }
+
+    pub fn map<U>(self, f: impl FnOnce(T) -> U) -> Reading<U> {
+        match self {
+            Self::Valid(value) => Reading::Valid(f(value)),
+            Self::Invalid(code) => Reading::Invalid(code),
+        }
+    }
+}
+
+/// Hands out the sequence numbers for one sensor's [Sample]s.
+#[derive(Clone, Debug)]
+pub struct Sequencer {
+    source: SourceId,
+    next_seq: u32,
+}
+
+impl Sequencer {
+    pub const fn new(source: SourceId) -> Self {
+        Self {
+            source,
+            next_seq: 0,
+        }
+    }
+
+    pub fn source(&self) -> SourceId {
+        self.source
+    }
+
+    /// Wraps `data` as the next sample, captured now.
+    pub fn stamp<T>(&mut self, data: T) -> Sample<T> {
+        self.stamp_at(Instant::now(), data)
+    }
+
+    /// Wraps `data` as the next sample, captured at `time`.
+    pub fn stamp_at<T>(&mut self, time: Instant, data: T) -> Sample<T> {
+        let seq = self.next_seq;
+        self.next_seq = self.next_seq.wrapping_add(1);
+
+        Sample {
+            time,
+            seq,
+            source: self.source,
+            data,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn numbers_each_source_separately() {
+        let mut mpu = Sequencer::new(SourceId::Mpu6050);
+        let mut bme = Sequencer::new(SourceId::Bme280);
+
+        for expected in 0..3 {
+            let sample = mpu.stamp_at(Instant::from_millis(expected as u64 * 10), ());
+            assert_eq!(sample.seq, expected);
+            assert_eq!(sample.source, SourceId::Mpu6050);
+        }
+
+        let sample = bme.stamp_at(Instant::from_millis(30), 101_325.0);
+        assert_eq!(sample.seq, 0);
+        assert_eq!(sample.source, SourceId::Bme280);
+        assert_eq!(sample.time, Instant::from_millis(30));
+        assert_eq!(sample.data, 101_325.0);
+
+        assert_eq!(mpu.stamp(()).seq, 3);
+    }
+
+    #[test]
+    fn sequence_numbers_wrap_around() {
+        let mut sequencer = Sequencer::new(SourceId::Gps);
+        sequencer.next_seq = u32::MAX;
+
+        assert_eq!(sequencer.stamp(()).seq, u32::MAX);
+        assert_eq!(sequencer.stamp(()).seq, 0);
+    }
+
+    #[test]
+    fn mapping_keeps_the_stamp() {
+        let sample = Sequencer::new(SourceId::Qmc5883l).stamp_at(Instant::from_millis(5), 2);
+        let mapped = sample.map(|value| value * 10);
+
+        assert_eq!(mapped.time, sample.time);
+        assert_eq!(mapped.seq, sample.seq);
+        assert_eq!(mapped.source, sample.source);
+        assert_eq!(mapped.data, 20);
+    }
+}
@@ -0,0 +1,48 @@
+//! Helpers shared by the unit tests.
+
+use core::{fmt::Display, ops::Sub};
+
+use nalgebra::Vector3;
+
+/// Repeatable noise, from a linear congruential generator seeded with the
+/// value inside.
+pub struct Noise(pub u32);
+
+impl Noise {
+    fn step(&mut self) -> u32 {
+        self.0 = self.0.wrapping_mul(1_664_525).wrapping_add(1_013_904_223);
+        self.0
+    }
+
+    /// Noise in `-amplitude..amplitude`.
+    pub fn next(&mut self, amplitude: f32) -> f32 {
+        ((self.step() >> 8) as f32 / (1 << 24) as f32 * 2.0 - 1.0) * amplitude
+    }
+
+    /// Noise in `-amplitude..amplitude` on each axis.
+    pub fn vector(&mut self, amplitude: f32) -> Vector3<f32> {
+        Vector3::new(
+            self.next(amplitude),
+            self.next(amplitude),
+            self.next(amplitude),
+        )
+    }
+
+    /// Any byte, equally likely.
+    pub fn byte(&mut self) -> u8 {
+        (self.step() >> 24) as u8
+    }
+}
+
+/// Asserts that `a` is no more than `tolerance` away from `b` - never true if
+/// either is NaN.
+#[track_caller]
+pub fn assert_close<T>(a: T, b: T, tolerance: T)
+where
+    T: Copy + PartialOrd + Sub<Output = T> + Display,
+{
+    assert!(
+        a - b <= tolerance && b - a <= tolerance,
+        "{a} != {b} (within {tolerance})"
+    );
+}
@@ -1,96 +1,574 @@
+use core::{
+    cell::{Cell, RefCell},
+    future::Future,
+    panic::Location,
+};
+
+use embassy_futures::select::{select, Either};
+use heapless::Vec;
+use libm::powf;
+
 use crate::prelude::*;
 
-pub struct Backoff<T, E, OP>
+/// How much longer each wait of a [Backoff] is than the one before, by default.
+pub const DEFAULT_MULTIPLIER: f32 = 1.5;
+
+/// The longest a [Backoff] waits between retries, by default.
+pub const DEFAULT_MAX_INTERVAL: Duration = Duration::from_secs(2);
+
+/// How far either side of its interval each wait of a [Backoff] is spread, by
+/// default.
+pub const DEFAULT_RANDOMIZATION_FACTOR: f32 = 0.25;
+
+static JITTER_SEED: Mutex<Cell<u32>> = Mutex::new(Cell::new(0x2545_F491));
+
+/// Seeds the [Jitter] of every [Backoff] created from now on - with the
+/// hardware RNG, say, so that boards don't retry in lockstep.
+pub fn seed_jitter(seed: u32) {
+    critical_section::with(|cs| JITTER_SEED.borrow(cs).set(seed));
+}
+
+/// A small xorshift PRNG for spreading out retries. Not for anything that needs
+/// real randomness.
+#[derive(Clone, Copy, Debug)]
+pub struct Jitter {
+    state: u32,
+}
+
+impl Jitter {
+    pub const fn new(seed: u32) -> Self {
+        // xorshift gets stuck at zero
+        Self {
+            state: if seed == 0 { 0x9E37_79B9 } else { seed },
+        }
+    }
+
+    /// A generator seeded from [seed_jitter], different every time this is
+    /// called.
+    pub fn from_global_seed() -> Self {
+        let seed = critical_section::with(|cs| {
+            let cell = JITTER_SEED.borrow(cs);
+            let seed = cell.get();
+            cell.set(seed.wrapping_mul(0x2C1B_3C6D).wrapping_add(0x297A_2D39));
+            seed
+        });
+
+        Self::new(seed)
+    }
+
+    pub fn next_u32(&mut self) -> u32 {
+        let mut x = self.state;
+        x ^= x << 13;
+        x ^= x >> 17;
+        x ^= x << 5;
+        self.state = x;
+        x
+    }
+
+    /// A number in `[0, 1)`.
+    pub fn next_f32(&mut self) -> f32 {
+        (self.next_u32() >> 8) as f32 / (1 << 24) as f32
+    }
+}
+
+/// The timing of a [Backoff] - how long to wait before each retry, and when to
+/// give up.
+///
+/// Doesn't touch the clock itself, so the timing can be checked on the host.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct BackoffPolicy {
+    /// How long to wait before the first retry.
+    ///
+    /// Defaults to 500ms.
+    pub initial_interval: Duration,
+    /// How much longer each wait is than the one before.
+    ///
+    /// Defaults to 1.5.
+    pub multiplier: f32,
+    /// The longest to wait between retries, however many there have been.
+    ///
+    /// Defaults to 2 seconds.
+    pub max_interval: Duration,
+    /// How far each wait is randomly spread either side of its interval, as a
+    /// fraction of it, so retries from different tasks don't line up.
+    ///
+    /// Defaults to 0.25.
+    pub randomization_factor: f32,
+    /// How long after the first attempt to keep retrying for, if at all.
+    ///
+    /// Defaults to 5 seconds.
+    pub max_elapsed_time: Option<Duration>,
+}
+
+impl BackoffPolicy {
+    /// The interval before retry number `retry` (counting from 0), before it is
+    /// randomised.
+    pub fn interval(&self, retry: u32) -> Duration {
+        let micros = self.initial_interval.as_micros() as f32 * powf(self.multiplier, retry as f32);
+
+        // this also catches the growth overflowing to infinity
+        let micros = micros.min(self.max_interval.as_micros() as f32);
+
+        Duration::from_micros(micros as u64)
+    }
+
+    /// Spreads `interval` randomly either side by the randomization factor,
+    /// given a `random` number in `[0, 1)`.
+    pub fn randomise(&self, interval: Duration, random: f32) -> Duration {
+        let spread = self.randomization_factor.clamp(0.0, 1.0) * (2.0 * random - 1.0);
+
+        Duration::from_micros((interval.as_micros() as f32 * (1.0 + spread)) as u64)
+    }
+
+    /// How long to wait before retry number `retry`, `elapsed` after the first
+    /// attempt, or [None] to give up.
+    ///
+    /// The wait is cut short so the last retry happens right at the
+    /// `max_elapsed_time`, rather than giving up early.
+    pub fn delay(&self, retry: u32, elapsed: Duration, random: f32) -> Option<Duration> {
+        let delay = self.randomise(self.interval(retry), random);
+
+        match self.max_elapsed_time {
+            Some(max_elapsed_time) if elapsed >= max_elapsed_time => None,
+            Some(max_elapsed_time) => Some(delay.min(max_elapsed_time - elapsed)),
+            None => Some(delay),
+        }
+    }
+}
+
+impl Default for BackoffPolicy {
+    fn default() -> Self {
+        Self {
+            initial_interval: DEFAULT_INTERVAL,
+            multiplier: DEFAULT_MULTIPLIER,
+            max_interval: DEFAULT_MAX_INTERVAL,
+            randomization_factor: DEFAULT_RANDOMIZATION_FACTOR,
+            max_elapsed_time: Some(DEFAULT_MAX_ELAPSED_TIME),
+        }
+    }
+}
+
+/// Something a [Backoff] can retry - made with [Backoff::new] or
+/// [Backoff::new_async].
+#[allow(async_fn_in_trait)]
+pub trait Retryable {
+    type Output;
+    type Error: core::fmt::Debug;
+
+    async fn attempt(&mut self) -> Result<Self::Output, Self::Error>;
+}
+
+/// A blocking operation for a [Backoff] to retry.
+pub struct Blocking<OP>(OP);
+
+impl<T, E, OP> Retryable for Blocking<OP>
 where
-    T: Copy,
     E: core::fmt::Debug,
     OP: FnMut() -> Result<T, E>,
+{
+    type Output = T;
+    type Error = E;
+
+    async fn attempt(&mut self) -> Result<T, E> {
+        (self.0)()
+    }
+}
+
+/// An async operation for a [Backoff] to retry.
+pub struct Async<OP>(OP);
+
+impl<T, E, OP> Retryable for Async<OP>
+where
+    E: core::fmt::Debug,
+    OP: AsyncFnMut() -> Result<T, E>,
+{
+    type Output = T;
+    type Error = E;
+
+    async fn attempt(&mut self) -> Result<T, E> {
+        (self.0)().await
+    }
+}
+
+/// Why [Backoff::retry_until] didn't succeed.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum RetryError<E> {
+    /// The last error, once the policy gave up or the retry predicate said it
+    /// wasn't worth retrying.
+    Failed(E),
+    /// The retries were cancelled before they succeeded.
+    Cancelled,
+}
+
+/// Says whether an error is worth retrying - by default, all of them are.
+pub type RetryPredicate<E> = fn(&E) -> bool;
+
+/// Retries an operation until it succeeds, waiting exponentially longer (with
+/// some jitter) between attempts, as set out by a [BackoffPolicy].
+///
+/// The operation can be blocking ([Backoff::new]) or async
+/// ([Backoff::new_async]), with the same builder either way.
+pub struct Backoff<OP, P = RetryPredicate<<OP as Retryable>::Error>>
+where
+    OP: Retryable,
+    P: FnMut(&OP::Error) -> bool,
 {
     ///An optional log level to print the error on each retry.
     ///
     /// Defaults to [None].
     log_level: Option<log::Level>,
-    /// The function to be ran on each repeat
+    /// The operation to be ran on each repeat
     ///
     /// Must return a [Result].
     op: OP,
-    /// How long to wait between retries.
-    ///
-    /// Defaults to 500ms.
-    interval: Duration,
-    /// Whether to have a maximum allowed time before returning an error, and how long for.
+    policy: BackoffPolicy,
+    jitter: Jitter,
+    /// Whether an error is worth retrying, or should be returned straight away.
     ///
-    /// Defaults to 5 seconds.
-    max_elapsed_time: Option<Duration>,
+    /// Defaults to retrying every error.
+    retry_if: P,
 }
 
-impl<T, E, OP> Backoff<T, E, OP>
+impl<T, E, OP> Backoff<Blocking<OP>>
 where
-    T: Copy,
     E: core::fmt::Debug,
     OP: FnMut() -> Result<T, E>,
 {
     pub fn new(op: OP) -> Self {
+        Self::from_retryable(Blocking(op))
+    }
+}
+
+impl<T, E, OP> Backoff<Async<OP>>
+where
+    E: core::fmt::Debug,
+    OP: AsyncFnMut() -> Result<T, E>,
+{
+    /// Retries the async closure `op`, such as
+    /// `async || radio.transmit(&frame).await` - which, unlike a closure
+    /// returning a future, can borrow what it captures mutably.
+    pub fn new_async(op: OP) -> Self {
+        Self::from_retryable(Async(op))
+    }
+}
+
+impl<OP: Retryable> Backoff<OP> {
+    pub fn from_retryable(op: OP) -> Self {
         Self {
             log_level: Some(log::Level::Trace),
             op,
-            interval: Duration::from_millis(500),
-            max_elapsed_time: Some(Duration::from_secs(5)),
+            policy: BackoffPolicy::default(),
+            jitter: Jitter::from_global_seed(),
+            retry_if: |_| true,
         }
     }
+}
 
+impl<OP, P> Backoff<OP, P>
+where
+    OP: Retryable,
+    P: FnMut(&OP::Error) -> bool,
+{
     pub fn with_log_level(mut self, log_level: impl Into<Option<log::Level>>) -> Self {
         self.set_log_level(log_level.into());
         self
     }
 
+    pub fn with_policy(mut self, policy: BackoffPolicy) -> Self {
+        self.set_policy(policy);
+        self
+    }
+
+    /// Sets how long to wait before the first retry.
     pub fn with_interval(mut self, interval: Duration) -> Self {
         self.set_interval(interval);
         self
     }
 
+    pub fn with_multiplier(mut self, multiplier: f32) -> Self {
+        self.set_multiplier(multiplier);
+        self
+    }
+
+    pub fn with_max_interval(mut self, max_interval: Duration) -> Self {
+        self.set_max_interval(max_interval);
+        self
+    }
+
+    pub fn with_randomization_factor(mut self, randomization_factor: f32) -> Self {
+        self.set_randomization_factor(randomization_factor);
+        self
+    }
+
     pub fn with_max_elapsed_time(mut self, max_elapsed_time: impl Into<Option<Duration>>) -> Self {
         self.set_max_elapsed_time(max_elapsed_time.into());
         self
     }
 
+    /// Uses a fixed seed for the jitter, so the waits are repeatable.
+    pub fn with_seed(mut self, seed: u32) -> Self {
+        self.jitter = Jitter::new(seed);
+        self
+    }
+
+    /// Only retries errors `retry_if` returns `true` for - anything else is
+    /// returned straight away, as it won't go away by trying again.
+    pub fn with_retry_if<Q>(self, retry_if: Q) -> Backoff<OP, Q>
+    where
+        Q: FnMut(&OP::Error) -> bool,
+    {
+        Backoff {
+            log_level: self.log_level,
+            op: self.op,
+            policy: self.policy,
+            jitter: self.jitter,
+            retry_if,
+        }
+    }
+
     pub fn set_log_level(&mut self, log_level: impl Into<Option<log::Level>>) {
         self.log_level = log_level.into();
     }
+    pub fn set_policy(&mut self, policy: BackoffPolicy) {
+        self.policy = policy;
+    }
     pub fn set_interval(&mut self, interval: Duration) {
-        self.interval = interval;
+        self.policy.initial_interval = interval;
+    }
+    pub fn set_multiplier(&mut self, multiplier: f32) {
+        self.policy.multiplier = multiplier;
+    }
+    pub fn set_max_interval(&mut self, max_interval: Duration) {
+        self.policy.max_interval = max_interval;
+    }
+    pub fn set_randomization_factor(&mut self, randomization_factor: f32) {
+        self.policy.randomization_factor = randomization_factor;
     }
     pub fn set_max_elapsed_time(&mut self, max_elapsed_time: impl Into<Option<Duration>>) {
-        self.max_elapsed_time = max_elapsed_time.into();
+        self.policy.max_elapsed_time = max_elapsed_time.into();
     }
 
-    pub async fn retry(&mut self) -> Result<T, E> {
-        let mut counter = 1;
+    pub fn policy(&self) -> &BackoffPolicy {
+        &self.policy
+    }
 
-        let counter_limit =
-            self.max_elapsed_time.unwrap_or(Duration::MAX).as_ticks() / self.interval.as_ticks();
+    pub async fn retry(&mut self) -> Result<OP::Output, OP::Error> {
+        let start = Instant::now();
+        let mut retry = 0;
 
         loop {
-            match (self.op)() {
+            let e = match self.op.attempt().await {
                 success @ Ok(_) => return success,
+                Err(e) if !(self.retry_if)(&e) => return Err(e),
+                Err(e) => e,
+            };
 
-                Err(e) if counter <= counter_limit => {
-                    if let Some(level) = self.log_level {
-                        log!(level, "{e:?}: ({counter})");
-                    }
+            let Some(delay) = self
+                .policy
+                .delay(retry, start.elapsed(), self.jitter.next_f32())
+            else {
+                return Err(e);
+            };
 
-                    counter += 1;
-                    Timer::after(self.interval).await;
-                    continue;
-                }
-                error @ Err(_) => {
-                    return error;
+            retry += 1;
+
+            if let Some(level) = self.log_level {
+                log!(level, "{e:?}: (retry {retry} in {}ms)", delay.as_millis());
+            }
+
+            Timer::after(delay).await;
+        }
+    }
+
+    /// Retries until the operation succeeds, the policy gives up, or `cancel`
+    /// completes - whichever happens first.
+    ///
+    /// An attempt in progress when `cancel` completes is dropped part way
+    /// through, so the operation must be safe to cancel.
+    pub async fn retry_until(
+        &mut self,
+        cancel: impl Future,
+    ) -> Result<OP::Output, RetryError<OP::Error>> {
+        match select(self.retry(), cancel).await {
+            Either::First(result) => result.map_err(RetryError::Failed),
+            Either::Second(_) => Err(RetryError::Cancelled),
+        }
+    }
+
+    /// Retries for at most `timeout`, including any attempt in progress.
+    pub async fn retry_with_timeout(
+        &mut self,
+        timeout: Duration,
+    ) -> Result<OP::Output, RetryError<OP::Error>> {
+        self.retry_until(Timer::after(timeout)).await
+    }
+}
+
+/// How often a rate-limited [PrintErr] logs a summary of the errors it has
+/// held back, by default.
+pub const DEFAULT_REPEAT_INTERVAL: Duration = Duration::from_secs(5);
+
+/// How many [PrintErr] call sites can be counted - errors from any more are
+/// logged every time, and only counted in [error_total].
+pub const MAX_ERROR_SITES: usize = 32;
+
+static ERROR_SITES: Mutex<RefCell<Vec<ErrorSite, MAX_ERROR_SITES>>> =
+    Mutex::new(RefCell::new(Vec::new()));
+
+/// Errors from call sites that didn't fit in [ERROR_SITES].
+static UNTRACKED_ERRORS: Mutex<Cell<u32>> = Mutex::new(Cell::new(0));
+
+/// The errors handled by one [PrintErr] call.
+#[derive(Clone, Copy, Debug)]
+pub struct ErrorSite {
+    pub location: &'static Location<'static>,
+    /// How many errors there have been here since boot.
+    pub count: u32,
+    pub last_error: Instant,
+    /// When an error was last logged here, if it ever has been.
+    last_logged: Option<Instant>,
+    /// How many errors have been held back since then.
+    suppressed: u32,
+    /// What the latest error here was logged at, and how often its repeats can
+    /// be, if they are limited.
+    level: log::Level,
+    interval: Option<Duration>,
+}
+
+/// What a call site should log for its latest error.
+enum Report {
+    /// Log the error.
+    Error,
+    /// Log the error, and that it has happened this many times since it was
+    /// last logged.
+    Repeated(u32),
+    /// Don't log anything.
+    Suppressed,
+}
+
+/// Counts an error at `location`, and works out whether to log it.
+/// `interval` is how often repeats can be logged, or [None] to log them all.
+fn record_error(
+    location: &'static Location<'static>,
+    level: log::Level,
+    interval: Option<Duration>,
+) -> Report {
+    let now = Instant::now();
+
+    critical_section::with(|cs| {
+        let mut sites = ERROR_SITES.borrow_ref_mut(cs);
+
+        let site = match sites.iter_mut().position(|site| site.location == location) {
+            Some(index) => &mut sites[index],
+            None => {
+                let site = ErrorSite {
+                    location,
+                    count: 0,
+                    last_error: now,
+                    last_logged: None,
+                    suppressed: 0,
+                    level,
+                    interval,
+                };
+
+                if sites.push(site).is_err() {
+                    let untracked = UNTRACKED_ERRORS.borrow(cs);
+                    untracked.set(untracked.get().saturating_add(1));
+                    return Report::Error;
                 }
+
+                sites.last_mut().unwrap()
             }
+        };
+
+        site.count = site.count.saturating_add(1);
+        site.last_error = now;
+        site.level = level;
+        site.interval = interval;
+
+        let due = match (interval, site.last_logged) {
+            (Some(interval), Some(last)) => now.saturating_duration_since(last) >= interval,
+            _ => true,
+        };
+
+        if !due {
+            site.suppressed += 1;
+            return Report::Suppressed;
         }
+
+        let suppressed = core::mem::take(&mut site.suppressed);
+        site.last_logged = Some(now);
+
+        match suppressed {
+            0 => Report::Error,
+            // including this one
+            n => Report::Repeated(n + 1),
+        }
+    })
+}
+
+/// Takes the repeats held back at every call site whose interval has passed
+/// by `now`, as if they had just been logged.
+fn take_repeats(now: Instant) -> Vec<(ErrorSite, u32), MAX_ERROR_SITES> {
+    critical_section::with(|cs| {
+        let mut sites = ERROR_SITES.borrow_ref_mut(cs);
+
+        sites
+            .iter_mut()
+            .filter(|site| match (site.interval, site.last_logged) {
+                (Some(interval), Some(last)) => {
+                    site.suppressed > 0 && now.saturating_duration_since(last) >= interval
+                }
+                _ => false,
+            })
+            .map(|site| {
+                site.last_logged = Some(now);
+                (*site, core::mem::take(&mut site.suppressed))
+            })
+            .collect()
+    })
+}
+
+/// Logs how many times each rate-limited call site has failed since it last
+/// logged, once its interval is up.
+///
+/// Otherwise repeats are only summed up by the next error, so a site that fails
+/// a few times and then stops would never report them - call this regularly.
+pub fn log_error_repeats() {
+    for (site, n) in take_repeats(Instant::now()) {
+        log!(
+            site.level,
+            "Error repeated {n} more times at {}:{}",
+            site.location.file(),
+            site.location.line()
+        );
     }
 }
 
+/// The call sites that have had errors, and how many.
+pub fn error_sites() -> Vec<ErrorSite, MAX_ERROR_SITES> {
+    critical_section::with(|cs| ERROR_SITES.borrow_ref(cs).clone())
+}
+
+/// How many errors have been handled by [PrintErr] since boot.
+pub fn error_total() -> u32 {
+    critical_section::with(|cs| {
+        ERROR_SITES
+            .borrow_ref(cs)
+            .iter()
+            .fold(UNTRACKED_ERRORS.borrow(cs).get(), |total, site| {
+                total.saturating_add(site.count)
+            })
+    })
+}
+
+/// Logs (and counts) the error of a [Result], carrying on with a default value.
+///
+/// Every call site's errors are counted, see [error_sites]. The `_limited`
+/// variants log the first error at a call site, and then only a summary of the
+/// repeats every [DEFAULT_REPEAT_INTERVAL], so something failing on every loop
+/// doesn't drown out everything else. Repeats still held back when the errors
+/// stop are logged by [log_error_repeats].
 pub trait PrintErr<T: Default> {
     fn print_warn(self) -> T;
     fn print_error(self) -> T;
@@ -99,6 +577,16 @@ pub trait PrintErr<T: Default> {
     fn print_info(self) -> T;
 
     fn print_log(self, log_level: log::Level) -> T;
+
+    fn print_warn_limited(self) -> T;
+    fn print_error_limited(self) -> T;
+    fn print_trace_limited(self) -> T;
+    fn print_debug_limited(self) -> T;
+    fn print_info_limited(self) -> T;
+
+    /// Logs the first error, and then the number of repeats at most once every
+    /// `interval`.
+    fn print_log_limited(self, log_level: log::Level, interval: Duration) -> T;
 }
 
 impl<T, E> PrintErr<T> for Result<T, E>
@@ -106,63 +594,360 @@ where
     T: Default,
     E: core::fmt::Debug,
 {
+    #[track_caller]
     fn print_warn(self) -> T {
-        match self {
-            Ok(t) => t,
-            Err(e) => {
-                warn!("{e:?}");
-                T::default()
-            }
-        }
+        self.print_log(log::Level::Warn)
     }
 
+    #[track_caller]
     fn print_error(self) -> T {
-        match self {
-            Ok(t) => t,
-            Err(e) => {
-                error!("{e:?}");
-                T::default()
-            }
-        }
+        self.print_log(log::Level::Error)
     }
 
+    #[track_caller]
     fn print_trace(self) -> T {
-        match self {
-            Ok(t) => t,
-            Err(e) => {
-                trace!("{e:?}");
-                T::default()
-            }
-        }
+        self.print_log(log::Level::Trace)
     }
 
+    #[track_caller]
     fn print_debug(self) -> T {
+        self.print_log(log::Level::Debug)
+    }
+
+    #[track_caller]
+    fn print_info(self) -> T {
+        self.print_log(log::Level::Info)
+    }
+
+    #[track_caller]
+    fn print_log(self, log_level: log::Level) -> T {
         match self {
             Ok(t) => t,
             Err(e) => {
-                debug!("{e:?}");
+                record_error(Location::caller(), log_level, None);
+                log!(log_level, "{e:?}");
                 T::default()
             }
         }
     }
 
-    fn print_info(self) -> T {
+    #[track_caller]
+    fn print_warn_limited(self) -> T {
+        self.print_log_limited(log::Level::Warn, DEFAULT_REPEAT_INTERVAL)
+    }
+
+    #[track_caller]
+    fn print_error_limited(self) -> T {
+        self.print_log_limited(log::Level::Error, DEFAULT_REPEAT_INTERVAL)
+    }
+
+    #[track_caller]
+    fn print_trace_limited(self) -> T {
+        self.print_log_limited(log::Level::Trace, DEFAULT_REPEAT_INTERVAL)
+    }
+
+    #[track_caller]
+    fn print_debug_limited(self) -> T {
+        self.print_log_limited(log::Level::Debug, DEFAULT_REPEAT_INTERVAL)
+    }
+
+    #[track_caller]
+    fn print_info_limited(self) -> T {
+        self.print_log_limited(log::Level::Info, DEFAULT_REPEAT_INTERVAL)
+    }
+
+    #[track_caller]
+    fn print_log_limited(self, log_level: log::Level, interval: Duration) -> T {
         match self {
             Ok(t) => t,
             Err(e) => {
-                info!("{e:?}");
+                let location = Location::caller();
+
+                match record_error(location, log_level, Some(interval)) {
+                    Report::Error => log!(log_level, "{e:?}"),
+                    Report::Repeated(n) => log!(
+                        log_level,
+                        "{e:?} (repeated {n} times at {}:{})",
+                        location.file(),
+                        location.line()
+                    ),
+                    Report::Suppressed => {}
+                }
+
                 T::default()
             }
         }
     }
+}
 
-    fn print_log(self, log_level: log::Level) -> T {
-        match self {
-            Ok(t) => t,
-            Err(e) => {
-                log!(log_level, "{e:?}");
-                T::default()
+#[cfg(test)]
+mod tests {
+    use embassy_futures::block_on;
+
+    use super::*;
+
+    fn policy() -> BackoffPolicy {
+        BackoffPolicy {
+            initial_interval: Duration::from_millis(100),
+            multiplier: 2.0,
+            max_interval: Duration::from_secs(1),
+            randomization_factor: 0.25,
+            max_elapsed_time: Some(Duration::from_secs(5)),
+        }
+    }
+
+    #[test]
+    fn interval_grows_by_the_multiplier() {
+        let intervals = [100, 200, 400, 800].map(Duration::from_millis);
+
+        for (retry, interval) in intervals.into_iter().enumerate() {
+            assert_eq!(policy().interval(retry as u32), interval);
+        }
+
+        let default = BackoffPolicy::default();
+        assert_eq!(default.interval(0), Duration::from_millis(500));
+        assert_eq!(default.interval(1), Duration::from_millis(750));
+        assert_eq!(default.interval(2), Duration::from_micros(1_125_000));
+    }
+
+    #[test]
+    fn interval_is_capped_at_max_interval() {
+        assert_eq!(policy().interval(4), Duration::from_secs(1));
+        assert_eq!(policy().interval(5), Duration::from_secs(1));
+
+        // long enough for the growth to overflow
+        assert_eq!(policy().interval(1_000), Duration::from_secs(1));
+        assert_eq!(policy().interval(u32::MAX), Duration::from_secs(1));
+
+        assert_eq!(
+            BackoffPolicy::default().interval(3),
+            Duration::from_micros(1_687_500)
+        );
+        assert_eq!(BackoffPolicy::default().interval(4), DEFAULT_MAX_INTERVAL);
+    }
+
+    #[test]
+    fn randomise_stays_within_the_factor() {
+        let policy = policy();
+        let interval = Duration::from_millis(400);
+
+        assert_eq!(policy.randomise(interval, 0.0), Duration::from_millis(300));
+        assert_eq!(policy.randomise(interval, 0.5), interval);
+
+        let mut jitter = Jitter::new(1);
+        let (mut shortest, mut longest) = (interval, interval);
+
+        for _ in 0..10_000 {
+            let delay = policy.randomise(interval, jitter.next_f32());
+
+            shortest = shortest.min(delay);
+            longest = longest.max(delay);
+        }
+
+        assert!(shortest >= Duration::from_millis(300), "{shortest:?}");
+        assert!(longest < Duration::from_millis(500), "{longest:?}");
+
+        // actually spread out over the range
+        assert!(shortest < Duration::from_millis(310), "{shortest:?}");
+        assert!(longest > Duration::from_millis(490), "{longest:?}");
+    }
+
+    #[test]
+    fn randomise_clamps_the_factor() {
+        let interval = Duration::from_millis(400);
+
+        let none = BackoffPolicy {
+            randomization_factor: 0.0,
+            ..policy()
+        };
+        assert_eq!(none.randomise(interval, 0.0), interval);
+        assert_eq!(none.randomise(interval, 0.99), interval);
+
+        // never a negative wait
+        let too_much = BackoffPolicy {
+            randomization_factor: 3.0,
+            ..policy()
+        };
+        assert_eq!(too_much.randomise(interval, 0.0), Duration::from_ticks(0));
+        assert_eq!(
+            too_much.randomise(interval, 0.75),
+            Duration::from_millis(600)
+        );
+
+        let negative = BackoffPolicy {
+            randomization_factor: -1.0,
+            ..policy()
+        };
+        assert_eq!(negative.randomise(interval, 0.0), interval);
+    }
+
+    #[test]
+    fn delay_is_clipped_at_max_elapsed_time() {
+        let policy = policy();
+
+        assert_eq!(
+            policy.delay(2, Duration::from_secs(1), 0.5),
+            Some(Duration::from_millis(400))
+        );
+
+        // the last retry lands right on the limit
+        assert_eq!(
+            policy.delay(4, Duration::from_millis(4_700), 0.5),
+            Some(Duration::from_millis(300))
+        );
+        assert_eq!(policy.delay(4, Duration::from_secs(5), 0.5), None);
+        assert_eq!(policy.delay(4, Duration::from_secs(6), 0.5), None);
+    }
+
+    #[test]
+    fn delay_never_gives_up_without_max_elapsed_time() {
+        let policy = BackoffPolicy {
+            max_elapsed_time: None,
+            ..policy()
+        };
+
+        assert_eq!(
+            policy.delay(10, Duration::from_secs(3_600), 0.5),
+            Some(Duration::from_secs(1))
+        );
+    }
+
+    #[test]
+    fn jitter_never_sticks_at_zero() {
+        let mut jitter = Jitter::new(0);
+
+        for _ in 0..10_000 {
+            assert_ne!(jitter.next_u32(), 0);
+        }
+
+        // nor when the global seed is zero
+        seed_jitter(0);
+        let mut jitter = Jitter::from_global_seed();
+        assert_ne!(jitter.next_u32(), 0);
+    }
+
+    #[test]
+    fn jitter_is_in_range() {
+        let mut jitter = Jitter::new(0x1234_5678);
+
+        for _ in 0..10_000 {
+            let random = jitter.next_f32();
+            assert!((0.0..1.0).contains(&random), "{random}");
+        }
+    }
+
+    #[test]
+    fn jitter_from_global_seed_differs_each_time() {
+        let mut first = Jitter::from_global_seed();
+        let mut second = Jitter::from_global_seed();
+
+        assert_ne!(first.next_u32(), second.next_u32());
+    }
+
+    /// An async operation which fails with each of `errors` in turn, and then
+    /// succeeds with how many attempts it took.
+    struct Flaky {
+        errors: &'static [u8],
+        attempts: u32,
+    }
+
+    impl Flaky {
+        async fn attempt(&mut self) -> Result<u32, u8> {
+            self.attempts += 1;
+
+            match self.errors.get(self.attempts as usize - 1) {
+                Some(&e) => Err(e),
+                None => Ok(self.attempts),
             }
         }
     }
+
+    #[test]
+    fn retries_async_operations_until_they_succeed() {
+        let mut flaky = Flaky {
+            errors: &[1, 1],
+            attempts: 0,
+        };
+
+        let result = block_on(
+            Backoff::new_async(async || flaky.attempt().await)
+                .with_interval(Duration::from_millis(1))
+                .with_seed(1)
+                .retry(),
+        );
+
+        assert_eq!(result, Ok(3));
+    }
+
+    #[test]
+    fn only_retries_errors_the_predicate_accepts() {
+        let mut flaky = Flaky {
+            errors: &[1, 2, 1],
+            attempts: 0,
+        };
+
+        let result = block_on(
+            Backoff::new_async(async || flaky.attempt().await)
+                .with_interval(Duration::from_millis(1))
+                .with_seed(1)
+                .with_retry_if(|e| *e == 1)
+                .retry(),
+        );
+
+        assert_eq!(result, Err(2));
+        assert_eq!(flaky.attempts, 2);
+    }
+
+    /// Fails `times` times at the caller's location, and returns it.
+    #[track_caller]
+    fn fail(times: u32) -> &'static Location<'static> {
+        for _ in 0..times {
+            Err::<(), _>("failed").print_warn();
+        }
+        Location::caller()
+    }
+
+    /// Fails `times` times at the caller's location, and returns it.
+    #[track_caller]
+    fn fail_limited(times: u32) -> &'static Location<'static> {
+        for _ in 0..times {
+            Err::<(), _>("failed").print_warn_limited();
+        }
+        Location::caller()
+    }
+
+    fn repeats_at(now: Instant, location: &'static Location<'static>) -> Option<u32> {
+        take_repeats(now)
+            .into_iter()
+            .find(|(site, _)| site.location == location)
+            .map(|(_, n)| n)
+    }
+
+    #[test]
+    fn reports_repeats_after_the_errors_stop() {
+        let location = fail_limited(3);
+        let now = Instant::now();
+
+        // the first error was logged, and the other two held back...
+        assert_eq!(repeats_at(now, location), None);
+
+        // ...until the interval is up
+        let later = now + DEFAULT_REPEAT_INTERVAL;
+        assert_eq!(repeats_at(later, location), Some(2));
+        assert_eq!(repeats_at(later + DEFAULT_REPEAT_INTERVAL, location), None);
+
+        let site = error_sites()
+            .into_iter()
+            .find(|site| site.location == location)
+            .unwrap();
+        assert_eq!(site.count, 3);
+    }
+
+    #[test]
+    fn unlimited_errors_have_no_repeats() {
+        let location = fail(3);
+
+        let later = Instant::now() + DEFAULT_REPEAT_INTERVAL;
+        assert_eq!(repeats_at(later, location), None);
+    }
 }
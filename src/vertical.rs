@@ -0,0 +1,360 @@
+use nalgebra::{Matrix3, UnitQuaternion, Vector3};
+
+use crate::{
+    ahrs::ATTITUDE_BUS,
+    altitude::ALTITUDE_BUS,
+    bus::{BusSubscriber, DataBus},
+    mpu6050::MPU_BUS,
+    prelude::*,
+    sample::Sample,
+    watchdog::{self, TaskId},
+};
+
+pub type VerticalBus = DataBus<Sample<VerticalState>, 4, 8>;
+pub static VERTICAL_BUS: VerticalBus = DataBus::new();
+pub type VerticalSubscriber = BusSubscriber<'static, Sample<VerticalState>, 4, 8>;
+
+/// Standard gravity, in metres per second squared.
+pub const STANDARD_GRAVITY: f32 = 9.806_65;
+
+/// The filter's uncertainty when it starts - the can is taken to be sitting
+/// on the ground, but the velocity and acceleration are only guesses.
+const INITIAL_VARIANCE: [f32; 3] = [1.0, 10.0, 10.0];
+
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct VerticalState {
+    /// altitude above the ground reference in metres
+    pub altitude: f32,
+    /// vertical velocity in metres per second, positive upwards
+    pub velocity: f32,
+    /// vertical acceleration in metres per second squared, gravity removed
+    pub acceleration: f32,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct KalmanConfig {
+    /// How much jerk the filter allows for, as the square root of the power
+    /// spectral density of the white jerk (in m/s³/√Hz) - over a step of `dt`
+    /// seconds the acceleration can wander by `jerk_noise * sqrt(dt)` m/s².
+    /// Higher values follow sudden changes in acceleration faster, but smooth
+    /// less.
+    ///
+    /// Defaults to 5 m/s³/√Hz.
+    pub jerk_noise: f32,
+    /// Standard deviation of the barometric altitude, in metres.
+    ///
+    /// Defaults to 0.5m.
+    pub altitude_noise: f32,
+    /// Standard deviation of the vertical acceleration, in metres per second
+    /// squared.
+    ///
+    /// Defaults to 0.5 m/s².
+    pub acceleration_noise: f32,
+}
+
+impl Default for KalmanConfig {
+    fn default() -> Self {
+        Self {
+            jerk_noise: 5.0,
+            altitude_noise: 0.5,
+            acceleration_noise: 0.5,
+        }
+    }
+}
+
+/// A Kalman filter tracking altitude, vertical velocity and vertical
+/// acceleration, treating the jerk as white noise.
+///
+/// Barometric altitude and accelerometer readings are fused as they arrive,
+/// with [VerticalKalman::update_altitude] and
+/// [VerticalKalman::update_acceleration]. The barometer keeps the altitude from
+/// drifting, while the accelerometer makes the velocity respond straight away.
+#[derive(Clone, Copy, Debug)]
+pub struct VerticalKalman {
+    config: KalmanConfig,
+    /// altitude, velocity, acceleration
+    x: Vector3<f32>,
+    /// covariance of `x`
+    p: Matrix3<f32>,
+}
+
+impl VerticalKalman {
+    /// Starts the filter at rest at `altitude`.
+    pub fn new(config: KalmanConfig, altitude: f32) -> Self {
+        Self {
+            config,
+            x: Vector3::new(altitude, 0.0, 0.0),
+            p: Matrix3::from_diagonal(&Vector3::from(INITIAL_VARIANCE)),
+        }
+    }
+
+    pub fn config(&self) -> &KalmanConfig {
+        &self.config
+    }
+
+    pub fn state(&self) -> VerticalState {
+        VerticalState {
+            altitude: self.x[0],
+            velocity: self.x[1],
+            acceleration: self.x[2],
+        }
+    }
+
+    /// Moves the estimate `dt` seconds forward, assuming constant acceleration.
+    pub fn predict(&mut self, dt: f32) {
+        if dt <= 0.0 {
+            return;
+        }
+
+        #[rustfmt::skip]
+        let f = Matrix3::new(
+            1.0, dt, 0.5 * dt * dt,
+            0.0, 1.0, dt,
+            0.0, 0.0, 1.0,
+        );
+
+        let dt2 = dt * dt;
+        let dt3 = dt2 * dt;
+        // spectral density of the jerk
+        let q = self.config.jerk_noise * self.config.jerk_noise;
+
+        // white jerk, integrated over the step
+        #[rustfmt::skip]
+        let process_noise = Matrix3::new(
+            dt3 * dt2 / 20.0, dt2 * dt2 / 8.0, dt3 / 6.0,
+            dt2 * dt2 / 8.0,  dt3 / 3.0,       dt2 / 2.0,
+            dt3 / 6.0,        dt2 / 2.0,       dt,
+        ) * q;
+
+        self.x = f * self.x;
+        self.p = f * self.p * f.transpose() + process_noise;
+    }
+
+    /// Corrects the estimate with a barometric altitude, in metres.
+    pub fn update_altitude(&mut self, altitude: f32) {
+        let noise = self.config.altitude_noise;
+        self.update(0, altitude, noise * noise);
+    }
+
+    /// Corrects the estimate with a vertical acceleration, in metres per second
+    /// squared with gravity removed.
+    pub fn update_acceleration(&mut self, acceleration: f32) {
+        let noise = self.config.acceleration_noise;
+        self.update(2, acceleration, noise * noise);
+    }
+
+    /// Corrects state `index` with a direct measurement of it.
+    fn update(&mut self, index: usize, measurement: f32, variance: f32) {
+        if !measurement.is_finite() {
+            return;
+        }
+
+        let innovation = measurement - self.x[index];
+        let innovation_variance = self.p[(index, index)] + variance;
+
+        let gain: Vector3<f32> = self.p.column(index) / innovation_variance;
+
+        let correction = gain * self.p.row(index);
+
+        self.x += gain * innovation;
+        self.p -= correction;
+
+        // keep rounding errors from making the covariance lopsided
+        self.p = (self.p + self.p.transpose()) * 0.5;
+    }
+}
+
+/// The vertical acceleration (in metres per second squared) of the can, from
+/// an accelerometer reading `acc` (in g) in the body frame and the `attitude`
+/// rotating it into the earth frame.
+pub fn vertical_acceleration(attitude: &UnitQuaternion<f32>, acc: Vector3<f32>) -> f32 {
+    // at rest the accelerometer reads 1g upwards
+    ((attitude * acc).z - 1.0) * STANDARD_GRAVITY
+}
+
+#[task]
+pub async fn vertical_stream(config: KalmanConfig) {
+    let heartbeat = watchdog::register(TaskId::Vertical, Duration::from_secs(1));
+    let mut mpu_bus = MPU_BUS.subscribe().expect("no MPU_BUS subscribers left");
+    let mut altitude_bus = ALTITUDE_BUS
+        .subscribe()
+        .expect("no ALTITUDE_BUS subscribers left");
+
+    // starting on the ground, so it runs on the IMU alone until the barometer
+    // (if there is one) has worked out its ground reference
+    let mut filter = VerticalKalman::new(config, 0.0);
+    let mut last_time = None;
+
+    loop {
+        let sample = mpu_bus.next().await;
+        heartbeat.beat();
+
+        let dt = last_time.map_or(0.0, |last| {
+            sample.time.saturating_duration_since(last).as_micros() as f32 / 1_000_000.0
+        });
+        last_time = Some(sample.time);
+
+        filter.predict(dt);
+
+        let attitude = ATTITUDE_BUS
+            .latest()
+            .map(|attitude| attitude.data.quaternion);
+
+        if let (Some(attitude), Some(acc)) = (attitude, sample.data.acc.valid()) {
+            filter.update_acceleration(vertical_acceleration(&attitude, acc));
+        }
+
+        // the barometer is much slower than the IMU, so its readings are folded
+        // in as they turn up
+        while let Some(altitude) = altitude_bus.try_next() {
+            filter.update_altitude(altitude.data.agl);
+        }
+
+        let state = sample.map(|_| filter.state());
+
+        trace!("{state:?}");
+
+        VERTICAL_BUS.publish(state);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_utils::{assert_close, Noise};
+
+    /// The MPU6050 and BME280 are both read at 10Hz.
+    const DT: f32 = 0.1;
+
+    /// Runs the filter for `seconds` against the trajectory `truth` (giving the
+    /// true state at a time in seconds), with noisy barometer and accelerometer
+    /// readings, checking the estimate against it from `settled` onwards.
+    ///
+    /// The filter starts at rest at the trajectory's first altitude.
+    fn track(truth: impl Fn(f32) -> VerticalState, seconds: f32, settled: f32) {
+        let mut noise = Noise(7);
+        let mut filter = VerticalKalman::new(KalmanConfig::default(), truth(0.0).altitude);
+
+        for step in 1..=(seconds / DT) as u32 {
+            let t = step as f32 * DT;
+            let expected = truth(t);
+
+            filter.predict(DT);
+
+            // uniform noise with the configured standard deviations of 0.5
+            filter.update_acceleration(expected.acceleration + noise.next(0.85));
+            filter.update_altitude(expected.altitude + noise.next(0.85));
+
+            if t < settled {
+                continue;
+            }
+
+            let state = filter.state();
+            assert_close(state.altitude, expected.altitude, TOLERANCE.altitude);
+            assert_close(state.velocity, expected.velocity, TOLERANCE.velocity);
+            assert_close(
+                state.acceleration,
+                expected.acceleration,
+                TOLERANCE.acceleration,
+            );
+        }
+    }
+
+    /// The acceleration estimate follows the readings closely, with the
+    /// default jerk noise, so is only as good as they are.
+    const TOLERANCE: VerticalState = VerticalState {
+        altitude: 0.75,
+        velocity: 0.75,
+        acceleration: 1.0,
+    };
+
+    #[test]
+    fn holds_still_at_rest() {
+        track(
+            |_| VerticalState {
+                altitude: 12.0,
+                ..Default::default()
+            },
+            20.0,
+            2.0,
+        );
+    }
+
+    #[test]
+    fn tracks_constant_acceleration() {
+        const ACCELERATION: f32 = 3.0;
+
+        track(
+            |t| VerticalState {
+                altitude: 0.5 * ACCELERATION * t * t,
+                velocity: ACCELERATION * t,
+                acceleration: ACCELERATION,
+            },
+            15.0,
+            2.0,
+        );
+    }
+
+    #[test]
+    fn tracks_a_ballistic_arc() {
+        // already climbing at 60m/s when the filter starts, which it doesn't
+        // know about
+        const ALTITUDE: f32 = 200.0;
+        const VELOCITY: f32 = 60.0;
+
+        track(
+            |t| VerticalState {
+                altitude: ALTITUDE + VELOCITY * t - 0.5 * STANDARD_GRAVITY * t * t,
+                velocity: VELOCITY - STANDARD_GRAVITY * t,
+                acceleration: -STANDARD_GRAVITY,
+            },
+            12.0,
+            3.0,
+        );
+    }
+
+    #[test]
+    fn ignores_non_finite_readings() {
+        let mut filter = VerticalKalman::new(KalmanConfig::default(), 100.0);
+
+        filter.predict(DT);
+        let state = filter.state();
+
+        filter.update_altitude(f32::NAN);
+        filter.update_acceleration(f32::INFINITY);
+        filter.predict(0.0);
+        filter.predict(-DT);
+
+        assert_eq!(filter.state(), state);
+    }
+
+    #[test]
+    fn vertical_acceleration_removes_gravity() {
+        let level = UnitQuaternion::identity();
+        assert_close(
+            vertical_acceleration(&level, Vector3::new(0.0, 0.0, 1.0)),
+            0.0,
+            1e-6,
+        );
+        assert_close(
+            vertical_acceleration(&level, Vector3::new(0.0, 0.0, 3.0)),
+            2.0 * STANDARD_GRAVITY,
+            1e-4,
+        );
+
+        // in free fall the accelerometer reads nothing
+        assert_close(
+            vertical_acceleration(&level, Vector3::zeros()),
+            -STANDARD_GRAVITY,
+            1e-6,
+        );
+
+        // lying on its side, gravity reads along the body x axis
+        let on_side = UnitQuaternion::from_euler_angles(0.0, -core::f32::consts::FRAC_PI_2, 0.0);
+        assert_close(
+            vertical_acceleration(&on_side, Vector3::new(1.0, 0.0, 0.0)),
+            0.0,
+            1e-5,
+        );
+    }
+}
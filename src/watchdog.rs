@@ -0,0 +1,333 @@
+//! Resetting the can if any critical task stops making progress.
+//!
+//! Each critical task [register]s when it starts, and then [Heartbeat::beat]s
+//! every time round its loop. [watchdog_supervisor] only feeds the TIMG1
+//! hardware watchdog while every registered task has beaten recently enough -
+//! once one hasn't, its [TaskId] is written to RTC memory (which survives the
+//! reset), and the watchdog is left to reset the chip. [report_last_reset]
+//! picks it up again after the restart.
+//!
+//! If nothing can run at all (a task stuck with interrupts disabled, or a
+//! panic) the supervisor can't run either, so the reset still happens but no
+//! task is blamed for it.
+
+use core::{
+    cell::RefCell,
+    ptr::{addr_of, addr_of_mut},
+};
+
+#[cfg(target_arch = "xtensa")]
+use hal::{macros::ram, peripherals::TIMG1, timer::Wdt};
+
+use crate::{errors::journal, prelude::*};
+
+#[cfg(target_arch = "xtensa")]
+pub type SupervisedWdt = Wdt<TIMG1>;
+
+static SUPERVISOR: Mutex<RefCell<Supervisor>> = Mutex::new(RefCell::new(Supervisor::new()));
+
+/// Marks [STARVED_TASK] as written by [record_starved], rather than left over
+/// from before the can was powered on.
+const STARVED_MAGIC: u32 = 0x5744_5447;
+
+/// The magic, then the [TaskId] that stopped beating.
+#[cfg_attr(target_arch = "xtensa", ram(rtc_fast, uninitialized))]
+static mut STARVED_TASK: [u32; 2] = [0; 2];
+
+/// Every task that can be supervised. These are recorded across resets and in
+/// the [journal] (as the code of a [Subsystem::Watchdog] fault), so existing
+/// values must never change.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+#[repr(u8)]
+pub enum TaskId {
+    Main,
+    Mpu,
+    Mag,
+    Ahrs,
+    Vertical,
+    Flight,
+    Display,
+    Radio,
+    BlackBox,
+    Sd,
+    Health,
+    Bme,
+    Gps,
+}
+
+pub const TASK_COUNT: usize = 13;
+
+impl TaskId {
+    pub fn from_u8(value: u8) -> Option<Self> {
+        Some(match value {
+            0 => Self::Main,
+            1 => Self::Mpu,
+            2 => Self::Mag,
+            3 => Self::Ahrs,
+            4 => Self::Vertical,
+            5 => Self::Flight,
+            6 => Self::Display,
+            7 => Self::Radio,
+            8 => Self::BlackBox,
+            9 => Self::Sd,
+            10 => Self::Health,
+            11 => Self::Bme,
+            12 => Self::Gps,
+            _ => return None,
+        })
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct WatchdogConfig {
+    /// How long the hardware watchdog waits to be fed before it resets the
+    /// chip.
+    ///
+    /// Defaults to 3s.
+    pub timeout: Duration,
+    /// How often the heartbeats are checked, and the watchdog fed.
+    ///
+    /// Defaults to 500ms.
+    pub check_interval: Duration,
+}
+
+impl Default for WatchdogConfig {
+    fn default() -> Self {
+        Self {
+            timeout: Duration::from_secs(3),
+            check_interval: Duration::from_millis(500),
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+struct Registration {
+    /// How long the task can go between heartbeats.
+    timeout: Duration,
+    last_beat: Instant,
+}
+
+/// Keeps track of when every registered task last checked in.
+#[derive(Clone, Debug)]
+pub struct Supervisor {
+    tasks: [Option<Registration>; TASK_COUNT],
+}
+
+impl Supervisor {
+    pub const fn new() -> Self {
+        Self {
+            tasks: [None; TASK_COUNT],
+        }
+    }
+
+    /// Starts expecting `task` to beat at least every `timeout`, counting from
+    /// `now`.
+    pub fn register(&mut self, task: TaskId, timeout: Duration, now: Instant) {
+        self.tasks[task as usize] = Some(Registration {
+            timeout,
+            last_beat: now,
+        });
+    }
+
+    /// Stops expecting heartbeats from `task`.
+    pub fn unregister(&mut self, task: TaskId) {
+        self.tasks[task as usize] = None;
+    }
+
+    pub fn beat(&mut self, task: TaskId, now: Instant) {
+        if let Some(registration) = &mut self.tasks[task as usize] {
+            registration.last_beat = now;
+        }
+    }
+
+    /// The task which has gone the longest past its timeout as of `now`, or
+    /// [None] if every registered task is alive.
+    ///
+    /// Tasks waiting on one that has stalled stall soon after, so the one
+    /// furthest overdue is most likely the cause.
+    pub fn starved(&self, now: Instant) -> Option<TaskId> {
+        (0..TASK_COUNT)
+            .filter_map(|index| {
+                let registration = self.tasks[index]?;
+                let deadline = registration.last_beat + registration.timeout;
+
+                (now > deadline).then(|| (index, now - deadline))
+            })
+            .max_by_key(|&(_, overdue)| overdue)
+            .and_then(|(index, _)| TaskId::from_u8(index as u8))
+    }
+}
+
+impl Default for Supervisor {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Proof that a task has registered, for it to check in with.
+#[derive(Debug)]
+pub struct Heartbeat {
+    task: TaskId,
+}
+
+impl Heartbeat {
+    pub fn task(&self) -> TaskId {
+        self.task
+    }
+
+    /// Tells the supervisor the task is still making progress.
+    pub fn beat(&self) {
+        let now = Instant::now();
+        critical_section::with(|cs| SUPERVISOR.borrow_ref_mut(cs).beat(self.task, now));
+    }
+}
+
+impl Drop for Heartbeat {
+    fn drop(&mut self) {
+        critical_section::with(|cs| SUPERVISOR.borrow_ref_mut(cs).unregister(self.task));
+    }
+}
+
+/// Starts supervising `task`, which must [Heartbeat::beat] at least every
+/// `timeout` from now on.
+pub fn register(task: TaskId, timeout: Duration) -> Heartbeat {
+    let now = Instant::now();
+    critical_section::with(|cs| SUPERVISOR.borrow_ref_mut(cs).register(task, timeout, now));
+
+    Heartbeat { task }
+}
+
+#[cfg(target_arch = "xtensa")]
+fn record_starved(task: TaskId) {
+    // SAFETY: only ever touched from here and `take_starved`, one at a time
+    unsafe {
+        addr_of_mut!(STARVED_TASK).write_volatile([STARVED_MAGIC, task as u32]);
+    }
+}
+
+/// The task the watchdog reset the can for, if it did - cleared once read.
+pub fn take_starved() -> Option<TaskId> {
+    // SAFETY: as in `record_starved`
+    let [magic, task] = unsafe {
+        let record = addr_of!(STARVED_TASK).read_volatile();
+        addr_of_mut!(STARVED_TASK).write_volatile([0; 2]);
+        record
+    };
+
+    if magic != STARVED_MAGIC {
+        return None;
+    }
+
+    TaskId::from_u8(task as u8)
+}
+
+/// Logs (and journals) the task the can was last reset for, if it was.
+///
+/// Call it once the loggers are running, so the fault makes it into the logs.
+pub fn report_last_reset() {
+    if let Some(task) = take_starved() {
+        error!("Reset by the watchdog, {task:?} had stopped");
+        journal::record_at(
+            FaultCode::new(Subsystem::Watchdog, task as u8),
+            Instant::now(),
+        );
+    }
+}
+
+#[cfg(target_arch = "xtensa")]
+#[task]
+pub async fn watchdog_supervisor(mut wdt: SupervisedWdt, config: WatchdogConfig) {
+    let mut ticker = Ticker::every(config.check_interval);
+
+    wdt.start(config.timeout.as_millis().millis());
+
+    loop {
+        ticker.next().await;
+
+        let starved =
+            critical_section::with(|cs| SUPERVISOR.borrow_ref(cs).starved(Instant::now()));
+
+        let Some(task) = starved else {
+            wdt.feed();
+            continue;
+        };
+
+        record_starved(task);
+        error!(
+            "{task:?} has stopped, resetting in {}ms",
+            config.timeout.as_millis()
+        );
+
+        // never feed the watchdog again, even if the task comes back
+        core::future::pending::<()>().await;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn at(ms: u64) -> Instant {
+        Instant::from_millis(ms)
+    }
+
+    #[test]
+    fn nothing_starves_while_tasks_beat() {
+        let mut supervisor = Supervisor::new();
+        assert_eq!(supervisor.starved(at(10_000)), None);
+
+        supervisor.register(TaskId::Mpu, Duration::from_millis(500), at(0));
+        supervisor.register(TaskId::Radio, Duration::from_secs(2), at(0));
+
+        // right on the deadline is still in time
+        assert_eq!(supervisor.starved(at(500)), None);
+
+        supervisor.beat(TaskId::Mpu, at(400));
+        assert_eq!(supervisor.starved(at(900)), None);
+        assert_eq!(supervisor.starved(at(901)), Some(TaskId::Mpu));
+    }
+
+    #[test]
+    fn blames_the_most_overdue_task() {
+        let mut supervisor = Supervisor::new();
+
+        // due by 1s
+        supervisor.register(TaskId::Mpu, Duration::from_secs(1), at(0));
+        // due by 700ms, so further overdue than the MPU6050 ever after
+        supervisor.register(TaskId::Gps, Duration::from_millis(200), at(500));
+        // due by 1.5s
+        supervisor.register(TaskId::Sd, Duration::from_millis(500), at(1_000));
+
+        assert_eq!(supervisor.starved(at(800)), Some(TaskId::Gps));
+        assert_eq!(supervisor.starved(at(2_000)), Some(TaskId::Gps));
+
+        supervisor.beat(TaskId::Gps, at(2_000));
+        assert_eq!(supervisor.starved(at(2_000)), Some(TaskId::Mpu));
+    }
+
+    #[test]
+    fn unregistered_tasks_are_not_supervised() {
+        let mut supervisor = Supervisor::new();
+
+        supervisor.register(TaskId::Display, Duration::from_millis(100), at(0));
+        supervisor.unregister(TaskId::Display);
+        assert_eq!(supervisor.starved(at(10_000)), None);
+
+        // beating doesn't register a task either
+        supervisor.beat(TaskId::Bme, at(0));
+        assert_eq!(supervisor.starved(at(10_000)), None);
+    }
+
+    #[test]
+    fn dropping_the_heartbeat_unregisters() {
+        let later = || Instant::now() + Duration::from_secs(10);
+        let starved = |now| critical_section::with(|cs| SUPERVISOR.borrow_ref(cs).starved(now));
+
+        let heartbeat = register(TaskId::Health, Duration::from_millis(100));
+        assert_eq!(heartbeat.task(), TaskId::Health);
+        assert_eq!(starved(later()), Some(TaskId::Health));
+
+        drop(heartbeat);
+        assert_eq!(starved(later()), None);
+    }
+}
@@ -0,0 +1,918 @@
+//! The telemetry frames sent from the cansat to the ground station.
+//!
+//! This crate is `no_std`, so the same types are used to encode frames on the
+//! can and to decode them on the ground.
+//!
+//! Every frame is laid out as:
+//!
+//! | bytes      | contents                                          |
+//! |------------|---------------------------------------------------|
+//! | 0..2       | [SYNC]                                            |
+//! | 2          | [VERSION] of the format                           |
+//! | 3          | [PacketType]                                      |
+//! | 4          | length of the payload                             |
+//! | 5..7       | sequence number, counting up by one every frame   |
+//! | 7..11      | mission time, in milliseconds since boot          |
+//! | 11..       | the payload                                       |
+//! | last 2     | CRC-16 of everything after the sync word          |
+//!
+//! All values are little endian. Sensor readings are sent as fixed point
+//! integers to keep frames small - the units of each are listed on
+//! [StateFrame].
+
+#![no_std]
+
+#[cfg(feature = "std")]
+extern crate std;
+
+use core::fmt;
+
+use crc::{Crc, CRC_16_IBM_3740};
+
+/// Marks the start of every frame.
+pub const SYNC: [u8; 2] = [0xCA, 0x57];
+/// Bumped whenever the layout of a frame or payload changes.
+pub const VERSION: u8 = 1;
+
+pub const HEADER_LEN: usize = 11;
+pub const CRC_LEN: usize = 2;
+pub const MAX_PAYLOAD_LEN: usize = u8::MAX as usize;
+pub const MAX_FRAME_LEN: usize = HEADER_LEN + MAX_PAYLOAD_LEN + CRC_LEN;
+
+const CRC: Crc<u16> = Crc::<u16>::new(&CRC_16_IBM_3740);
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum FrameError {
+    /// The buffer being encoded into is too small for the frame
+    BufferTooSmall,
+    /// Not enough bytes have been received for a whole frame yet
+    Incomplete,
+    /// The frame doesn't start with [SYNC]
+    BadSync,
+    /// The frame was encoded with a different version of this format
+    UnsupportedVersion(u8),
+    UnknownPacketType(u8),
+    /// The payload is the wrong length for its packet type
+    BadLength,
+    /// The frame was corrupted on the way
+    BadCrc,
+}
+
+impl fmt::Display for FrameError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::BufferTooSmall => write!(f, "buffer too small for the frame"),
+            Self::Incomplete => write!(f, "incomplete frame"),
+            Self::BadSync => write!(f, "missing sync word"),
+            Self::UnsupportedVersion(version) => write!(f, "unsupported version {version}"),
+            Self::UnknownPacketType(packet_type) => {
+                write!(f, "unknown packet type {packet_type}")
+            }
+            Self::BadLength => write!(f, "wrong payload length"),
+            Self::BadCrc => write!(f, "CRC mismatch"),
+        }
+    }
+}
+
+#[cfg(feature = "std")]
+impl std::error::Error for FrameError {}
+
+/// What a frame's payload holds.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[repr(u8)]
+pub enum PacketType {
+    /// A [StateFrame]
+    State = 1,
+}
+
+impl PacketType {
+    pub fn from_u8(value: u8) -> Option<Self> {
+        match value {
+            1 => Some(Self::State),
+            _ => None,
+        }
+    }
+}
+
+/// Readings from the MPU6050.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct Imu {
+    /// acceleration in g, sent in milli-g
+    pub acc: [f32; 3],
+    /// angular rate in degrees per second, sent in tenths of a degree per
+    /// second
+    pub gyro: [f32; 3],
+}
+
+/// Readings from the BME280, and the altitude worked out from them.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct Baro {
+    /// pressure in pascals, sent in whole pascals
+    pub pressure: f32,
+    /// temperature in degrees celsius, sent in hundredths of a degree
+    pub temperature: f32,
+    /// altitude above the ground in metres, sent in centimetres
+    pub altitude: f32,
+}
+
+/// A position from the GPS.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct Gps {
+    /// latitude in degrees, sent in 10^-7 degrees
+    pub latitude: f64,
+    /// longitude in degrees, sent in 10^-7 degrees
+    pub longitude: f64,
+    /// altitude above mean sea level in metres, sent in centimetres
+    pub altitude: f32,
+    /// how many satellites were used in the fix
+    pub satellites: u8,
+}
+
+/// The combined state of the can, sent several times a second.
+///
+/// Readings which failed (or sensors which aren't fitted) are [None], and
+/// sent as zeros with their bit cleared in the validity flags.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct StateFrame {
+    pub imu: Option<Imu>,
+    pub baro: Option<Baro>,
+    /// magnetic field in gauss, sent in milli-gauss
+    pub mag: Option<[f32; 3]>,
+    pub gps: Option<Gps>,
+    /// the firmware's `FlightPhase`, as a `u8` - see [phase_name]
+    pub phase: u8,
+    /// the firmware's health flags, one bit per subsystem
+    pub health: u16,
+}
+
+const IMU_VALID: u8 = 1 << 0;
+const BARO_VALID: u8 = 1 << 1;
+const MAG_VALID: u8 = 1 << 2;
+const GPS_VALID: u8 = 1 << 3;
+
+const STATE_PAYLOAD_LEN: usize = 1 + 1 + 2 + 6 + 6 + 4 + 2 + 4 + 6 + 4 + 4 + 4 + 1;
+
+/// The name of a [StateFrame::phase].
+pub fn phase_name(phase: u8) -> &'static str {
+    match phase {
+        0 => "PreLaunch",
+        1 => "Ascent",
+        2 => "Apogee",
+        3 => "Descent",
+        4 => "Landed",
+        _ => "Unknown",
+    }
+}
+
+/// Rounds `value * scale` to the nearest integer, saturating at the limits of
+/// `i32` (and mapping NaN to 0).
+fn fixed(value: f64, scale: f64) -> i32 {
+    let scaled = value * scale;
+    let rounded = if scaled < 0.0 {
+        scaled - 0.5
+    } else {
+        scaled + 0.5
+    };
+    // `as` saturates, and turns NaN into 0
+    rounded as i32
+}
+
+fn fixed_i16(value: f32, scale: f32) -> i16 {
+    fixed(value as f64, scale as f64).clamp(i16::MIN as i32, i16::MAX as i32) as i16
+}
+
+/// Appends values to a payload being encoded.
+struct Writer<'a> {
+    buf: &'a mut [u8],
+    len: usize,
+}
+
+impl Writer<'_> {
+    fn bytes(&mut self, bytes: &[u8]) {
+        self.buf[self.len..self.len + bytes.len()].copy_from_slice(bytes);
+        self.len += bytes.len();
+    }
+
+    fn i16s(&mut self, values: &[f32], scale: f32) {
+        for &value in values {
+            self.bytes(&fixed_i16(value, scale).to_le_bytes());
+        }
+    }
+
+    fn i32(&mut self, value: f64, scale: f64) {
+        self.bytes(&fixed(value, scale).to_le_bytes());
+    }
+}
+
+/// Takes values back out of a payload, in the order they were written.
+struct Reader<'a> {
+    bytes: &'a [u8],
+}
+
+impl Reader<'_> {
+    fn array<const N: usize>(&mut self) -> Result<[u8; N], FrameError> {
+        let (value, rest) = self
+            .bytes
+            .split_first_chunk::<N>()
+            .ok_or(FrameError::BadLength)?;
+        self.bytes = rest;
+        Ok(*value)
+    }
+
+    fn u8(&mut self) -> Result<u8, FrameError> {
+        self.array::<1>().map(|[value]| value)
+    }
+
+    fn u16(&mut self) -> Result<u16, FrameError> {
+        self.array().map(u16::from_le_bytes)
+    }
+
+    fn u32(&mut self) -> Result<u32, FrameError> {
+        self.array().map(u32::from_le_bytes)
+    }
+
+    fn i16s<const N: usize>(&mut self, scale: f32) -> Result<[f32; N], FrameError> {
+        let mut values = [0.0; N];
+
+        for value in &mut values {
+            *value = i16::from_le_bytes(self.array()?) as f32 / scale;
+        }
+
+        Ok(values)
+    }
+
+    fn i32(&mut self, scale: f64) -> Result<f64, FrameError> {
+        Ok(i32::from_le_bytes(self.array()?) as f64 / scale)
+    }
+}
+
+impl StateFrame {
+    fn encode_payload(&self, writer: &mut Writer) {
+        let flags = self.imu.map_or(0, |_| IMU_VALID)
+            | self.baro.map_or(0, |_| BARO_VALID)
+            | self.mag.map_or(0, |_| MAG_VALID)
+            | self.gps.map_or(0, |_| GPS_VALID);
+
+        writer.bytes(&[flags, self.phase]);
+        writer.bytes(&self.health.to_le_bytes());
+
+        let imu = self.imu.unwrap_or_default();
+        writer.i16s(&imu.acc, 1_000.0);
+        writer.i16s(&imu.gyro, 10.0);
+
+        let baro = self.baro.unwrap_or_default();
+        writer.bytes(&(baro.pressure.max(0.0) as u32).to_le_bytes());
+        writer.i16s(&[baro.temperature], 100.0);
+        writer.i32(baro.altitude as f64, 100.0);
+
+        writer.i16s(&self.mag.unwrap_or_default(), 1_000.0);
+
+        let gps = self.gps.unwrap_or_default();
+        writer.i32(gps.latitude, 1e7);
+        writer.i32(gps.longitude, 1e7);
+        writer.i32(gps.altitude as f64, 100.0);
+        writer.bytes(&[gps.satellites]);
+    }
+
+    fn decode_payload(payload: &[u8]) -> Result<Self, FrameError> {
+        if payload.len() != STATE_PAYLOAD_LEN {
+            return Err(FrameError::BadLength);
+        }
+
+        let mut reader = Reader { bytes: payload };
+
+        let flags = reader.u8()?;
+        let phase = reader.u8()?;
+        let health = reader.u16()?;
+
+        let imu = Imu {
+            acc: reader.i16s(1_000.0)?,
+            gyro: reader.i16s(10.0)?,
+        };
+
+        let pressure = reader.u32()? as f32;
+        let [temperature] = reader.i16s(100.0)?;
+        let baro = Baro {
+            pressure,
+            temperature,
+            altitude: reader.i32(100.0)? as f32,
+        };
+
+        let mag = reader.i16s(1_000.0)?;
+
+        let gps = Gps {
+            latitude: reader.i32(1e7)?,
+            longitude: reader.i32(1e7)?,
+            altitude: reader.i32(100.0)? as f32,
+            satellites: reader.u8()?,
+        };
+
+        let valid = |flag: u8| flags & flag != 0;
+
+        Ok(Self {
+            imu: valid(IMU_VALID).then_some(imu),
+            baro: valid(BARO_VALID).then_some(baro),
+            mag: valid(MAG_VALID).then_some(mag),
+            gps: valid(GPS_VALID).then_some(gps),
+            phase,
+            health,
+        })
+    }
+}
+
+/// The contents of a frame.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Packet {
+    State(StateFrame),
+}
+
+impl Packet {
+    pub fn packet_type(&self) -> PacketType {
+        match self {
+            Self::State(_) => PacketType::State,
+        }
+    }
+}
+
+/// A single telemetry frame.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Frame {
+    /// Counts up by one every frame (wrapping around), so the ground station
+    /// can tell how many were lost.
+    pub seq: u16,
+    /// milliseconds since the can booted
+    pub time_ms: u32,
+    pub packet: Packet,
+}
+
+impl Frame {
+    /// Writes the frame into the start of `buf`, returning its length.
+    pub fn encode(&self, buf: &mut [u8]) -> Result<usize, FrameError> {
+        let payload_len = match self.packet {
+            Packet::State(_) => STATE_PAYLOAD_LEN,
+        };
+        let len = HEADER_LEN + payload_len + CRC_LEN;
+
+        if buf.len() < len {
+            return Err(FrameError::BufferTooSmall);
+        }
+
+        let mut writer = Writer { buf, len: 0 };
+
+        writer.bytes(&SYNC);
+        writer.bytes(&[VERSION, self.packet.packet_type() as u8, payload_len as u8]);
+        writer.bytes(&self.seq.to_le_bytes());
+        writer.bytes(&self.time_ms.to_le_bytes());
+
+        match &self.packet {
+            Packet::State(state) => state.encode_payload(&mut writer),
+        }
+
+        let crc = CRC.checksum(&writer.buf[SYNC.len()..writer.len]);
+        writer.bytes(&crc.to_le_bytes());
+
+        Ok(writer.len)
+    }
+
+    /// Reads the frame at the start of `bytes`, returning it along with its
+    /// length.
+    pub fn decode(bytes: &[u8]) -> Result<(Self, usize), FrameError> {
+        let len = frame_len(bytes)?;
+
+        if bytes.len() < len {
+            return Err(FrameError::Incomplete);
+        }
+
+        let (data, crc) = bytes[SYNC.len()..len].split_at(len - SYNC.len() - CRC_LEN);
+
+        if CRC.checksum(data).to_le_bytes() != crc {
+            return Err(FrameError::BadCrc);
+        }
+
+        let mut reader = Reader { bytes: data };
+
+        let version = reader.u8()?;
+        if version != VERSION {
+            return Err(FrameError::UnsupportedVersion(version));
+        }
+
+        let packet_type = reader.u8()?;
+        let packet_type =
+            PacketType::from_u8(packet_type).ok_or(FrameError::UnknownPacketType(packet_type))?;
+        let _payload_len = reader.u8()?;
+        let seq = reader.u16()?;
+        let time_ms = reader.u32()?;
+
+        let packet = match packet_type {
+            PacketType::State => Packet::State(StateFrame::decode_payload(reader.bytes)?),
+        };
+
+        Ok((
+            Self {
+                seq,
+                time_ms,
+                packet,
+            },
+            len,
+        ))
+    }
+}
+
+/// The length of the frame at the start of `bytes`, read from its header.
+fn frame_len(bytes: &[u8]) -> Result<usize, FrameError> {
+    if bytes.len() < HEADER_LEN {
+        if !SYNC.starts_with(&bytes[..bytes.len().min(SYNC.len())]) {
+            return Err(FrameError::BadSync);
+        }
+        return Err(FrameError::Incomplete);
+    }
+
+    if bytes[..SYNC.len()] != SYNC {
+        return Err(FrameError::BadSync);
+    }
+
+    Ok(HEADER_LEN + bytes[4] as usize + CRC_LEN)
+}
+
+/// Stamps outgoing packets with sequence numbers, on the can.
+#[derive(Clone, Debug, Default)]
+pub struct Encoder {
+    next_seq: u16,
+}
+
+impl Encoder {
+    pub const fn new() -> Self {
+        Self { next_seq: 0 }
+    }
+
+    /// Encodes `packet` as the next frame into `buf`, returning its length.
+    ///
+    /// The sequence number is used up even if `buf` is too small, so the
+    /// ground station sees the frame as lost.
+    pub fn encode(
+        &mut self,
+        time_ms: u32,
+        packet: Packet,
+        buf: &mut [u8],
+    ) -> Result<usize, FrameError> {
+        let frame = Frame {
+            seq: self.next_seq,
+            time_ms,
+            packet,
+        };
+
+        self.next_seq = self.next_seq.wrapping_add(1);
+
+        frame.encode(buf)
+    }
+}
+
+/// Picks frames out of a stream of bytes, such as from a serial port or a
+/// recording.
+///
+/// Bytes are fed in with [Decoder::push], and frames taken out with
+/// [Decoder::next_frame]. Anything that isn't a valid frame is skipped over a
+/// byte at a time until the next sync word is found.
+pub struct Decoder {
+    buf: [u8; MAX_FRAME_LEN],
+    len: usize,
+}
+
+impl Decoder {
+    pub const fn new() -> Self {
+        Self {
+            buf: [0; MAX_FRAME_LEN],
+            len: 0,
+        }
+    }
+
+    /// Adds a received byte.
+    ///
+    /// The buffer can always hold a whole frame, so this only overflows if
+    /// [Decoder::next_frame] isn't called - in that case the oldest byte is
+    /// dropped.
+    pub fn push(&mut self, byte: u8) {
+        if self.len == self.buf.len() {
+            self.discard(1);
+        }
+
+        self.buf[self.len] = byte;
+        self.len += 1;
+    }
+
+    /// The next frame in the buffered bytes, an error for a corrupt frame
+    /// (which is then skipped), or [None] if more bytes are needed.
+    pub fn next_frame(&mut self) -> Option<Result<Frame, FrameError>> {
+        loop {
+            match Frame::decode(&self.buf[..self.len]) {
+                Ok((frame, len)) => {
+                    self.discard(len);
+                    return Some(Ok(frame));
+                }
+                Err(FrameError::Incomplete) => return None,
+                // skip to the next possible sync word
+                Err(FrameError::BadSync) => self.discard(1),
+                Err(e) => {
+                    self.discard(1);
+                    return Some(Err(e));
+                }
+            }
+        }
+    }
+
+    /// How many bytes are waiting to be decoded.
+    pub fn buffered(&self) -> usize {
+        self.len
+    }
+
+    fn discard(&mut self, n: usize) {
+        self.buf.copy_within(n..self.len, 0);
+        self.len -= n;
+    }
+}
+
+impl Default for Decoder {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn state() -> StateFrame {
+        StateFrame {
+            imu: Some(Imu {
+                acc: [0.012, -0.981, 9.5],
+                gyro: [-250.3, 0.1, 1000.0],
+            }),
+            baro: Some(Baro {
+                pressure: 101_325.0,
+                temperature: -12.34,
+                altitude: 1234.56,
+            }),
+            mag: Some([0.215, -0.431, 0.007]),
+            gps: Some(Gps {
+                latitude: 52.205_337_5,
+                longitude: -0.121_817_4,
+                altitude: 15_432.1,
+                satellites: 11,
+            }),
+            phase: 3,
+            health: 0x01ff,
+        }
+    }
+
+    fn frame(seq: u16, state: StateFrame) -> Frame {
+        Frame {
+            seq,
+            time_ms: 123_456_789,
+            packet: Packet::State(state),
+        }
+    }
+
+    fn encode(frame: &Frame) -> ([u8; MAX_FRAME_LEN], usize) {
+        let mut buf = [0; MAX_FRAME_LEN];
+        let len = frame.encode(&mut buf).unwrap();
+        (buf, len)
+    }
+
+    fn decode_state(bytes: &[u8]) -> StateFrame {
+        match Frame::decode(bytes).unwrap().0.packet {
+            Packet::State(state) => state,
+        }
+    }
+
+    /// The state as it comes out the other side, rounded to the precision it
+    /// is sent at.
+    fn quantised(state: StateFrame) -> StateFrame {
+        let (buf, len) = encode(&frame(0, state));
+        decode_state(&buf[..len])
+    }
+
+    /// Recalculates the CRC of a frame after its contents have been changed.
+    fn reseal(buf: &mut [u8], len: usize) {
+        let crc = CRC.checksum(&buf[SYNC.len()..len - CRC_LEN]);
+        buf[len - CRC_LEN..len].copy_from_slice(&crc.to_le_bytes());
+    }
+
+    fn assert_close(a: f64, b: f64, tolerance: f64) {
+        assert!(
+            (a - b).abs() <= tolerance,
+            "{a} != {b} (within {tolerance})"
+        );
+    }
+
+    /// Feeds `bytes` into `decoder` a byte at a time, as the ground station
+    /// does, collecting what comes out.
+    fn feed(
+        decoder: &mut Decoder,
+        bytes: &[u8],
+        frames: &mut [Option<Frame>; 16],
+        errors: &mut usize,
+    ) {
+        for &byte in bytes {
+            decoder.push(byte);
+
+            while let Some(result) = decoder.next_frame() {
+                match result {
+                    Ok(frame) => {
+                        let slot = frames.iter_mut().find(|slot| slot.is_none()).unwrap();
+                        *slot = Some(frame);
+                    }
+                    Err(_) => *errors += 1,
+                }
+            }
+        }
+    }
+
+    fn seqs(frames: &[Option<Frame>; 16]) -> impl Iterator<Item = u16> + '_ {
+        frames.iter().flatten().map(|frame| frame.seq)
+    }
+
+    #[test]
+    fn round_trips_every_field() {
+        let sent = frame(0xbeef, state());
+        let (buf, len) = encode(&sent);
+
+        assert_eq!(len, HEADER_LEN + STATE_PAYLOAD_LEN + CRC_LEN);
+
+        let (received, decoded_len) = Frame::decode(&buf[..len]).unwrap();
+        assert_eq!(decoded_len, len);
+        assert_eq!(received.seq, sent.seq);
+        assert_eq!(received.time_ms, sent.time_ms);
+
+        let Packet::State(received) = received.packet;
+        let sent = state();
+
+        assert_eq!(received.phase, sent.phase);
+        assert_eq!(received.health, sent.health);
+
+        let (imu, sent_imu) = (received.imu.unwrap(), sent.imu.unwrap());
+        for axis in 0..3 {
+            assert_close(imu.acc[axis] as f64, sent_imu.acc[axis] as f64, 0.0005);
+            assert_close(imu.gyro[axis] as f64, sent_imu.gyro[axis] as f64, 0.05);
+        }
+
+        let (baro, sent_baro) = (received.baro.unwrap(), sent.baro.unwrap());
+        assert_close(baro.pressure as f64, sent_baro.pressure as f64, 0.5);
+        assert_close(baro.temperature as f64, sent_baro.temperature as f64, 0.005);
+        assert_close(baro.altitude as f64, sent_baro.altitude as f64, 0.005);
+
+        let (mag, sent_mag) = (received.mag.unwrap(), sent.mag.unwrap());
+        for axis in 0..3 {
+            assert_close(mag[axis] as f64, sent_mag[axis] as f64, 0.0005);
+        }
+
+        let (gps, sent_gps) = (received.gps.unwrap(), sent.gps.unwrap());
+        assert_close(gps.latitude, sent_gps.latitude, 0.5e-7);
+        assert_close(gps.longitude, sent_gps.longitude, 0.5e-7);
+        assert_close(gps.altitude as f64, sent_gps.altitude as f64, 0.005);
+        assert_eq!(gps.satellites, sent_gps.satellites);
+    }
+
+    #[test]
+    fn round_trips_missing_sections() {
+        let empty = StateFrame::default();
+        let (buf, len) = encode(&frame(0, empty));
+        assert_eq!(decode_state(&buf[..len]), empty);
+
+        // each section is flagged on its own
+        let only_gps = StateFrame {
+            imu: None,
+            baro: None,
+            mag: None,
+            ..state()
+        };
+        let (buf, len) = encode(&frame(1, only_gps));
+        let received = decode_state(&buf[..len]);
+
+        assert_eq!(received.imu, None);
+        assert_eq!(received.baro, None);
+        assert_eq!(received.mag, None);
+        assert!(received.gps.is_some());
+    }
+
+    #[test]
+    fn saturates_out_of_range_values() {
+        let extreme = StateFrame {
+            imu: Some(Imu {
+                acc: [100.0, -100.0, f32::NAN],
+                gyro: [1e6, -1e6, f32::INFINITY],
+            }),
+            baro: Some(Baro {
+                pressure: -5.0,
+                temperature: 1000.0,
+                altitude: 1e9,
+            }),
+            mag: None,
+            gps: Some(Gps {
+                latitude: 1e9,
+                longitude: -1e9,
+                altitude: f32::NAN,
+                satellites: u8::MAX,
+            }),
+            ..StateFrame::default()
+        };
+
+        let (buf, len) = encode(&frame(0, extreme));
+        let received = decode_state(&buf[..len]);
+
+        let imu = received.imu.unwrap();
+        assert_eq!(imu.acc, [32.767, -32.768, 0.0]);
+        assert_eq!(imu.gyro, [3276.7, -3276.8, 3276.7]);
+
+        let baro = received.baro.unwrap();
+        assert_eq!(baro.pressure, 0.0);
+        assert_eq!(baro.temperature, 327.67);
+        assert_close(baro.altitude as f64, i32::MAX as f64 / 100.0, 1.0);
+
+        let gps = received.gps.unwrap();
+        assert_close(gps.latitude, i32::MAX as f64 / 1e7, 1e-7);
+        assert_close(gps.longitude, i32::MIN as f64 / 1e7, 1e-7);
+        assert_eq!(gps.altitude, 0.0);
+        assert_eq!(gps.satellites, u8::MAX);
+    }
+
+    #[test]
+    fn encoder_counts_up_and_wraps() {
+        let mut encoder = Encoder { next_seq: u16::MAX };
+        let mut buf = [0; MAX_FRAME_LEN];
+
+        for expected in [u16::MAX, 0, 1] {
+            let len = encoder
+                .encode(0, Packet::State(StateFrame::default()), &mut buf)
+                .unwrap();
+            assert_eq!(Frame::decode(&buf[..len]).unwrap().0.seq, expected);
+        }
+
+        // a failed encode still uses up its sequence number
+        assert_eq!(
+            encoder.encode(0, Packet::State(StateFrame::default()), &mut buf[..10]),
+            Err(FrameError::BufferTooSmall)
+        );
+        let len = encoder
+            .encode(0, Packet::State(StateFrame::default()), &mut buf)
+            .unwrap();
+        assert_eq!(Frame::decode(&buf[..len]).unwrap().0.seq, 3);
+    }
+
+    #[test]
+    fn every_single_bit_flip_is_caught() {
+        let (buf, len) = encode(&frame(42, state()));
+
+        // the sync word isn't covered by the CRC, but is checked on its own
+        for byte in 0..len {
+            for bit in 0..8 {
+                let mut corrupted = buf;
+                corrupted[byte] ^= 1 << bit;
+
+                let result = Frame::decode(&corrupted[..len]);
+
+                match byte {
+                    0 | 1 => assert_eq!(result, Err(FrameError::BadSync)),
+                    // a longer frame than was received
+                    4 if corrupted[4] > buf[4] => {
+                        assert_eq!(result, Err(FrameError::Incomplete))
+                    }
+                    _ => assert_eq!(result, Err(FrameError::BadCrc), "byte {byte} bit {bit}"),
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn rejects_other_versions() {
+        let (mut buf, len) = encode(&frame(0, state()));
+        buf[2] = VERSION + 1;
+        reseal(&mut buf, len);
+
+        assert_eq!(
+            Frame::decode(&buf[..len]),
+            Err(FrameError::UnsupportedVersion(VERSION + 1))
+        );
+    }
+
+    #[test]
+    fn rejects_unknown_packet_types() {
+        let (mut buf, len) = encode(&frame(0, state()));
+        buf[3] = 0xee;
+        reseal(&mut buf, len);
+
+        assert_eq!(
+            Frame::decode(&buf[..len]),
+            Err(FrameError::UnknownPacketType(0xee))
+        );
+    }
+
+    #[test]
+    fn rejects_the_wrong_payload_length() {
+        let (mut buf, len) = encode(&frame(0, state()));
+
+        // a valid frame one byte shorter than a state payload
+        buf[4] -= 1;
+        let len = len - 1;
+        buf.copy_within(len + 1 - CRC_LEN..len + 1, len - CRC_LEN);
+        reseal(&mut buf, len);
+
+        assert_eq!(Frame::decode(&buf[..len]), Err(FrameError::BadLength));
+    }
+
+    #[test]
+    fn waits_for_the_rest_of_a_truncated_frame() {
+        let (buf, len) = encode(&frame(0, state()));
+
+        for truncated in [0, 1, 2, HEADER_LEN - 1, HEADER_LEN, len - 1] {
+            assert_eq!(
+                Frame::decode(&buf[..truncated]),
+                Err(FrameError::Incomplete),
+                "{truncated} bytes"
+            );
+        }
+
+        assert_eq!(Frame::decode(&[0xCA, 0x00]), Err(FrameError::BadSync));
+        assert_eq!(
+            Frame::encode(&frame(0, state()), &mut [0; HEADER_LEN]),
+            Err(FrameError::BufferTooSmall)
+        );
+    }
+
+    #[test]
+    fn decoder_resyncs_through_garbage() {
+        let mut decoder = Decoder::new();
+        let mut frames = [None; 16];
+        let mut errors = 0;
+
+        // including a partial sync word, and a sync word with nothing after it
+        let garbage = [0x00, 0xff, 0xCA, 0x12, 0x57, 0xCA, 0xCA, 0x57, 0x01, 0x02];
+
+        feed(&mut decoder, &garbage, &mut frames, &mut errors);
+
+        for seq in 0..8 {
+            let (buf, len) = encode(&frame(seq, state()));
+            feed(&mut decoder, &buf[..len], &mut frames, &mut errors);
+            feed(
+                &mut decoder,
+                &garbage[..seq as usize % 6],
+                &mut frames,
+                &mut errors,
+            );
+        }
+
+        // the sync word in the garbage looks like the start of a frame, which
+        // is only found to be corrupt once enough bytes have arrived - but
+        // nothing after it is lost
+        assert_eq!(errors, 1);
+        assert!(seqs(&frames).eq(0..8));
+        assert_eq!(frames[0].unwrap().packet, Packet::State(quantised(state())));
+    }
+
+    #[test]
+    fn decoder_joins_split_frames() {
+        let mut decoder = Decoder::new();
+        let mut frames = [None; 16];
+        let mut errors = 0;
+
+        let (first, first_len) = encode(&frame(7, state()));
+        let (second, second_len) = encode(&frame(8, StateFrame::default()));
+
+        feed(&mut decoder, &first[..5], &mut frames, &mut errors);
+        assert_eq!(decoder.next_frame(), None);
+
+        feed(&mut decoder, &first[5..first_len], &mut frames, &mut errors);
+        feed(&mut decoder, &second[..1], &mut frames, &mut errors);
+        feed(
+            &mut decoder,
+            &second[1..second_len],
+            &mut frames,
+            &mut errors,
+        );
+
+        assert!(seqs(&frames).eq([7, 8]));
+        assert_eq!(errors, 0);
+        assert_eq!(decoder.buffered(), 0);
+    }
+
+    #[test]
+    fn decoder_recovers_from_a_corrupted_length() {
+        let mut decoder = Decoder::new();
+        let mut frames = [None; 16];
+        let mut errors = 0;
+
+        // claims to be much longer than it is, so swallows the frames after it
+        // until there are enough bytes to find its CRC is wrong
+        let (mut corrupted, len) = encode(&frame(0, state()));
+        corrupted[4] = u8::MAX;
+        feed(&mut decoder, &corrupted[..len], &mut frames, &mut errors);
+
+        for seq in 1..=8 {
+            let (buf, len) = encode(&frame(seq, state()));
+            feed(&mut decoder, &buf[..len], &mut frames, &mut errors);
+        }
+
+        assert_eq!(errors, 1);
+        assert!(seqs(&frames).eq(1..=8));
+        assert_eq!(decoder.buffered(), 0);
+    }
+}